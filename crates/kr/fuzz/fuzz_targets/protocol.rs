@@ -0,0 +1,20 @@
+//! Parse anything as what the phone sends: responses, requests and the wire messages around them.
+//! What parses has to serialize and parse again to the same JSON
+#![no_main]
+
+use akr::protocol::{Request, Response, WireMessage};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(response) = Response::parse(data) {
+        let json = serde_json::to_value(&response).unwrap();
+        let again = Response::parse(&serde_json::to_vec(&response).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(&again).unwrap(), json);
+    }
+    if let Ok(request) = serde_json::from_slice::<Request>(data) {
+        let json = serde_json::to_value(&request).unwrap();
+        let again: Request = serde_json::from_slice(&serde_json::to_vec(&request).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(&again).unwrap(), json);
+    }
+    let _ = WireMessage::new(data.to_vec());
+});
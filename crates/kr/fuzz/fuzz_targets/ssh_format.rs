@@ -0,0 +1,19 @@
+//! Parse anything as the keys and certificates akr reads: private key blobs of both types, key files,
+//! public keys and certificates
+#![no_main]
+
+use akr::ssh_format::{parse_openssh_sk_private_key, SkKeyType, SshFido2KeyPairHandle};
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    for key_type in [SkKeyType::EcdsaP256, SkKeyType::Ed25519] {
+        let _ = SshFido2KeyPairHandle::parse_private_key_blob(key_type, &mut Cursor::new(data.to_vec()));
+    }
+    let _ = SshFido2KeyPairHandle::parse_public_key_from_certificate(data);
+    let _ = SshFido2KeyPairHandle::parse_key_from_public_key(data.to_vec());
+    let _ = SshFido2KeyPairHandle::parse_application_from_public_key(data.to_vec());
+    if let Ok(armored) = std::str::from_utf8(data) {
+        let _ = parse_openssh_sk_private_key(armored);
+    }
+});
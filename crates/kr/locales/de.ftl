@@ -0,0 +1,27 @@
+# Die Meldungen von akr auf Deutsch, Übersetzungen der Ids von en.ftl
+
+## notifications about sign requests
+notify-approve = Auf dem Telefon bestätigen: { $what }
+notify-waiting = Warte auf Bestätigung auf { $devices }
+notify-approved = Bestätigt: { $what }
+notify-denied = Auf dem Telefon abgelehnt: { $what }
+notify-timed-out = Nicht innerhalb von { $secs }s bestätigt: { $what }
+notify-throttled = Zu viele Signaturanfragen für { $what }
+notify-throttled-body = Sie werden die nächsten { $secs }s abgelehnt
+
+## asking for a local confirmation before a sign request goes out
+confirm-reason = die Signaturanfrage von { $what } an dein Telefon schicken
+confirm-prompt = Soll akr { $reason }?
+
+## akr pair
+pair-scan = Scanne den QR-Code oben, um dein Gerät zu koppeln...
+pair-scan-renew = Scanne den QR-Code oben mit der neu installierten App, um sie wieder zu koppeln...
+pair-scan-another = Du bist bereits mit { $devices } gekoppelt.
+    Scanne den QR-Code oben mit einem weiteren Gerät, um es hinzuzufügen
+pair-link = Scannen klappt nicht? Öffne diesen Link auf deinem Telefon/Tablet: { $link }
+pair-answered = Dein Telefon/Tablet hat geantwortet, die Kopplung wird abgeschlossen...
+pair-done = Erfolgreich gekoppelt mit
+pair-kept = Deine { $keys } Schlüssel wurden behalten, { $replaced } alte Kopplung(en) ersetzt.
+
+## errors
+error = Fehler:
@@ -0,0 +1,28 @@
+# The messages of akr, in English. Every other locale translates these ids, what it lacks is shown in English.
+# `akr translations --missing <locale>` lists them.
+
+## notifications about sign requests
+notify-approve = Approve on your phone: { $what }
+notify-waiting = Waiting for approval on { $devices }
+notify-approved = Approved: { $what }
+notify-denied = Denied on your phone: { $what }
+notify-timed-out = Not approved within { $secs }s: { $what }
+notify-throttled = Too many sign requests for { $what }
+notify-throttled-body = Refusing them for the next { $secs }s
+
+## asking for a local confirmation before a sign request goes out
+confirm-reason = send the sign request of { $what } to your phone
+confirm-prompt = Do you want akr to { $reason }?
+
+## akr pair
+pair-scan = Scan the above QR code to pair your device...
+pair-scan-renew = Scan the above QR code with the reinstalled app to pair it again...
+pair-scan-another = You are already paired with { $devices }.
+    To add another device, scan the above QR code with it
+pair-link = Can't scan it? Open this link on your phone/tablet: { $link }
+pair-answered = Your phone/tablet answered, finishing the pairing...
+pair-done = Paired successfully with
+pair-kept = Kept your { $keys } keys, replaced { $replaced } old pairing(s).
+
+## errors
+error = Error:
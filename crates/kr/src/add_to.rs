@@ -0,0 +1,189 @@
+//! `akr add-to github|gitlab` adds one of your public keys to your account, right after `akr generate`
+//!
+//! The personal access token comes from GITHUB_TOKEN (or GH_TOKEN) and GITLAB_TOKEN, then from stdin when
+//! it's not a terminal, else pinentry asks for it. It needs the "write:public_key" scope on GitHub
+//! ("write:ssh_signing_key" with `--signing`) and "api" on GitLab. `--url` is for GitHub Enterprise's API,
+//! e.g. "https://github.example.com/api/v3", or a GitLab of your own.
+
+use crate::cli::AddToArgs;
+use crate::error::Error;
+use crate::prompt::PasswordPrompt;
+use crate::{output, sshsig, transport};
+use ansi_term::Colour::Green;
+use std::io::{IsTerminal, Read};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Forge {
+    GitHub,
+    GitLab,
+}
+
+impl FromStr for Forge {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "github" => Ok(Forge::GitHub),
+            "gitlab" => Ok(Forge::GitLab),
+            _ => Err(Error::UnknownForge(s.to_string())),
+        }
+    }
+}
+
+impl Forge {
+    pub fn name(self) -> &'static str {
+        match self {
+            Forge::GitHub => "GitHub",
+            Forge::GitLab => "GitLab",
+        }
+    }
+
+    fn token_vars(self) -> &'static [&'static str] {
+        match self {
+            Forge::GitHub => &["GITHUB_TOKEN", "GH_TOKEN"],
+            Forge::GitLab => &["GITLAB_TOKEN"],
+        }
+    }
+
+    fn default_url(self) -> &'static str {
+        match self {
+            Forge::GitHub => "https://api.github.com",
+            Forge::GitLab => "https://gitlab.com",
+        }
+    }
+}
+
+pub async fn run(args: AddToArgs) -> Result<(), Error> {
+    let handle = sshsig::find_key_pair_handle(args.key.as_deref())?;
+    let key = handle.authorized_public_key()?;
+    let title = args
+        .title
+        .unwrap_or_else(|| format!("{} ({})", handle.key_comment(), whoami::hostname()));
+    let url = args.url.as_deref().unwrap_or(args.forge.default_url());
+    let url = url.trim_end_matches('/');
+    let token = token(args.forge)?;
+
+    let client = transport::proxy::reqwest_client()?;
+    let request = match args.forge {
+        Forge::GitHub => {
+            let endpoint = match args.signing {
+                true => "ssh_signing_keys",
+                false => "keys",
+            };
+            client
+                .post(format!("{}/user/{}", url, endpoint))
+                .bearer_auth(&token)
+                .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+                .header(reqwest::header::USER_AGENT, "akr")
+                .json(&serde_json::json!({ "title": title, "key": key }))
+        }
+        Forge::GitLab => {
+            let usage_type = match args.signing {
+                true => "signing",
+                false => "auth",
+            };
+            client
+                .post(format!("{}/api/v4/user/keys", url))
+                .header("PRIVATE-TOKEN", &token)
+                .json(&serde_json::json!({ "title": title, "key": key, "usage_type": usage_type }))
+        }
+    };
+
+    let response = request.send().await?;
+    let status = response.status();
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(Error::KeyUploadFailed(
+            args.forge.name(),
+            status.as_u16(),
+            error_message(&body),
+        ));
+    }
+
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "forge": args.forge.name(),
+            "id": body.get("id"),
+            "title": title,
+            "fingerprint": handle.fingerprint()?,
+            "signing": args.signing,
+        }));
+    }
+    println!(
+        "{} {} to your {} account as \"{}\"",
+        Green.paint("Added"),
+        handle.key_comment(),
+        args.forge.name(),
+        title
+    );
+    Ok(())
+}
+
+fn token(forge: Forge) -> Result<String, Error> {
+    let from_env = forge
+        .token_vars()
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|token| !token.trim().is_empty());
+    if let Some(token) = from_env {
+        return Ok(token.trim().to_string());
+    }
+
+    let token = match std::io::stdin().is_terminal() {
+        false => {
+            let mut token = String::new();
+            std::io::stdin().read_to_string(&mut token)?;
+            token
+        }
+        true => {
+            let mut buffer = [0u8; 256];
+            let len = PasswordPrompt::new(forge.name().to_string())
+                .with_title(format!("{} token", forge.name()), "Token:".to_string())
+                .with_description(format!(
+                    "Enter a personal access token of your {} account to add the SSH key with",
+                    forge.name()
+                ))
+                .invoke(&mut buffer)?;
+            String::from_utf8(buffer[..len].to_vec())?
+        }
+    };
+    match token.trim() {
+        "" => Err(Error::MissingToken(forge.name(), forge.token_vars()[0])),
+        token => Ok(token.to_string()),
+    }
+}
+
+/// What GitHub, e.g. `{"message": "Validation Failed", "errors": [{"message": "key is already in use"}]}`,
+/// or GitLab, e.g. `{"message": {"key": ["has already been taken"]}}`, said was wrong
+fn error_message(body: &serde_json::Value) -> String {
+    let errors = body
+        .get("errors")
+        .and_then(serde_json::Value::as_array)
+        .map(|errors| {
+            errors
+                .iter()
+                .filter_map(|error| error.get("message").and_then(serde_json::Value::as_str))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if !errors.is_empty() {
+        return errors.join(", ");
+    }
+
+    match body.get("message").or_else(|| body.get("error")) {
+        Some(serde_json::Value::String(message)) => message.clone(),
+        Some(serde_json::Value::Object(fields)) => fields
+            .iter()
+            .flat_map(|(field, problems)| {
+                let problems = problems.as_array().cloned().unwrap_or_default();
+                problems
+                    .into_iter()
+                    .filter_map(|problem| Some(format!("{} {}", field, problem.as_str()?)))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        _ => "no reason given".to_string(),
+    }
+}
@@ -0,0 +1,480 @@
+//! `akr age-plugin`, encrypting files with age to a key on your phone/tablet
+//!
+//! An identity is an X25519 key pair whose secret the phone derives with hmac-secret from a random salt. The
+//! recipient, "age1akr1...", is only the public half, so encrypting doesn't involve the phone; decrypting has it
+//! derive the secret again. The identity, "AGE-PLUGIN-AKR-1...", holds the salt, the public key and the key
+//! handle, it's useless without the phone. age runs the plugin as `age-plugin-akr`, a symlink to akr, speaking
+//! https://github.com/C2SP/C2SP/blob/main/age-plugin.md over stdin/stdout. The stanzas are wrapped like age's own
+//! X25519 ones, with another HKDF label.
+
+use crate::cli::AgePluginArgs;
+use crate::crypto;
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use crate::memory::Secret;
+use crate::secret;
+use crate::ssh_format::SshFido2KeyPairHandle;
+use crate::sshsig;
+use base64::Engine;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// The name age runs the plugin as, "age-plugin-" and the name in the recipients and identities
+const PLUGIN_PROGRAM: &str = "age-plugin-akr";
+const RECIPIENT_HRP: &str = "age1akr";
+const IDENTITY_HRP: &str = "age-plugin-akr-";
+const STANZA_TYPE: &str = "akr";
+const WRAP_LABEL: &[u8] = b"akr.age-encryption.org/v1/X25519";
+const KEY_LEN: usize = 32;
+/// the columns of a stanza body
+const BODY_LINE_LEN: usize = 64;
+
+/// An identity: which key derives the secret and from what, with the public key to match stanzas without asking
+struct Identity {
+    salt: Vec<u8>,
+    public_key: Vec<u8>,
+    key_handle: Vec<u8>,
+}
+
+impl Identity {
+    fn parse(encoded: &str) -> Option<Self> {
+        let data = bech32::decode(IDENTITY_HRP, &encoded.to_lowercase())?;
+        if data.len() <= 2 * KEY_LEN {
+            return None;
+        }
+        Some(Identity {
+            salt: data[..KEY_LEN].to_vec(),
+            public_key: data[KEY_LEN..2 * KEY_LEN].to_vec(),
+            key_handle: data[2 * KEY_LEN..].to_vec(),
+        })
+    }
+
+    fn encode(&self) -> String {
+        let data = [&self.salt[..], &self.public_key, &self.key_handle].concat();
+        bech32::encode(IDENTITY_HRP, &data).to_uppercase()
+    }
+
+    fn recipient(&self) -> String {
+        bech32::encode(RECIPIENT_HRP, &self.public_key)
+    }
+
+    fn key_pair_handle(&self) -> Result<SshFido2KeyPairHandle, Error> {
+        let mut handles = StoredIdentity::load_from_disk()?.key_pair_handles;
+        handles.extend(StoredIdentity::load_added_key_pair_handles()?);
+        handles
+            .into_iter()
+            .find(|handle| handle.key_handle == self.key_handle)
+            .ok_or(Error::UnknownKey)
+    }
+
+    /// the X25519 secret key, from the phone
+    async fn secret_key(&self) -> Result<Secret, Error> {
+        let handle = self.key_pair_handle()?;
+        let secret = secret::derive(&handle, self.salt.clone()).await?;
+        let public_key = crypto::x25519_base(&secret).ok_or(Error::NoDerivedSecret)?;
+        match public_key[..] == self.public_key[..] {
+            true => Ok(secret),
+            false => Err(Error::AgePlugin(
+                "the phone derived another key than the identity's".into(),
+            )),
+        }
+    }
+}
+
+/// `akr age-plugin`: `--generate` prints a new identity, `--age-plugin` is how age runs it
+pub async fn run(args: AgePluginArgs) -> Result<(), Error> {
+    match (args.state_machine.as_deref(), args.generate) {
+        (Some(state_machine), _) => run_state_machine(state_machine).await,
+        (None, true) => generate(args.key.as_deref()).await,
+        (None, false) => Err(Error::AgePlugin("expected --generate or --age-plugin".into())),
+    }
+}
+
+async fn generate(key: Option<&str>) -> Result<(), Error> {
+    let handle = sshsig::find_key_pair_handle(key)?;
+    let salt = crypto::random_bytes(secret::SECRET_LEN);
+    eprintln!(
+        "Approve creating an age identity with {} on your phone",
+        handle.key_comment()
+    );
+    let secret = secret::derive(&handle, salt.clone()).await?;
+    let identity = Identity {
+        salt,
+        public_key: crypto::x25519_base(&secret)
+            .ok_or(Error::NoDerivedSecret)?
+            .to_vec(),
+        key_handle: handle.key_handle.clone(),
+    };
+
+    println!(
+        "# created: {}",
+        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+    );
+    println!("# key: {}", handle.key_comment());
+    println!("# recipient: {}", identity.recipient());
+    println!("{}", identity.encode());
+    eprintln!(
+        "Encrypt with `age -r {}`, decrypt with this identity in a file given to `age -d -i`. age runs the plugin \
+         as {} on your PATH, e.g. `ln -s \"$(command -v akr)\" ~/.local/bin/{}`",
+        identity.recipient(),
+        PLUGIN_PROGRAM,
+        PLUGIN_PROGRAM
+    );
+    Ok(())
+}
+
+/// Whether age started akr through the `age-plugin-akr` symlink
+pub fn is_plugin_program() -> bool {
+    std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .and_then(|path| path.file_name().map(|name| name == PLUGIN_PROGRAM))
+        .unwrap_or(false)
+}
+
+/// `age-plugin-akr --age-plugin=<state machine>`
+pub async fn run_plugin_program() -> ! {
+    let state_machine = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.strip_prefix("--age-plugin=").map(str::to_string));
+    let result = match state_machine {
+        Some(state_machine) => run_state_machine(&state_machine).await,
+        None => Err(Error::AgePlugin(format!("{} is run by age", PLUGIN_PROGRAM))),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+async fn run_state_machine(state_machine: &str) -> Result<(), Error> {
+    let mut connection = Connection {
+        input: std::io::stdin().lock(),
+        output: std::io::stdout().lock(),
+    };
+    match state_machine {
+        "recipient-v1" => wrap(&mut connection),
+        "identity-v1" => unwrap(&mut connection).await,
+        _ => Err(Error::AgePlugin(format!(
+            "unknown state machine {}",
+            state_machine
+        ))),
+    }
+}
+
+/// "-> type args..." and its body
+struct Stanza {
+    kind: String,
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+struct Connection<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    fn read(&mut self) -> Result<Stanza, Error> {
+        let header = self.read_line()?;
+        let mut args = match header.strip_prefix("-> ") {
+            Some(header) => header.split(' ').map(str::to_string).collect::<Vec<_>>(),
+            None => return Err(Error::AgePlugin(format!("expected a stanza, got '{}'", header))),
+        };
+        let kind = args.remove(0);
+
+        // the body ends with its first line shorter than a full one, which may be empty
+        let mut encoded = String::new();
+        loop {
+            let line = self.read_line()?;
+            encoded.push_str(&line);
+            if line.len() < BODY_LINE_LEN {
+                break;
+            }
+        }
+        let body = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(encoded)
+            .map_err(|_| Error::AgePlugin(format!("invalid body of {}", kind)))?;
+        Ok(Stanza { kind, args, body })
+    }
+
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        match self.input.read_line(&mut line)? {
+            0 => Err(Error::AgePlugin("age closed the connection".into())),
+            _ => Ok(line.trim_end_matches('\n').to_string()),
+        }
+    }
+
+    fn write(&mut self, kind: &str, args: &[&str], body: &[u8]) -> Result<(), Error> {
+        let mut stanza = format!("-> {}", kind);
+        for arg in args {
+            stanza.push(' ');
+            stanza.push_str(arg);
+        }
+        stanza.push('\n');
+        let encoded = base64::engine::general_purpose::STANDARD_NO_PAD.encode(body);
+        for line in encoded.as_bytes().chunks(BODY_LINE_LEN) {
+            stanza.push_str(&String::from_utf8_lossy(line));
+            stanza.push('\n');
+        }
+        if encoded.len().is_multiple_of(BODY_LINE_LEN) {
+            stanza.push('\n');
+        }
+        self.output.write_all(stanza.as_bytes())?;
+        Ok(self.output.flush()?)
+    }
+
+    /// the commands of phase 1, up to "done"
+    fn read_phase(&mut self) -> Result<Vec<Stanza>, Error> {
+        let mut stanzas = vec![];
+        loop {
+            let stanza = self.read()?;
+            if stanza.kind == "done" {
+                return Ok(stanzas);
+            }
+            stanzas.push(stanza);
+        }
+    }
+
+    /// a command of phase 2, which age answers with "ok" or "fail"
+    fn command(&mut self, kind: &str, args: &[&str], body: &[u8]) -> Result<Stanza, Error> {
+        self.write(kind, args, body)?;
+        self.read()
+    }
+}
+
+/// recipient-v1, wrapping file keys to recipients, or to the recipients of identities
+fn wrap<R: BufRead, W: Write>(connection: &mut Connection<R, W>) -> Result<(), Error> {
+    let mut recipients = vec![];
+    let mut file_keys = vec![];
+    let mut errors = vec![];
+    let (mut recipient_index, mut identity_index) = (0, 0);
+    for stanza in connection.read_phase()? {
+        match (stanza.kind.as_str(), stanza.args.first()) {
+            ("add-recipient", Some(recipient)) => {
+                match bech32::decode(RECIPIENT_HRP, recipient).filter(|key| key.len() == KEY_LEN) {
+                    Some(public_key) => recipients.push(public_key),
+                    None => errors.push(("recipient", recipient_index)),
+                }
+                recipient_index += 1;
+            }
+            ("add-identity", Some(identity)) => {
+                match Identity::parse(identity) {
+                    Some(identity) => recipients.push(identity.public_key),
+                    None => errors.push(("identity", identity_index)),
+                }
+                identity_index += 1;
+            }
+            ("wrap-file-key", _) => file_keys.push(stanza.body),
+            _ => {}
+        }
+    }
+
+    if !errors.is_empty() {
+        for (kind, index) in errors {
+            let index = index.to_string();
+            connection.command("error", &[kind, &index], format!("invalid {}", kind).as_bytes())?;
+        }
+        return connection.write("done", &[], &[]);
+    }
+    for (file_index, file_key) in file_keys.iter().enumerate() {
+        let file_index = file_index.to_string();
+        for public_key in &recipients {
+            let (args, body) = wrap_file_key(public_key, file_key)?;
+            let mut stanza_args = vec![file_index.as_str(), STANZA_TYPE];
+            stanza_args.extend(args.iter().map(String::as_str));
+            connection.command("recipient-stanza", &stanza_args, &body)?;
+        }
+    }
+    connection.write("done", &[], &[])
+}
+
+/// identity-v1, unwrapping a file key with the first identity that has a stanza for it
+async fn unwrap<R: BufRead, W: Write>(connection: &mut Connection<R, W>) -> Result<(), Error> {
+    let mut identities = vec![];
+    let mut stanzas: Vec<(usize, Stanza)> = vec![];
+    for stanza in connection.read_phase()? {
+        match stanza.kind.as_str() {
+            "add-identity" => {
+                identities.push(stanza.args.first().and_then(|identity| Identity::parse(identity)));
+            }
+            "recipient-stanza" => {
+                let mut args = stanza.args.into_iter();
+                let file_index = args.next().and_then(|index| index.parse().ok());
+                if let (Some(file_index), Some(kind)) = (file_index, args.next()) {
+                    let stanza = Stanza {
+                        kind,
+                        args: args.collect(),
+                        body: stanza.body,
+                    };
+                    stanzas.push((file_index, stanza));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (index, _) in identities
+        .iter()
+        .enumerate()
+        .filter(|(_, identity)| identity.is_none())
+    {
+        connection.command("error", &["identity", &index.to_string()], b"invalid identity")?;
+    }
+    // the phone is only asked once per identity
+    let mut secret_keys = HashMap::new();
+    let mut unwrapped = vec![];
+    for (file_index, stanza) in &stanzas {
+        if stanza.kind != STANZA_TYPE || unwrapped.contains(file_index) {
+            continue;
+        }
+        let tag = stanza.args.first().map(String::as_str);
+        let Some((index, identity)) = identities.iter().enumerate().find_map(|(i, identity)| {
+            identity
+                .as_ref()
+                .filter(|identity| Some(recipient_tag(&identity.public_key).as_str()) == tag)
+                .map(|identity| (i, identity))
+        }) else {
+            continue;
+        };
+        if let Entry::Vacant(entry) = secret_keys.entry(index) {
+            connection.command("msg", &[], b"Approve decrypting on your phone")?;
+            let secret_key = identity.secret_key().await;
+            if let Err(e) = &secret_key {
+                let index = index.to_string();
+                connection.command("error", &["identity", &index], e.to_string().as_bytes())?;
+            }
+            entry.insert(secret_key.ok());
+        }
+        let Some(secret_key) = &secret_keys[&index] else {
+            continue;
+        };
+        if let Some(file_key) = unwrap_file_key(secret_key, &identity.public_key, stanza) {
+            connection.command("file-key", &[&file_index.to_string()], &file_key)?;
+            unwrapped.push(*file_index);
+        }
+    }
+    connection.write("done", &[], &[])
+}
+
+/// the first bytes of the sha256 of the recipient, "tag" of the stanza
+fn recipient_tag(public_key: &[u8]) -> String {
+    let hash = crypto::sha256(public_key);
+    base64::engine::general_purpose::STANDARD_NO_PAD.encode(&hash[..4])
+}
+
+/// "akr <tag> <ephemeral share>", the file key sealed with the HKDF of the shared secret
+fn wrap_file_key(public_key: &[u8], file_key: &[u8]) -> Result<(Vec<String>, Vec<u8>), Error> {
+    let ephemeral = Secret::new(crypto::random_bytes(KEY_LEN));
+    let share = crypto::x25519_base(&ephemeral).ok_or(Error::CryptoInit)?;
+    let shared =
+        crypto::x25519(&ephemeral, public_key).ok_or_else(|| Error::AgePlugin("invalid recipient".into()))?;
+    let key = wrap_key(&share, public_key, &shared[..])?;
+    let body = crypto::aead_seal(file_key, &[0; crypto::AEAD_NONCE_LEN], &key);
+    let args = vec![
+        recipient_tag(public_key),
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(share),
+    ];
+    Ok((args, body))
+}
+
+fn unwrap_file_key(secret_key: &[u8], public_key: &[u8], stanza: &Stanza) -> Option<Vec<u8>> {
+    let share = base64::engine::general_purpose::STANDARD_NO_PAD
+        .decode(stanza.args.get(1)?)
+        .ok()?;
+    let shared = crypto::x25519(secret_key, &share)?;
+    let key = wrap_key(&share, public_key, &shared[..]).ok()?;
+    crypto::aead_open(&stanza.body, &[0; crypto::AEAD_NONCE_LEN], &key)
+}
+
+/// HKDF-SHA256 of the shared secret, salted with the ephemeral share and the recipient
+fn wrap_key(
+    share: &[u8],
+    public_key: &[u8],
+    shared: &[u8],
+) -> Result<Zeroizing<[u8; crypto::KEY_LEN]>, Error> {
+    let salt = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, &[share, public_key].concat());
+    let mut key = Zeroizing::new([0; crypto::KEY_LEN]);
+    salt.extract(shared)
+        .expand(&[WRAP_LABEL], ring::hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key[..]))
+        .map_err(|_| Error::AgePlugin("HKDF failed".into()))?;
+    Ok(key)
+}
+
+/// Bech32 as age uses it, without the length limit of BIP 173
+mod bech32 {
+    const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+    fn polymod(values: &[u8]) -> u32 {
+        let mut checksum = 1u32;
+        for value in values {
+            let top = checksum >> 25;
+            checksum = (checksum & 0x1ffffff) << 5 ^ *value as u32;
+            for (i, generator) in GENERATOR.iter().enumerate() {
+                if (top >> i) & 1 == 1 {
+                    checksum ^= generator;
+                }
+            }
+        }
+        checksum
+    }
+
+    fn expand_hrp(hrp: &str) -> Vec<u8> {
+        let mut values: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+        values.push(0);
+        values.extend(hrp.bytes().map(|b| b & 31));
+        values
+    }
+
+    /// regroup bits, padding the last group when encoding
+    fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+        let (mut acc, mut bits) = (0u32, 0u32);
+        let mut out = vec![];
+        for value in data {
+            acc = acc << from | *value as u32;
+            bits += from;
+            while bits >= to {
+                bits -= to;
+                out.push((acc >> bits & ((1 << to) - 1)) as u8);
+            }
+        }
+        match pad {
+            true if bits > 0 => out.push((acc << (to - bits) & ((1 << to) - 1)) as u8),
+            false if bits >= from || acc << (to - bits) & ((1 << to) - 1) != 0 => return None,
+            _ => {}
+        }
+        Some(out)
+    }
+
+    pub fn encode(hrp: &str, data: &[u8]) -> String {
+        let mut values = convert_bits(data, 8, 5, true).unwrap_or_default();
+        let checksum = polymod(&[expand_hrp(hrp), values.clone(), vec![0; 6]].concat()) ^ 1;
+        values.extend((0..6).map(|i| (checksum >> (5 * (5 - i)) & 31) as u8));
+        let mut encoded = format!("{}1", hrp);
+        encoded.extend(values.iter().map(|value| CHARSET[*value as usize] as char));
+        encoded
+    }
+
+    /// the data of `encoded`, when it's for `hrp` and the checksum matches
+    pub fn decode(hrp: &str, encoded: &str) -> Option<Vec<u8>> {
+        let (found_hrp, data) = encoded.rsplit_once('1')?;
+        if found_hrp != hrp || data.len() < 6 {
+            return None;
+        }
+        let values = data
+            .bytes()
+            .map(|b| CHARSET.iter().position(|c| *c == b).map(|i| i as u8))
+            .collect::<Option<Vec<u8>>>()?;
+        if polymod(&[expand_hrp(hrp), values.clone()].concat()) != 1 {
+            return None;
+        }
+        convert_bits(&values[..values.len() - 6], 5, 8, false)
+    }
+}
@@ -0,0 +1,140 @@
+//! Attestation of the keys created with `akr generate`, the proof a key lives in the authenticator
+//!
+//! The phone/tablet answers a register request with a "packed" attestation statement, see
+//! https://www.w3.org/TR/webauthn-2/#sctn-packed-attestation: a signature over the authenticator data
+//! and the challenge, made with the key of the attestation certificate, or with the new key itself
+//! when there is no certificate. The certificate chain is kept so it can be checked against the
+//! vendor's root later on.
+
+use crate::crypto;
+use crate::error::Error;
+use crate::protocol::{Base64Buffer, RegisterResponse};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+
+const PACKED: &str = "packed";
+const NONE: &str = "none";
+
+/// rp id hash, flags, counter, aaguid
+const CREDENTIAL_ID_LEN_OFFSET: usize = 32 + 1 + 4 + 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attestation {
+    pub format: String,
+    /// DER encoded, the attestation certificate first, empty for self attestation
+    pub certificates: Vec<Base64Buffer>,
+    pub verified_at: i64,
+}
+
+impl Attestation {
+    /// signed by the key itself, that doesn't say anything about where it lives
+    pub fn is_self_attested(&self) -> bool {
+        self.certificates.is_empty()
+    }
+
+    pub fn certificate_chain(&self) -> Result<Vec<X509>, Error> {
+        Ok(self
+            .certificates
+            .iter()
+            .map(|der| X509::from_der(&der.0))
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    /// the subject of the attestation certificate, e.g. the authenticator model
+    pub fn attested_by(&self) -> Result<Option<String>, Error> {
+        let chain = self.certificate_chain()?;
+        let subject = chain.first().map(|leaf| {
+            leaf.subject_name()
+                .entries()
+                .filter_map(|entry| entry.data().as_utf8().ok().map(|data| data.to_string()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+        Ok(subject)
+    }
+}
+
+/// Check the attestation statement of a new credential, `None` if the app didn't attest it
+pub fn verify(
+    response: &RegisterResponse,
+    rp_id: &str,
+    challenge: &[u8],
+) -> Result<Option<Attestation>, Error> {
+    let format = match response.attestation_format.as_deref() {
+        None | Some(NONE) => return Ok(None),
+        Some(PACKED) => PACKED,
+        Some(format) => return Err(Error::AttestationFailed(format!("unsupported format {}", format))),
+    };
+    let authenticator_data = match &response.attestation_data {
+        Some(data) => &data.0,
+        None => return Err(Error::AttestationFailed("no authenticator data".into())),
+    };
+    let signature = match &response.attestation_signature {
+        Some(signature) => &signature.0,
+        None => return Err(Error::AttestationFailed("no signature".into())),
+    };
+
+    // the statement has to be about this credential, for this rp id
+    let rp_id_hash = crypto::sha256(rp_id.as_bytes());
+    if !authenticator_data.starts_with(rp_id_hash.as_ref()) {
+        return Err(Error::AttestationFailed("made for another rp id".into()));
+    }
+    if credential_id(authenticator_data) != Some(&response.key_handle.0[..]) {
+        return Err(Error::AttestationFailed("made for another credential".into()));
+    }
+
+    let certificates = response.attestation_certificates.clone().unwrap_or_default();
+    let attestation = Attestation {
+        format: format.to_string(),
+        certificates,
+        verified_at: chrono::Utc::now().timestamp(),
+    };
+    let chain = attestation.certificate_chain()?;
+
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(challenge);
+    let signed = match chain.first() {
+        Some(leaf) => verify_signature(&leaf.public_key()?, &message, signature)?,
+        None => crate::ssh_format::verify_sk_signature(
+            crate::ssh_format::SkKeyType::from_public_key(&response.public_key.0),
+            &response.public_key.0,
+            authenticator_data,
+            challenge,
+            signature,
+        ),
+    };
+    if !signed {
+        return Err(Error::AttestationFailed("the signature did not verify".into()));
+    }
+
+    // every certificate is issued by the next one, the last one is checked against the vendor's root
+    for pair in chain.windows(2) {
+        if !pair[0].verify(&*pair[1].public_key()?)? {
+            return Err(Error::AttestationFailed("broken certificate chain".into()));
+        }
+    }
+    Ok(Some(attestation))
+}
+
+/// the credential id in the attested credential data of `authenticator_data`
+fn credential_id(authenticator_data: &[u8]) -> Option<&[u8]> {
+    let len = authenticator_data.get(CREDENTIAL_ID_LEN_OFFSET..CREDENTIAL_ID_LEN_OFFSET + 2)?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    let start = CREDENTIAL_ID_LEN_OFFSET + 2;
+    authenticator_data.get(start..start + len)
+}
+
+fn verify_signature(key: &PKey<Public>, message: &[u8], signature: &[u8]) -> Result<bool, Error> {
+    let verified = match key.id() {
+        Id::ED25519 => Verifier::new_without_digest(key)?.verify_oneshot(signature, message)?,
+        _ => {
+            let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+            verifier.update(message)?;
+            verifier.verify(signature)?
+        }
+    };
+    Ok(verified)
+}
@@ -0,0 +1,297 @@
+//! The audit log of the agent, one JSON object per line in "~/.akr/audit.log"
+//!
+//! Every list, add, sign and remove request is recorded with the process asking and the outcome. Each event
+//! carries the HMAC-SHA256 of the line before it as "prev", under a key made from the keychain key of the
+//! profile, so changing or dropping an event breaks the chain from there on, which `akr audit verify` points
+//! out, and chaining forged events takes the keychain too. Events written without a keychain, or before the
+//! chain was keyed, carry the plain sha256 and have no "chain"; none may follow a keyed one. Only dropping the
+//! last events goes unnoticed, `audit_export` keeps a copy elsewhere.
+
+use crate::audit_export;
+use crate::cli::AuditCommand;
+use crate::crypto;
+use crate::error::Error;
+use crate::keychain;
+use crate::output;
+use ansi_term::Colour::{Green, Red};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroizing;
+
+const FILE: &str = "audit.log";
+/// an event is a few hundred bytes, the last line always fits
+const TAIL: u64 = 64 * 1024;
+/// "chain" of the events whose "prev" is keyed
+const KEYED_CHAIN: &str = "hmac-sha256";
+/// what the chain key is made for, see `keychain::derive_key`
+const KEY_PURPOSE: &str = "akr audit log";
+
+fn path() -> Result<PathBuf, Error> {
+    Ok(crate::create_home_path()?.join(FILE))
+}
+
+/// set by `disable`
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Record nothing from here on, `akr replay` doesn't do anything worth auditing
+pub fn disable() {
+    DISABLED.store(true, Ordering::Relaxed);
+}
+
+/// Append an event, not being able to write it is reported but never fails the operation
+pub fn record(operation: &str, outcome: &str, details: serde_json::Value) {
+    if DISABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    match append(operation, outcome, details) {
+        Ok(event) => audit_export::ship(&event),
+        Err(e) => eprintln!("couldn't write the audit log: {}", e),
+    }
+}
+
+fn append(operation: &str, outcome: &str, details: serde_json::Value) -> Result<serde_json::Value, Error> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path()?)?;
+    // the chain breaks when two agents append at once
+    file.lock()?;
+
+    let key = keychain::derive_key(KEY_PURPOSE)?;
+    let mut event = serde_json::json!({
+        "at": chrono::Utc::now().timestamp(),
+        "operation": operation,
+        "outcome": outcome,
+        "prev": last_line(&mut file)?.map(|line| link(&line, key.as_deref())),
+    });
+    if key.is_some() {
+        event["chain"] = KEYED_CHAIN.into();
+    }
+    if let (Some(event), serde_json::Value::Object(details)) = (event.as_object_mut(), details) {
+        event.extend(details);
+    }
+    let mut line = serde_json::to_vec(&event)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(event)
+}
+
+fn last_line(file: &mut File) -> Result<Option<Vec<u8>>, Error> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(len.saturating_sub(TAIL)))?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail)?;
+    Ok(tail
+        .rsplit(|b| *b == b'\n')
+        .find(|line| !line.is_empty())
+        .map(<[u8]>::to_vec))
+}
+
+/// the "prev" of the event after `line`, keyed if there is a key
+fn link(line: &[u8], key: Option<&[u8; crypto::KEY_LEN]>) -> String {
+    match key {
+        Some(key) => hex::encode(crypto::hmac_sha256(key, line)),
+        None => hex::encode(crypto::sha256(line)),
+    }
+}
+
+/// The events of the log, after checking the chain
+fn load() -> Result<Vec<serde_json::Value>, Error> {
+    let path = path()?;
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let data = std::fs::read(path)?;
+    check_chain(&data, || {
+        keychain::derive_key(KEY_PURPOSE)?.ok_or(Error::AuditKeyUnavailable)
+    })
+}
+
+/// The events of `data` if their chain is intact, `key` is only asked for once there are keyed events
+fn check_chain(
+    data: &[u8],
+    key: impl FnOnce() -> Result<Zeroizing<[u8; crypto::KEY_LEN]>, Error>,
+) -> Result<Vec<serde_json::Value>, Error> {
+    let mut key = Some(key);
+    let mut chain_key = None;
+    let mut events = vec![];
+    let mut prev: Option<&[u8]> = None;
+    for (i, line) in data
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .enumerate()
+    {
+        let event: serde_json::Value =
+            serde_json::from_slice(line).map_err(|_| Error::AuditLogTampered(i + 1))?;
+        let expected = match event.get("chain").map(|chain| chain.as_str()) {
+            // a plain sha256 after a keyed event is a forgery
+            None if chain_key.is_none() => prev.map(|prev| link(prev, None)),
+            Some(Some(KEYED_CHAIN)) => {
+                if let Some(key) = key.take() {
+                    chain_key = Some(key()?);
+                }
+                prev.map(|prev| link(prev, chain_key.as_deref()))
+            }
+            _ => return Err(Error::AuditLogTampered(i + 1)),
+        };
+        let found = event.get("prev").and_then(|prev| prev.as_str());
+        let intact = match (found, expected.as_deref()) {
+            (Some(found), Some(expected)) => crypto::constant_time_eq(found.as_bytes(), expected.as_bytes()),
+            (found, expected) => found == expected,
+        };
+        if !intact {
+            return Err(Error::AuditLogTampered(i + 1));
+        }
+        prev = Some(line);
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// `akr audit`
+pub fn run(command: AuditCommand) -> Result<(), Error> {
+    match command {
+        AuditCommand::Show { limit } => {
+            let events = load()?;
+            let events = &events[events.len().saturating_sub(limit)..];
+            if output::is_json() {
+                return output::print_json(&events);
+            }
+            for event in events {
+                print_event(event);
+            }
+        }
+        AuditCommand::Verify => {
+            let events = load()?;
+            if output::is_json() {
+                return output::print_json(&serde_json::json!({ "events": events.len(), "intact": true }));
+            }
+            println!(
+                "{} {} events",
+                Green.paint("The audit log is intact,"),
+                events.len()
+            );
+        }
+        AuditCommand::Export { output: path } => {
+            let events = load()?;
+            match path {
+                Some(path) => std::fs::write(path, serde_json::to_vec_pretty(&events)?)?,
+                None => output::print_json(&events)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_event(event: &serde_json::Value) {
+    let field = |name: &str| {
+        event
+            .get(name)
+            .and_then(|value| value.as_str())
+            .unwrap_or_default()
+    };
+    let at = event
+        .get("at")
+        .and_then(|at| at.as_i64())
+        .and_then(|at| chrono::NaiveDateTime::from_timestamp_opt(at, 0))
+        .map(|at| at.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default();
+    let outcome = match field("outcome") {
+        outcome @ ("denied" | "failure" | "error") => Red.paint(outcome).to_string(),
+        outcome => outcome.to_string(),
+    };
+    let pid = event
+        .get("pid")
+        .and_then(|pid| pid.as_i64())
+        .map(|pid| format!(" pid {}", pid))
+        .unwrap_or_default();
+    println!(
+        "{} {:<10} {:<8} {}{} {}",
+        at,
+        field("operation"),
+        outcome,
+        field("key"),
+        pid,
+        field("command")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; crypto::KEY_LEN] = [7; crypto::KEY_LEN];
+
+    /// a log of `count` events, keyed from the `keyed_from`th on
+    fn log(count: usize, keyed_from: usize) -> Vec<Vec<u8>> {
+        let mut lines: Vec<Vec<u8>> = vec![];
+        for i in 0..count {
+            let key = (i >= keyed_from).then_some(&KEY);
+            let mut event = serde_json::json!({
+                "at": i,
+                "operation": "sign",
+                "outcome": "success",
+                "prev": lines.last().map(|line| link(line, key)),
+            });
+            if key.is_some() {
+                event["chain"] = KEYED_CHAIN.into();
+            }
+            lines.push(serde_json::to_vec(&event).unwrap());
+        }
+        lines
+    }
+
+    fn check(lines: &[Vec<u8>]) -> Result<Vec<serde_json::Value>, Error> {
+        check_chain(&lines.join(&b'\n'), || Ok(Zeroizing::new(KEY)))
+    }
+
+    #[test]
+    fn keyed_chains_after_plain_ones_are_intact() {
+        assert_eq!(check(&log(4, 0)).unwrap().len(), 4);
+        assert_eq!(check(&log(4, 2)).unwrap().len(), 4);
+        assert_eq!(check(&log(4, 4)).unwrap().len(), 4);
+        // a log without keyed events never needs the key
+        let lines = log(3, 3).join(&b'\n');
+        assert!(check_chain(&lines, || Err(Error::AuditKeyUnavailable)).is_ok());
+    }
+
+    #[test]
+    fn changed_events_break_the_chain() {
+        let mut lines = log(4, 0);
+        lines[1] = String::from_utf8(lines[1].clone())
+            .unwrap()
+            .replace("success", "denied")
+            .into_bytes();
+        assert!(matches!(check(&lines), Err(Error::AuditLogTampered(3))));
+    }
+
+    #[test]
+    fn plain_events_after_keyed_ones_are_forged() {
+        let mut lines = log(4, 0);
+        let forged = serde_json::json!({
+            "at": 3,
+            "operation": "sign",
+            "outcome": "success",
+            "prev": link(&lines[2], None),
+        });
+        lines[3] = serde_json::to_vec(&forged).unwrap();
+        assert!(matches!(check(&lines), Err(Error::AuditLogTampered(4))));
+    }
+
+    #[test]
+    fn keyed_events_need_the_key() {
+        let lines = log(3, 0).join(&b'\n');
+        let other = [8; crypto::KEY_LEN];
+        assert!(matches!(
+            check_chain(&lines, || Ok(Zeroizing::new(other))),
+            Err(Error::AuditLogTampered(2))
+        ));
+        assert!(matches!(
+            check_chain(&lines, || Err(Error::AuditKeyUnavailable)),
+            Err(Error::AuditKeyUnavailable)
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+//! Shipping the audit log to syslog or an HTTPS webhook, so the evidence of each login is kept centrally
+//!
+//! `audit_syslog` ("local", or "host:port" of a server taking UDP) and `audit_webhook` in the config file turn
+//! it on. The agent sends the events it records in the background, in batches, retrying with backoff while a
+//! destination is unreachable. Events it couldn't send wait for the next batch, at most `MAX_PENDING` of them.
+//! "~/.akr/audit.log" stays the reference either way.
+
+use crate::config;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const BATCH_SIZE: usize = 50;
+/// how long an event waits for others to go along with it
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+const MAX_PENDING: usize = 1000;
+/// how long the agent waits for the last events to go when it shuts down
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+/// authpriv, RFC 5424 section 6.2.1
+const SYSLOG_FACILITY: u8 = 10;
+
+/// one per destination, each sends at its own pace
+static DESTINATIONS: Mutex<Vec<(UnboundedSender<serde_json::Value>, JoinHandle<()>)>> = Mutex::new(vec![]);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyslogTarget {
+    /// the system logger, through /dev/log (/var/run/syslog on macOS)
+    Local,
+    /// "host:port" of a syslog server
+    Udp(String),
+}
+
+impl FromStr for SyslogTarget {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "local" {
+            return match cfg!(unix) {
+                true => Ok(SyslogTarget::Local),
+                false => Err(Error::AuditExport("there is no local syslog on Windows".into())),
+            };
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(SyslogTarget::Udp(s.to_string()))
+            }
+            _ => Err(Error::AuditExport(format!(
+                "invalid syslog target '{}', expected \"local\" or host:port",
+                s
+            ))),
+        }
+    }
+}
+
+/// Where the events are POSTed to, as a JSON array
+#[derive(Debug, Clone)]
+pub struct Webhook(pub reqwest::Url);
+
+impl FromStr for Webhook {
+    type Err = Error;
+
+    /// https only, besides http to localhost for a collector on the same machine
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = reqwest::Url::parse(s)
+            .map_err(|e| Error::AuditExport(format!("invalid webhook '{}': {}", s, e)))?;
+        let local = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+        match url.scheme() {
+            "https" => Ok(Webhook(url)),
+            "http" if local => Ok(Webhook(url)),
+            _ => Err(Error::AuditExport(format!("the webhook '{}' isn't https", s))),
+        }
+    }
+}
+
+enum Destination {
+    Syslog(SyslogTarget),
+    Webhook(reqwest::Client, reqwest::Url),
+}
+
+/// Start shipping what `ship` is given, in the agent. Does nothing unless the config file has a destination
+pub fn start() -> Result<(), Error> {
+    let config = config::get();
+    let mut destinations = vec![];
+    if let Some(target) = &config.audit_syslog {
+        destinations.push(Destination::Syslog(target.clone()));
+    }
+    if let Some(Webhook(url)) = &config.audit_webhook {
+        destinations.push(Destination::Webhook(
+            crate::transport::proxy::reqwest_client()?,
+            url.clone(),
+        ));
+    }
+
+    let started = destinations.into_iter().map(|destination| {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (sender, tokio::spawn(run(destination, receiver)))
+    });
+    DESTINATIONS.lock().unwrap().extend(started);
+    Ok(())
+}
+
+/// Send an event of the audit log to the destinations, if the agent started any
+pub fn ship(event: &serde_json::Value) {
+    for (destination, _) in DESTINATIONS.lock().unwrap().iter() {
+        let _ = destination.send(event.clone());
+    }
+}
+
+/// Send the events still waiting and stop, when the agent shuts down. False if some couldn't be sent in time
+pub async fn finish() -> bool {
+    let destinations = std::mem::take(&mut *DESTINATIONS.lock().unwrap());
+    // each runs until its channel is closed and it sent what it had
+    let running: Vec<_> = destinations.into_iter().map(|(_, running)| running).collect();
+    tokio::time::timeout(FINISH_TIMEOUT, futures::future::join_all(running))
+        .await
+        .is_ok()
+}
+
+async fn run(destination: Destination, mut events: UnboundedReceiver<serde_json::Value>) {
+    let retry = RetryPolicy {
+        max_attempts: 5,
+        initial_backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(30),
+        ..RetryPolicy::default()
+    };
+    let mut batch = vec![];
+    let mut open = true;
+    loop {
+        // wait for an event, unless there are some left over from a failed batch
+        if batch.is_empty() {
+            match events.recv().await {
+                Some(event) => batch.push(event),
+                None => return,
+            }
+        }
+        let deadline = Instant::now() + BATCH_DELAY;
+        while open && batch.len() < BATCH_SIZE {
+            match tokio::time::timeout_at(deadline, events.recv()).await {
+                Ok(Some(event)) => batch.push(event),
+                Ok(None) => open = false,
+                Err(_) => break,
+            }
+        }
+
+        for attempt in 1..=retry.max_attempts {
+            match destination.send(&batch).await {
+                Ok(()) => {
+                    batch.clear();
+                    break;
+                }
+                Err(e) if attempt == retry.max_attempts => {
+                    tracing::warn!("couldn't ship {} audit events: {}", batch.len(), e);
+                }
+                Err(_) => tokio::time::sleep(retry.backoff(attempt)).await,
+            }
+        }
+        if !open {
+            if !batch.is_empty() {
+                tracing::warn!("dropped {} audit events that couldn't be shipped", batch.len());
+            }
+            return;
+        }
+        if batch.len() > MAX_PENDING {
+            let dropped = batch.len() - MAX_PENDING;
+            batch.drain(..dropped);
+            tracing::warn!(
+                "dropped the {} oldest audit events that couldn't be shipped",
+                dropped
+            );
+        }
+    }
+}
+
+impl Destination {
+    async fn send(&self, events: &[serde_json::Value]) -> Result<(), Error> {
+        match self {
+            Destination::Syslog(target) => {
+                for event in events {
+                    target.send(&syslog_message(event)?).await?;
+                }
+                Ok(())
+            }
+            Destination::Webhook(client, url) => {
+                let response = client
+                    .post(url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_vec(events)?)
+                    .send()
+                    .await?;
+                match response.status().is_success() {
+                    true => Ok(()),
+                    false => Err(Error::AuditExport(format!(
+                        "the webhook answered {}",
+                        response.status()
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+impl SyslogTarget {
+    async fn send(&self, message: &[u8]) -> Result<(), Error> {
+        match self {
+            #[cfg(unix)]
+            SyslogTarget::Local => {
+                let path = match cfg!(target_os = "macos") {
+                    true => "/var/run/syslog",
+                    false => "/dev/log",
+                };
+                tokio::net::UnixDatagram::unbound()?
+                    .send_to(message, path)
+                    .await?;
+            }
+            #[cfg(not(unix))]
+            SyslogTarget::Local => unreachable!("not parsed on this platform"),
+            SyslogTarget::Udp(address) => {
+                let address = tokio::net::lookup_host(address)
+                    .await?
+                    .next()
+                    .ok_or_else(|| Error::AuditExport(format!("couldn't resolve {}", address)))?;
+                let any = match address.is_ipv4() {
+                    true => "0.0.0.0:0",
+                    false => "[::]:0",
+                };
+                tokio::net::UdpSocket::bind(any)
+                    .await?
+                    .send_to(message, address)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// RFC 5424, the event as the message: notice for what went through, warning for the rest
+fn syslog_message(event: &serde_json::Value) -> Result<Vec<u8>, Error> {
+    let severity = match event.get("outcome").and_then(|outcome| outcome.as_str()) {
+        Some("success" | "requested") => 5,
+        _ => 4,
+    };
+    let mut message = format!(
+        "<{}>1 {} {} akr {} - - ",
+        SYSLOG_FACILITY * 8 + severity,
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        whoami::hostname(),
+        std::process::id()
+    )
+    .into_bytes();
+    message.extend(serde_json::to_vec(event)?);
+    Ok(message)
+}
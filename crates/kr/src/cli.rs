@@ -1,4 +1,14 @@
 use clap::Clap;
+use std::path::PathBuf;
+
+use crate::add_to::Forge;
+use crate::completions::Shell;
+use crate::confirmation::LocalConfirmation;
+use crate::policy::ForwardingAction;
+use crate::ssh_format::ExportFormat;
+use crate::transport::proxy::Proxy;
+use crate::transport::TransportKind;
+use crate::update::Channel;
 
 /// This doc string acts as a help message when the user runs '--help'
 /// as do all doc strings on fields
@@ -10,6 +20,29 @@ use clap::Clap;
 )]
 #[clap(setting = clap::AppSettings::ColoredHelp)]
 pub struct Opts {
+    /// How to reach your authenticator: "relay" for your paired phone/tablet,
+    /// or "loopback" for a FIDO2 security key plugged into this machine (default relay),
+    /// "mock" answers from the script of AKR_MOCK_SCRIPT
+    #[clap(long, global = true)]
+    pub transport: Option<TransportKind>,
+
+    /// Reach the relays through this proxy: http://[user:password@]host:port or socks5://...
+    /// defaults to HTTPS_PROXY or ALL_PROXY
+    #[clap(long, global = true)]
+    pub proxy: Option<Proxy>,
+
+    /// Print the output of the command as JSON, for scripts
+    #[clap(long, global = true)]
+    pub json: bool,
+
+    /// Use this profile's pairings, keys and agent socket, instead of the one from `akr profile switch`
+    #[clap(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Keep the identity store in plaintext, without asking the OS keychain for its key
+    #[clap(long, global = true)]
+    pub no_keychain: bool,
+
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -21,14 +54,59 @@ pub enum Command {
         /// Run the setup step before pairing
         #[clap(long)]
         setup: bool,
+        /// Pair a phone/tablet again after reinstalling the app, keeping the keys already loaded
+        #[clap(long)]
+        renew: bool,
     },
     /// Load keys from the Akamai MFA app on your phone/tablet
     Load,
+    /// Attach a CA-signed certificate to one of your keys
+    LoadCert {
+        /// the certificate file, e.g. "~/.ssh/id_ecdsa_sk-cert.pub"
+        file: String,
+    },
     /// Generate a new SSH credential
-    Generate {
-        /// a common name for the credential
+    Generate(GenerateArgs),
+    /// List your keys with their fingerprints, labels and tags
+    List {
+        /// only the keys with this tag
+        #[clap(long)]
+        tag: Option<String>,
+        /// Print the keys in authorized_keys format instead
+        #[clap(long)]
+        public_key: bool,
+        /// also how often each key signed and the host it last signed a login to
+        #[clap(long, short)]
+        verbose: bool,
+    },
+    /// Import sk keys made with `ssh-keygen -t ecdsa-sk` or `-t ed25519-sk`, to use them through the agent
+    Import {
+        /// the private key files, e.g. "~/.ssh/id_ecdsa_sk"
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
+    /// Print one of your public keys, for servers, certificate authorities or cloud consoles
+    Export {
+        /// a fingerprint, public key file, public key or key name, can be omitted if you have a single key
+        key: Option<String>,
+        /// "authorized_keys", "openssh-pub" (the ".pub" file) or "pem" (SubjectPublicKeyInfo)
+        #[clap(long, default_value = "authorized_keys")]
+        format: ExportFormat,
+    },
+    /// Give one of your keys a label, shown by `list` and as the key comment in the agent
+    Rename(RenameArgs),
+    /// Delete a key from your phone/tablet and this machine
+    Delete {
+        /// the key's fingerprint from `akr list`, or its public key or name
+        key: String,
+    },
+    /// Show the attestation of a key, the proof it was created in the authenticator
+    Attestation {
+        /// a public key file, public key or key name, can be omitted if you have a single key
+        key: Option<String>,
+        /// Print the attestation certificate chain as PEM, to check it against the vendor's root
         #[clap(long)]
-        name: String,
+        pem: bool,
     },
     /// Setup the background daemon and ssh configuration
     Setup(SetupArgs),
@@ -36,13 +114,467 @@ pub enum Command {
     /// Start the ssh-agent daemon
     /// Note: don't run this manually, see `setup` to
     /// install this as a background service
-    Start,
-    /// Get pairing info from your phone/tablet
+    Start(StartArgs),
+    /// Stop the agent installed as a background service with `setup`
+    Stop,
+    /// Restart the agent installed as a background service with `setup`
+    Restart,
+    /// Run the requests recorded with `akr start --record <dir>` through an agent again,
+    /// answered with what the phone/tablet answered then
+    Replay(ReplayArgs),
+    /// Sign data with one of your keys, in the format of `ssh-keygen -Y sign`
+    Sign(SignArgs),
+    /// Derive a secret with one of your keys, e.g. to unlock a LUKS volume with your phone
+    DeriveSecret(DeriveSecretArgs),
+    /// Create age identities with your keys, and the plugin age runs to use them
+    AgePlugin(AgePluginArgs),
+    /// Sign your git commits and tags with one of your keys
+    GitConfig(GitConfigArgs),
+    /// Add one of your public keys to your GitHub or GitLab account
+    AddTo(AddToArgs),
+    /// Serve WebAuthn assertions from your phone/tablet to browsers on this machine
+    #[cfg(feature = "webauthn-bridge")]
+    WebauthnBridge(BridgeArgs),
+    /// Show whether the agent is running and your phones/tablets and the relays are reachable
     Status,
     /// Health check of all the dep systems and system configs
     Check,
-    /// Unpair from your phone/tablet
-    Unpair,
+    /// Look for problems with ssh, the agent and this machine, and how to fix them
+    Doctor,
+    /// Install the agent as a service, with the dir of its socket and a config file, for the scripts of
+    /// packages. `akr setup` does this and the ssh config
+    InstallService(ServiceArgs),
+    /// Stop the agent and remove its service, the config and keys stay
+    UninstallService(UninstallServiceArgs),
+    /// Report the pairings, keys and attestations of this machine for fleet tooling, exits with 9 when it
+    /// doesn't comply with the team policy or isn't paired
+    Inventory,
+    /// Replace akr with the latest release, checking its signature first, for installs from a tarball
+    Update(UpdateArgs),
+    /// Unpair from all your phones/tablets
+    Unpair {
+        /// Also stop the agent and wipe the keys and everything else akr stored for the profile,
+        /// to decommission this machine
+        #[clap(long)]
+        reset: bool,
+    },
+    /// Send the requests that were queued while the network was down
+    FlushQueue,
+    /// Make the running agent read the keys on disk again
+    Reload,
+    /// Make the running agent refuse to list keys and sign until `akr unlock`
+    Lock,
+    /// Let the running agent list keys and sign again after `akr lock`
+    Unlock,
+    /// Restore the identity file from its last good backup when it can't be read
+    Repair,
+    /// Rotate the keys that encrypt messages with your phones/tablets
+    RotateKeys(RotateKeysArgs),
+    /// Delete the keys that haven't signed for a while
+    Prune(PruneArgs),
+    /// Print the messages of akr to translate, in the format of the catalogs in "locales"
+    Translations(TranslationsArgs),
+    /// Print a script that completes the commands, options and keys of akr in your shell
+    Completions(CompletionsArgs),
+    /// Send a test prompt to your phones/tablets and back, to tell network problems from app problems
+    Test(TestArgs),
+    /// Have your phone/tablet open the approval of a login before ssh asks for it, for a `Match exec` of the
+    /// ssh config
+    Prewarm(PrewarmArgs),
+    /// Record where ssh connects to for the agent, run by the ssh config of `setup --host-context`
+    #[clap(setting = clap::AppSettings::Hidden)]
+    HostContext(HostContextArgs),
+    /// Manage your paired phones/tablets
+    Devices {
+        #[clap(subcommand)]
+        command: DevicesCommand,
+    },
+    /// Read and change the settings in the config file
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage profiles, e.g. for work and personal keys on separate phones
+    Profile {
+        #[clap(subcommand)]
+        command: ProfileCommand,
+    },
+    /// Let your phone/tablet approve the logins to a host for a while after approving one
+    Policy {
+        #[clap(subcommand)]
+        command: PolicyCommand,
+    },
+    /// Show the policy of your team the agent enforces, from policy_url of the config
+    TeamPolicy(TeamPolicyArgs),
+    /// Read and check the log of what the agent was asked to do
+    Audit {
+        #[clap(subcommand)]
+        command: AuditCommand,
+    },
+    /// Bind the key of the identity store to this machine, so a copy of ~/.akr is useless elsewhere
+    DeviceBinding {
+        #[clap(subcommand)]
+        command: DeviceBindingCommand,
+    },
+}
+
+#[derive(Clap)]
+pub enum DeviceBindingCommand {
+    /// Show how the key of the identity store is kept and whether there is a TPM
+    Status,
+    /// Bind the key anew and encrypt a plaintext store with it
+    Migrate {
+        /// tpm, software or none
+        #[clap(long)]
+        to: String,
+    },
+}
+
+#[derive(Clap)]
+pub enum AuditCommand {
+    /// Print the last events
+    Show {
+        #[clap(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Check that no event was changed or removed
+    Verify,
+    /// Write every event as a JSON array, once the log verified
+    Export {
+        /// a file instead of stdout
+        #[clap(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clap)]
+pub enum PolicyCommand {
+    /// List the approval windows
+    List,
+    /// Add an approval window, or change the one for the same host and user
+    Add {
+        /// a host name, "*" matches any part of it, or the SHA256 fingerprint of a host key
+        host: String,
+        /// only for logins as this user
+        #[clap(long)]
+        user: Option<String>,
+        /// how long after an approval the logins to the host don't need another one
+        #[clap(long, default_value = "5")]
+        minutes: u32,
+    },
+    /// Remove the approval windows for a host, for every user unless --user is given
+    Remove {
+        host: String,
+        #[clap(long)]
+        user: Option<String>,
+    },
+    /// Choose what happens with the sign requests for a key that come through a forwarded agent
+    Forwarding {
+        /// the SHA256 fingerprint of a key, its rp id, e.g. "ssh:work", or "*" for every key
+        key: String,
+        /// "allow", "deny" or "fresh_approval" (ask your phone/tablet even within an approval window)
+        action: ForwardingAction,
+    },
+}
+
+#[derive(Clap)]
+pub enum ProfileCommand {
+    /// List the profiles
+    List,
+    /// Create a new profile, pair it with `akr --profile <name> pair`
+    Create { name: String },
+    /// Use this profile when no --profile is given
+    Switch { name: String },
+}
+
+#[derive(Clap)]
+pub enum ConfigCommand {
+    /// Print the value of a setting
+    Get { key: String },
+    /// Change a setting
+    Set { key: String, value: String },
+    /// Remove a setting, going back to its default
+    Unset { key: String },
+    /// Print every setting in the config file
+    List,
+}
+
+#[derive(Clap)]
+pub enum DevicesCommand {
+    /// List the paired phones/tablets
+    List,
+    /// Unpair a single phone/tablet
+    Remove {
+        /// the device number from `akr devices list` or its name
+        device: String,
+    },
+}
+
+#[derive(Clap)]
+pub struct StartArgs {
+    /// Also hold regular (non-FIDO2) ed25519/ecdsa keys added with ssh-add
+    /// these are stored encrypted under "~/.akr/local" and used for signing locally
+    #[clap(long)]
+    pub local_keys: bool,
+
+    /// Seconds to wait for a signature to be approved on your phone/tablet (default 60)
+    #[clap(long)]
+    pub sign_timeout: Option<u64>,
+
+    /// Refuse signatures whose counter did not increase, instead of only warning
+    #[clap(long)]
+    pub strict_sign_counter: bool,
+
+    /// Confirm every sign request on this computer before it goes to your phone/tablet,
+    /// with "touch_id" (macOS) or a "pinentry" dialog
+    #[clap(long)]
+    pub local_confirmation: Option<LocalConfirmation>,
+
+    /// Ask your phone/tablet for its current keys when ssh lists identities,
+    /// caching the answer for this many seconds
+    #[clap(long)]
+    pub refresh_keys: Option<u64>,
+
+    /// Attempts at a request to your phone/tablet that fails on a network error, 1 to never retry
+    /// (default 3)
+    #[clap(long)]
+    pub retry_attempts: Option<u32>,
+
+    /// Milliseconds to wait before retrying, doubled for every retry after that (default 250)
+    #[clap(long)]
+    pub retry_backoff: Option<u64>,
+
+    /// Also retry requests that aren't safe to repeat (generating keys, unpairing)
+    #[clap(long)]
+    pub retry_non_idempotent: bool,
+
+    /// Log the requests to your phone/tablet at this level (error, warn, info, debug or trace),
+    /// instead of as set in RUST_LOG
+    #[clap(long)]
+    pub log_level: Option<tracing::Level>,
+
+    /// Serve Prometheus metrics of the sign requests on this address, e.g. 127.0.0.1:9184
+    #[clap(long)]
+    pub metrics_address: Option<std::net::SocketAddr>,
+
+    /// Run an agent for every profile, each listening on its own socket
+    #[clap(long)]
+    pub all_profiles: bool,
+
+    /// Also serve the keys to PuTTY, WinSCP and FileZilla, like Pageant does (Windows only)
+    #[clap(long)]
+    pub pageant: bool,
+
+    /// Write every request to the agent and every exchange with your phone/tablet to this directory,
+    /// without private keys and passphrases, for `akr replay`
+    #[clap(long)]
+    pub record: Option<PathBuf>,
+
+    /// Stop the agent already running for this profile and take its place, or take the socket over from
+    /// another agent listening on it
+    #[clap(long)]
+    pub takeover: bool,
+
+    /// Accept connections from processes of other users, e.g. on a shared bastion, instead of refusing them
+    #[clap(long)]
+    pub allow_other_users: bool,
+
+    /// Let the agent dump core, e.g. to debug a crash. Off by default, a core dump holds the pairing keys
+    #[clap(long)]
+    pub allow_core_dumps: bool,
+}
+
+#[derive(Clap)]
+pub struct ReplayArgs {
+    /// the directory of `akr start --record`
+    pub dir: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct GenerateArgs {
+    /// a common name for the credential, `default_rp_id` from the config file if not given
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// the full application string instead of "ssh:<name>", it has to start with "ssh:"
+    #[clap(long, conflicts_with = "name")]
+    pub rp_id: Option<String>,
+
+    /// the user the credential belongs to, like `ssh-keygen -O user=<user>`
+    #[clap(long)]
+    pub user: Option<String>,
+
+    /// Create a resident key that `ssh-keygen -K` can download, `resident_keys` from the config file if not given
+    #[clap(long, overrides_with = "no-resident")]
+    pub resident: bool,
+
+    /// Don't create a resident key
+    #[clap(long, overrides_with = "resident")]
+    pub no_resident: bool,
+
+    /// Require user verification, e.g. a fingerprint, every time the key is used
+    #[clap(long)]
+    pub uv_required: bool,
+
+    /// Also write a key file for `ssh -i` and `ssh-add`, e.g. "~/.ssh/id_akr", and its ".pub"
+    #[clap(long)]
+    pub write: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct RenameArgs {
+    /// the key's fingerprint from `akr list`, or its public key or current name
+    pub key: String,
+
+    /// the new label, omit to keep the current one
+    pub label: Option<String>,
+
+    /// add a tag, can be given more than once
+    #[clap(long)]
+    pub tag: Vec<String>,
+
+    /// remove a tag, can be given more than once
+    #[clap(long)]
+    pub untag: Vec<String>,
+
+    /// remove the label, going back to the key's comment
+    #[clap(long, conflicts_with = "label")]
+    pub clear: bool,
+}
+
+#[derive(Clap)]
+pub struct SignArgs {
+    /// the signature namespace, e.g. "git" or "file"
+    #[clap(short = 'n', long)]
+    pub namespace: String,
+
+    /// the key to use: a public key file, public key or key name
+    /// can be omitted if you have a single key
+    #[clap(short = 'f', long)]
+    pub key: Option<String>,
+
+    /// the file to sign, writes "<file>.sig"
+    /// omit to sign stdin and print the signature
+    pub file: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct DeriveSecretArgs {
+    /// the key to derive the secret with: its fingerprint, public key (file) or name
+    #[clap(short = 'k', long)]
+    pub key: String,
+
+    /// 32 bytes as hex, the same salt gives the same secret again
+    #[clap(short = 's', long)]
+    pub salt: String,
+
+    /// write the raw secret to this file instead of printing it as hex
+    #[clap(short = 'o', long)]
+    pub output: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct AgePluginArgs {
+    /// Create an identity with one of your keys, printed with its recipient
+    #[clap(long)]
+    pub generate: bool,
+
+    /// the key for --generate: its fingerprint, public key (file) or name
+    /// can be omitted if you have a single key
+    #[clap(short = 'k', long)]
+    pub key: Option<String>,
+
+    /// the state machine age runs the plugin with, recipient-v1 or identity-v1
+    #[clap(long = "age-plugin")]
+    pub state_machine: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct AddToArgs {
+    /// "github" or "gitlab"
+    #[clap(possible_values = &["github", "gitlab"])]
+    pub forge: Forge,
+
+    /// the key to add: its fingerprint, public key (file) or name
+    /// can be omitted if you have a single key
+    #[clap(short = 'k', long)]
+    pub key: Option<String>,
+
+    /// what the account calls the key, defaults to its name and this machine's
+    #[clap(long)]
+    pub title: Option<String>,
+
+    /// Add it as a key for signing commits instead, see `git-config`
+    #[clap(long)]
+    pub signing: bool,
+
+    /// the API of GitHub Enterprise, e.g. "https://github.example.com/api/v3", or your own GitLab
+    #[clap(long)]
+    pub url: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct UpdateArgs {
+    /// stable or beta
+    #[clap(long, default_value = "stable", possible_values = &["stable", "beta"])]
+    pub channel: Channel,
+
+    /// Only say whether there is a newer release
+    #[clap(long)]
+    pub check: bool,
+}
+
+#[derive(Clap)]
+pub struct GitConfigArgs {
+    /// the key to sign with: a public key file, public key or key name
+    /// can be omitted if you have a single key
+    #[clap(short = 'k', long)]
+    pub key: Option<String>,
+
+    /// Update the global git config instead of the current repository's
+    #[clap(long)]
+    pub global: bool,
+}
+
+#[derive(Clap)]
+pub struct RotateKeysArgs {
+    /// only rotate the keys of this device, its number from `akr devices list` or its name
+    #[clap(long)]
+    pub device: Option<String>,
+
+    /// Check the pairing keys and show their fingerprints and age instead of rotating them
+    #[clap(long)]
+    pub audit: bool,
+}
+
+#[derive(Clap)]
+pub struct PruneArgs {
+    /// how long a key has to be unused, e.g. "90d", "12w" or "36h"
+    #[clap(long)]
+    pub unused_for: String,
+
+    /// also have your phones/tablets delete the credentials, so they can't sign anymore
+    #[clap(long)]
+    pub revoke: bool,
+
+    /// Only show the keys that would be deleted
+    #[clap(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clap)]
+pub struct TranslationsArgs {
+    /// only the messages the catalog of this locale lacks, e.g. "de"
+    #[clap(long)]
+    pub missing: Option<String>,
+}
+
+#[cfg(feature = "webauthn-bridge")]
+#[derive(Clap)]
+pub struct BridgeArgs {
+    /// the port to listen on, on 127.0.0.1
+    #[clap(long, default_value = "8421")]
+    pub port: u16,
 }
 
 #[derive(Clap)]
@@ -55,4 +587,113 @@ pub struct SetupArgs {
     /// Only print out the config changes without making them
     #[clap(long)]
     pub print_only: bool,
+
+    /// Show how the ssh config would change, as a diff, without changing it or installing the agent
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Take akr's block out of the ssh config, and out of the files it includes
+    /// only the one for --host if given
+    #[clap(long)]
+    pub remove: bool,
+
+    /// Only write a block for this host, e.g. "github.com", which offers it just the keys of --key,
+    /// the agent has to be set up already
+    #[clap(long)]
+    pub host: Option<String>,
+
+    /// a key for --host: its fingerprint, public key (file) or name, can be given more than once
+    /// can be omitted if you have a single key
+    #[clap(long, requires = "host")]
+    pub key: Vec<String>,
+
+    /// Have ssh tell the agent the host, user and port of each connection, for your phone/tablet to show
+    #[clap(long, conflicts_with = "host")]
+    pub host_context: bool,
+
+    /// Install a systemd user socket unit instead, which starts the agent on the first ssh connection
+    #[clap(long)]
+    pub systemd: bool,
+
+    /// Install a LaunchAgent that starts the agent at login (macOS, the default there)
+    #[clap(long)]
+    pub launchd: bool,
+}
+
+#[derive(Clap)]
+pub struct ServiceArgs {
+    /// Install a systemd user socket unit, which starts the agent on the first ssh connection
+    #[clap(long)]
+    pub systemd: bool,
+
+    /// Install a LaunchAgent that starts the agent at login (macOS, the default there)
+    #[clap(long)]
+    pub launchd: bool,
+
+    /// Install it for every user, into /usr/lib/systemd/user or /Library/LaunchAgents, as root
+    #[clap(long)]
+    pub global: bool,
+
+    /// Only write the files of --global below this dir, e.g. the staging dir of a package
+    #[clap(long)]
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct UninstallServiceArgs {
+    /// Remove the service installed for every user with `install-service --global`
+    #[clap(long)]
+    pub global: bool,
+
+    /// Remove the files of --global below this dir
+    #[clap(long)]
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct TeamPolicyArgs {
+    /// fetch it now instead of showing the one the agent fetched last
+    #[clap(long)]
+    pub fetch: bool,
+}
+
+#[derive(Clap)]
+pub struct TestArgs {
+    /// only test this device, by its number in `akr devices list` or its name
+    #[clap(long)]
+    pub device: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct PrewarmArgs {
+    /// the host of the login, %n in the ssh config
+    pub host: String,
+    /// the user of the login, %r in the ssh config
+    #[clap(long)]
+    pub user: Option<String>,
+    /// send the request and wait for it to go out, instead of in the background
+    #[clap(long, hidden = true)]
+    pub wait: bool,
+}
+
+#[derive(Clap)]
+pub struct HostContextArgs {
+    /// the host as given to ssh, %n
+    pub host: String,
+    /// the HostName it resolved to, %h
+    pub hostname: String,
+    /// the remote user, %r
+    pub user: String,
+    /// %p
+    pub port: String,
+}
+
+#[derive(Clap)]
+pub struct CompletionsArgs {
+    /// "bash", "zsh", "fish" or "powershell"
+    #[clap(possible_values = &["bash", "zsh", "fish", "powershell"], required_unless_present = "keys")]
+    pub shell: Option<Shell>,
+    /// print the fingerprints and names of the keys on this machine, the scripts complete keys with them
+    #[clap(long, hidden = true)]
+    pub keys: bool,
 }
@@ -1,11 +1,26 @@
-use crate::error::{QueueDenyError, QueueDenyExplanation, QueueEvaluation};
+use crate::error::{ErrorKind, QueueDenyError, QueueDenyExplanation, QueueEvaluation};
+use crate::identity::{AppCapabilities, StoredIdentity};
+use crate::metrics::METRICS;
 use crate::pairing::Pairing;
-use crate::protocol::{Request, RequestBody, ResponseBody, WireMessage};
+use crate::protocol::{Request, RequestBody, Response, ResponseBody, WireMessage};
+use crate::recorder;
+use crate::retry::RetryPolicy;
+use crate::transport::bluetooth::BluetoothClient;
 use crate::transport::krypton_aws::AwsClient;
 use crate::transport::krypton_azure::AzureQueueClient;
-use crate::transport::Transport;
+use crate::transport::local_network::LocalNetworkClient;
+use crate::transport::loopback::LoopbackTransport;
+use crate::transport::mock::MockTransport;
+use crate::transport::outbox::{Outbox, QueuedMessage};
+use crate::transport::push::PushState;
+use crate::transport::{DeviceTransport, Transport, TransportKind};
 use crate::{error::Error, transport};
 use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::Instrument;
 use transport::pzqueue::PZQueueClient;
 use uuid::Uuid;
 
@@ -13,19 +28,84 @@ pub struct Client {
     pzq: PZQueueClient,
     aws: AwsClient,
     azure: AzureQueueClient,
+    /// reaches the phones/tablets in range directly, next to the relay
+    bluetooth: BluetoothClient,
+    /// reaches the phones/tablets on the same network directly, next to the relay
+    local_network: LocalNetworkClient,
+    /// answers requests instead of the paired devices, if set
+    device: Option<Box<dyn DeviceTransport>>,
+    /// how requests that failed on a transient error are retried
+    retry: RetryPolicy,
+    /// what the apps of the paired devices speak, as of their last answer to an `IdRequest`
+    app_capabilities: Vec<AppCapabilities>,
 }
 
 impl Client {
     pub fn new() -> Result<Client, Error> {
+        let device: Option<Box<dyn DeviceTransport>> = match transport::selected() {
+            TransportKind::Relay => None,
+            TransportKind::Loopback => Some(Box::new(LoopbackTransport)),
+            TransportKind::Mock => Some(Box::new(MockTransport)),
+        };
+
+        let id = StoredIdentity::load_from_disk().ok();
         Ok(Client {
-            pzq: PZQueueClient::new(),
+            pzq: PZQueueClient::new()?,
             aws: AwsClient::new()?,
-            azure: AzureQueueClient::new(),
+            azure: AzureQueueClient::new()?,
+            bluetooth: BluetoothClient::new(
+                id.as_ref()
+                    .map(|id| id.bluetooth_peers.clone())
+                    .unwrap_or_default(),
+            ),
+            local_network: LocalNetworkClient::new(),
+            device,
+            retry: RetryPolicy::default(),
+            app_capabilities: id.map(|id| id.app_capabilities).unwrap_or_default(),
         })
     }
 
-    pub fn pairing() -> Result<Pairing, Error> {
-        Ok(Pairing::load_from_disk()?)
+    /// Whether a request using `feature` is worth sending: the app of a paired device supports it, or didn't
+    /// say what it supports. The loopback and mock transports get everything
+    pub fn supports(&self, feature: &str) -> bool {
+        if self.device.is_some() {
+            return true;
+        }
+        let pairings = Self::pairings().unwrap_or_default();
+        pairings.is_empty()
+            || pairings
+                .iter()
+                .any(|pairing| self.pairing_supports(pairing, feature))
+    }
+
+    pub fn pairing_supports(&self, pairing: &Pairing, feature: &str) -> bool {
+        let queue_uuid = pairing.queue_uuid().ok();
+        self.app_capabilities
+            .iter()
+            .find(|app| Some(app.queue_uuid) == queue_uuid)
+            .is_none_or(|app| app.capabilities.supports(feature))
+    }
+
+    /// `supports`, failing with which devices to update otherwise
+    pub fn require(&self, feature: &'static str) -> Result<(), Error> {
+        if self.supports(feature) {
+            return Ok(());
+        }
+        let device_names = Self::pairings()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|pairing| pairing.device_name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(Error::UnsupportedByApp(device_names, feature))
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn pairings() -> Result<Vec<Pairing>, Error> {
+        Pairing::load_all_from_disk()
     }
 }
 
@@ -36,62 +116,303 @@ impl Client {
         Ok(())
     }
 
+    #[tracing::instrument(skip_all, fields(queue = %queue_uuid))]
     pub async fn send(
         &self,
         device_token: Option<String>,
         queue_uuid: Uuid,
         message: WireMessage,
     ) -> Result<(), Error> {
-        let pzq_send = self.pzq.send(device_token, queue_uuid, message.clone());
-        let aws_send = self.aws.send(None, queue_uuid, message.clone());
-        let azure_send = self.azure.send(None, queue_uuid, message);
+        let pzq_send = traced("pzq", self.pzq.send(device_token, queue_uuid, message.clone()));
+        let aws_send = traced("aws", self.aws.send(None, queue_uuid, message.clone()));
+        let azure_send = traced("azure", self.azure.send(None, queue_uuid, message.clone()));
+        let bluetooth_send = traced(
+            "bluetooth",
+            self.bluetooth.send(None, queue_uuid, message.clone()),
+        );
+        let local_network_send = traced(
+            "local_network",
+            self.local_network.send(None, queue_uuid, message),
+        );
 
         // send both at the same time and wait for first success
-        let (r1, r2, r3) = futures::future::join3(pzq_send, aws_send, azure_send).await;
-        if r1.is_err() && r2.is_err() && r3.is_err() {
+        let (r1, r2, r3, r4, r5) =
+            futures::future::join5(pzq_send, aws_send, azure_send, bluetooth_send, local_network_send).await;
+        if r1.is_err() && r2.is_err() && r3.is_err() && r4.is_err() && r5.is_err() {
             return r1;
         }
 
         Ok(())
     }
 
+    #[tracing::instrument(skip_all, fields(queue = %queue_uuid))]
     pub async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
     where
-        F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Copy,
+        T: Send,
+        F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync + Copy,
     {
         // receive the first one to complete
-        let pzq_recv = self.pzq.receive(queue_uuid, on_messages);
-        let aws_recv = self.aws.receive(queue_uuid, on_messages);
-        let azure_recv = self.azure.receive(queue_uuid, on_messages);
+        let pzq_recv = traced("pzq", self.pzq.receive(queue_uuid, on_messages));
+        let aws_recv = traced("aws", self.aws.receive(queue_uuid, on_messages));
+        let azure_recv = traced("azure", self.azure.receive(queue_uuid, on_messages));
+        // only answer if the phone/tablet was in range, the relay is the fallback
+        let bluetooth_recv = traced("bluetooth", self.bluetooth.receive(queue_uuid, on_messages));
+        let local_network_recv = traced(
+            "local_network",
+            self.local_network.receive(queue_uuid, on_messages),
+        );
 
-        let (res, _) = futures::future::select_ok(vec![pzq_recv, aws_recv, azure_recv]).await?;
+        let (res, _) = futures::future::select_ok(vec![
+            pzq_recv,
+            aws_recv,
+            azure_recv,
+            bluetooth_recv,
+            local_network_recv,
+        ])
+        .await?;
         Ok(res)
     }
 
+    /// broadcast a request to every paired device, the first to respond wins
     pub async fn send_request<R>(&self, request: RequestBody) -> Result<R, Error>
     where
         R: TryFrom<ResponseBody>,
         Error: From<R::Error>,
     {
-        let mut pairing = Self::pairing()?;
-        let queue_uuid = pairing.queue_uuid()?;
+        self.broadcast_request(request, None).await
+    }
+
+    /// like `send_request`, but keep waiting for a response until `timeout`
+    /// has passed instead of giving up after a single receive window
+    pub async fn send_request_with_timeout<R>(
+        &self,
+        request: RequestBody,
+        timeout: Duration,
+    ) -> Result<R, Error>
+    where
+        R: TryFrom<ResponseBody>,
+        Error: From<R::Error>,
+    {
+        let deadline = Instant::now() + timeout;
+        match tokio::time::timeout(timeout, self.broadcast_request(request, Some(deadline))).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::ResponseTimedOut),
+        }
+    }
+
+    /// send a request to one specific paired device
+    pub async fn send_request_to_device<R>(&self, pairing: Pairing, request: RequestBody) -> Result<R, Error>
+    where
+        R: TryFrom<ResponseBody>,
+        Error: From<R::Error>,
+    {
+        self.unicast_request(pairing, request, None).await
+    }
+
+    /// like `send_request_to_device`, but keep waiting for a response until `timeout` has passed
+    pub async fn send_request_to_device_with_timeout<R>(
+        &self,
+        pairing: Pairing,
+        request: RequestBody,
+        timeout: Duration,
+    ) -> Result<R, Error>
+    where
+        R: TryFrom<ResponseBody>,
+        Error: From<R::Error>,
+    {
+        let deadline = Instant::now() + timeout;
+        match tokio::time::timeout(timeout, self.unicast_request(pairing, request, Some(deadline))).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::ResponseTimedOut),
+        }
+    }
+
+    async fn unicast_request<R>(
+        &self,
+        pairing: Pairing,
+        request: RequestBody,
+        deadline: Option<Instant>,
+    ) -> Result<R, Error>
+    where
+        R: TryFrom<ResponseBody>,
+        Error: From<R::Error>,
+    {
         let request = Request::new(request);
-        let wire_message = pairing.seal(&request)?;
+        let response = self
+            .retry
+            .run(&request.body, deadline, || {
+                self.send_sealed_request(pairing.clone(), &request, deadline)
+            })
+            .instrument(request_span(&request))
+            .await;
+        recorder::exchange(&request, &response);
+        let response = response.map_err(|e| Self::queue_request(&[pairing], &request, e))?;
+        Ok(std::convert::TryFrom::try_from(response.body)?)
+    }
+
+    /// send a request to one paired device without waiting for a response,
+    /// queueing it for `akr flush-queue` if the network is down
+    pub async fn send_or_queue(&self, pairing: &Pairing, request: &Request) -> Result<(), Error> {
+        let queue_uuid = pairing.queue_uuid()?;
+        let wire_message = pairing.seal(request)?;
 
         self.send(pairing.device_token.clone(), queue_uuid, wire_message)
-            .await?;
+            .await
+            .map_err(|e| Self::queue_request(std::slice::from_ref(pairing), request, e))
+    }
+
+    /// send a request the devices don't answer to the paired ones whose app supports `feature`, without
+    /// waiting and without queueing it, it's of no use later
+    pub async fn notify(&self, request: RequestBody, feature: &str) -> Result<(), Error> {
+        // the loopback and mock transports have no app to get ready
+        if self.device.is_some() {
+            return Ok(());
+        }
+        let request = Request::new(request);
+        let sends = Self::pairings()?
+            .into_iter()
+            .filter(|pairing| self.pairing_supports(pairing, feature))
+            .map(|pairing| {
+                let request = &request;
+                async move {
+                    let wire_message = pairing.seal(request)?;
+                    self.send(pairing.device_token.clone(), pairing.queue_uuid()?, wire_message)
+                        .await
+                }
+            })
+            .collect::<Vec<_>>();
+        let results = futures::future::join_all(sends).await;
+        match results.iter().any(Result::is_ok) {
+            true => Ok(()),
+            false => results.into_iter().next().unwrap_or(Ok(())),
+        }
+    }
 
+    /// send the requests queued while the network was down,
+    /// returns how many were sent and how many are still queued
+    pub async fn flush_queue(&self) -> Result<(usize, usize), Error> {
+        let mut sent = 0;
+        let mut remaining = vec![];
+
+        for message in Outbox::load()? {
+            if message.is_expired() {
+                continue;
+            }
+
+            let wire_message = message.wire_message()?;
+            let device_token = message.device_token.clone();
+            match self.send(device_token, message.queue_uuid, wire_message).await {
+                Ok(()) => sent += 1,
+                Err(e) => {
+                    eprintln!("couldn't send queued {} request: {}", message.request, e);
+                    remaining.push(message);
+                }
+            }
+        }
+
+        Outbox::store(&remaining)?;
+        Ok((sent, remaining.len()))
+    }
+
+    /// Keep `request` for `akr flush-queue` if it failed because the network is down and it can wait,
+    /// otherwise hand back `error`
+    fn queue_request(pairings: &[Pairing], request: &Request, error: Error) -> Error {
+        if error.kind() != ErrorKind::Retryable || !request.body.is_queueable() {
+            return error;
+        }
+
+        let queued = pairings.iter().try_for_each(|pairing| {
+            Outbox::push(QueuedMessage::new(
+                pairing.queue_uuid()?,
+                pairing.device_token.clone(),
+                request.body.name(),
+                pairing.seal(request)?,
+            ))
+        });
+
+        match queued {
+            Ok(()) => Error::RequestQueued(request.body.name()),
+            Err(e) => e,
+        }
+    }
+
+    async fn broadcast_request<R>(&self, request: RequestBody, deadline: Option<Instant>) -> Result<R, Error>
+    where
+        R: TryFrom<ResponseBody>,
+        Error: From<R::Error>,
+    {
+        // retries keep the request id, so the phone/tablet can tell it's the same request
+        let request = Request::new(request);
         let response = self
-            .receive(queue_uuid, |messages| {
-                pairing.find_response(&request.id, messages)
+            .retry
+            .run(&request.body, deadline, || async {
+                match &self.device {
+                    Some(device) => device.exchange(&request, deadline).await,
+                    None => self.relay_request(&request, deadline).await,
+                }
             })
+            .instrument(request_span(&request))
+            .await;
+        recorder::exchange(&request, &response);
+        let response = response.map_err(|e| match &self.device {
+            Some(_) => e,
+            None => Self::queue_request(&Self::pairings().unwrap_or_default(), &request, e),
+        })?;
+        Ok(std::convert::TryFrom::try_from(response.body)?)
+    }
+
+    async fn relay_request(&self, request: &Request, deadline: Option<Instant>) -> Result<Response, Error> {
+        let requests = Self::pairings()?
+            .into_iter()
+            .map(|pairing| Box::pin(self.send_sealed_request(pairing, request, deadline)))
+            .collect::<Vec<_>>();
+
+        let (response, _) = futures::future::select_ok(requests).await?;
+        Ok(response)
+    }
+
+    #[tracing::instrument(skip_all, fields(device = %pairing.device_name))]
+    async fn send_sealed_request(
+        &self,
+        mut pairing: Pairing,
+        request: &Request,
+        deadline: Option<Instant>,
+    ) -> Result<Response, Error> {
+        let queue_uuid = pairing.queue_uuid()?;
+        let wire_message = pairing.seal(request)?;
+
+        let sent_at = Instant::now();
+        self.send(pairing.device_token.clone(), queue_uuid, wire_message)
             .await?;
+        tracing::debug!("sent request, waiting for the response");
+
+        let response = loop {
+            let received = self
+                .receive(queue_uuid, |messages| {
+                    pairing.find_response(&request.id, messages)
+                })
+                .await;
+
+            // each receive only polls for one window, keep going until the deadline
+            match received {
+                Err(Error::ResponseTimedOut) if deadline.is_some_and(|d| Instant::now() < d) => continue,
+                received => break received?,
+            }
+        };
+        METRICS.observe_round_trip(sent_at.elapsed());
+        tracing::debug!("received the response");
 
-        pairing.aws_push_id = response.aws_push_id.or(pairing.aws_push_id);
-        pairing.device_token = response.device_token.or(pairing.device_token);
+        pairing.aws_push_id = response.aws_push_id.clone().or(pairing.aws_push_id);
+        pairing.device_token = response.device_token.clone().or(pairing.device_token);
+        pairing.last_seen_at = chrono::Utc::now().timestamp();
         pairing.store_to_disk()?;
 
-        Ok(std::convert::TryFrom::try_from(response.body)?)
+        Ok(response)
+    }
+
+    /// whether responses are pushed by the relay or polled for
+    pub fn push_state(&self) -> PushState {
+        self.pzq.push_state()
     }
 
     pub async fn pz_health_check(&self) -> Result<QueueEvaluation, Error> {
@@ -121,3 +442,27 @@ impl Client {
         }
     }
 }
+
+/// the span the sending of `request` and waiting for its response run in
+fn request_span(request: &Request) -> tracing::Span {
+    tracing::info_span!("request", id = %request.id, kind = request.body.name())
+}
+
+/// Run one transport's part of a send or receive in its own span, logging how it went
+fn traced<'a, T: Send + 'a>(
+    transport: &'static str,
+    operation: impl Future<Output = Result<T, Error>> + Send + 'a,
+) -> Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>> {
+    let span = tracing::debug_span!("transport", name = transport);
+    Box::pin(
+        async move {
+            let result = operation.await;
+            match &result {
+                Ok(_) => tracing::debug!("succeeded"),
+                Err(e) => tracing::debug!(error = %e, "failed"),
+            }
+            result
+        }
+        .instrument(span),
+    )
+}
@@ -0,0 +1,562 @@
+//! `akr completions <shell>` prints a completion script for bash, zsh, fish or PowerShell, made from the
+//! commands and options of `akr --help` so it never falls behind them
+//!
+//! Where a command takes a key, e.g. `akr export` or `akr delete`, the script completes the fingerprints and
+//! names of the keys on this machine, from `akr completions --keys` at the time it completes.
+
+use crate::cli::{CompletionsArgs, Opts};
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use clap::{App, AppSettings, Arg, ArgSettings, IntoApp};
+use std::fmt::Write;
+use std::str::FromStr;
+
+const BIN: &str = "akr";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl FromStr for Shell {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            "powershell" => Ok(Shell::Powershell),
+            _ => Err(Error::UnknownShell(s.to_string())),
+        }
+    }
+}
+
+/// What an option or a positional argument takes
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    /// the fingerprint or name of a key
+    Key,
+    Choices(Vec<String>),
+    /// anything, the shell completes file names
+    File,
+}
+
+struct Opt {
+    long: Option<String>,
+    short: Option<char>,
+    about: String,
+    value: Option<Value>,
+}
+
+impl Opt {
+    fn flags(&self) -> Vec<String> {
+        let long = self.long.iter().map(|long| format!("--{}", long));
+        long.chain(self.short.map(|short| format!("-{}", short)))
+            .collect()
+    }
+}
+
+/// A command, e.g. "akr devices list", with what can follow it
+struct Command {
+    path: String,
+    subcommands: Vec<(String, String)>,
+    options: Vec<Opt>,
+    positionals: Vec<Value>,
+}
+
+impl Command {
+    fn takes(&self, value: &Value) -> bool {
+        self.positionals.contains(value)
+    }
+
+    fn choices(&self) -> Vec<&str> {
+        self.positionals
+            .iter()
+            .filter_map(|value| match value {
+                Value::Choices(choices) => Some(choices),
+                _ => None,
+            })
+            .flatten()
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// `akr completions`
+pub fn run(args: CompletionsArgs) -> Result<(), Error> {
+    if args.keys {
+        return print_keys();
+    }
+    let Some(shell) = args.shell else {
+        return Ok(());
+    };
+
+    let app = Opts::into_app();
+    let globals = app.get_arguments().map(option).collect::<Vec<_>>();
+    let mut commands = vec![];
+    collect(&app, BIN.to_string(), &globals, &mut commands);
+    let script = match shell {
+        Shell::Bash => bash(&commands),
+        Shell::Zsh => zsh(&commands),
+        Shell::Fish => fish(&commands),
+        Shell::Powershell => powershell(&commands),
+    };
+    print!("{}", script);
+    Ok(())
+}
+
+fn print_keys() -> Result<(), Error> {
+    let mut handles = StoredIdentity::load_from_disk()?.key_pair_handles;
+    handles.extend(StoredIdentity::load_added_key_pair_handles()?);
+    handles.retain(|handle| handle.application.starts_with("ssh:"));
+    let mut keys = vec![];
+    for handle in &handles {
+        keys.push(handle.fingerprint()?);
+        keys.push(handle.key_comment().to_string());
+    }
+    keys.dedup();
+    for key in keys {
+        println!("{}", key);
+    }
+    Ok(())
+}
+
+/// The first line of a help text
+fn about(about: Option<&str>) -> String {
+    about
+        .and_then(|about| about.lines().next())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn value(arg: &Arg) -> Value {
+    match arg.get_possible_values() {
+        Some(choices) => Value::Choices(choices.iter().map(|choice| choice.to_string()).collect()),
+        None if arg.get_name() == "key" => Value::Key,
+        None => Value::File,
+    }
+}
+
+fn option(arg: &Arg) -> Opt {
+    Opt {
+        long: arg.get_long().map(str::to_string),
+        short: arg.get_short(),
+        about: about(arg.get_about()),
+        value: arg.is_set(ArgSettings::TakesValue).then(|| value(arg)),
+    }
+}
+
+fn collect(app: &App, path: String, globals: &[Opt], commands: &mut Vec<Command>) {
+    let help = Opt {
+        long: Some("help".to_string()),
+        short: Some('h'),
+        about: "Prints help information".to_string(),
+        value: None,
+    };
+    let mut options = app
+        .get_arguments()
+        .filter(|arg| arg.get_long().is_some() || arg.get_short().is_some())
+        .filter(|arg| !arg.is_set(ArgSettings::Hidden))
+        .map(option)
+        .collect::<Vec<_>>();
+    if path != BIN {
+        options.extend(globals.iter().map(|global| Opt {
+            long: global.long.clone(),
+            short: global.short,
+            about: global.about.clone(),
+            value: global.value.clone(),
+        }));
+    }
+    options.push(help);
+
+    let subcommands = app
+        .get_subcommands()
+        .filter(|subcommand| !subcommand.is_set(AppSettings::Hidden))
+        .map(|subcommand| (subcommand.get_name().to_string(), about(subcommand.get_about())))
+        .collect();
+    commands.push(Command {
+        path: path.clone(),
+        subcommands,
+        options,
+        positionals: app.get_positionals().map(value).collect(),
+    });
+    for subcommand in app
+        .get_subcommands()
+        .filter(|subcommand| !subcommand.is_set(AppSettings::Hidden))
+    {
+        collect(
+            subcommand,
+            format!("{} {}", path, subcommand.get_name()),
+            globals,
+            commands,
+        );
+    }
+}
+
+/// The commands below akr, as `case` patterns
+fn subcommand_patterns(commands: &[Command]) -> String {
+    commands
+        .iter()
+        .filter(|command| command.path != BIN)
+        .map(|command| format!("\"{}\"", command.path))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+fn bash(commands: &[Command]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# bash completion for akr, from `akr completions bash`");
+    let _ = writeln!(out, "_akr_keys() {{");
+    let _ = writeln!(out, "    local IFS=$'\\n'");
+    let _ = writeln!(
+        out,
+        "    COMPREPLY+=($(compgen -W \"$(akr completions --keys 2>/dev/null)\" -- \"$cur\"))"
+    );
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "_akr() {{");
+    let _ = writeln!(
+        out,
+        "    local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}"
+    );
+    let _ = writeln!(out, "    local cmd=akr flags=\"\" words=\"\" keys=0 files=0 i");
+    let _ = writeln!(out, "    COMPREPLY=()");
+    let _ = writeln!(out, "    for ((i = 1; i < COMP_CWORD; i++)); do");
+    let _ = writeln!(out, "        case \"$cmd ${{COMP_WORDS[i]}}\" in");
+    let _ = writeln!(
+        out,
+        "            {}) cmd=\"$cmd ${{COMP_WORDS[i]}}\" ;;",
+        subcommand_patterns(commands)
+    );
+    let _ = writeln!(out, "        esac");
+    let _ = writeln!(out, "    done");
+    let _ = writeln!(out, "    case \"$cmd\" in");
+    for command in commands {
+        let _ = writeln!(out, "        \"{}\")", command.path);
+        let _ = writeln!(out, "            case \"$prev\" in");
+        for option in &command.options {
+            let reply = match &option.value {
+                None => continue,
+                Some(Value::Key) => "_akr_keys".to_string(),
+                Some(Value::Choices(choices)) => {
+                    format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", choices.join(" "))
+                }
+                Some(Value::File) => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "                {}) {}; return ;;",
+                option.flags().join("|"),
+                reply
+            );
+        }
+        let _ = writeln!(out, "            esac");
+        let flags = command.options.iter().flat_map(Opt::flags).collect::<Vec<_>>();
+        let words = command
+            .subcommands
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .chain(command.choices())
+            .collect::<Vec<_>>();
+        let _ = writeln!(out, "            flags=\"{}\"", flags.join(" "));
+        let _ = writeln!(out, "            words=\"{}\"", words.join(" "));
+        let _ = writeln!(
+            out,
+            "            keys={} files={}",
+            command.takes(&Value::Key) as u8,
+            command.takes(&Value::File) as u8
+        );
+        let _ = writeln!(out, "            ;;");
+    }
+    let _ = writeln!(out, "    esac");
+    let _ = writeln!(out, "    if [[ $cur == -* ]]; then");
+    let _ = writeln!(out, "        COMPREPLY=($(compgen -W \"$flags\" -- \"$cur\"))");
+    let _ = writeln!(out, "        return");
+    let _ = writeln!(out, "    fi");
+    let _ = writeln!(out, "    ((keys)) && _akr_keys");
+    let _ = writeln!(out, "    COMPREPLY+=($(compgen -W \"$words\" -- \"$cur\"))");
+    let _ = writeln!(out, "    ((files)) && COMPREPLY+=($(compgen -f -- \"$cur\"))");
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "complete -F _akr akr");
+    out
+}
+
+/// `text` in single quotes for zsh, a quote inside closes them and opens them again
+fn zsh_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+fn zsh(commands: &[Command]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "#compdef akr");
+    let _ = writeln!(out, "# zsh completion for akr, from `akr completions zsh`");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "_akr_keys() {{");
+    let _ = writeln!(out, "    local -a akr_keys");
+    let _ = writeln!(
+        out,
+        "    akr_keys=(${{(f)\"$(akr completions --keys 2>/dev/null)\"}})"
+    );
+    let _ = writeln!(out, "    compadd -a akr_keys");
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "_akr() {{");
+    let _ = writeln!(
+        out,
+        "    local cur=${{words[CURRENT]}} prev=${{words[CURRENT-1]}} cmd=akr i"
+    );
+    let _ = writeln!(out, "    local -a subcommands flags choices");
+    let _ = writeln!(out, "    local keys=0 files=0");
+    let _ = writeln!(out, "    for ((i = 2; i < CURRENT; i++)); do");
+    let _ = writeln!(out, "        case \"$cmd ${{words[i]}}\" in");
+    let _ = writeln!(
+        out,
+        "            {}) cmd=\"$cmd ${{words[i]}}\" ;;",
+        subcommand_patterns(commands)
+    );
+    let _ = writeln!(out, "        esac");
+    let _ = writeln!(out, "    done");
+    let _ = writeln!(out, "    case \"$cmd\" in");
+    for command in commands {
+        let _ = writeln!(out, "        \"{}\")", command.path);
+        let _ = writeln!(out, "            case \"$prev\" in");
+        for option in &command.options {
+            let reply = match &option.value {
+                None => continue,
+                Some(Value::Key) => "_akr_keys".to_string(),
+                Some(Value::Choices(choices)) => format!("compadd -- {}", choices.join(" ")),
+                Some(Value::File) => "_files".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "                {}) {}; return ;;",
+                option.flags().join("|"),
+                reply
+            );
+        }
+        let _ = writeln!(out, "            esac");
+        let described =
+            |name: &str, about: &str| zsh_quote(&format!("{}:{}", name.replace(':', "\\:"), about));
+        let subcommands = command
+            .subcommands
+            .iter()
+            .map(|(name, about)| described(name, about))
+            .collect::<Vec<_>>();
+        let flags = command
+            .options
+            .iter()
+            .flat_map(|option| {
+                option
+                    .flags()
+                    .into_iter()
+                    .map(|flag| described(&flag, &option.about))
+            })
+            .collect::<Vec<_>>();
+        let _ = writeln!(out, "            subcommands=({})", subcommands.join(" "));
+        let _ = writeln!(out, "            flags=({})", flags.join(" "));
+        let _ = writeln!(out, "            choices=({})", command.choices().join(" "));
+        let _ = writeln!(
+            out,
+            "            keys={} files={}",
+            command.takes(&Value::Key) as u8,
+            command.takes(&Value::File) as u8
+        );
+        let _ = writeln!(out, "            ;;");
+    }
+    let _ = writeln!(out, "    esac");
+    let _ = writeln!(out, "    if [[ $cur == -* ]]; then");
+    let _ = writeln!(out, "        _describe option flags");
+    let _ = writeln!(out, "        return");
+    let _ = writeln!(out, "    fi");
+    let _ = writeln!(out, "    ((keys)) && _akr_keys");
+    let _ = writeln!(out, "    ((${{#choices}})) && compadd -a choices");
+    let _ = writeln!(out, "    ((${{#subcommands}})) && _describe command subcommands");
+    let _ = writeln!(out, "    ((files)) && _files");
+    let _ = writeln!(out, "}}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "if [[ \"$funcstack[1]\" == _akr ]]; then");
+    let _ = writeln!(out, "    _akr \"$@\"");
+    let _ = writeln!(out, "else");
+    let _ = writeln!(out, "    compdef _akr akr");
+    let _ = writeln!(out, "fi");
+    out
+}
+
+/// `text` in single quotes for fish, which escapes a quote and a backslash inside
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(commands: &[Command]) -> String {
+    let mut out = String::new();
+    let parents = commands
+        .iter()
+        .filter(|command| command.path != BIN)
+        .map(|command| fish_quote(&command.path))
+        .collect::<Vec<_>>();
+    let _ = writeln!(out, "# fish completion for akr, from `akr completions fish`");
+    let _ = writeln!(out, "function __akr_is");
+    let _ = writeln!(out, "    set -l commands {}", parents.join(" "));
+    let _ = writeln!(out, "    set -l words (commandline -opc)");
+    let _ = writeln!(out, "    set -e words[1]");
+    let _ = writeln!(out, "    set -l cmd akr");
+    let _ = writeln!(out, "    for word in $words");
+    let _ = writeln!(out, "        if contains -- \"$cmd $word\" $commands");
+    let _ = writeln!(out, "            set cmd \"$cmd $word\"");
+    let _ = writeln!(out, "        end");
+    let _ = writeln!(out, "    end");
+    let _ = writeln!(out, "    test \"$cmd\" = \"$argv[1]\"");
+    let _ = writeln!(out, "end");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "complete -c akr -f");
+    let keys = "-a '(akr completions --keys 2>/dev/null)'";
+    for command in commands {
+        let condition = fish_quote(&format!("__akr_is \"{}\"", command.path));
+        for (name, about) in &command.subcommands {
+            let _ = writeln!(
+                out,
+                "complete -c akr -n {} -a {} -d {}",
+                condition,
+                name,
+                fish_quote(about)
+            );
+        }
+        for option in &command.options {
+            let mut line = format!("complete -c akr -n {}", condition);
+            if let Some(long) = &option.long {
+                let _ = write!(line, " -l {}", long);
+            }
+            if let Some(short) = option.short {
+                let _ = write!(line, " -s {}", short);
+            }
+            match &option.value {
+                None => {}
+                Some(Value::Key) => line.push_str(&format!(" -x {}", keys)),
+                Some(Value::Choices(choices)) => {
+                    line.push_str(&format!(" -x -a {}", fish_quote(&choices.join(" "))))
+                }
+                Some(Value::File) => line.push_str(" -r -F"),
+            }
+            let _ = writeln!(out, "{} -d {}", line, fish_quote(&option.about));
+        }
+        if command.takes(&Value::Key) {
+            let _ = writeln!(out, "complete -c akr -n {} {}", condition, keys);
+        }
+        let choices = command.choices();
+        if !choices.is_empty() {
+            let _ = writeln!(
+                out,
+                "complete -c akr -n {} -a {}",
+                condition,
+                fish_quote(&choices.join(" "))
+            );
+        }
+        if command.takes(&Value::File) {
+            let _ = writeln!(out, "complete -c akr -n {} -F", condition);
+        }
+    }
+    out
+}
+
+/// `text` in single quotes for PowerShell, which doubles a quote inside
+fn powershell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+/// `@('a', 'b')`
+fn powershell_array<'a>(items: impl IntoIterator<Item = &'a str>) -> String {
+    let items = items.into_iter().map(powershell_quote).collect::<Vec<_>>();
+    format!("@({})", items.join(", "))
+}
+
+fn powershell(commands: &[Command]) -> String {
+    const KEYS: &str = "<keys>";
+    let mut out = String::new();
+    let parents = commands
+        .iter()
+        .filter(|command| command.path != BIN)
+        .map(|command| command.path.as_str());
+    let _ = writeln!(
+        out,
+        "# PowerShell completion for akr, from `akr completions powershell`"
+    );
+    let _ = writeln!(
+        out,
+        "Register-ArgumentCompleter -Native -CommandName 'akr' -ScriptBlock {{"
+    );
+    let _ = writeln!(out, "    param($wordToComplete, $commandAst, $cursorPosition)");
+    let _ = writeln!(out, "    $words = @($commandAst.CommandElements |");
+    let _ = writeln!(
+        out,
+        "        Where-Object {{ $_.Extent.EndOffset -lt $cursorPosition }} |"
+    );
+    let _ = writeln!(out, "        ForEach-Object {{ $_.ToString() }})");
+    let _ = writeln!(out, "    $commands = {}", powershell_array(parents));
+    let _ = writeln!(out, "    $cmd = 'akr'");
+    let _ = writeln!(out, "    foreach ($word in ($words | Select-Object -Skip 1)) {{");
+    let _ = writeln!(
+        out,
+        "        if ($commands -contains \"$cmd $word\") {{ $cmd = \"$cmd $word\" }}"
+    );
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out, "    $prev = $words[-1]");
+    let _ = writeln!(out, "    $specs = @{{");
+    for command in commands {
+        let _ = writeln!(out, "        {} = @{{", powershell_quote(&command.path));
+        let _ = writeln!(out, "            values = @{{");
+        for option in &command.options {
+            let values = match &option.value {
+                None => continue,
+                Some(Value::Key) => powershell_array([KEYS]),
+                Some(Value::Choices(choices)) => powershell_array(choices.iter().map(String::as_str)),
+                // nothing matches, PowerShell completes paths then
+                Some(Value::File) => "@()".to_string(),
+            };
+            for flag in option.flags() {
+                let _ = writeln!(out, "                {} = {}", powershell_quote(&flag), values);
+            }
+        }
+        let _ = writeln!(out, "            }}");
+        let flags = command.options.iter().flat_map(Opt::flags).collect::<Vec<_>>();
+        let _ = writeln!(
+            out,
+            "            flags = {}",
+            powershell_array(flags.iter().map(String::as_str))
+        );
+        let words = command
+            .subcommands
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .chain(command.choices())
+            .chain(command.takes(&Value::Key).then_some(KEYS));
+        let _ = writeln!(out, "            words = {}", powershell_array(words));
+        let _ = writeln!(out, "        }}");
+    }
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out, "    $spec = $specs[$cmd]");
+    let _ = writeln!(out, "    $candidates = if ($spec.values.ContainsKey($prev)) {{");
+    let _ = writeln!(out, "        $spec.values[$prev]");
+    let _ = writeln!(out, "    }} elseif ($wordToComplete -like '-*') {{");
+    let _ = writeln!(out, "        $spec.flags");
+    let _ = writeln!(out, "    }} else {{");
+    let _ = writeln!(out, "        $spec.words");
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out, "    $candidates |");
+    let _ = writeln!(
+        out,
+        "        ForEach-Object {{ if ($_ -eq '{}') {{ akr completions --keys 2>$null }} else {{ $_ }} }} |",
+        KEYS
+    );
+    let _ = writeln!(out, "        Where-Object {{ $_ -like \"$wordToComplete*\" }} |");
+    let _ = writeln!(
+        out,
+        "        ForEach-Object {{ [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_) }}"
+    );
+    let _ = writeln!(out, "}}");
+    out
+}
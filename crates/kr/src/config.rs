@@ -0,0 +1,341 @@
+//! Settings from "~/.config/akr/config.toml", read at startup
+//!
+//! Flags given on the command line win over the config file, which wins over the built in defaults.
+//! `akr config get/set/unset` edit the file in place, keeping its comments and layout.
+//!
+//! Settings in a `[profiles.<name>]` table apply to that profile only, on top of the ones at the top level.
+//! `socket_path` isn't shared, every profile gets its own agent.
+
+use crate::audit_export::{SyslogTarget, Webhook};
+use crate::confirmation::LocalConfirmation;
+use crate::device_binding::Binding;
+use crate::error::Error;
+use crate::profile;
+use crate::transport::proxy::Proxy;
+use crate::transport::TransportKind;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use toml_edit::{value, Array, Document, Item, Table};
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// where the agent listens, instead of "$XDG_RUNTIME_DIR/akr/akr-ssh-agent.sock" (or "~/.akr/akr-ssh-agent.sock")
+    pub socket_path: Option<PathBuf>,
+    /// accept connections from other users, e.g. on a shared bastion, instead of refusing them
+    pub allow_other_users: Option<bool>,
+    #[serde(default, deserialize_with = "parse")]
+    pub transport: Option<TransportKind>,
+    #[serde(default, deserialize_with = "parse")]
+    pub proxy: Option<Proxy>,
+    /// seconds to wait for a signature to be approved
+    pub sign_timeout: Option<u64>,
+    /// sign requests a minute for each key and for all of them, see `rate_limit`
+    pub sign_rate_limit: Option<u32>,
+    pub sign_rate_limit_global: Option<u32>,
+    /// confirm each sign request on this computer first, see `confirmation`
+    #[serde(default, deserialize_with = "parse")]
+    pub local_confirmation: Option<LocalConfirmation>,
+    pub retry_attempts: Option<u32>,
+    /// milliseconds
+    pub retry_backoff: Option<u64>,
+    #[serde(default, deserialize_with = "parse")]
+    pub log_level: Option<tracing::Level>,
+    /// the name `akr generate` uses without `--name`
+    pub default_rp_id: Option<String>,
+    /// whether `akr generate` creates resident keys without `--resident` or `--no-resident`
+    pub resident_keys: Option<bool>,
+    /// desktop notifications about sign requests, on unless set to false
+    pub notifications: Option<bool>,
+    /// notifications about approved, denied and timed out requests, on unless set to false
+    pub notify_results: Option<bool>,
+    /// host patterns the agent refuses to sign logins to, see `policy::allows_destination`
+    pub denied_hosts: Option<Vec<String>>,
+    /// when set, the only host patterns the agent signs logins to
+    pub allowed_hosts: Option<Vec<String>>,
+    /// encrypt the identity store with a key from the OS keychain, on unless set to false
+    pub keychain: Option<bool>,
+    /// bind the key of a new identity store to this machine, see `device_binding`
+    #[serde(default, deserialize_with = "parse")]
+    pub device_binding: Option<Binding>,
+    /// where the agent ships its audit log, see `audit_export`
+    #[serde(default, deserialize_with = "parse")]
+    pub audit_syslog: Option<SyslogTarget>,
+    #[serde(default, deserialize_with = "parse")]
+    pub audit_webhook: Option<Webhook>,
+    /// where the agent fetches the policy of the team from, see `team_policy`
+    #[serde(default, deserialize_with = "parse")]
+    pub policy_url: Option<reqwest::Url>,
+    /// the public key the team policy is signed with, as in authorized_keys
+    pub policy_public_key: Option<String>,
+    /// where `akr update` looks for releases, see `update`
+    #[serde(default, deserialize_with = "parse")]
+    pub update_url: Option<reqwest::Url>,
+    /// the minisign public key the releases are signed with
+    pub update_public_key: Option<String>,
+    /// the origins `akr webauthn-bridge` answers, e.g. "https://example.com", see `webauthn`
+    pub webauthn_origins: Option<Vec<String>>,
+    /// the profile used without `--profile`, see `akr profile switch`
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Config>,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    Integer,
+    Bool,
+    /// comma separated with `akr config set`
+    List,
+}
+
+/// the settings `akr config` knows, with how they're stored
+const KEYS: &[(&str, Kind)] = &[
+    ("socket_path", Kind::String),
+    ("allow_other_users", Kind::Bool),
+    ("transport", Kind::String),
+    ("proxy", Kind::String),
+    ("sign_timeout", Kind::Integer),
+    ("sign_rate_limit", Kind::Integer),
+    ("sign_rate_limit_global", Kind::Integer),
+    ("local_confirmation", Kind::String),
+    ("retry_attempts", Kind::Integer),
+    ("retry_backoff", Kind::Integer),
+    ("log_level", Kind::String),
+    ("default_rp_id", Kind::String),
+    ("resident_keys", Kind::Bool),
+    ("notifications", Kind::Bool),
+    ("notify_results", Kind::Bool),
+    ("denied_hosts", Kind::List),
+    ("allowed_hosts", Kind::List),
+    ("keychain", Kind::Bool),
+    ("device_binding", Kind::String),
+    ("audit_syslog", Kind::String),
+    ("audit_webhook", Kind::String),
+    ("policy_url", Kind::String),
+    ("policy_public_key", Kind::String),
+    ("update_url", Kind::String),
+    ("update_public_key", Kind::String),
+    ("webauthn_origins", Kind::List),
+    ("profile", Kind::String),
+];
+
+/// Deserialize a string setting with the same parser as its command line flag
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => s.parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf, Error> {
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => dirs::home_dir().ok_or(Error::CannotReadHomeDir)?.join(".config"),
+        };
+        Ok(config_dir.join("akr").join("config.toml"))
+    }
+
+    fn read_document() -> Result<Document, Error> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Document::new());
+        }
+        std::fs::read_to_string(&path)?
+            .parse::<Document>()
+            .map_err(|e| Error::InvalidConfig(path.display().to_string(), e.to_string()))
+    }
+
+    fn from_document(document: &Document) -> Result<Config, Error> {
+        toml_edit::de::from_document(document.clone()).map_err(|e| {
+            Error::InvalidConfig(
+                Self::path().unwrap_or_default().display().to_string(),
+                e.to_string(),
+            )
+        })
+    }
+
+    /// the whole file, with the tables of every profile
+    pub fn read_file() -> Result<Config, Error> {
+        Self::from_document(&Self::read_document()?)
+    }
+
+    /// the settings of the current profile
+    pub fn read() -> Result<Config, Error> {
+        Ok(Self::read_file()?.for_profile(profile::current()))
+    }
+
+    fn for_profile(mut self, name: &str) -> Config {
+        let shared_socket = self.socket_path.take().filter(|_| profile::is_default(name));
+        let settings = self.profiles.remove(name).unwrap_or_default();
+        Config {
+            socket_path: settings.socket_path.or(shared_socket),
+            allow_other_users: settings.allow_other_users.or(self.allow_other_users),
+            transport: settings.transport.or(self.transport),
+            proxy: settings.proxy.or(self.proxy),
+            sign_timeout: settings.sign_timeout.or(self.sign_timeout),
+            sign_rate_limit: settings.sign_rate_limit.or(self.sign_rate_limit),
+            sign_rate_limit_global: settings.sign_rate_limit_global.or(self.sign_rate_limit_global),
+            local_confirmation: settings.local_confirmation.or(self.local_confirmation),
+            retry_attempts: settings.retry_attempts.or(self.retry_attempts),
+            retry_backoff: settings.retry_backoff.or(self.retry_backoff),
+            log_level: settings.log_level.or(self.log_level),
+            default_rp_id: settings.default_rp_id.or(self.default_rp_id),
+            resident_keys: settings.resident_keys.or(self.resident_keys),
+            notifications: settings.notifications.or(self.notifications),
+            notify_results: settings.notify_results.or(self.notify_results),
+            denied_hosts: settings.denied_hosts.or(self.denied_hosts),
+            allowed_hosts: settings.allowed_hosts.or(self.allowed_hosts),
+            keychain: settings.keychain.or(self.keychain),
+            device_binding: settings.device_binding.or(self.device_binding),
+            audit_syslog: settings.audit_syslog.or(self.audit_syslog),
+            audit_webhook: settings.audit_webhook.or(self.audit_webhook),
+            policy_url: settings.policy_url.or(self.policy_url),
+            policy_public_key: settings.policy_public_key.or(self.policy_public_key),
+            update_url: settings.update_url.or(self.update_url),
+            update_public_key: settings.update_public_key.or(self.update_public_key),
+            webauthn_origins: settings.webauthn_origins.or(self.webauthn_origins),
+            profile: self.profile,
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// Read the config file for `get`, failing on a broken one
+pub fn load() -> Result<&'static Config, Error> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config);
+    }
+    let config = Config::read()?;
+    Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The settings from the config file, the defaults if it can't be read
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| Config::read().unwrap_or_default())
+}
+
+fn kind(key: &str) -> Result<Kind, Error> {
+    KEYS.iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, kind)| *kind)
+        .ok_or_else(|| Error::UnknownConfigKey(key.to_string()))
+}
+
+/// the named profile whose table `key` is read from and written to, None for the top level
+fn scope(key: &str) -> Option<&'static str> {
+    let name = profile::current();
+    (key != "profile" && !profile::is_default(name)).then_some(name)
+}
+
+fn table<'a>(document: &'a Document, key: &str) -> Option<&'a Table> {
+    match scope(key) {
+        Some(name) => document.get("profiles")?.get(name)?.as_table(),
+        None => Some(document.as_table()),
+    }
+}
+
+fn table_mut<'a>(document: &'a mut Document, key: &str) -> &'a mut Table {
+    let name = match scope(key) {
+        Some(name) => name,
+        None => return document.as_table_mut(),
+    };
+    let profiles = document
+        .entry("profiles")
+        .or_insert_with(|| {
+            let mut profiles = Table::new();
+            profiles.set_implicit(true);
+            Item::Table(profiles)
+        })
+        .as_table_mut()
+        .expect("profiles is a table");
+    profiles
+        .entry(name)
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_mut()
+        .expect("profile is a table")
+}
+
+/// the value of `key` for the current profile, if set
+pub fn get_value(key: &str) -> Result<Option<String>, Error> {
+    kind(key)?;
+    let document = Config::read_document()?;
+    let inherited = || match key {
+        "socket_path" => None,
+        _ => document.get(key),
+    };
+    Ok(table(&document, key)
+        .and_then(|table| table.get(key))
+        .or_else(inherited)
+        .and_then(Item::as_value)
+        .map(|value| match (value.as_str(), value.as_array()) {
+            (Some(s), _) => s.to_string(),
+            (_, Some(list)) => list
+                .iter()
+                .filter_map(|item| item.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => value.to_string().trim().to_string(),
+        }))
+}
+
+/// every setting in the config file
+pub fn values() -> Result<Vec<(&'static str, String)>, Error> {
+    let mut values = vec![];
+    for (key, _) in KEYS {
+        if let Some(value) = get_value(key)? {
+            values.push((*key, value));
+        }
+    }
+    Ok(values)
+}
+
+pub fn set_value(key: &str, new_value: &str) -> Result<(), Error> {
+    let invalid = |e: String| Error::InvalidConfig(key.to_string(), e);
+    let item = match kind(key)? {
+        Kind::String => value(new_value),
+        Kind::Integer => value(new_value.parse::<i64>().map_err(|e| invalid(e.to_string()))?),
+        Kind::Bool => value(new_value.parse::<bool>().map_err(|e| invalid(e.to_string()))?),
+        Kind::List => value(
+            new_value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .collect::<Array>(),
+        ),
+    };
+
+    let mut document = Config::read_document()?;
+    table_mut(&mut document, key)[key] = item;
+    // don't write anything the next start would choke on
+    Config::from_document(&document)?;
+    store(&document)
+}
+
+pub fn unset_value(key: &str) -> Result<(), Error> {
+    kind(key)?;
+    let mut document = Config::read_document()?;
+    table_mut(&mut document, key).remove(key);
+    store(&document)
+}
+
+fn store(document: &Document) -> Result<(), Error> {
+    let path = Config::path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    crate::util::write_atomically(&path, document.to_string().as_bytes())
+}
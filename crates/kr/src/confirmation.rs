@@ -0,0 +1,85 @@
+//! Confirming sign requests on this computer before they go to the phone/tablet
+//!
+//! With `local_confirmation` in the config file, or `akr start --local-confirmation`, the agent asks for Touch ID
+//! (LocalAuthentication, falling back to the login password) or shows a pinentry dialog first. A terminal that is
+//! remote-controlled then can't have the phone prompt without someone at the keyboard.
+
+use crate::error::Error;
+use crate::i18n;
+use crate::prompt::ConfirmPrompt;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalConfirmation {
+    Pinentry,
+    /// macOS only
+    TouchId,
+}
+
+impl FromStr for LocalConfirmation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pinentry" => Ok(LocalConfirmation::Pinentry),
+            "touch_id" if cfg!(target_os = "macos") => Ok(LocalConfirmation::TouchId),
+            "touch_id" => Err(Error::InvalidLocalConfirmation(
+                "Touch ID is only available on macOS".into(),
+            )),
+            _ => Err(Error::InvalidLocalConfirmation(format!(
+                "'{}', expected pinentry or touch_id",
+                s
+            ))),
+        }
+    }
+}
+
+impl LocalConfirmation {
+    /// Ask the user at this computer about the sign request for `what`, waiting at most `timeout`
+    pub async fn confirm(self, what: &str, timeout: Duration) -> Result<(), Error> {
+        let reason = i18n::tr("confirm-reason", &[("what", &what)]);
+        let confirmed = match self {
+            LocalConfirmation::Pinentry => {
+                let prompt = ConfirmPrompt::new(i18n::tr("confirm-prompt", &[("reason", &reason)]));
+                let confirm = tokio::task::spawn_blocking(move || prompt.invoke());
+                matches!(tokio::time::timeout(timeout, confirm).await, Ok(Ok(true)))
+            }
+            LocalConfirmation::TouchId => touch_id(&reason, timeout).await?,
+        };
+        match confirmed {
+            true => Ok(()),
+            false => Err(Error::NotConfirmedLocally),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+async fn touch_id(reason: &str, timeout: Duration) -> Result<bool, Error> {
+    // JXA reaches LocalAuthentication without linking to it, the reply comes in on another thread
+    const SCRIPT: &str = r#"
+ObjC.import('LocalAuthentication');
+function run(argv) {
+    const context = $.LAContext.alloc.init;
+    let result = null;
+    // LAPolicyDeviceOwnerAuthentication, Touch ID or the login password
+    context.evaluatePolicyLocalizedReasonReply(2, argv[0], (ok, error) => { result = ok; });
+    while (result === null) {
+        $.NSRunLoop.currentRunLoop.runUntilDate($.NSDate.dateWithTimeIntervalSinceNow(0.1));
+    }
+    return result ? 'ok' : 'denied';
+}"#;
+    let output = tokio::process::Command::new("osascript")
+        .args(["-l", "JavaScript", "-e", SCRIPT, reason])
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(timeout, output).await {
+        Ok(output) => Ok(String::from_utf8_lossy(&output?.stdout).trim() == "ok"),
+        Err(_) => Ok(false),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+async fn touch_id(_reason: &str, _timeout: Duration) -> Result<bool, Error> {
+    unreachable!("not parsed on this platform")
+}
@@ -0,0 +1,138 @@
+//! Control messages from the CLI to the running agent, e.g. `akr reload` and `akr lock`
+//!
+//! They go over the agent socket as SSH_AGENTC_EXTENSION messages named "control@akr", with a JSON request
+//! as contents. The agent answers SSH_AGENT_SUCCESS followed by its state, as JSON too. Connections that went
+//! through ssh, forwarded agents included, can't send them.
+
+use crate::error::Error;
+use crate::output;
+use crate::util::{read_string, write_data};
+use ansi_term::Colour::{Green, Yellow};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const EXTENSION: &str = "control@akr";
+const AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH_AGENTC_EXTENSION: u8 = 27;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    /// read the identity store from disk again
+    Reload,
+    /// refuse to list and sign until `Unlock`, like `ssh-add -x` but without a passphrase
+    Lock,
+    Unlock,
+}
+
+/// What the agent tells about itself, after carrying out the request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentState {
+    pub pid: u32,
+    pub version: String,
+    pub profile: String,
+    /// unix time
+    pub started_at: i64,
+    pub locked: bool,
+    /// the keys of the identity store, those added with a lifetime and the software ones
+    pub identities: usize,
+}
+
+impl ControlRequest {
+    /// read from the contents of the extension message
+    pub fn parse(contents: &[u8]) -> Result<ControlRequest, Error> {
+        let json = read_string(&mut Cursor::new(contents.to_vec()))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+impl AgentState {
+    /// the contents of the SSH_AGENT_SUCCESS reply
+    pub fn to_reply(&self) -> Result<Vec<u8>, Error> {
+        let mut reply = vec![];
+        write_data(&mut reply, &serde_json::to_vec(self)?)?;
+        Ok(reply)
+    }
+}
+
+/// Send `request` to the agent listening on `socket`
+pub async fn send(socket: &Path, request: ControlRequest) -> Result<AgentState, Error> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(socket).await?;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(socket)?;
+    tokio::time::timeout(AGENT_TIMEOUT, exchange(stream, request))
+        .await
+        .map_err(|_| Error::AgentTimeout)?
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: ControlRequest,
+) -> Result<AgentState, Error> {
+    let mut message = vec![SSH_AGENTC_EXTENSION];
+    write_data(&mut message, EXTENSION.as_bytes())?;
+    write_data(&mut message, &serde_json::to_vec(&request)?)?;
+    stream.write_u32(message.len() as u32).await?;
+    stream.write_all(&message).await?;
+
+    let len = stream.read_u32().await?;
+    let mut reply = vec![0; (len as usize).min(1024 * 1024)];
+    stream.read_exact(&mut reply).await?;
+
+    match reply.split_first() {
+        Some((&SSH_AGENT_SUCCESS, contents)) => {
+            let json = read_string(&mut Cursor::new(contents.to_vec()))?;
+            Ok(serde_json::from_str(&json)?)
+        }
+        Some((&SSH_AGENT_FAILURE, _)) => Err(Error::ControlRefused),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+/// `akr reload`, `akr lock` and `akr unlock`
+pub async fn run(request: ControlRequest) -> Result<(), Error> {
+    let state = send(&crate::agent_socket_path()?, request).await?;
+    if output::is_json() {
+        return output::print_json(&state);
+    }
+
+    match request {
+        ControlRequest::Status => print_state(&state),
+        ControlRequest::Reload => println!(
+            "{} {} identities",
+            Green.paint("Reloaded, the agent has"),
+            state.identities
+        ),
+        ControlRequest::Lock => println!(
+            "{}",
+            Yellow.paint("Locked the agent, unlock it with `akr unlock`")
+        ),
+        ControlRequest::Unlock => println!("{}", Green.paint("Unlocked the agent")),
+    }
+    Ok(())
+}
+
+pub fn print_state(state: &AgentState) {
+    let started = chrono::NaiveDateTime::from_timestamp_opt(state.started_at, 0)
+        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default();
+    println!(
+        "akr {} (pid {}, profile {}), started {}{}",
+        state.version,
+        state.pid,
+        state.profile,
+        started,
+        match state.locked {
+            true => ", locked",
+            false => "",
+        }
+    );
+}
@@ -0,0 +1,465 @@
+//! The primitives of akr: boxes exchanged with the phone/tablet, the key of the identity store, hashes, the
+//! X25519 and chacha20-poly1305 of age, ed25519 signatures of releases and random bytes
+//!
+//! Keys and messages are plain bytes, so the backend stays out of the rest of akr. The wire format is libsodium's
+//! and a backend has to match it byte for byte for existing pairings to keep working: a box is
+//! xsalsa20-poly1305 under a curve25519 key agreement with its random nonce in front, and the phone/tablet
+//! answers a pairing with its public key in a sealed box. The backend is the RustCrypto crates (`rust_crypto`), or
+//! libsodium through sodiumoxide when built with `--features libsodium`.
+
+#[cfg(feature = "libsodium")]
+mod sodium;
+#[cfg(feature = "libsodium")]
+use sodium as backend;
+
+#[cfg(any(test, not(feature = "libsodium")))]
+#[cfg_attr(feature = "libsodium", allow(dead_code))]
+mod rust_crypto;
+#[cfg(not(feature = "libsodium"))]
+use rust_crypto as backend;
+
+use crate::error::Error;
+use crate::memory::Secret;
+use zeroize::Zeroizing;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 24;
+pub const KEY_LEN: usize = 32;
+pub const SHA256_LEN: usize = 32;
+pub const SHA512_LEN: usize = 64;
+pub const BLAKE2B_512_LEN: usize = 64;
+pub const AEAD_NONCE_LEN: usize = 12;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// a curve25519 public key
+pub type PublicKey = [u8; PUBLIC_KEY_LEN];
+
+/// A key of secret boxes, the identity store's and the one of the software device binding
+#[derive(Clone)]
+pub struct Key(Secret);
+
+impl Key {
+    pub fn generate() -> Self {
+        Key(Secret::new(random_bytes(KEY_LEN)))
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        match bytes.len() == KEY_LEN {
+            true => Some(Key(Secret::from(bytes))),
+            false => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn array(&self) -> &[u8; KEY_LEN] {
+        self.0[..].try_into().expect("a key of KEY_LEN bytes")
+    }
+}
+
+pub fn init() -> Result<(), Error> {
+    match backend::init() {
+        true => Ok(()),
+        false => Err(Error::CryptoInit),
+    }
+}
+
+/// A new key pair for boxes
+pub fn gen_keypair() -> (PublicKey, Secret) {
+    let secret_key = Secret::new(random_bytes(KEY_LEN));
+    let public_key = backend::scalarmult_base(secret_key[..].try_into().expect("32 bytes"));
+    (public_key, secret_key)
+}
+
+pub fn public_key_of(secret_key: &[u8]) -> Result<PublicKey, Error> {
+    let secret_key = secret_key.try_into().map_err(|_| Error::InvalidPairingKeys)?;
+    Ok(backend::scalarmult_base(secret_key))
+}
+
+fn keys<'a>(public_key: &'a [u8], secret_key: &'a [u8]) -> Result<(&'a PublicKey, &'a [u8; KEY_LEN]), Error> {
+    match (public_key.try_into(), secret_key.try_into()) {
+        (Ok(public_key), Ok(secret_key)) => Ok((public_key, secret_key)),
+        _ => Err(Error::InvalidPairingKeys),
+    }
+}
+
+fn random_array<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    backend::fill_random(&mut bytes);
+    bytes
+}
+
+/// Box `message` from `secret_key` to `public_key`, with a random nonce in front
+pub fn seal(message: &[u8], public_key: &[u8], secret_key: &[u8]) -> Result<Vec<u8>, Error> {
+    let (public_key, secret_key) = keys(public_key, secret_key)?;
+    let nonce = random_array::<NONCE_LEN>();
+    let ciphertext =
+        backend::box_seal(message, &nonce, public_key, secret_key).ok_or(Error::InvalidPairingKeys)?;
+    Ok([&nonce[..], &ciphertext].concat())
+}
+
+/// Open a box `seal` made from `public_key` to `secret_key`
+pub fn open(sealed: &[u8], public_key: &[u8], secret_key: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if sealed.len() < NONCE_LEN {
+        return Err(Error::InvalidCiphertext);
+    }
+    let (public_key, secret_key) = keys(public_key, secret_key)?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = nonce.try_into().expect("NONCE_LEN bytes");
+    backend::box_open(ciphertext, nonce, public_key, secret_key)
+        .map(Zeroizing::new)
+        .ok_or(Error::UnsealFailed)
+}
+
+/// Open a sealed box to the key pair, which doesn't tell who sealed it
+pub fn open_sealed(sealed: &[u8], public_key: &[u8], secret_key: &[u8]) -> Result<Vec<u8>, Error> {
+    let (public_key, secret_key) = keys(public_key, secret_key)?;
+    backend::sealed_box_open(sealed, public_key, secret_key).ok_or(Error::UnsealFailed)
+}
+
+/// Seal `message` in a secret box under `key`, with a random nonce in front
+pub fn seal_with_key(message: &[u8], key: &Key) -> Vec<u8> {
+    let nonce = random_array::<NONCE_LEN>();
+    [&nonce[..], &backend::secretbox_seal(message, &nonce, key.array())].concat()
+}
+
+/// Open what `seal_with_key` sealed, None if it's too short, tampered with or under another key
+pub fn open_with_key(sealed: &[u8], key: &Key) -> Option<Zeroizing<Vec<u8>>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    backend::secretbox_open(
+        ciphertext,
+        nonce.try_into().expect("NONCE_LEN bytes"),
+        key.array(),
+    )
+    .map(Zeroizing::new)
+}
+
+/// The X25519 public key of `secret_key`, None unless it's 32 bytes
+pub fn x25519_base(secret_key: &[u8]) -> Option<PublicKey> {
+    Some(backend::scalarmult_base(secret_key.try_into().ok()?))
+}
+
+/// The X25519 shared secret, None for malformed or small order public keys
+pub fn x25519(secret_key: &[u8], public_key: &[u8]) -> Option<Zeroizing<[u8; KEY_LEN]>> {
+    backend::scalarmult(secret_key.try_into().ok()?, public_key.try_into().ok()?).map(Zeroizing::new)
+}
+
+/// chacha20-poly1305 as in RFC 8439, the tag after the ciphertext
+pub fn aead_seal(message: &[u8], nonce: &[u8; AEAD_NONCE_LEN], key: &[u8; KEY_LEN]) -> Vec<u8> {
+    backend::aead_seal(message, nonce, key)
+}
+
+pub fn aead_open(sealed: &[u8], nonce: &[u8; AEAD_NONCE_LEN], key: &[u8; KEY_LEN]) -> Option<Vec<u8>> {
+    backend::aead_open(sealed, nonce, key)
+}
+
+/// Whether `signature` is an ed25519 signature of `message` by `public_key`
+pub fn ed25519_verify(signature: &[u8], message: &[u8], public_key: &[u8]) -> bool {
+    match (signature.try_into(), public_key.try_into()) {
+        (Ok(signature), Ok(public_key)) => backend::ed25519_verify(signature, message, public_key),
+        _ => false,
+    }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; SHA256_LEN] {
+    backend::sha256(data)
+}
+
+/// HMAC-SHA256 of RFC 2104, through `ring`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; SHA256_LEN] {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+    let mut tag = [0; SHA256_LEN];
+    tag.copy_from_slice(ring::hmac::sign(&key, message).as_ref());
+    tag
+}
+
+/// A passphrase hashed with a random salt by PBKDF2-HMAC-SHA256, slow enough to make guessing it expensive
+#[derive(Clone)]
+pub struct PassphraseHash {
+    salt: [u8; PASSPHRASE_SALT_LEN],
+    hash: [u8; SHA256_LEN],
+}
+
+const PASSPHRASE_SALT_LEN: usize = 16;
+const PASSPHRASE_ITERATIONS: u32 = 210_000;
+
+impl PassphraseHash {
+    pub fn new(passphrase: &[u8]) -> Self {
+        let salt = random_array();
+        let mut hash = [0; SHA256_LEN];
+        ring::pbkdf2::derive(
+            ring::pbkdf2::PBKDF2_HMAC_SHA256,
+            std::num::NonZeroU32::new(PASSPHRASE_ITERATIONS).expect("iterations"),
+            &salt,
+            passphrase,
+            &mut hash,
+        );
+        PassphraseHash { salt, hash }
+    }
+
+    /// Whether `passphrase` is the one hashed, compared in constant time
+    pub fn verify(&self, passphrase: &[u8]) -> bool {
+        ring::pbkdf2::verify(
+            ring::pbkdf2::PBKDF2_HMAC_SHA256,
+            std::num::NonZeroU32::new(PASSPHRASE_ITERATIONS).expect("iterations"),
+            &self.salt,
+            passphrase,
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+pub fn sha512(data: &[u8]) -> [u8; SHA512_LEN] {
+    backend::sha512(data)
+}
+
+pub fn blake2b_512(data: &[u8]) -> [u8; BLAKE2B_512_LEN] {
+    backend::blake2b_512(data)
+}
+
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    backend::fill_random(&mut bytes);
+    bytes
+}
+
+pub fn random_u32() -> u32 {
+    u32::from_le_bytes(random_array())
+}
+
+/// Compare in constant time, for hashes of secrets
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    backend::constant_time_eq(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+        28, 29, 30, 31,
+    ];
+    const MESSAGE: &[u8] = b"akr pairs with the phone over boxes";
+
+    /// another secret key, 7i + 3
+    fn other_key() -> [u8; 32] {
+        std::array::from_fn(|i| (i as u8).wrapping_mul(7).wrapping_add(3))
+    }
+
+    fn nonce() -> [u8; NONCE_LEN] {
+        std::array::from_fn(|i| 0x40 + i as u8)
+    }
+
+    // the vectors are libsodium's output for the same inputs
+
+    #[test]
+    fn x25519_matches_libsodium() {
+        let public_key = x25519_base(&KEY).unwrap();
+        assert_eq!(
+            hex::encode(public_key),
+            "8f40c5adb68f25624ae5b214ea767a6ec94d829d3d7b5e1ad1ba6f3e2138285f"
+        );
+        let other = x25519_base(&other_key()).unwrap();
+        assert_eq!(
+            hex::encode(other),
+            "bb50ff9e82a574cfbf820e97f60fb9c143ec7415cf514f8cfd98eff59e059614"
+        );
+        let shared = "778562d69ba3131858b8258e8251e1c4d51a881db5f53c49dad6a15d94440e4d";
+        assert_eq!(hex::encode(&x25519(&KEY, &other).unwrap()[..]), shared);
+        assert_eq!(
+            hex::encode(&x25519(&other_key(), &public_key).unwrap()[..]),
+            shared
+        );
+        // the identity has small order, the shared secret would be zero
+        assert!(x25519(&KEY, &[0; 32]).is_none());
+        assert!(x25519(&KEY, &[0; 31]).is_none());
+    }
+
+    #[test]
+    fn box_matches_libsodium() {
+        let other = x25519_base(&other_key()).unwrap();
+        let sealed = backend::box_seal(MESSAGE, &nonce(), &other, &KEY).unwrap();
+        assert_eq!(
+            hex::encode(&sealed),
+            "d238a7ebdaf7b5f3a45ea190fe6265cb3080954ba1ea704a23ead7550b70fe2d46610ee6ab2d79560001ad4ffe457d7126de67"
+        );
+        let public_key = x25519_base(&KEY).unwrap();
+        let opened = backend::box_open(&sealed, &nonce(), &public_key, &other_key()).unwrap();
+        assert_eq!(opened, MESSAGE);
+        assert!(backend::box_seal(MESSAGE, &nonce(), &[0; 32], &KEY).is_none());
+    }
+
+    #[test]
+    fn secretbox_matches_libsodium() {
+        let sealed = backend::secretbox_seal(MESSAGE, &nonce(), &KEY);
+        assert_eq!(
+            hex::encode(&sealed),
+            "74d6339cb4890401dfe51f81bfbfc61a2b7c27594a30d258b57906081ce17a85a6526624052c31adc4ba54af8bf9e13bac511a"
+        );
+        assert_eq!(backend::secretbox_open(&sealed, &nonce(), &KEY).unwrap(), MESSAGE);
+    }
+
+    #[test]
+    fn sealed_box_of_libsodium_opens() {
+        let sealed = hex::decode(
+            "da783dd333a1a21ac238a05888666ffc3ea8716782360bf8db43b9568dc9725583832b1cdadf11132a754e42f063a2490858f1\
+             57dd65fcb4e14c9affd618cd0729fc519ac25218c72c9720afce0c56890b9e9e",
+        )
+        .unwrap();
+        let public_key = x25519_base(&other_key()).unwrap();
+        assert_eq!(open_sealed(&sealed, &public_key, &other_key()).unwrap(), MESSAGE);
+        assert!(open_sealed(&sealed, &public_key, &KEY).is_err());
+        assert!(open_sealed(&sealed[..40], &public_key, &other_key()).is_err());
+    }
+
+    #[test]
+    fn aead_matches_libsodium() {
+        let nonce: [u8; AEAD_NONCE_LEN] = nonce()[..AEAD_NONCE_LEN].try_into().unwrap();
+        let sealed = aead_seal(MESSAGE, &nonce, &KEY);
+        assert_eq!(
+            hex::encode(&sealed),
+            "993f0ea1022b87732bef8169cf874db9dca943682c932f0ade63a43e9807580588d6ead0db9dca05395eb47bfa85f4fa759a75"
+        );
+        assert_eq!(aead_open(&sealed, &nonce, &KEY).unwrap(), MESSAGE);
+    }
+
+    #[test]
+    fn hashes_match_libsodium() {
+        assert_eq!(
+            hex::encode(blake2b_512(MESSAGE)),
+            "e9f76f486c0d24ff08d2568b26577a231b3da2fa5cabe9fad4bf2058db33fe7541a15f874c0268657372d0c203339085b3509\
+             50eec4cb28fbdc101948dd824b7"
+        );
+        // more than two blocks
+        assert_eq!(
+            hex::encode(blake2b_512(&[b'a'; 300])),
+            "a2ff3040eda405b929c2fc2fd93e8add6ac3bb5369b679bae170ac6956863ca006285f132a868000fc3fae5bc696e5d17fe3f\
+             ddfb4a342876c40451184742986"
+        );
+        assert_eq!(
+            hex::encode(blake2b_512(b"")),
+            "786a02f742015903c6c6fd852552d272912f4740e15847618a86e217f71f5419d25e1031afee585313896444934eb04b903a6\
+             85b1448b755d56f701afe9be2ce"
+        );
+        assert_eq!(
+            hex::encode(sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn ed25519_verifies_rfc8032() {
+        // test 2 of RFC 8032
+        let public_key =
+            hex::decode("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c").unwrap();
+        let signature = hex::decode(
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2ea\
+             eb4302aeeb00d291612bb0c00",
+        )
+        .unwrap();
+        assert!(ed25519_verify(&signature, &[0x72], &public_key));
+        assert!(!ed25519_verify(&signature, &[0x73], &public_key));
+        assert!(!ed25519_verify(&signature[..63], &[0x72], &public_key));
+    }
+
+    #[test]
+    fn hmac_sha256_matches_rfc4231() {
+        // test cases 2 and 6 of RFC 4231, the second with a key longer than a block
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn verifies_passphrases() {
+        let hash = PassphraseHash::new(b"correct horse");
+        assert!(hash.verify(b"correct horse"));
+        assert!(!hash.verify(b"correct horse "));
+        // salted, the same passphrase hashes differently
+        assert_ne!(PassphraseHash::new(b"correct horse").hash, hash.hash);
+    }
+
+    #[test]
+    fn round_trips() {
+        let (public_key, secret_key) = gen_keypair();
+        let (other_public_key, other_secret_key) = gen_keypair();
+        assert_eq!(public_key_of(&secret_key).unwrap(), public_key);
+        let key = Key::generate();
+        for len in [0, 1, 15, 16, 17, 63, 64, 65, 200] {
+            let message = random_bytes(len);
+            let sealed = seal(&message, &other_public_key, &secret_key).unwrap();
+            assert_eq!(
+                &open(&sealed, &public_key, &other_secret_key).unwrap()[..],
+                &message[..]
+            );
+            let mut tampered = sealed.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(open(&tampered, &public_key, &other_secret_key).is_err());
+
+            let sealed = seal_with_key(&message, &key);
+            assert_eq!(&open_with_key(&sealed, &key).unwrap()[..], &message[..]);
+            assert!(open_with_key(&sealed, &Key::generate()).is_none());
+        }
+        assert!(open_with_key(&[0; NONCE_LEN - 1], &key).is_none());
+    }
+
+    /// the pure Rust backend against libsodium, on inputs of every length up to a few blocks
+    #[cfg(feature = "libsodium")]
+    #[test]
+    fn rust_crypto_matches_libsodium() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x616b72);
+        for len in 0..300 {
+            let message: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let key: [u8; 32] = rng.gen();
+            let other: [u8; 32] = rng.gen();
+            let nonce: [u8; 24] = rng.gen();
+            let aead_nonce: [u8; 12] = rng.gen();
+            assert_eq!(rust_crypto::sha256(&message), sodium::sha256(&message));
+            assert_eq!(rust_crypto::sha512(&message), sodium::sha512(&message));
+            assert_eq!(rust_crypto::blake2b_512(&message), sodium::blake2b_512(&message));
+            assert_eq!(rust_crypto::scalarmult_base(&key), sodium::scalarmult_base(&key));
+            assert_eq!(
+                rust_crypto::scalarmult(&key, &other),
+                sodium::scalarmult(&key, &other)
+            );
+            assert_eq!(
+                rust_crypto::secretbox_seal(&message, &nonce, &key),
+                sodium::secretbox_seal(&message, &nonce, &key)
+            );
+            assert_eq!(
+                rust_crypto::aead_seal(&message, &aead_nonce, &key),
+                sodium::aead_seal(&message, &aead_nonce, &key)
+            );
+            let public_key = sodium::scalarmult_base(&other);
+            let boxed = sodium::box_seal(&message, &nonce, &public_key, &key).unwrap();
+            assert_eq!(
+                rust_crypto::box_seal(&message, &nonce, &public_key, &key).unwrap(),
+                boxed
+            );
+            let sealed = sodiumoxide::crypto::sealedbox::seal(
+                &message,
+                &sodiumoxide::crypto::box_::PublicKey(public_key),
+            );
+            assert_eq!(
+                rust_crypto::sealed_box_open(&sealed, &public_key, &other).unwrap(),
+                message
+            );
+        }
+    }
+}
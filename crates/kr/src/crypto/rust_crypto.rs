@@ -0,0 +1,137 @@
+//! The pure Rust backend: the RustCrypto crates of the NaCl boxes, chacha20-poly1305 and BLAKE2b, with sha2,
+//! curve25519-dalek, ed25519-dalek and subtle
+
+use chacha20poly1305::ChaCha20Poly1305;
+use crypto_box::SalsaBox;
+use crypto_secretbox::aead::{Aead, KeyInit};
+use crypto_secretbox::XSalsa20Poly1305;
+use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use sha2::Digest;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+pub fn init() -> bool {
+    true
+}
+
+pub fn fill_random(buffer: &mut [u8]) {
+    getrandom::getrandom(buffer).expect("the random number generator of the OS failed");
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    sha2::Sha256::digest(data).into()
+}
+
+pub fn sha512(data: &[u8]) -> [u8; 64] {
+    sha2::Sha512::digest(data).into()
+}
+
+pub fn blake2b_512(data: &[u8]) -> [u8; 64] {
+    blake2::Blake2b512::digest(data).into()
+}
+
+/// a curve25519 secret key as X25519 uses it
+fn clamp(scalar: &[u8; 32]) -> Scalar {
+    let mut bits = *scalar;
+    bits[0] &= 248;
+    bits[31] &= 127;
+    bits[31] |= 64;
+    let clamped = Scalar::from_bits(bits);
+    bits.zeroize();
+    clamped
+}
+
+pub fn scalarmult_base(scalar: &[u8; 32]) -> [u8; 32] {
+    (&ED25519_BASEPOINT_TABLE * &clamp(scalar))
+        .to_montgomery()
+        .to_bytes()
+}
+
+/// None for points of small order, whose shared secret is zero
+pub fn scalarmult(scalar: &[u8; 32], point: &[u8; 32]) -> Option<[u8; 32]> {
+    let shared = (MontgomeryPoint(*point) * clamp(scalar)).to_bytes();
+    match shared == [0; 32] {
+        true => None,
+        false => Some(shared),
+    }
+}
+
+/// xsalsa20-poly1305, the tag in front like libsodium's
+pub fn secretbox_seal(message: &[u8], nonce: &[u8; 24], key: &[u8; 32]) -> Vec<u8> {
+    XSalsa20Poly1305::new(key.into())
+        .encrypt(nonce.into(), message)
+        .expect("a message shorter than the stream of xsalsa20")
+}
+
+pub fn secretbox_open(sealed: &[u8], nonce: &[u8; 24], key: &[u8; 32]) -> Option<Vec<u8>> {
+    XSalsa20Poly1305::new(key.into())
+        .decrypt(nonce.into(), sealed)
+        .ok()
+}
+
+/// a box between the two, as crypto_box_beforenm. None when the public key is of small order, which
+/// libsodium refuses too
+fn salsa_box(public_key: &[u8; 32], secret_key: &[u8; 32]) -> Option<SalsaBox> {
+    scalarmult(secret_key, public_key)?;
+    Some(SalsaBox::new(
+        &crypto_box::PublicKey::from(*public_key),
+        &crypto_box::SecretKey::from(*secret_key),
+    ))
+}
+
+pub fn box_seal(
+    message: &[u8],
+    nonce: &[u8; 24],
+    public_key: &[u8; 32],
+    secret_key: &[u8; 32],
+) -> Option<Vec<u8>> {
+    salsa_box(public_key, secret_key)?
+        .encrypt(nonce.into(), message)
+        .ok()
+}
+
+pub fn box_open(
+    sealed: &[u8],
+    nonce: &[u8; 24],
+    public_key: &[u8; 32],
+    secret_key: &[u8; 32],
+) -> Option<Vec<u8>> {
+    salsa_box(public_key, secret_key)?
+        .decrypt(nonce.into(), sealed)
+        .ok()
+}
+
+/// An ephemeral public key and a box from it, as crypto_box_seal
+pub fn sealed_box_open(sealed: &[u8], _public_key: &[u8; 32], secret_key: &[u8; 32]) -> Option<Vec<u8>> {
+    crypto_box::SecretKey::from(*secret_key).unseal(sealed).ok()
+}
+
+/// chacha20-poly1305 of RFC 8439, the tag after the ciphertext
+pub fn aead_seal(message: &[u8], nonce: &[u8; 12], key: &[u8; 32]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(nonce.into(), message)
+        .expect("a message shorter than the stream of chacha20")
+}
+
+pub fn aead_open(sealed: &[u8], nonce: &[u8; 12], key: &[u8; 32]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(nonce.into(), sealed)
+        .ok()
+}
+
+/// Strict verification, like libsodium: no small order keys, no malleable signatures
+pub fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+    let (Ok(public_key), Ok(signature)) = (
+        ed25519_dalek::PublicKey::from_bytes(public_key),
+        ed25519_dalek::Signature::try_from(&signature[..]),
+    ) else {
+        return false;
+    };
+    public_key.verify_strict(message, &signature).is_ok()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && bool::from(a.ct_eq(b))
+}
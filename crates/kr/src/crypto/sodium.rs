@@ -0,0 +1,108 @@
+//! The libsodium backend, through sodiumoxide, with `--features libsodium`
+
+use sodiumoxide::crypto::aead::chacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::hash::{sha256, sha512};
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::crypto::sign::ed25519;
+use sodiumoxide::crypto::{box_, generichash, sealedbox, secretbox};
+
+pub fn init() -> bool {
+    sodiumoxide::init().is_ok()
+}
+
+pub fn fill_random(buffer: &mut [u8]) {
+    sodiumoxide::randombytes::randombytes_into(buffer);
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    sha256::hash(data).0
+}
+
+pub fn sha512(data: &[u8]) -> [u8; 64] {
+    sha512::hash(data).0
+}
+
+pub fn blake2b_512(data: &[u8]) -> [u8; 64] {
+    let mut state = generichash::State::new(Some(64), None).expect("a valid hash length");
+    state.update(data).expect("a hash that isn't finalized");
+    let digest = state.finalize().expect("a hash that isn't finalized");
+    digest.as_ref().try_into().expect("64 bytes")
+}
+
+pub fn scalarmult_base(scalar: &[u8; 32]) -> [u8; 32] {
+    curve25519::scalarmult_base(&curve25519::Scalar(*scalar)).0
+}
+
+pub fn scalarmult(scalar: &[u8; 32], point: &[u8; 32]) -> Option<[u8; 32]> {
+    curve25519::scalarmult(&curve25519::Scalar(*scalar), &curve25519::GroupElement(*point))
+        .ok()
+        .map(|shared| shared.0)
+}
+
+pub fn secretbox_seal(message: &[u8], nonce: &[u8; 24], key: &[u8; 32]) -> Vec<u8> {
+    secretbox::seal(message, &secretbox::Nonce(*nonce), &secretbox::Key(*key))
+}
+
+pub fn secretbox_open(sealed: &[u8], nonce: &[u8; 24], key: &[u8; 32]) -> Option<Vec<u8>> {
+    secretbox::open(sealed, &secretbox::Nonce(*nonce), &secretbox::Key(*key)).ok()
+}
+
+pub fn box_seal(
+    message: &[u8],
+    nonce: &[u8; 24],
+    public_key: &[u8; 32],
+    secret_key: &[u8; 32],
+) -> Option<Vec<u8>> {
+    // crypto_box_easy fails for small order keys, which sodiumoxide doesn't pass on
+    scalarmult(secret_key, public_key)?;
+    Some(box_::seal(
+        message,
+        &box_::Nonce(*nonce),
+        &box_::PublicKey(*public_key),
+        &box_::SecretKey(*secret_key),
+    ))
+}
+
+pub fn box_open(
+    sealed: &[u8],
+    nonce: &[u8; 24],
+    public_key: &[u8; 32],
+    secret_key: &[u8; 32],
+) -> Option<Vec<u8>> {
+    box_::open(
+        sealed,
+        &box_::Nonce(*nonce),
+        &box_::PublicKey(*public_key),
+        &box_::SecretKey(*secret_key),
+    )
+    .ok()
+}
+
+pub fn sealed_box_open(sealed: &[u8], public_key: &[u8; 32], secret_key: &[u8; 32]) -> Option<Vec<u8>> {
+    sealedbox::open(
+        sealed,
+        &box_::PublicKey(*public_key),
+        &box_::SecretKey(*secret_key),
+    )
+    .ok()
+}
+
+pub fn aead_seal(message: &[u8], nonce: &[u8; 12], key: &[u8; 32]) -> Vec<u8> {
+    aead::seal(message, None, &aead::Nonce(*nonce), &aead::Key(*key))
+}
+
+pub fn aead_open(sealed: &[u8], nonce: &[u8; 12], key: &[u8; 32]) -> Option<Vec<u8>> {
+    aead::open(sealed, None, &aead::Nonce(*nonce), &aead::Key(*key)).ok()
+}
+
+pub fn ed25519_verify(signature: &[u8; 64], message: &[u8], public_key: &[u8; 32]) -> bool {
+    ed25519::verify_detached(
+        &ed25519::Signature::new(*signature),
+        message,
+        &ed25519::PublicKey(*public_key),
+    )
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    sodiumoxide::utils::memcmp(a, b)
+}
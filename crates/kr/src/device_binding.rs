@@ -0,0 +1,390 @@
+//! Binding the key of the identity store to this machine, so copying "~/.akr" and the keychain elsewhere is useless
+//!
+//! With `device_binding = "tpm"` in the config file the key is sealed by the TPM (through tpm2-tools), which only
+//! unseals it on the same chip. "software" encrypts it with a key derived from the machine id instead, which stops
+//! plain copies but not someone who also reads the id (/etc/machine-id, IOPlatformUUID on macOS, MachineGuid on
+//! Windows). The Secure Enclave only keeps keys of signed apps, on macOS only the fallback is there. A bound key is
+//! kept in the keychain like any other, or in "~/.akr" when there is none. `akr device-binding migrate` rebinds the
+//! key of an existing store.
+
+use crate::cli::DeviceBindingCommand;
+use crate::crypto::{self, Key};
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use crate::keychain;
+use crate::output;
+use ansi_term::Colour::Green;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const TPM_PREFIX: &str = "tpm:";
+const SOFTWARE_PREFIX: &str = "software:";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    Tpm,
+    Software,
+}
+
+impl FromStr for Binding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tpm" => Ok(Binding::Tpm),
+            "software" => Ok(Binding::Software),
+            _ => Err(Error::DeviceBinding(format!(
+                "unknown binding '{}', expected tpm or software",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Tpm => f.write_str("tpm"),
+            Binding::Software => f.write_str("software"),
+        }
+    }
+}
+
+/// The binding the key `stored` by `bind` has, None for a key kept as is
+pub fn binding_of(stored: &str) -> Option<Binding> {
+    if stored.starts_with(TPM_PREFIX) {
+        Some(Binding::Tpm)
+    } else if stored.starts_with(SOFTWARE_PREFIX) {
+        Some(Binding::Software)
+    } else {
+        None
+    }
+}
+
+/// What to store for `key`, bound with `binding`
+pub fn bind(key: &str, binding: Option<Binding>) -> Result<String, Error> {
+    match binding {
+        None => Ok(key.to_string()),
+        Some(Binding::Tpm) => Ok(format!(
+            "{}{}",
+            TPM_PREFIX,
+            hex::encode(tpm::seal(key.as_bytes())?)
+        )),
+        Some(Binding::Software) => {
+            let sealed = crypto::seal_with_key(key.as_bytes(), &machine_key()?);
+            Ok(format!("{}{}", SOFTWARE_PREFIX, hex::encode(sealed)))
+        }
+    }
+}
+
+/// The key `bind` stored, failing on any other machine
+pub fn unbind(stored: &str) -> Result<String, Error> {
+    let decode = |hex: &str| {
+        hex::decode(hex.trim()).map_err(|_| Error::DeviceBinding("the bound key is malformed".into()))
+    };
+    let key = match binding_of(stored) {
+        None => return Ok(stored.to_string()),
+        Some(Binding::Tpm) => tpm::unseal(&decode(&stored[TPM_PREFIX.len()..])?)?,
+        Some(Binding::Software) => {
+            let sealed = decode(&stored[SOFTWARE_PREFIX.len()..])?;
+            if sealed.len() < crypto::NONCE_LEN {
+                return Err(Error::DeviceBinding("the bound key is malformed".into()));
+            }
+            crypto::open_with_key(&sealed, &machine_key()?)
+                .ok_or(Error::BoundToAnotherDevice)?
+                .to_vec()
+        }
+    };
+    Ok(String::from_utf8_lossy(&key).to_string())
+}
+
+/// `akr device-binding`
+pub fn run(command: DeviceBindingCommand) -> Result<(), Error> {
+    match command {
+        DeviceBindingCommand::Status => {
+            let stored = keychain::stored_key()?;
+            let binding = stored.as_deref().and_then(binding_of);
+            let tpm = tpm::available();
+            if output::is_json() {
+                return output::print_json(&serde_json::json!({
+                    "encrypted": stored.is_some(),
+                    "binding": binding.map(|binding| binding.to_string()),
+                    "tpm": tpm,
+                }));
+            }
+            match (&stored, binding) {
+                (None, _) => println!("The identity store isn't encrypted"),
+                (Some(_), None) => println!("The key of the identity store isn't bound to this machine"),
+                (Some(_), Some(Binding::Tpm)) => {
+                    println!("The key of the identity store is sealed by the TPM")
+                }
+                (Some(_), Some(Binding::Software)) => {
+                    println!("The key of the identity store is bound to the machine id")
+                }
+            }
+            println!("TPM: {}", if tpm { "available" } else { "not found" });
+        }
+        DeviceBindingCommand::Migrate { to } => {
+            let binding = match to.as_str() {
+                "none" => None,
+                to => Some(to.parse()?),
+            };
+            keychain::rebind(binding)?;
+            // loading encrypts a store that was plaintext so far, there may be none yet
+            let _ = StoredIdentity::load_from_disk();
+            match binding {
+                _ if output::is_json() => {}
+                Some(binding) => println!(
+                    "{} {}",
+                    Green.paint("The key of the identity store is bound with"),
+                    binding
+                ),
+                None => println!(
+                    "{}",
+                    Green.paint("The key of the identity store is no longer bound")
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// where a bound key is kept without a keychain
+fn path(account: &str) -> Result<PathBuf, Error> {
+    Ok(crate::create_home_path()?.join(format!("{}.bound", account)))
+}
+
+pub fn load_file(account: &str) -> Result<Option<String>, Error> {
+    let path = path(account)?;
+    match path.exists() {
+        true => Ok(Some(std::fs::read_to_string(path)?)),
+        false => Ok(None),
+    }
+}
+
+pub fn store_file(account: &str, stored: &str) -> Result<(), Error> {
+    crate::util::write_atomically(&path(account)?, stored.as_bytes())
+}
+
+pub fn delete_file(account: &str) -> Result<(), Error> {
+    let path = path(account)?;
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// the key of the software binding, from the id the OS gave this machine when it was installed
+fn machine_key() -> Result<Key, Error> {
+    let mut input = b"akr device binding\0".to_vec();
+    input.extend(machine_id()?.trim().as_bytes());
+    Key::from_slice(&crypto::sha256(&input))
+        .ok_or_else(|| Error::DeviceBinding("couldn't derive the machine key".into()))
+}
+
+#[cfg(target_os = "linux")]
+fn machine_id() -> Result<String, Error> {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .filter(|id| !id.trim().is_empty())
+        .ok_or_else(|| Error::DeviceBinding("this machine has no machine-id".into()))
+}
+
+#[cfg(target_os = "macos")]
+fn machine_id() -> Result<String, Error> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+        .output()?;
+    // "IOPlatformUUID" = "564D0A1E-..."
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.contains("\"IOPlatformUUID\""))
+        .and_then(|line| line.split('"').nth(3))
+        .map(str::to_string)
+        .ok_or_else(|| Error::DeviceBinding("couldn't read the IOPlatformUUID".into()))
+}
+
+#[cfg(windows)]
+fn machine_id() -> Result<String, Error> {
+    let output = std::process::Command::new("reg")
+        .args([
+            "query",
+            r"HKLM\SOFTWARE\Microsoft\Cryptography",
+            "/v",
+            "MachineGuid",
+        ])
+        .output()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find(|line| line.contains("MachineGuid"))
+        .and_then(|line| line.split_whitespace().last())
+        .map(str::to_string)
+        .ok_or_else(|| Error::DeviceBinding("couldn't read the MachineGuid".into()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn machine_id() -> Result<String, Error> {
+    Err(Error::DeviceBinding("no machine id on this platform".into()))
+}
+
+#[cfg(target_os = "linux")]
+mod tpm {
+    use crate::crypto;
+    use crate::error::Error;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Stdio};
+
+    const DEVICE: &str = "/dev/tpmrm0";
+
+    pub fn available() -> bool {
+        Path::new(DEVICE).exists()
+            && Command::new("tpm2_getcap")
+                .arg("properties-fixed")
+                .output()
+                .is_ok_and(|output| output.status.success())
+    }
+
+    /// a scratch directory for the contexts, gone when dropped
+    struct WorkDir(PathBuf);
+
+    impl WorkDir {
+        fn new() -> Result<Self, Error> {
+            let name = hex::encode(crypto::random_bytes(8));
+            let path = std::env::temp_dir().join(format!("akr-tpm-{}", name));
+            std::os::unix::fs::DirBuilderExt::mode(&mut std::fs::DirBuilder::new(), 0o700).create(&path)?;
+            Ok(WorkDir(path))
+        }
+
+        fn file(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().to_string()
+        }
+    }
+
+    impl Drop for WorkDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn run(args: &[&str], input: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        let mut child = match Command::new(args[0])
+            .args(&args[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::DeviceBinding("tpm2-tools aren't installed".into()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // secrets go through stdin, never the command line
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input)?;
+        }
+        let output = child.wait_with_output()?;
+        match output.status.success() {
+            true => Ok(output.stdout),
+            false => Err(Error::DeviceBinding(format!(
+                "{}: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        }
+    }
+
+    /// the storage primary key, the same every time from the same template and the chip's seed
+    fn create_primary(dir: &WorkDir) -> Result<String, Error> {
+        let primary = dir.file("primary.ctx");
+        run(
+            &["tpm2_createprimary", "-Q", "-C", "o", "-G", "ecc", "-c", &primary],
+            None,
+        )?;
+        Ok(primary)
+    }
+
+    /// the public and private parts of a sealed object, the length of the public one first
+    pub fn seal(secret: &[u8]) -> Result<Vec<u8>, Error> {
+        let dir = WorkDir::new()?;
+        let primary = create_primary(&dir)?;
+        let (public, private) = (dir.file("seal.pub"), dir.file("seal.priv"));
+        run(
+            &[
+                "tpm2_create",
+                "-Q",
+                "-C",
+                &primary,
+                "-i",
+                "-",
+                "-u",
+                &public,
+                "-r",
+                &private,
+            ],
+            Some(secret),
+        )?;
+
+        let public = std::fs::read(public)?;
+        let mut sealed = (public.len() as u16).to_be_bytes().to_vec();
+        sealed.extend(public);
+        sealed.extend(std::fs::read(private)?);
+        Ok(sealed)
+    }
+
+    pub fn unseal(sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        let malformed = || Error::DeviceBinding("the sealed key is malformed".into());
+        let (len, sealed) = sealed.split_at_checked(2).ok_or_else(malformed)?;
+        let (public, private) = sealed
+            .split_at_checked(u16::from_be_bytes([len[0], len[1]]) as usize)
+            .ok_or_else(malformed)?;
+
+        let dir = WorkDir::new()?;
+        let primary = create_primary(&dir)?;
+        let (public_path, private_path, object) =
+            (dir.file("seal.pub"), dir.file("seal.priv"), dir.file("seal.ctx"));
+        std::fs::write(&public_path, public)?;
+        std::fs::write(&private_path, private)?;
+        // another chip's primary key can't load the object
+        run(
+            &[
+                "tpm2_load",
+                "-Q",
+                "-C",
+                &primary,
+                "-u",
+                &public_path,
+                "-r",
+                &private_path,
+                "-c",
+                &object,
+            ],
+            None,
+        )
+        .map_err(|_| Error::BoundToAnotherDevice)?;
+        run(&["tpm2_unseal", "-c", &object], None)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod tpm {
+    use crate::error::Error;
+
+    pub fn available() -> bool {
+        false
+    }
+
+    pub fn seal(_secret: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::DeviceBinding(
+            "the TPM is only used on Linux, use the software binding".into(),
+        ))
+    }
+
+    pub fn unseal(_sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::BoundToAnotherDevice)
+    }
+}
@@ -0,0 +1,355 @@
+//! `akr doctor`: look for the usual reasons ssh doesn't reach the agent or requests to the phone/tablet
+//! fail, and say how to fix them
+
+use crate::error::Error;
+use crate::output;
+use crate::transport::proxy;
+use crate::util;
+use ansi_term::Colour::{Green, Red, Yellow};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// requests to it are signed, and rejected once the clock is off by more than 5 minutes
+const SIGNED_ENDPOINT: &str = "https://sqs.us-east-1.amazonaws.com/";
+const MAX_CLOCK_SKEW_SECONDS: i64 = 5 * 60;
+const AGENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Outcome {
+    Ok(String),
+    /// works for now, with the fix to apply
+    Warn(String, String),
+    Fail(String, String),
+}
+
+struct Finding {
+    check: &'static str,
+    outcome: Outcome,
+}
+
+pub async fn run() -> Result<(), Error> {
+    let socket = crate::agent_socket_path()?;
+
+    let findings = vec![
+        Finding {
+            check: "ssh version",
+            outcome: check_ssh_version(),
+        },
+        Finding {
+            check: "ssh config",
+            outcome: check_ssh_config(&socket),
+        },
+        Finding {
+            check: "agent socket",
+            outcome: check_socket(&socket).await,
+        },
+        Finding {
+            check: "daemon",
+            outcome: check_daemons(),
+        },
+        Finding {
+            check: "clock",
+            outcome: check_clock().await,
+        },
+        Finding {
+            check: "other agents",
+            outcome: check_conflicting_agents(&socket),
+        },
+    ];
+
+    if output::is_json() {
+        let findings = findings
+            .iter()
+            .map(|finding| {
+                let (status, detail, fix) = match &finding.outcome {
+                    Outcome::Ok(detail) => ("ok", detail, None),
+                    Outcome::Warn(problem, fix) => ("warn", problem, Some(fix)),
+                    Outcome::Fail(problem, fix) => ("fail", problem, Some(fix)),
+                };
+                serde_json::json!({
+                    "check": finding.check,
+                    "status": status,
+                    "detail": detail,
+                    "fix": fix,
+                })
+            })
+            .collect::<Vec<_>>();
+        return output::print_json(&findings);
+    }
+
+    let mut problems = 0;
+    for finding in &findings {
+        match &finding.outcome {
+            Outcome::Ok(detail) => println!("{} {}: {}", Green.paint("✓"), finding.check, detail),
+            Outcome::Warn(problem, fix) | Outcome::Fail(problem, fix) => {
+                problems += 1;
+                let mark = match finding.outcome {
+                    Outcome::Warn(..) => Yellow.paint("!"),
+                    _ => Red.paint("✗"),
+                };
+                println!("{} {}: {}", mark, finding.check, problem);
+                println!("    fix: {}", fix);
+            }
+        }
+    }
+
+    if problems == 0 {
+        println!("\n{}", Green.paint("No problems found"));
+    }
+    Ok(())
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    // `ssh -V` prints to stderr
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some(text)
+}
+
+fn check_ssh_version() -> Outcome {
+    let fix = "install OpenSSH 8.2 or newer, older versions don't support FIDO2 keys".to_string();
+    let output = match command_output("ssh", &["-V"]) {
+        Some(output) => output,
+        None => return Outcome::Fail("ssh is not installed".to_string(), fix),
+    };
+
+    // e.g. "OpenSSH_9.2p1 Debian-2, OpenSSL 3.0.9"
+    let version = output
+        .split_once("OpenSSH_")
+        .map(|(_, rest)| rest)
+        .and_then(|rest| {
+            let (major, rest) = rest.split_once('.')?;
+            let minor = rest.chars().take_while(char::is_ascii_digit).collect::<String>();
+            Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?))
+        });
+
+    match version {
+        Some((major, minor)) if (major, minor) >= (8, 2) => {
+            Outcome::Ok(format!("OpenSSH {}.{}", major, minor))
+        }
+        Some((major, minor)) => Outcome::Fail(format!("OpenSSH {}.{} is too old", major, minor), fix),
+        None => Outcome::Warn(format!("couldn't tell the version of `{}`", output.trim()), fix),
+    }
+}
+
+/// See whether ssh, for any host, talks to the agent on `socket`
+fn check_ssh_config(socket_path: &Path) -> Outcome {
+    let socket = socket_path.display().to_string();
+    let identity_agent = identity_agent();
+    let identity_agent = identity_agent.as_deref();
+    let auth_sock_is_agent = std::env::var("SSH_AUTH_SOCK").is_ok_and(|sock| is_socket(&sock, socket_path));
+
+    match identity_agent {
+        Some(agent) if is_socket(agent, socket_path) => Outcome::Ok(format!("IdentityAgent is {}", agent)),
+        Some("SSH_AUTH_SOCK") | None if auth_sock_is_agent => {
+            Outcome::Ok("SSH_AUTH_SOCK points to the agent".to_string())
+        }
+        Some("none") => Outcome::Fail(
+            "ssh is configured not to use any agent (IdentityAgent none)".to_string(),
+            "remove `IdentityAgent none` from your ssh config and run `akr setup`".to_string(),
+        ),
+        Some(agent) if agent != "SSH_AUTH_SOCK" => Outcome::Fail(
+            format!("ssh uses the agent on {}", agent),
+            format!(
+                "set `IdentityAgent {}` in your ssh config, e.g. with `akr setup`",
+                socket
+            ),
+        ),
+        _ => Outcome::Fail(
+            "ssh doesn't use the akr agent".to_string(),
+            format!(
+                "run `akr setup`, or `export SSH_AUTH_SOCK={}` in your shell profile",
+                socket
+            ),
+        ),
+    }
+}
+
+/// whether `agent` is `socket`, or the link to it where the socket was before the runtime directory
+fn is_socket(agent: &str, socket: &Path) -> bool {
+    let agent = util::expand_home(Path::new(agent));
+    agent == socket
+        || matches!(
+            (std::fs::canonicalize(&agent), std::fs::canonicalize(socket)),
+            (Ok(agent), Ok(socket)) if agent == socket
+        )
+}
+
+/// the IdentityAgent ssh uses, as in the ssh config
+fn identity_agent() -> Option<String> {
+    let effective = command_output("ssh", &["-G", "akr-doctor.invalid"])?;
+    effective
+        .lines()
+        .find_map(|line| line.strip_prefix("identityagent "))
+        .map(|agent| agent.trim().to_string())
+}
+
+async fn check_socket(socket: &Path) -> Outcome {
+    // named pipes on Windows have no file to look at
+    #[cfg(unix)]
+    if let Some(problem) = check_socket_file(socket) {
+        return problem;
+    }
+
+    match tokio::time::timeout(AGENT_TIMEOUT, crate::status::agent_identities(socket)).await {
+        Ok(Ok(identities)) => Outcome::Ok(format!("the agent answers with {} identities", identities)),
+        _ => Outcome::Fail(
+            format!(
+                "nothing answers on {}, it's left over from an agent that exited",
+                socket.display()
+            ),
+            "restart the agent: `akr start`, or `akr setup` to run it as a service".to_string(),
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn check_socket_file(socket: &Path) -> Option<Outcome> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = match std::fs::metadata(socket) {
+        Ok(metadata) => metadata,
+        Err(_) => {
+            return Some(Outcome::Fail(
+                format!("{} doesn't exist", socket.display()),
+                "start the agent with `akr start`, or install it as a service with `akr setup`".to_string(),
+            ))
+        }
+    };
+
+    if metadata.uid() != nix::unistd::getuid().as_raw() {
+        return Some(Outcome::Fail(
+            format!("{} belongs to another user", socket.display()),
+            format!("remove it and restart the agent: `rm {}`", socket.display()),
+        ));
+    }
+
+    // anyone who can reach the socket can ask for signatures
+    if let Some(dir) = socket.parent() {
+        let mode = std::fs::metadata(dir)
+            .map(|m| m.permissions().mode())
+            .unwrap_or(0);
+        if mode & 0o077 != 0 {
+            return Some(Outcome::Warn(
+                format!(
+                    "{} is accessible by other users ({:o})",
+                    dir.display(),
+                    mode & 0o777
+                ),
+                format!("chmod 700 {}", dir.display()),
+            ));
+        }
+    }
+
+    None
+}
+
+/// More than one `akr start` fight over the socket, the one that bound it last wins
+fn check_daemons() -> Outcome {
+    let own_pid = std::process::id().to_string();
+    let pids = command_output("ps", &["-Ao", "pid=,args="])
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut args = line.split_whitespace();
+            let pid = args.next()?;
+            let program = Path::new(args.next()?).file_name()?;
+            (program == "akr" && pid != own_pid && args.any(|arg| arg == "start")).then(|| pid.to_string())
+        })
+        .collect::<Vec<_>>();
+
+    match pids.len() {
+        0 if crate::launch::socket_unit_active() => {
+            Outcome::Ok("started by the systemd socket unit on the first ssh connection".to_string())
+        }
+        0 => Outcome::Warn(
+            "no `akr start` process is running".to_string(),
+            "start the agent with `akr start`, or install it as a service with `akr setup`".to_string(),
+        ),
+        1 => Outcome::Ok(format!("running as pid {}", pids[0])),
+        _ => Outcome::Warn(
+            format!("{} agents are running (pids {})", pids.len(), pids.join(", ")),
+            format!(
+                "stop them (`kill {}`) and start a single agent again",
+                pids.join(" ")
+            ),
+        ),
+    }
+}
+
+async fn check_clock() -> Outcome {
+    let response = match proxy::reqwest_client() {
+        Ok(client) => {
+            client
+                .head(SIGNED_ENDPOINT)
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await
+        }
+        Err(e) => return Outcome::Warn(e.to_string(), "check your --proxy setting".to_string()),
+    };
+    let server_time = response
+        .ok()
+        .and_then(|response| response.headers().get(reqwest::header::DATE).cloned())
+        .and_then(|date| chrono::DateTime::parse_from_rfc2822(date.to_str().ok()?).ok());
+
+    let server_time = match server_time {
+        Some(server_time) => server_time.timestamp(),
+        None => {
+            return Outcome::Warn(
+                "couldn't get the time from the relay to compare".to_string(),
+                "check your network connection, see `akr status`".to_string(),
+            )
+        }
+    };
+
+    let skew = chrono::Utc::now().timestamp() - server_time;
+    let fix = "sync the clock, e.g. enable NTP with `timedatectl set-ntp true`".to_string();
+    match skew.abs() {
+        s if s > MAX_CLOCK_SKEW_SECONDS => Outcome::Fail(
+            format!("the clock is off by {}s, relay requests will be rejected", skew),
+            fix,
+        ),
+        s if s > MAX_CLOCK_SKEW_SECONDS / 5 => Outcome::Warn(format!("the clock is off by {}s", skew), fix),
+        _ => Outcome::Ok(format!("off by {}s", skew)),
+    }
+}
+
+/// gpg-agent and gnome-keyring take over SSH_AUTH_SOCK, which only matters without IdentityAgent
+fn check_conflicting_agents(socket: &Path) -> Outcome {
+    let auth_sock = std::env::var("SSH_AUTH_SOCK").unwrap_or_default();
+    if auth_sock.is_empty() || is_socket(&auth_sock, socket) {
+        return Outcome::Ok("none".to_string());
+    }
+    if identity_agent().is_some_and(|agent| util::expand_home(Path::new(&agent)) == socket) {
+        return Outcome::Ok(format!(
+            "ssh uses IdentityAgent instead of SSH_AUTH_SOCK ({})",
+            auth_sock
+        ));
+    }
+
+    if auth_sock.contains("gpg-agent") || auth_sock.contains("gnupg") {
+        Outcome::Warn(
+            format!("SSH_AUTH_SOCK points to gpg-agent ({})", auth_sock),
+            "remove `enable-ssh-support` from ~/.gnupg/gpg-agent.conf, or rely on IdentityAgent from `akr setup`"
+                .to_string(),
+        )
+    } else if auth_sock.contains("keyring") {
+        Outcome::Warn(
+            format!("SSH_AUTH_SOCK points to gnome-keyring ({})", auth_sock),
+            "disable its ssh component (`systemctl --user disable gcr-ssh-agent.socket`), \
+             or rely on IdentityAgent from `akr setup`"
+                .to_string(),
+        )
+    } else {
+        Outcome::Warn(
+            format!("SSH_AUTH_SOCK points to another agent ({})", auth_sock),
+            "rely on IdentityAgent from `akr setup`, so ssh asks akr for your keys".to_string(),
+        )
+    }
+}
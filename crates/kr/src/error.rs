@@ -34,12 +34,21 @@ pub enum Error {
     #[error("Invalid wire message")]
     InvalidWireProtocol,
 
+    #[error("Invalid wire message: a field of {0} bytes, longer than the {1} it can have")]
+    FieldTooLong(usize, usize),
+
+    #[error("Invalid wire message: a field of {0} bytes with only {1} left")]
+    TruncatedMessage(usize, usize),
+
     #[error("Invalid response message received")]
     UnexpectedResponse,
 
     #[error("Not paired with Akamai MFA. Please run the `pair` command.")]
     NotPaired,
 
+    #[error("No paired device named '{0}'. See `akr devices list`.")]
+    UnknownDevice(String),
+
     #[error("Failed to load stored id")]
     StoredIdentityNotFound,
 
@@ -47,7 +56,7 @@ pub enum Error {
     BadAuthenticatorData,
 
     #[error("QR Code rendering failed: '{0}'")]
-    QrCodeRendering(#[from] qr2term::QrError),
+    QrCodeRendering(#[from] qrcode::types::QrError),
 
     #[error("Invalid utf8 contents: '{0}'")]
     InvalidUtf8(#[from] std::str::Utf8Error),
@@ -76,12 +85,237 @@ pub enum Error {
     #[error("Base64 invalid: '{0}'")]
     Base64Encoding(#[from] base64::DecodeError),
 
+    #[error("The pairing code expired, run `akr pair` again for a new one")]
+    PairingExpired,
+
+    #[error("There are no keys to keep, pair with `akr pair` instead")]
+    NoKeysToRenew,
+
+    #[error("{0} didn't sign with your existing keys, is it signed in to the same account?")]
+    RenewedDeviceMismatch(String),
+
     #[error("Response was never received")]
     ResponseTimedOut,
 
+    #[error("Couldn't reach your phone/tablet, the {0} request was queued. Run `akr flush-queue` once you're back online")]
+    RequestQueued(&'static str),
+
+    #[error("No approval received from your phone within {0}s")]
+    ApprovalTimedOut(u64),
+
+    #[error("Invalid certificate file")]
+    InvalidCertificate,
+
     #[error("Unknown key selected")]
     UnknownKey,
 
+    #[error("The attestation of the new key didn't verify: {0}")]
+    AttestationFailed(String),
+
+    #[error("There is no attestation for this key, only keys created with `akr generate` have one")]
+    NoAttestation,
+
+    #[error("Signature from the device did not verify for '{0}'")]
+    SignatureVerificationFailed(String),
+
+    #[error("The device answered with authenticator data for another rpId than '{0}'")]
+    RpIdHashMismatch(String),
+
+    #[error("The device answered with a credential for '{0}' it doesn't list or that wasn't asked for")]
+    UnknownCredential(String),
+
+    #[error("The device did not confirm user {0}, which the key requires")]
+    KeyFlagsNotSatisfied(&'static str),
+
+    #[error("Signature counter went from {previous} to {received}, the credential may be cloned")]
+    CounterRollback { previous: u32, received: u32 },
+
+    #[error("Unknown transport '{0}', expected \"relay\", \"loopback\" or \"mock\"")]
+    UnknownTransport(String),
+
+    #[error("Unknown format '{0}', expected \"authorized_keys\", \"pem\" or \"openssh-pub\"")]
+    UnknownExportFormat(String),
+
+    #[error("Unknown service '{0}', expected \"github\" or \"gitlab\"")]
+    UnknownForge(String),
+
+    #[error("No {0} token, set {1} or enter one when asked")]
+    MissingToken(&'static str, &'static str),
+
+    #[error("{0} didn't add the key ({1}): {2}")]
+    KeyUploadFailed(&'static str, u16, String),
+
+    #[error("'{0}' isn't a host pattern of the ssh config, it can't be empty or have spaces")]
+    InvalidHostPattern(String),
+
+    #[error("Unknown shell '{0}', expected \"bash\", \"zsh\", \"fish\" or \"powershell\"")]
+    UnknownShell(String),
+
+    #[error("Refused by the team policy: {0}")]
+    RefusedByTeamPolicy(String),
+
+    #[error("The team policy isn't valid: {0}")]
+    InvalidTeamPolicy(String),
+
+    #[error("There is no team policy from policy_url yet, nothing is signed without one")]
+    NoTeamPolicy,
+
+    #[error("Unknown channel '{0}', expected \"stable\" or \"beta\"")]
+    UnknownChannel(String),
+
+    #[error("There is no key to verify the releases with, set update_public_key")]
+    NoReleaseKey,
+
+    #[error("The release has no binary for {0}")]
+    NoReleaseForPlatform(String),
+
+    #[error("The release isn't valid: {0}")]
+    InvalidRelease(String),
+
+    #[error("{0} was installed by a package manager, update it with that")]
+    ManagedInstall(String),
+
+    #[error("The {0} request is not supported by the loopback transport")]
+    UnsupportedTransportRequest(&'static str),
+
+    #[error("Mock transport: {0}")]
+    MockTransport(String),
+
+    #[error("An agent is already running (pid {0}), `akr stop` it or `akr start --takeover` to replace it")]
+    AgentRunning(String),
+
+    #[error("{0} already listens on {1}, stop it, set socket_path in the config file or `akr start --takeover` to replace its socket")]
+    SocketInUse(String, String),
+
+    #[error("{0} belongs to another user, check XDG_RUNTIME_DIR")]
+    SocketDirNotOwned(String),
+
+    #[error("Bluetooth error: {0}")]
+    Bluetooth(String),
+
+    #[error("Local network error: {0}")]
+    LocalNetwork(String),
+
+    #[error("Proxy error: {0}")]
+    Proxy(String),
+
+    #[error("Invalid config {0}: {1}")]
+    InvalidConfig(String, String),
+
+    #[error("Unknown config key '{0}', see `akr config list`")]
+    UnknownConfigKey(String),
+
+    #[error("No origin may use the WebAuthn bridge, allow them with `akr config set webauthn_origins https://example.com`")]
+    NoWebauthnOrigins,
+
+    #[error("No key name given, pass --name or set default_rp_id with `akr config set`")]
+    MissingKeyName,
+
+    #[error("ssh only uses keys whose rp id starts with \"ssh:\", not '{0}'")]
+    InvalidRpId(String),
+
+    #[error("Invalid profile name '{0}', use letters, digits, '-' and '_'")]
+    InvalidProfileName(String),
+
+    #[error("No profile named '{0}', create it with `akr profile create`")]
+    UnknownProfile(String),
+
+    #[error("The profile '{0}' already exists")]
+    ProfileExists(String),
+
+    #[error("The identity file can't be read ({0}), `akr repair` restores its last backup")]
+    CorruptIdentity(String),
+
+    #[error("The identity file was written by a newer akr (version {0}), upgrade akr to use it")]
+    UnsupportedIdentityVersion(u32),
+
+    #[error("No backup of the identity file that can be read, run `akr pair` again")]
+    NoIdentityBackup,
+
+    #[error("Another akr is changing the identity store, try again in a moment")]
+    StoreBusy,
+
+    #[error("The identity store can't be written while it's only locked for reading")]
+    StoreLockedForReading,
+
+    #[error("The agent refused the request, restart it with `akr restart` if it's older than this akr")]
+    ControlRefused,
+
+    #[error("The agent didn't answer in time")]
+    AgentTimeout,
+
+    #[error("No approval window for '{0}', see `akr policy list`")]
+    UnknownPolicyRule(String),
+
+    #[error("Unknown action '{0}' for forwarded requests, expected allow, deny or fresh_approval")]
+    UnknownForwardingAction(String),
+
+    #[error("The audit log was changed or cut before line {0}")]
+    AuditLogTampered(usize),
+
+    #[error("The audit log is keyed with the keychain of this profile, which isn't available")]
+    AuditKeyUnavailable,
+
+    #[error("Audit export error: {0}")]
+    AuditExport(String),
+
+    #[error("The {0} extension isn't supported by this transport")]
+    UnsupportedExtension(&'static str),
+
+    #[error("Invalid local confirmation {0}")]
+    InvalidLocalConfirmation(String),
+
+    #[error("Your phone/tablet speaks protocol {0} and akr {1}, update {2}")]
+    ProtocolMismatch(String, &'static str, &'static str),
+
+    #[error("Couldn't understand the response of your phone/tablet (protocol {0}): {1}, update akr and the Akamai MFA app")]
+    UnexpectedResponseFormat(String, String),
+
+    #[error("The Akamai MFA app on {0} doesn't support {1} yet, update it to use this")]
+    UnsupportedByApp(String, &'static str),
+
+    #[error("Invalid duration '{0}', e.g. \"90d\", \"12w\" or \"36h\"")]
+    InvalidDuration(String),
+
+    #[error("The sign request was not confirmed on this computer")]
+    NotConfirmedLocally,
+
+    #[error("The salt must be 32 bytes, as 64 hex characters")]
+    InvalidSalt,
+
+    #[error("The device didn't derive a secret, its app may not support hmac-secret")]
+    NoDerivedSecret,
+
+    #[error("age plugin error: {0}")]
+    AgePlugin(String),
+
+    #[error("Keychain error: {0}")]
+    Keychain(String),
+
+    #[error("There is no keychain on this platform to keep the key of the identity store")]
+    KeychainUnavailable,
+
+    #[error("The identity store is encrypted but its key isn't in the keychain, restore the keychain or run `akr pair` again")]
+    IdentityStoreLocked,
+
+    #[error("The identity store couldn't be decrypted, it was changed or sealed with another key")]
+    IdentityStoreCorrupted,
+
+    #[error("Device binding error: {0}")]
+    DeviceBinding(String),
+
+    #[error("The key of the identity store is bound to another machine, run `akr pair` again here")]
+    BoundToAnotherDevice,
+
+    #[error("No FIDO2 security key found")]
+    NoSecurityKey,
+
+    #[error("Security key error: 0x{0:02x}")]
+    CtapStatus(u8),
+
+    #[error("Invalid response from the security key")]
+    InvalidCtapResponse,
+
     #[error("Invalid RP prefix")]
     BadRpPrefix,
 
@@ -91,18 +325,52 @@ pub enum Error {
     #[error("Request error: {0}")]
     HttpRequestError(#[from] reqwest::Error),
 
+    #[cfg(feature = "webauthn-bridge")]
+    #[error("HTTP server error: {0}")]
+    HttpServer(#[from] hyper::Error),
+
     #[error("Template error: {0}")]
     TemplateFailed(#[from] askama::Error),
 
     #[error("Couldn't Parse SSH version: '{0}'")]
     RunScriptError(#[from] ScriptError),
 
+    #[error("systemd: {0}")]
+    Systemd(String),
+
+    #[error("launchd: {0}")]
+    Launchd(String),
+
+    #[error("Scheduled task: {0}")]
+    ScheduledTask(String),
+
+    #[error("git config failed: {0}")]
+    GitConfigFailed(String),
+
+    #[error("Missing signature namespace (-n)")]
+    MissingSignatureNamespace,
+
     #[error("Unable to read azure token details")]
     CannotReadAzureToken,
 
     #[error("Sign flags contain incompatible bits")]
     IllegalFlags,
 
+    #[error("Unsupported key type: {0}")]
+    UnsupportedKeyType(String),
+
+    #[error("Not an OpenSSH private key file")]
+    InvalidKeyFile,
+
+    #[error("{0} already exists, pick another file for the key")]
+    KeyFileExists(String),
+
+    #[error("The key file has a passphrase, remove it with `ssh-keygen -p -f <file>` to import it")]
+    EncryptedKeyFile,
+
+    #[error("Unsupported key constraint: {0}")]
+    UnsupportedKeyConstraint(u8),
+
     #[error("Openssl operation failed: {0}")]
     SslError(#[from] ErrorStack),
 
@@ -119,6 +387,291 @@ impl From<Infallible> for Error {
     }
 }
 
+/// Whether a failed request is worth sending again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// the network or a relay queue failed, the same request may well go through next time
+    Retryable,
+    /// retrying won't change anything, e.g. the request was refused or nobody answered it
+    Fatal,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        use std::io::ErrorKind::*;
+
+        fn rusoto<E>(e: &rusoto_core::RusotoError<E>) -> bool {
+            match e {
+                rusoto_core::RusotoError::HttpDispatch(_) => true,
+                rusoto_core::RusotoError::Unknown(response) => response.status.is_server_error(),
+                _ => false,
+            }
+        }
+
+        let retryable = match self {
+            Error::IOError(e) => matches!(
+                e.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | BrokenPipe
+                    | TimedOut
+                    | UnexpectedEof
+            ),
+            Error::HttpRequestError(e) => {
+                e.is_connect()
+                    || e.is_timeout()
+                    || e.status().is_some_and(|status| {
+                        status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    })
+            }
+            Error::AwsSqsSendError(e) => rusoto(e),
+            Error::AwsSqsCreateQueueError(e) => rusoto(e),
+            Error::AwsSqsReceiveError(e) => rusoto(e),
+            Error::AwsSqsDeleteError(e) => rusoto(e),
+            Error::AwsSnsPublishError(e) => rusoto(e),
+            _ => false,
+        };
+
+        match retryable {
+            true => ErrorKind::Retryable,
+            false => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// What a command failed on, `akr` exits with its `exit_code` so scripts can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// a file, the OS or a library failed
+    System,
+    /// the command line, the config file or a key, profile or rule asked for
+    Usage,
+    /// pairing with a phone/tablet, or a pairing that's gone
+    Pairing,
+    /// reaching the phone/tablet: the relay, bluetooth, the local network or a proxy
+    Transport,
+    /// the phone/tablet or security key refused the request, or its answer didn't verify
+    Authenticator,
+    /// messages akr and the app don't agree on
+    Protocol,
+    /// the identity store, its keychain key and the audit log
+    Store,
+    /// the running agent and its socket
+    Agent,
+}
+
+impl ErrorCategory {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCategory::System => "system",
+            ErrorCategory::Usage => "usage",
+            ErrorCategory::Pairing => "pairing",
+            ErrorCategory::Transport => "transport",
+            ErrorCategory::Authenticator => "authenticator",
+            ErrorCategory::Protocol => "protocol",
+            ErrorCategory::Store => "store",
+            ErrorCategory::Agent => "agent",
+        }
+    }
+
+    /// 2 like most tools for usage errors, 1 for anything else unexpected
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::System => 1,
+            ErrorCategory::Usage => 2,
+            ErrorCategory::Pairing => 3,
+            ErrorCategory::Transport => 4,
+            ErrorCategory::Authenticator => 5,
+            ErrorCategory::Protocol => 6,
+            ErrorCategory::Store => 7,
+            ErrorCategory::Agent => 8,
+        }
+    }
+}
+
+impl Error {
+    /// The category and the stable code of the error, "not_paired" for `NotPaired`. Codes don't change
+    /// when a message or a variant does, scripts can match them
+    pub fn classify(&self) -> (ErrorCategory, &'static str) {
+        use ErrorCategory::*;
+
+        match self {
+            Error::Json(..) => (System, "json"),
+            Error::IOError(..) => (System, "io"),
+            Error::InvalidPairingKeys => (Pairing, "invalid_pairing_keys"),
+            Error::CannotCreateHomeDir => (System, "cannot_create_home_dir"),
+            Error::CannotReadHomeDir => (System, "cannot_read_home_dir"),
+            Error::InvalidPairingHelloMessage => (Pairing, "invalid_pairing_hello_message"),
+            Error::CryptoInit => (System, "crypto_init"),
+            Error::InvalidCiphertext => (Pairing, "invalid_ciphertext"),
+            Error::UnsealFailed => (Pairing, "unseal_failed"),
+            Error::InvalidWireProtocol => (Protocol, "invalid_wire_protocol"),
+            Error::FieldTooLong(..) => (Protocol, "field_too_long"),
+            Error::TruncatedMessage(..) => (Protocol, "truncated_message"),
+            Error::UnexpectedResponse => (Protocol, "unexpected_response"),
+            Error::NotPaired => (Pairing, "not_paired"),
+            Error::UnknownDevice(..) => (Pairing, "unknown_device"),
+            Error::StoredIdentityNotFound => (Store, "stored_identity_not_found"),
+            Error::BadAuthenticatorData => (Authenticator, "bad_authenticator_data"),
+            Error::QrCodeRendering(..) => (System, "qr_code_rendering"),
+            Error::InvalidUtf8(..) => (System, "invalid_utf8"),
+            Error::AwsHttpClient(..) => (Transport, "aws_http_client"),
+            Error::AwsSqsSendError(..) => (Transport, "aws_sqs_send"),
+            Error::AwsSqsCreateQueueError(..) => (Transport, "aws_sqs_create_queue"),
+            Error::AwsSqsReceiveError(..) => (Transport, "aws_sqs_receive"),
+            Error::AwsSqsDeleteError(..) => (Transport, "aws_sqs_delete"),
+            Error::AwsSnsPublishError(..) => (Transport, "aws_sns_publish"),
+            Error::InvalidUUID(..) => (System, "invalid_uuid"),
+            Error::Base64Encoding(..) => (System, "base64_encoding"),
+            Error::PairingExpired => (Pairing, "pairing_expired"),
+            Error::NoKeysToRenew => (Pairing, "no_keys_to_renew"),
+            Error::RenewedDeviceMismatch(..) => (Pairing, "renewed_device_mismatch"),
+            Error::ResponseTimedOut => (Transport, "response_timed_out"),
+            Error::RequestQueued(..) => (Transport, "request_queued"),
+            Error::ApprovalTimedOut(..) => (Authenticator, "approval_timed_out"),
+            Error::InvalidCertificate => (Usage, "invalid_certificate"),
+            Error::UnknownKey => (Usage, "unknown_key"),
+            Error::AttestationFailed(..) => (Authenticator, "attestation_failed"),
+            Error::NoAttestation => (Usage, "no_attestation"),
+            Error::SignatureVerificationFailed(..) => (Authenticator, "signature_verification_failed"),
+            Error::RpIdHashMismatch(..) => (Authenticator, "rp_id_hash_mismatch"),
+            Error::UnknownCredential(..) => (Authenticator, "unknown_credential"),
+            Error::KeyFlagsNotSatisfied(..) => (Authenticator, "key_flags_not_satisfied"),
+            Error::CounterRollback { .. } => (Authenticator, "counter_rollback"),
+            Error::UnknownTransport(..) => (Usage, "unknown_transport"),
+            Error::UnknownExportFormat(..) => (Usage, "unknown_export_format"),
+            Error::UnknownShell(..) => (Usage, "unknown_shell"),
+            Error::RefusedByTeamPolicy(..) => (Usage, "refused_by_team_policy"),
+            Error::InvalidTeamPolicy(..) => (Usage, "invalid_team_policy"),
+            Error::NoTeamPolicy => (Usage, "no_team_policy"),
+            Error::InvalidHostPattern(..) => (Usage, "invalid_host_pattern"),
+            Error::UnknownForge(..) => (Usage, "unknown_forge"),
+            Error::MissingToken(..) => (Usage, "missing_token"),
+            Error::KeyUploadFailed(..) => (Usage, "key_upload_failed"),
+            Error::UnknownChannel(..) => (Usage, "unknown_channel"),
+            Error::NoReleaseKey => (Usage, "no_release_key"),
+            Error::NoReleaseForPlatform(..) => (Usage, "no_release_for_platform"),
+            Error::InvalidRelease(..) => (System, "invalid_release"),
+            Error::ManagedInstall(..) => (Usage, "managed_install"),
+            Error::UnsupportedTransportRequest(..) => (Transport, "unsupported_transport_request"),
+            Error::MockTransport(..) => (Transport, "mock_transport"),
+            Error::AgentRunning(..) => (Agent, "agent_running"),
+            Error::SocketInUse(..) => (Agent, "socket_in_use"),
+            Error::SocketDirNotOwned(..) => (Agent, "socket_dir_not_owned"),
+            Error::Bluetooth(..) => (Transport, "bluetooth"),
+            Error::LocalNetwork(..) => (Transport, "local_network"),
+            Error::Proxy(..) => (Transport, "proxy"),
+            Error::InvalidConfig(..) => (Usage, "invalid_config"),
+            Error::UnknownConfigKey(..) => (Usage, "unknown_config_key"),
+            Error::NoWebauthnOrigins => (Usage, "no_webauthn_origins"),
+            Error::MissingKeyName => (Usage, "missing_key_name"),
+            Error::InvalidRpId(..) => (Usage, "invalid_rp_id"),
+            Error::InvalidProfileName(..) => (Usage, "invalid_profile_name"),
+            Error::UnknownProfile(..) => (Usage, "unknown_profile"),
+            Error::ProfileExists(..) => (Usage, "profile_exists"),
+            Error::CorruptIdentity(..) => (Store, "corrupt_identity"),
+            Error::UnsupportedIdentityVersion(..) => (Store, "unsupported_identity_version"),
+            Error::NoIdentityBackup => (Store, "no_identity_backup"),
+            Error::StoreBusy => (Store, "store_busy"),
+            Error::StoreLockedForReading => (Store, "store_locked_for_reading"),
+            Error::ControlRefused => (Agent, "control_refused"),
+            Error::AgentTimeout => (Agent, "agent_timeout"),
+            Error::UnknownPolicyRule(..) => (Usage, "unknown_policy_rule"),
+            Error::UnknownForwardingAction(..) => (Usage, "unknown_forwarding_action"),
+            Error::AuditLogTampered(..) => (Store, "audit_log_tampered"),
+            Error::AuditKeyUnavailable => (Store, "audit_key_unavailable"),
+            Error::AuditExport(..) => (System, "audit_export"),
+            Error::UnsupportedExtension(..) => (Authenticator, "unsupported_extension"),
+            Error::InvalidLocalConfirmation(..) => (Usage, "invalid_local_confirmation"),
+            Error::ProtocolMismatch(..) => (Protocol, "protocol_mismatch"),
+            Error::UnexpectedResponseFormat(..) => (Protocol, "unexpected_response_format"),
+            Error::UnsupportedByApp(..) => (Protocol, "unsupported_by_app"),
+            Error::InvalidDuration(..) => (Usage, "invalid_duration"),
+            Error::NotConfirmedLocally => (Authenticator, "not_confirmed_locally"),
+            Error::InvalidSalt => (Usage, "invalid_salt"),
+            Error::NoDerivedSecret => (Authenticator, "no_derived_secret"),
+            Error::AgePlugin(..) => (System, "age_plugin"),
+            Error::Keychain(..) => (Store, "keychain"),
+            Error::KeychainUnavailable => (Store, "keychain_unavailable"),
+            Error::IdentityStoreLocked => (Store, "identity_store_locked"),
+            Error::IdentityStoreCorrupted => (Store, "identity_store_corrupted"),
+            Error::DeviceBinding(..) => (Store, "device_binding"),
+            Error::BoundToAnotherDevice => (Store, "bound_to_another_device"),
+            Error::NoSecurityKey => (Authenticator, "no_security_key"),
+            Error::CtapStatus(..) => (Authenticator, "ctap_status"),
+            Error::InvalidCtapResponse => (Authenticator, "invalid_ctap_response"),
+            Error::BadRpPrefix => (Usage, "bad_rp_prefix"),
+            Error::DeviceError(..) => (Authenticator, "device_error"),
+            Error::HttpRequestError(..) => (Transport, "http_request"),
+            #[cfg(feature = "webauthn-bridge")]
+            Error::HttpServer(..) => (System, "http_server"),
+            Error::TemplateFailed(..) => (System, "template_failed"),
+            Error::RunScriptError(..) => (System, "run_script"),
+            Error::Systemd(..) => (System, "systemd"),
+            Error::Launchd(..) => (System, "launchd"),
+            Error::ScheduledTask(..) => (System, "scheduled_task"),
+            Error::GitConfigFailed(..) => (System, "git_config_failed"),
+            Error::MissingSignatureNamespace => (Usage, "missing_signature_namespace"),
+            Error::CannotReadAzureToken => (Transport, "cannot_read_azure_token"),
+            Error::IllegalFlags => (Usage, "illegal_flags"),
+            Error::UnsupportedKeyType(..) => (Usage, "unsupported_key_type"),
+            Error::InvalidKeyFile => (Usage, "invalid_key_file"),
+            Error::KeyFileExists(..) => (Usage, "key_file_exists"),
+            Error::EncryptedKeyFile => (Usage, "encrypted_key_file"),
+            Error::UnsupportedKeyConstraint(..) => (Usage, "unsupported_key_constraint"),
+            Error::SslError(..) => (System, "ssl"),
+            Error::FromUtf8Error(..) => (System, "from_utf8"),
+            Error::OsshKeysError(..) => (System, "ossh_keys"),
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        self.classify().0
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.classify().1
+    }
+
+    /// What to do about it, for the errors whose message doesn't say already
+    pub fn hint(&self) -> Option<&'static str> {
+        let hint = match self {
+            Error::ResponseTimedOut => {
+                "Check that your phone/tablet is online and the Akamai MFA app may run in the background"
+            }
+            Error::ApprovalTimedOut(_) => "Approve the request in time, or raise sign_timeout with `akr config set`",
+            Error::DeviceError(_) => "The request was refused on your phone/tablet, it says why in the app",
+            Error::UnsealFailed | Error::InvalidCiphertext | Error::InvalidPairingKeys => {
+                "The pairing keys don't match anymore, pair again with `akr pair --renew`"
+            }
+            Error::StoredIdentityNotFound => "Pair with `akr pair` first",
+            Error::UnknownKey => "`akr list` shows your keys and their fingerprints",
+            Error::SignatureVerificationFailed(_) => "Run `akr load` to get the keys on your phone/tablet again",
+            Error::CounterRollback { .. } => {
+                "If you didn't restore the credential from a backup, delete it with `akr delete` and generate a new one"
+            }
+            Error::NoSecurityKey => "Plug in the security key, on Linux check that you may open /dev/hidraw*",
+            Error::CtapStatus(_) => "The security key refused the request, check that it supports FIDO2",
+            Error::AgentTimeout => "Restart the agent with `akr restart`",
+            Error::Keychain(_) => "Pass --no-keychain to keep the identity store unencrypted",
+            Error::CannotCreateHomeDir | Error::CannotReadHomeDir => "Check the permissions of ~/.akr",
+            Error::NoTeamPolicy => "`akr team-policy --fetch` fetches it, check it with `akr config get policy_url`",
+            Error::NoReleaseKey => "The minisign public key of the releases, `akr config set update_public_key <key>`",
+            Error::KeyUploadFailed(_, 401 | 403, _) => {
+                "The token needs the write:public_key scope on GitHub (write:ssh_signing_key for --signing), api on GitLab"
+            }
+            // they say what to do, or only happen while trying the mock and loopback transports
+            Error::RequestQueued(_) | Error::MockTransport(_) | Error::UnsupportedTransportRequest(_) => return None,
+            _ if self.category() == ErrorCategory::Transport => {
+                "`akr doctor` checks the connection to the relay and your proxy settings"
+            }
+            _ => return None,
+        };
+        Some(hint)
+    }
+}
+
 impl From<Error> for ssh_agent::error::Error {
     fn from(error: Error) -> ssh_agent::error::Error {
         ssh_agent::error::Error {
@@ -0,0 +1,128 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use super::{GitConfigArgs, SignArgs};
+use crate::error::Error;
+use crate::sshsig;
+use ansi_term::Colour::Green;
+
+/// The name akr answers to when git runs it as `gpg.ssh.program`
+/// it is a symlink to akr in "~/.akr", created by `akr git-config`
+const SIGNING_PROGRAM: &str = "akr-ssh-keygen";
+
+pub fn run(args: GitConfigArgs) -> Result<(), Error> {
+    let handle = sshsig::find_key_pair_handle(args.key.as_deref())?;
+    let program = install_signing_program()?;
+
+    // "key::" tells git the signing key is a public key literal rather than a file
+    let signing_key = format!("key::{}", handle.authorized_public_key()?);
+    git_config(args.global, "user.signingkey", &signing_key)?;
+    git_config(args.global, "gpg.format", "ssh")?;
+    git_config(args.global, "gpg.ssh.program", &program.to_string_lossy())?;
+
+    println!("{} {}", Green.paint("git will sign with"), handle.key_comment());
+    println!("Sign with `git commit -S`, or set `commit.gpgsign` to sign every commit");
+    Ok(())
+}
+
+fn install_signing_program() -> Result<PathBuf, Error> {
+    let path = crate::create_home_path()?.join(SIGNING_PROGRAM);
+    if path.symlink_metadata().is_ok() {
+        std::fs::remove_file(&path)?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(std::env::current_exe()?, &path)?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file(std::env::current_exe()?, &path)?;
+    Ok(path)
+}
+
+fn git_config(global: bool, key: &str, value: &str) -> Result<(), Error> {
+    let mut command = Command::new("git");
+    command.arg("config");
+    if global {
+        command.arg("--global");
+    }
+
+    let output = command.arg(key).arg(value).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::GitConfigFailed(stderr.trim().to_string()));
+    }
+    Ok(())
+}
+
+/// Whether akr was started through the `gpg.ssh.program` symlink
+pub fn is_signing_program() -> bool {
+    std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .and_then(|path| path.file_name().map(|name| name == SIGNING_PROGRAM))
+        .unwrap_or(false)
+}
+
+/// the named profile the symlink was created for, "~/.akr/profiles/<name>/akr-ssh-keygen"
+fn symlink_profile() -> Option<String> {
+    let path = PathBuf::from(std::env::args_os().next()?);
+    let home = path.parent()?;
+    match home.parent()?.file_name()? == "profiles" {
+        true => Some(home.file_name()?.to_string_lossy().to_string()),
+        false => None,
+    }
+}
+
+/// Speak the `ssh-keygen -Y` interface git uses:
+/// signing goes to the phone, everything else (verify, find-principals...) to ssh-keygen
+pub async fn run_signing_program() -> ! {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // sign with the profile whose home the symlink is in
+    let _ = crate::profile::select(symlink_profile());
+
+    let operation = args
+        .iter()
+        .position(|arg| arg == "-Y")
+        .and_then(|i| args.get(i + 1));
+    if operation.map(String::as_str) != Some("sign") {
+        let status = Command::new("ssh-keygen").args(&args).status();
+        std::process::exit(status.ok().and_then(|status| status.code()).unwrap_or(1));
+    }
+
+    if let Err(e) = sign(args).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
+
+/// `-Y sign -n <namespace> -f <key> [-U] <file>...`
+async fn sign(args: Vec<String>) -> Result<(), Error> {
+    let mut namespace = None;
+    let mut key = None;
+    let mut files = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => namespace = args.next(),
+            "-f" => key = args.next(),
+            // options that take a value we don't use
+            "-Y" | "-O" => {
+                args.next();
+            }
+            // e.g. -U: the key is in an agent, which is where it lives anyway
+            _ if arg.starts_with('-') => {}
+            _ => files.push(arg),
+        }
+    }
+
+    let namespace = namespace.ok_or(Error::MissingSignatureNamespace)?;
+    for file in files {
+        sshsig::run(SignArgs {
+            namespace: namespace.clone(),
+            key: key.clone(),
+            file: Some(file),
+        })
+        .await?;
+    }
+    Ok(())
+}
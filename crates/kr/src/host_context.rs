@@ -0,0 +1,135 @@
+//! Where ssh is connecting to, as ssh itself worked it out, for the phone/tablet to show with a login
+//!
+//! `akr setup --host-context` adds a `Match exec` block to the end of the ssh config, which has ssh run
+//! `akr host-context %n %h %r %p` before it connects. That records the host as typed, the HostName it
+//! resolved to, the remote user and the port for the ssh process, the agent looks them up by the pid of the
+//! ssh on the other end of a connection. The command always succeeds, so the `Match` doesn't change a thing.
+//!
+//! The agent falls back on what it reads from the command line of ssh without the block, that misses the
+//! hosts of aliases and ssh run by other programs.
+
+use crate::cli::HostContextArgs;
+use crate::error::Error;
+use crate::ssh_agent::process_command_line;
+use crate::{policy, profile, util};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// the subcommand of the hook
+pub const COMMAND: &str = "host-context";
+const DIR: &str = "host_context";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostContext {
+    /// as given to ssh, e.g. "prod-bastion"
+    pub host: String,
+    /// what the config made of it, e.g. "10.1.2.3"
+    pub hostname: String,
+    pub user: String,
+    pub port: Option<u16>,
+    /// of the ssh, a process that got its pid later doesn't get the context
+    pub command: String,
+}
+
+impl HostContext {
+    /// The context recorded for the ssh with `pid` and `command` line
+    pub fn load(pid: i32, command: &str) -> Option<HostContext> {
+        let contents = std::fs::read(dir().ok()?.join(format!("{}.json", pid))).ok()?;
+        serde_json::from_slice::<HostContext>(&contents)
+            .ok()
+            .filter(|context| context.command == command)
+    }
+}
+
+/// the same for every profile, the hook doesn't know which one's agent ssh uses
+fn dir() -> Result<PathBuf, Error> {
+    Ok(profile::dir(profile::DEFAULT)?.join(DIR))
+}
+
+/// `akr host-context`, run by ssh. Quiet, ssh would show whatever it prints with every connection
+pub fn run(args: HostContextArgs) {
+    if let Err(e) = record(args) {
+        tracing::debug!("couldn't record the host context: {}", e);
+    }
+}
+
+fn record(args: HostContextArgs) -> Result<(), Error> {
+    let Some((pid, command)) = ssh_process() else {
+        return Ok(());
+    };
+    let context = HostContext {
+        host: args.host,
+        hostname: args.hostname,
+        user: args.user,
+        port: args.port.parse().ok(),
+        command,
+    };
+
+    let dir = dir()?;
+    std::fs::create_dir_all(&dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    prune(&dir);
+    util::write_atomically(&dir.join(format!("{}.json", pid)), &serde_json::to_vec(&context)?)
+}
+
+/// Forget the contexts of the processes that are gone
+fn prune(dir: &std::path::Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let pid = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<i32>().ok());
+        let Some(pid) = pid else {
+            continue;
+        };
+        let recorded = std::fs::read(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<HostContext>(&contents).ok());
+        let running = process_command_line(pid);
+        if recorded.is_none_or(|recorded| Some(recorded.command) != running) {
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
+/// The pid and command line of the ssh that runs the hook, through a shell or not
+fn ssh_process() -> Option<(i32, String)> {
+    let mut pid = parent_pid(std::process::id() as i32)?;
+    // ssh runs the command with the user's shell, which may or may not exec it
+    for _ in 0..2 {
+        let command = process_command_line(pid)?;
+        if policy::ssh_destination(&command).is_some() {
+            return Some((pid, command));
+        }
+        pid = parent_pid(pid)?;
+    }
+    None
+}
+
+fn parent_pid(pid: i32) -> Option<i32> {
+    // "<pid> (<command>) <state> <ppid> ...", the command may have spaces and parentheses
+    #[cfg(target_os = "linux")]
+    let ppid = std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| {
+            let (_, rest) = stat.rsplit_once(')')?;
+            rest.split_whitespace().nth(1)?.parse().ok()
+        });
+
+    #[cfg(not(target_os = "linux"))]
+    let ppid = std::process::Command::new("ps")
+        .args(["-o", "ppid=", "-p", &pid.to_string()])
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse().ok());
+
+    ppid.filter(|ppid| *ppid > 1)
+}
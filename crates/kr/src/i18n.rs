@@ -0,0 +1,99 @@
+//! Translations of the messages of the CLI and the notifications, from the catalogs in "locales", one per
+//! language in a subset of the Fluent syntax: `id = text` with `{ $name }` for arguments, indented lines
+//! continue the message, `#` starts a comment
+//!
+//! The locale is the first of LC_ALL, LC_MESSAGES and LANG that is set, "de_DE.UTF-8" picks "de". Messages a
+//! catalog lacks, and every message for languages without a catalog, are in English. `akr translations` prints
+//! the English catalog to start a translation from, `--missing <locale>` what a catalog still lacks.
+
+use crate::cli::TranslationsArgs;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+const ENGLISH: &str = "en";
+const CATALOGS: &[(&str, &str)] = &[
+    (ENGLISH, include_str!("../locales/en.ftl")),
+    ("de", include_str!("../locales/de.ftl")),
+];
+
+/// the messages of the locale, then the English ones
+static MESSAGES: OnceLock<Vec<HashMap<&'static str, String>>> = OnceLock::new();
+
+/// The language of the environment's locale, e.g. "de" for "de_DE.UTF-8", none for "C" and "POSIX"
+fn language() -> Option<String> {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|locale| !locale.is_empty())?;
+    let language = locale.split(['_', '.', '@', '-']).next()?.to_lowercase();
+    Some(language).filter(|language| !language.is_empty() && language != "c" && language != "posix")
+}
+
+fn catalog(language: &str) -> Option<&'static str> {
+    CATALOGS
+        .iter()
+        .find(|(name, _)| *name == language)
+        .map(|(_, catalog)| *catalog)
+}
+
+/// The messages of a catalog in the order they're in
+fn parse(catalog: &'static str) -> Vec<(&'static str, String)> {
+    let mut messages: Vec<(&'static str, String)> = vec![];
+    for line in catalog.lines() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            if let Some((_, text)) = messages.last_mut() {
+                text.push('\n');
+                text.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((id, text)) = line.split_once('=') {
+            messages.push((id.trim(), text.trim().to_string()));
+        }
+    }
+    messages
+}
+
+fn messages() -> &'static [HashMap<&'static str, String>] {
+    MESSAGES.get_or_init(|| {
+        let language = language().filter(|language| language != ENGLISH);
+        language
+            .as_deref()
+            .and_then(catalog)
+            .into_iter()
+            .chain(catalog(ENGLISH))
+            .map(|catalog| parse(catalog).into_iter().collect())
+            .collect()
+    })
+}
+
+/// The message `id` in the locale's language, with `{ $name }` replaced by the argument of that name,
+/// e.g. `tr("notify-approved", &[("what", &what)])`
+pub fn tr(id: &str, args: &[(&str, &dyn Display)]) -> String {
+    let Some(message) = messages().iter().find_map(|messages| messages.get(id)) else {
+        // only a typo in an id ends up here
+        return id.to_string();
+    };
+    args.iter().fold(message.clone(), |message, (name, value)| {
+        message.replace(&format!("{{ ${} }}", name), &value.to_string())
+    })
+}
+
+/// `akr translations`
+pub fn run(args: TranslationsArgs) {
+    let present = args
+        .missing
+        .as_deref()
+        .and_then(catalog)
+        .map(|catalog| parse(catalog).into_iter().map(|(id, _)| id).collect::<Vec<_>>())
+        .unwrap_or_default();
+    for (id, text) in parse(catalog(ENGLISH).unwrap_or_default()) {
+        if !present.contains(&id) {
+            println!("{} = {}", id, text.replace('\n', "\n    "));
+        }
+    }
+}
@@ -1,65 +1,501 @@
+use crate::attestation::Attestation;
 use crate::create_home_path;
+use crate::crypto;
 use crate::error::Error;
-use crate::protocol::Base64Buffer;
+use crate::keychain;
+use crate::protocol::{Base64Buffer, Capabilities};
 use crate::ssh_format::SshFido2KeyPairHandle;
+use crate::util::{copy_dir, replace_dir, temporary_path, write_atomically};
+use base64::Engine;
 use serde::{Deserialize, Serialize};
-use sodiumoxide::hex;
-use std::path::PathBuf;
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::TryLockError;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct StoredIdentity {
     pub device_id: Option<Base64Buffer>,
     pub key_pair_handles: Vec<SshFido2KeyPairHandle>,
+    pub bluetooth_peers: Vec<BluetoothPeer>,
+    pub app_capabilities: Vec<AppCapabilities>,
+}
+
+/// What the app of a paired phone/tablet speaks, from its answer at pairing, see `Capabilities`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppCapabilities {
+    /// the relay queue of the pairing
+    pub queue_uuid: Uuid,
+    #[serde(flatten)]
+    pub capabilities: Capabilities,
+}
+
+/// A paired phone/tablet that can also be reached over bluetooth LE
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BluetoothPeer {
+    /// the relay queue of the pairing
+    pub queue_uuid: Uuid,
+    /// the GATT service the phone/tablet advertises
+    pub service_uuid: Uuid,
+}
+
+/// How much a key signed, for `akr list --verbose`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KeyUsage {
+    /// the signatures since akr counts them, older versions only kept the time of the last one
+    #[serde(default)]
+    pub count: u64,
+    /// unix time
+    pub last_used_at: Option<i64>,
+    /// "user@host" of the last login signed for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_host: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct StoredId {
+    /// files written before there was a version are version 0
+    #[serde(default)]
+    pub version: u32,
     pub device_id: Option<Base64Buffer>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bluetooth_peers: Vec<BluetoothPeer>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub app_capabilities: Vec<AppCapabilities>,
+}
+
+thread_local! {
+    /// the store locks held by this thread, see `StoredIdentity::lock`
+    static LOCKS_HELD: Cell<usize> = const { Cell::new(0) };
+    /// whether the outermost of them is exclusive
+    static HELD_EXCLUSIVELY: Cell<bool> = const { Cell::new(false) };
+}
+
+/// An advisory lock on the store (flock on unix, LockFileEx on Windows), released when dropped
+struct StoreLock {
+    _file: Option<std::fs::File>,
+}
+
+impl StoreLock {
+    fn held(file: Option<std::fs::File>, exclusive: bool) -> StoreLock {
+        if LOCKS_HELD.with(Cell::get) == 0 {
+            HELD_EXCLUSIVELY.with(|held| held.set(exclusive));
+        }
+        LOCKS_HELD.with(|held| held.set(held.get() + 1));
+        StoreLock { _file: file }
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        LOCKS_HELD.with(|held| held.set(held.get() - 1));
+    }
 }
 
 impl StoredIdentity {
     const ID_FILE: &'static str = "id";
+    /// the version of the id file this akr writes
+    const ID_VERSION: u32 = 1;
+    const LOCK_FILE: &'static str = "id.lock";
+    /// how long to wait for another akr to be done with the store
+    const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+    const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+    /// the previous versions of the id file, for `akr repair`
+    const BACKUPS_DIR: &'static str = "backups";
+    const MAX_BACKUPS: usize = 5;
     const PUBLIC_KEYS_DIR: &'static str = "pub";
+    /// keys added through the agent (ssh-add), kept apart from the
+    /// phone synced keys so `akr load` doesn't wipe them
+    const ADDED_KEYS_DIR: &'static str = "added";
+    /// encrypted software keys added through the agent
+    const LOCAL_KEYS_DIR: &'static str = "local";
+    /// last signature counter seen for each key handle
+    const COUNTERS_DIR: &'static str = "counters";
+    /// certificates attached to keys with `akr load-cert`
+    const CERTIFICATES_DIR: &'static str = "certs";
+    /// attestations of the keys created with `akr generate`
+    const ATTESTATIONS_DIR: &'static str = "attestations";
+    /// how much each key signed, see `KeyUsage`
+    const LAST_USED_DIR: &'static str = "last_used";
+    /// the ".pub" files the ssh config of `akr setup --host` gives as IdentityFile
+    const IDENTITY_FILES_DIR: &'static str = "identity_files";
+    /// keys `ssh-add -D` removed from the agent, their handles and key files stay
+    const REMOVED_KEYS_DIR: &'static str = "removed";
 
     fn dir_path() -> Result<PathBuf, Error> {
         Ok(create_home_path()?)
     }
 
-    fn id_path() -> Result<PathBuf, Error> {
+    pub fn id_path() -> Result<PathBuf, Error> {
         Ok(Self::dir_path()?.join(Self::ID_FILE))
     }
 
+    fn backups_dir_path() -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?.join(Self::BACKUPS_DIR))
+    }
+
     fn pub_keys_dir_path() -> Result<PathBuf, Error> {
         Ok(Self::dir_path()?.join(Self::PUBLIC_KEYS_DIR))
     }
 
+    fn added_keys_dir_path() -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?.join(Self::ADDED_KEYS_DIR))
+    }
+
+    pub fn local_keys_dir_path() -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?.join(Self::LOCAL_KEYS_DIR))
+    }
+
+    fn local_key_path(pub_key_blob: &[u8]) -> Result<PathBuf, Error> {
+        let name = hex::encode(crypto::sha256(pub_key_blob));
+        Ok(Self::local_keys_dir_path()?.join(name))
+    }
+
+    fn key_pair_handle_file_name(handle: &SshFido2KeyPairHandle) -> String {
+        Self::key_handle_file_name(&handle.key_handle)
+    }
+
+    fn key_handle_file_name(key_handle: &[u8]) -> String {
+        hex::encode(crypto::sha256(key_handle))
+    }
+
+    fn removed_keys_dir_path() -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?.join(Self::REMOVED_KEYS_DIR))
+    }
+
+    fn removed_key_path(pub_key_blob: &[u8]) -> Result<PathBuf, Error> {
+        Ok(Self::removed_keys_dir_path()?.join(Self::key_handle_file_name(pub_key_blob)))
+    }
+
+    fn certificate_path(pub_key_blob: &[u8]) -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?
+            .join(Self::CERTIFICATES_DIR)
+            .join(Self::key_handle_file_name(pub_key_blob)))
+    }
+
+    fn attestation_path(key_handle: &[u8]) -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?
+            .join(Self::ATTESTATIONS_DIR)
+            .join(Self::key_handle_file_name(key_handle)))
+    }
+
+    fn last_used_path(key_handle: &[u8]) -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?
+            .join(Self::LAST_USED_DIR)
+            .join(Self::key_handle_file_name(key_handle)))
+    }
+
+    fn sign_counter_path(key_handle: &[u8]) -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?
+            .join(Self::COUNTERS_DIR)
+            .join(Self::key_handle_file_name(key_handle)))
+    }
+
+    fn key_pair_handle_path(handle: &SshFido2KeyPairHandle) -> Result<PathBuf, Error> {
+        Ok(Self::pub_keys_dir_path()?.join(Self::key_pair_handle_file_name(handle)))
+    }
+
+    fn added_key_pair_handle_path(handle: &SshFido2KeyPairHandle) -> Result<PathBuf, Error> {
+        Ok(Self::added_keys_dir_path()?.join(Self::key_pair_handle_file_name(handle)))
+    }
+
+    fn load_key_pair_handles_from_dir(dir: PathBuf) -> Vec<SshFido2KeyPairHandle> {
+        if let Ok(dir) = std::fs::read_dir(dir) {
+            dir.into_iter()
+                .filter_map(|entry| {
+                    let path: PathBuf = entry.ok()?.path();
+                    if path.is_dir() {
+                        return None;
+                    }
+                    let contents = keychain::open(&std::fs::read(path).ok()?).ok()?;
+                    let kp: SshFido2KeyPairHandle = serde_json::from_slice(&contents).ok()?;
+                    Some(kp)
+                })
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
     pub fn store_key_pair_handle(handle: &SshFido2KeyPairHandle) -> Result<(), Error> {
+        let _lock = Self::lock(true)?;
+        Self::write_key_pair_handle(&Self::pub_keys_dir_path()?, handle)
+    }
+
+    fn write_key_pair_handle(dir: &Path, handle: &SshFido2KeyPairHandle) -> Result<(), Error> {
         // filter out keys for other purposes
         if !handle.application.starts_with("ssh:") {
             return Ok(());
         }
 
-        let dir_path = Self::pub_keys_dir_path()?;
+        if !dir.exists() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let path = dir.join(Self::key_pair_handle_file_name(handle));
+        write_atomically(&path, &keychain::seal(&serde_json::to_vec(handle)?)?)?;
+        Ok(())
+    }
+
+    /// delete a single stored key handle, returns whether it was on disk
+    pub fn remove_key_pair_handle(handle: &SshFido2KeyPairHandle) -> Result<bool, Error> {
+        let _lock = Self::lock(true)?;
+        let path = Self::key_pair_handle_path(handle)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    /// persist a key handle added through the agent
+    pub fn store_added_key_pair_handle(handle: &SshFido2KeyPairHandle) -> Result<(), Error> {
+        let _lock = Self::lock(true)?;
+        let dir_path = Self::added_keys_dir_path()?;
         if !dir_path.exists() {
             std::fs::create_dir_all(&dir_path)?;
         }
 
-        let name = hex::encode(
-            sodiumoxide::crypto::hash::sha256::hash(handle.key_handle.as_slice()).as_ref(),
-        );
-        let path = dir_path.join(&name);
-        std::fs::write(path, serde_json::to_vec(handle)?)?;
+        let mut handle = handle.clone();
+        handle.created_at = handle.created_at.or_else(|| Some(chrono::Utc::now().timestamp()));
+        let path = Self::added_key_pair_handle_path(&handle)?;
+        write_atomically(&path, &keychain::seal(&serde_json::to_vec(&handle)?)?)?;
+        Ok(())
+    }
+
+    /// load all key handles previously added through the agent
+    pub fn load_added_key_pair_handles() -> Result<Vec<SshFido2KeyPairHandle>, Error> {
+        let _lock = Self::lock(false)?;
+        Ok(Self::load_key_pair_handles_from_dir(Self::added_keys_dir_path()?))
+    }
+
+    /// delete a key handle added through the agent, returns whether it was on disk
+    pub fn remove_added_key_pair_handle(handle: &SshFido2KeyPairHandle) -> Result<bool, Error> {
+        let _lock = Self::lock(true)?;
+        let path = Self::added_key_pair_handle_path(handle)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    /// attach a certificate (wire format blob) to the key it certifies
+    pub fn store_certificate(pub_key_blob: &[u8], certificate: &[u8]) -> Result<(), Error> {
+        let path = Self::certificate_path(pub_key_blob)?;
+        if let Some(dir_path) = path.parent() {
+            std::fs::create_dir_all(dir_path)?;
+        }
+
+        std::fs::write(path, Base64Buffer(certificate.to_vec()).to_string())?;
+        Ok(())
+    }
+
+    pub fn load_certificate(pub_key_blob: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let path = Self::certificate_path(pub_key_blob)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        Ok(Some(
+            base64::engine::general_purpose::STANDARD.decode(contents.trim())?,
+        ))
+    }
+
+    /// detach the certificate of a key, returns whether there was one
+    pub fn remove_certificate(pub_key_blob: &[u8]) -> Result<bool, Error> {
+        let path = Self::certificate_path(pub_key_blob)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(true)
+    }
+
+    /// Where the ".pub" file of a key for IdentityFile goes, it's only there after `store_identity_file`
+    pub fn identity_file_path(handle: &SshFido2KeyPairHandle) -> Result<PathBuf, Error> {
+        let name = Self::key_handle_file_name(&handle.fmt_public_key()?);
+        Ok(Self::dir_path()?
+            .join(Self::IDENTITY_FILES_DIR)
+            .join(format!("{}.pub", name)))
+    }
+
+    pub fn store_identity_file(handle: &SshFido2KeyPairHandle) -> Result<PathBuf, Error> {
+        let path = Self::identity_file_path(handle)?;
+        if let Some(dir_path) = path.parent() {
+            std::fs::create_dir_all(dir_path)?;
+        }
+
+        std::fs::write(&path, format!("{}\n", handle.openssh_public_key()?))?;
+        Ok(path)
+    }
+
+    pub fn remove_identity_file(handle: &SshFido2KeyPairHandle) -> Result<(), Error> {
+        let path = Self::identity_file_path(handle)?;
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn store_attestation(key_handle: &[u8], attestation: &Attestation) -> Result<(), Error> {
+        let path = Self::attestation_path(key_handle)?;
+        if let Some(dir_path) = path.parent() {
+            std::fs::create_dir_all(dir_path)?;
+        }
+
+        std::fs::write(path, serde_json::to_vec_pretty(attestation)?)?;
         Ok(())
     }
 
-    pub fn clear_stored_key_handles() -> Result<(), Error> {
-        if Self::pub_keys_dir_path()?.exists() {
-            let _ = std::fs::remove_dir_all(Self::pub_keys_dir_path()?)?;
+    pub fn load_attestation(key_handle: &[u8]) -> Result<Option<Attestation>, Error> {
+        let path = Self::attestation_path(key_handle)?;
+        if !path.exists() {
+            return Ok(None);
         }
 
+        Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    /// how much a key signed, nothing if it never did
+    pub fn load_usage(key_handle: &[u8]) -> Result<KeyUsage, Error> {
+        let path = Self::last_used_path(key_handle)?;
+        if !path.exists() {
+            return Ok(KeyUsage::default());
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        // older versions only wrote the time
+        if let Ok(last_used_at) = contents.trim().parse() {
+            return Ok(KeyUsage {
+                last_used_at: Some(last_used_at),
+                ..KeyUsage::default()
+            });
+        }
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// count a signature of the key, for `host` if it was a login
+    pub fn record_use(key_handle: &[u8], host: Option<String>) -> Result<(), Error> {
+        let path = Self::last_used_path(key_handle)?;
+        if let Some(dir_path) = path.parent() {
+            std::fs::create_dir_all(dir_path)?;
+        }
+
+        let mut usage = Self::load_usage(key_handle).unwrap_or_default();
+        usage.count += 1;
+        usage.last_used_at = Some(chrono::Utc::now().timestamp());
+        if host.is_some() {
+            usage.last_host = host;
+        }
+        std::fs::write(path, serde_json::to_vec(&usage)?)?;
         Ok(())
     }
 
+    /// the last signature counter returned for a key handle, if any
+    pub fn load_sign_counter(key_handle: &[u8]) -> Result<Option<u32>, Error> {
+        let path = Self::sign_counter_path(key_handle)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = std::fs::read_to_string(path)?;
+        Ok(contents.trim().parse().ok())
+    }
+
+    pub fn store_sign_counter(key_handle: &[u8], counter: u32) -> Result<(), Error> {
+        let path = Self::sign_counter_path(key_handle)?;
+        if let Some(dir_path) = path.parent() {
+            std::fs::create_dir_all(dir_path)?;
+        }
+
+        std::fs::write(path, counter.to_string())?;
+        Ok(())
+    }
+
+    /// write an (already encrypted) software key and its `.pub` file
+    /// returns the paths of the public and private key files
+    pub fn store_local_key(
+        pub_key_blob: &[u8],
+        private_key: &str,
+        public_key: &str,
+    ) -> Result<(PathBuf, PathBuf), Error> {
+        let dir_path = Self::local_keys_dir_path()?;
+        if !dir_path.exists() {
+            std::fs::create_dir_all(&dir_path)?;
+        }
+
+        let priv_path = Self::local_key_path(pub_key_blob)?;
+        let pub_path = priv_path.with_extension("pub");
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&priv_path)?;
+        file.write_all(private_key.as_bytes())?;
+        std::fs::write(&pub_path, public_key)?;
+
+        Ok((pub_path, priv_path))
+    }
+
+    /// delete a stored software key, returns whether it was on disk
+    pub fn remove_local_key(pub_key_blob: &[u8]) -> Result<bool, Error> {
+        let priv_path = Self::local_key_path(pub_key_blob)?;
+        if !priv_path.exists() {
+            return Ok(false);
+        }
+
+        std::fs::remove_file(&priv_path)?;
+        let _ = std::fs::remove_file(priv_path.with_extension("pub"));
+        Ok(true)
+    }
+
+    /// keep a key out of the agent, across its restarts, until it's added again
+    pub fn mark_removed(pub_key_blob: &[u8]) -> Result<(), Error> {
+        let path = Self::removed_key_path(pub_key_blob)?;
+        if let Some(dir_path) = path.parent() {
+            std::fs::create_dir_all(dir_path)?;
+        }
+
+        std::fs::write(path, Base64Buffer(pub_key_blob.to_vec()).to_string())?;
+        Ok(())
+    }
+
+    /// the keys of `mark_removed`
+    pub fn load_removed_keys() -> Result<HashSet<Vec<u8>>, Error> {
+        let entries = match std::fs::read_dir(Self::removed_keys_dir_path()?) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut removed = HashSet::new();
+        for entry in entries {
+            let contents = std::fs::read_to_string(entry?.path())?;
+            removed.insert(base64::engine::general_purpose::STANDARD.decode(contents.trim())?);
+        }
+        Ok(removed)
+    }
+
+    /// let a key of `mark_removed` back into the agent, returns whether it was marked
+    pub fn unmark_removed(pub_key_blob: &[u8]) -> Result<bool, Error> {
+        let path = Self::removed_key_path(pub_key_blob)?;
+        if !path.exists() {
+            return Ok(false);
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(true)
+    }
+
     pub fn load_from_disk() -> Result<Self, Error> {
         let path = Self::id_path()?;
 
@@ -67,47 +503,385 @@ impl StoredIdentity {
             return Err(Error::StoredIdentityNotFound);
         }
 
-        let contents = std::fs::read_to_string(path)?;
-        let id: StoredId = serde_json::from_str(&contents)?;
+        let (identity, contents) = {
+            let _lock = Self::lock(false)?;
+            let contents = std::fs::read(path)?;
+            let id = Self::parse_id(&contents)?;
+            let key_pair_handles = Self::load_key_pair_handles_from_dir(Self::pub_keys_dir_path()?);
+            let identity = StoredIdentity {
+                device_id: id.device_id,
+                key_pair_handles,
+                bluetooth_peers: id.bluetooth_peers,
+                app_capabilities: id.app_capabilities,
+            };
+            (identity, contents)
+        };
+        // stores from before the encryption are sealed the first time they're loaded
+        if !keychain::is_sealed(&contents) && keychain::is_available() {
+            identity.store_to_disk()?;
+            for handle in Self::load_added_key_pair_handles()? {
+                Self::store_added_key_pair_handle(&handle)?;
+            }
+        }
+        Ok(identity)
+    }
 
-        let key_pair_handles = if let Ok(dir) = std::fs::read_dir(Self::pub_keys_dir_path()?) {
-            dir.into_iter()
-                .map(|entry| {
-                    let path: PathBuf = entry.ok()?.path();
-                    if path.is_dir() {
+    /// Whether the id file, a backup or a key handle is sealed with the key of the keychain
+    pub fn has_sealed_files() -> Result<bool, Error> {
+        let is_sealed = |path: &Path| std::fs::read(path).is_ok_and(|contents| keychain::is_sealed(&contents));
+        let mut files = vec![Self::id_path()?];
+        files.extend(Self::backups()?);
+        for dir in [Self::pub_keys_dir_path()?, Self::added_keys_dir_path()?] {
+            if let Ok(entries) = std::fs::read_dir(dir) {
+                files.extend(entries.filter_map(|entry| Some(entry.ok()?.path())));
+            }
+        }
+        Ok(files.iter().any(|path| is_sealed(path)))
+    }
+
+    /// when the id file and the key handle dirs last changed, to notice `akr load` and co from the agent
+    pub fn change_stamp() -> Vec<Option<SystemTime>> {
+        [
+            Self::id_path(),
+            Self::pub_keys_dir_path(),
+            Self::added_keys_dir_path(),
+        ]
+        .into_iter()
+        .map(|path| std::fs::metadata(path.ok()?).ok()?.modified().ok())
+        .collect()
+    }
+
+    fn parse_id(contents: &[u8]) -> Result<StoredId, Error> {
+        let id: StoredId = serde_json::from_slice(&keychain::open(contents)?)
+            .map_err(|e| Error::CorruptIdentity(e.to_string()))?;
+        if id.version > Self::ID_VERSION {
+            return Err(Error::UnsupportedIdentityVersion(id.version));
+        }
+        Ok(id)
+    }
+
+    /// Lock the store, exclusively to write the id file or the key handles and shared to read them, so
+    /// the agent and concurrent runs of akr never see or leave half of a store. Nested calls on the same
+    /// thread reuse the outer lock, asking for an exclusive one while only holding a shared one is an
+    /// error as the shared lock can't be upgraded without letting another writer in first
+    fn lock(exclusive: bool) -> Result<StoreLock, Error> {
+        if LOCKS_HELD.with(Cell::get) > 0 {
+            if exclusive && !HELD_EXCLUSIVELY.with(Cell::get) {
+                return Err(Error::StoreLockedForReading);
+            }
+            return Ok(StoreLock::held(None, exclusive));
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(Self::dir_path()?.join(Self::LOCK_FILE))?;
+        let try_lock = || match exclusive {
+            true => file.try_lock(),
+            false => file.try_lock_shared(),
+        };
+        match try_lock() {
+            Ok(()) => return Ok(StoreLock::held(Some(file), exclusive)),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        let wait = || {
+            let give_up = Instant::now() + Self::LOCK_TIMEOUT;
+            loop {
+                std::thread::sleep(Self::LOCK_RETRY_INTERVAL);
+                match try_lock() {
+                    Ok(()) => return Ok(()),
+                    Err(TryLockError::WouldBlock) if Instant::now() < give_up => {}
+                    Err(TryLockError::WouldBlock) => return Err(Error::StoreBusy),
+                    Err(TryLockError::Error(e)) => return Err(e.into()),
+                }
+            }
+        };
+        // the agent's worker thread hands its other tasks over while this one waits
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) if runtime.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(wait)?
+            }
+            _ => wait()?,
+        }
+        Ok(StoreLock::held(Some(file), exclusive))
+    }
+
+    /// the file names of the key handles stored or about to be
+    fn key_handle_file_names<'a>(
+        handles: impl Iterator<Item = &'a SshFido2KeyPairHandle>,
+    ) -> BTreeSet<String> {
+        handles
+            .filter(|handle| handle.application.starts_with("ssh:"))
+            .map(Self::key_pair_handle_file_name)
+            .collect()
+    }
+
+    /// Keep the id file and key handles about to be replaced by `id` and `handles`, unless the id file is
+    /// broken or neither changed
+    fn back_up(id: &[u8], handles: &[SshFido2KeyPairHandle]) -> Result<(), Error> {
+        let current = match std::fs::read(Self::id_path()?) {
+            Ok(current) => current,
+            Err(_) => return Ok(()),
+        };
+        let stored = Self::load_key_pair_handles_from_dir(Self::pub_keys_dir_path()?);
+        let unchanged = keychain::open(&current).is_ok_and(|old| old == id)
+            && Self::key_handle_file_names(stored.iter()) == Self::key_handle_file_names(handles.iter());
+        if unchanged || Self::parse_id(&current).is_err() {
+            return Ok(());
+        }
+
+        let dir_path = Self::backups_dir_path()?;
+        if !dir_path.exists() {
+            std::fs::create_dir_all(&dir_path)?;
+        }
+        let written = chrono::Utc::now().timestamp_millis();
+        let backup = dir_path.join(format!("{}.{}", Self::ID_FILE, written));
+        if Self::pub_keys_dir_path()?.is_dir() {
+            let copy = temporary_path(&Self::backed_up_key_handles(&backup));
+            copy_dir(&Self::pub_keys_dir_path()?, &copy)?;
+            std::fs::rename(&copy, Self::backed_up_key_handles(&backup))?;
+        }
+        write_atomically(&backup, &current)?;
+        for old in Self::backups()?.iter().skip(Self::MAX_BACKUPS) {
+            std::fs::remove_file(old)?;
+            let handles = Self::backed_up_key_handles(old);
+            if handles.exists() {
+                std::fs::remove_dir_all(handles)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the key handles kept with a backup of the id file, "pub.<time>" next to "id.<time>"
+    fn backed_up_key_handles(backup: &Path) -> PathBuf {
+        let mut path = backup.with_file_name(Self::PUBLIC_KEYS_DIR);
+        if let Some(written) = backup.extension() {
+            path.set_extension(written);
+        }
+        path
+    }
+
+    /// the backups of the id file, newest first
+    pub fn backups() -> Result<Vec<PathBuf>, Error> {
+        let mut backups = match std::fs::read_dir(Self::backups_dir_path()?) {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    if path.file_stem()? != Self::ID_FILE || !path.is_file() {
                         return None;
                     }
-                    let contents = std::fs::read_to_string(path).ok()?;
-                    let kp: SshFido2KeyPairHandle = serde_json::from_str(&contents).ok()?;
-                    Some(kp)
+                    let written: i64 = path.extension()?.to_str()?.parse().ok()?;
+                    Some((written, path))
                 })
-                .filter_map(std::convert::identity)
-                .collect()
-        } else {
-            vec![]
+                .collect(),
+            Err(_) => vec![],
         };
+        backups.sort_by_key(|(written, _)| std::cmp::Reverse(*written));
+        Ok(backups.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Put the newest backup that reads in place of an id file that doesn't, returns the backup used
+    /// or `None` when the id file was fine. The broken file is kept next to it as "id.broken", the key
+    /// handles backed up with it replace the current ones
+    pub fn repair() -> Result<Option<PathBuf>, Error> {
+        let _lock = Self::lock(true)?;
+        let path = Self::id_path()?;
+        let readable = |path: &PathBuf| Self::parse_id(&std::fs::read(path)?);
+        match readable(&path) {
+            Ok(_) => return Ok(None),
+            // the backups are sealed with the same key, none of them would read either
+            Err(Error::IdentityStoreLocked) => return Err(Error::IdentityStoreLocked),
+            Err(_) => {}
+        }
+
+        let backup = Self::backups()?
+            .into_iter()
+            .find(|backup| readable(backup).is_ok())
+            .ok_or(Error::NoIdentityBackup)?;
+        if path.exists() {
+            std::fs::rename(&path, path.with_extension("broken"))?;
+        }
+        write_atomically(&path, &std::fs::read(&backup)?)?;
 
-        Ok(StoredIdentity {
-            device_id: id.device_id,
-            key_pair_handles,
-        })
+        let handles = Self::backed_up_key_handles(&backup);
+        if handles.is_dir() {
+            let dir_path = Self::pub_keys_dir_path()?;
+            let copy = temporary_path(&dir_path);
+            copy_dir(&handles, &copy)?;
+            replace_dir(&dir_path, &copy)?;
+        }
+        Ok(Some(backup))
+    }
+
+    /// remember that the phone/tablet of a pairing advertises `service_uuid`
+    pub fn set_bluetooth_peer(&mut self, queue_uuid: Uuid, service_uuid: Option<Uuid>) {
+        self.bluetooth_peers.retain(|peer| peer.queue_uuid != queue_uuid);
+        if let Some(service_uuid) = service_uuid {
+            self.bluetooth_peers.push(BluetoothPeer {
+                queue_uuid,
+                service_uuid,
+            });
+        }
+    }
+
+    /// remember what the app of a pairing speaks, an app that didn't say keeps what it said before
+    pub fn set_app_capabilities(&mut self, queue_uuid: Uuid, capabilities: Option<Capabilities>) {
+        if let Some(capabilities) = capabilities {
+            self.app_capabilities.retain(|app| app.queue_uuid != queue_uuid);
+            self.app_capabilities.push(AppCapabilities {
+                queue_uuid,
+                capabilities,
+            });
+        }
+    }
+
+    /// forget the bluetooth details and capabilities of a pairing that's gone
+    pub fn forget_pairing(&mut self, queue_uuid: Uuid) {
+        self.set_bluetooth_peer(queue_uuid, None);
+        self.app_capabilities.retain(|app| app.queue_uuid != queue_uuid);
+    }
+
+    /// follow a pairing to its new queue after its keys were rotated
+    pub fn move_pairing(&mut self, from: Uuid, to: Uuid) {
+        self.bluetooth_peers
+            .iter_mut()
+            .filter(|peer| peer.queue_uuid == from)
+            .for_each(|peer| peer.queue_uuid = to);
+        self.app_capabilities
+            .iter_mut()
+            .filter(|app| app.queue_uuid == from)
+            .for_each(|app| app.queue_uuid = to);
     }
 
     pub fn store_to_disk(&self) -> Result<(), Error> {
+        let _lock = Self::lock(true)?;
         let path = Self::id_path()?;
-        std::fs::write(
-            &path,
-            serde_json::to_string_pretty(&StoredId {
-                device_id: self.device_id.clone(),
-            })?,
-        )?;
+        let id = serde_json::to_string_pretty(&StoredId {
+            version: Self::ID_VERSION,
+            device_id: self.device_id.clone(),
+            bluetooth_peers: self.bluetooth_peers.clone(),
+            app_capabilities: self.app_capabilities.clone(),
+        })?;
 
-        Self::clear_stored_key_handles()?;
+        // labels and tags only exist on this machine, the phone/tablet sends the keys without them
+        let dir_path = Self::pub_keys_dir_path()?;
+        let previous = Self::load_key_pair_handles_from_dir(dir_path.clone());
+        let handles = self
+            .key_pair_handles
+            .iter()
+            .map(|handle| {
+                let mut handle = handle.clone();
+                let old = previous.iter().find(|old| old.key_handle == handle.key_handle);
+                if let Some(old) = old {
+                    handle.label = handle.label.or_else(|| old.label.clone());
+                    if handle.tags.is_empty() {
+                        handle.tags = old.tags.clone();
+                    }
+                }
+                handle.created_at = handle
+                    .created_at
+                    .or_else(|| old.and_then(|old| old.created_at))
+                    .or_else(|| Some(chrono::Utc::now().timestamp()));
+                handle
+            })
+            .collect::<Vec<_>>();
+        Self::back_up(id.as_bytes(), &handles)?;
 
-        self.key_pair_handles
+        // the key handles go to a new directory put in place of the old one once they're all written,
+        // a failed write leaves the store as it was
+        let new_dir = temporary_path(&dir_path);
+        std::fs::create_dir_all(&new_dir)?;
+        let written = handles
             .iter()
-            .map(Self::store_key_pair_handle)
-            .collect::<Result<(), Error>>()?;
-        Ok(())
+            .try_for_each(|handle| Self::write_key_pair_handle(&new_dir, handle))
+            .and_then(|()| write_atomically(&path, &keychain::seal(id.as_bytes())?))
+            .and_then(|()| replace_dir(&dir_path, &new_dir));
+        if written.is_err() {
+            let _ = std::fs::remove_dir_all(&new_dir);
+        }
+        written
+    }
+
+    /// write back a key changed in place, e.g. by `akr rename`, wherever it is stored
+    pub fn update_key_pair_handle(handle: &SshFido2KeyPairHandle) -> Result<(), Error> {
+        let _lock = Self::lock(true)?;
+        match Self::added_key_pair_handle_path(handle)?.exists() {
+            true => Self::store_added_key_pair_handle(handle),
+            false => Self::store_key_pair_handle(handle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::use_temporary_home;
+
+    fn handle(byte: u8) -> SshFido2KeyPairHandle {
+        SshFido2KeyPairHandle {
+            application: "ssh:".to_string(),
+            public_key: vec![byte; 32],
+            key_handle: vec![byte; 16],
+            flags: SshFido2KeyPairHandle::SSH_SK_USER_PRESENCE_REQD,
+            key_type: Default::default(),
+            comment: String::new(),
+            label: None,
+            tags: vec![],
+            created_at: None,
+        }
+    }
+
+    fn identity(device_id: u8, handles: &[u8]) -> StoredIdentity {
+        StoredIdentity {
+            device_id: Some(Base64Buffer(vec![device_id])),
+            key_pair_handles: handles.iter().copied().map(handle).collect(),
+            bluetooth_peers: vec![],
+            app_capabilities: vec![],
+        }
+    }
+
+    fn stored_key_handles() -> BTreeSet<Vec<u8>> {
+        let identity = StoredIdentity::load_from_disk().unwrap();
+        identity
+            .key_pair_handles
+            .into_iter()
+            .map(|handle| handle.key_handle)
+            .collect()
+    }
+
+    #[test]
+    fn backs_up_and_repairs_the_key_handles_with_the_id_file() {
+        use_temporary_home();
+        identity(1, &[1, 2]).store_to_disk().unwrap();
+        assert_eq!(stored_key_handles(), [vec![1; 16], vec![2; 16]].into());
+
+        identity(2, &[3]).store_to_disk().unwrap();
+        assert_eq!(stored_key_handles(), [vec![3; 16]].into());
+        let backup = &StoredIdentity::backups().unwrap()[0];
+        let backed_up = std::fs::read_dir(StoredIdentity::backed_up_key_handles(backup)).unwrap();
+        assert_eq!(backed_up.count(), 2);
+
+        std::fs::write(StoredIdentity::id_path().unwrap(), b"broken").unwrap();
+        assert_eq!(StoredIdentity::repair().unwrap().as_ref(), Some(backup));
+        assert_eq!(stored_key_handles(), [vec![1; 16], vec![2; 16]].into());
+    }
+
+    #[test]
+    fn refuses_an_exclusive_lock_under_a_shared_one() {
+        use_temporary_home();
+        let shared = StoredIdentity::lock(false).unwrap();
+        assert!(StoredIdentity::lock(false).is_ok());
+        assert!(matches!(
+            StoredIdentity::lock(true),
+            Err(Error::StoreLockedForReading)
+        ));
+        drop(shared);
+
+        let _exclusive = StoredIdentity::lock(true).unwrap();
+        assert!(StoredIdentity::lock(false).is_ok());
+        assert!(StoredIdentity::lock(true).is_ok());
     }
 }
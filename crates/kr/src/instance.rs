@@ -0,0 +1,191 @@
+//! One agent per profile: while it runs, the agent holds a lock on "agent.pid" in the profile's home, with its
+//! pid in it, so a second `akr start` finds it instead of taking its socket, and `--takeover` can stop it
+//!
+//! A lock nobody holds was left by an agent that died, the next one adopts it as is. So is a socket nobody
+//! listens on, while one another agent (gnome-keyring, ssh-agent) listens on is only replaced with `--takeover`.
+//!
+//! The socket goes in "akr" of XDG_RUNTIME_DIR when there is one, a directory only the user can enter, with a
+//! link to it where it used to be for the ssh configs written before. It's only the user's either way.
+
+use crate::error::Error;
+use crate::ssh_agent::Agent;
+use std::fs::{File, TryLockError};
+use std::io::{Read, Seek, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+
+const FILE: &str = "agent.pid";
+/// the agent being taken over finishes the requests it's answering first, for at most its signing timeout
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(Agent::DEFAULT_SIGN_TIMEOUT.as_secs() + 10);
+
+/// The lock of the running agent, held until it exits
+pub struct Instance {
+    _lock: File,
+}
+
+/// Take the lock, stopping the agent holding it if `takeover`
+pub async fn acquire(takeover: bool) -> Result<Instance, Error> {
+    let path = crate::create_home_path()?.join(FILE);
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path)?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            let pid = pid
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| Error::AgentRunning(pid.trim().to_string()))?;
+            if !takeover {
+                return Err(Error::AgentRunning(pid.to_string()));
+            }
+
+            println!("stopping the agent running as pid {}", pid);
+            stop(pid)?;
+            let deadline = Instant::now() + TAKEOVER_TIMEOUT;
+            while let Err(TryLockError::WouldBlock) = file.try_lock() {
+                if Instant::now() > deadline {
+                    return Err(Error::AgentRunning(pid.to_string()));
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+    Ok(Instance { _lock: file })
+}
+
+fn stop(pid: u32) -> Result<(), Error> {
+    #[cfg(unix)]
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(pid as i32),
+        nix::sys::signal::Signal::SIGTERM,
+    )
+    .map_err(std::io::Error::from)?;
+    #[cfg(windows)]
+    std::process::Command::new("taskkill")
+        .args(["/F", "/PID", &pid.to_string()])
+        .output()?;
+    Ok(())
+}
+
+/// "akr" in XDG_RUNTIME_DIR, if set
+#[cfg(unix)]
+pub fn runtime_dir() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+    Some(PathBuf::from(dir).join("akr"))
+}
+
+/// The socket the agent bound, removed when it shuts down unless another agent bound the path since
+#[cfg(unix)]
+pub struct Socket {
+    path: PathBuf,
+    ino: u64,
+    link: Option<PathBuf>,
+}
+
+/// Bind the agent's socket at `path`, for the user only unless `shared`
+#[cfg(unix)]
+pub async fn bind(
+    path: &Path,
+    takeover: bool,
+    shared: bool,
+) -> Result<(tokio::net::UnixListener, Socket), Error> {
+    let runtime_dir = runtime_dir().filter(|dir| path.parent() == Some(dir.as_path()));
+    let link = match &runtime_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+            if std::fs::metadata(dir)?.uid() != nix::unistd::getuid().as_raw() {
+                return Err(Error::SocketDirNotOwned(dir.display().to_string()));
+            }
+            Some(crate::create_home_path()?.join(crate::SSH_AGENT_PIPE))
+        }
+        None => None,
+    };
+
+    if let Some(link) = &link {
+        match std::fs::symlink_metadata(link) {
+            // left by the last agent, it leads to the socket about to be replaced
+            Ok(metadata) if metadata.file_type().is_symlink() => std::fs::remove_file(link)?,
+            _ => clear_socket(link, takeover).await?,
+        }
+    }
+    clear_socket(path, takeover).await?;
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    // the connections of other users are refused anyway, unless `shared`
+    let mode = match shared {
+        true => 0o666,
+        false => 0o600,
+    };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    let ino = std::fs::metadata(path)?.ino();
+    if let Some(link) = &link {
+        std::os::unix::fs::symlink(path, link)?;
+    }
+
+    let socket = Socket {
+        path: path.to_path_buf(),
+        ino,
+        link,
+    };
+    Ok((listener, socket))
+}
+
+#[cfg(unix)]
+impl Socket {
+    pub fn remove(self) {
+        if std::fs::metadata(&self.path).is_ok_and(|metadata| metadata.ino() == self.ino) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+        if let Some(link) = self.link {
+            if std::fs::read_link(&link).is_ok_and(|target| target == self.path) {
+                let _ = std::fs::remove_file(&link);
+            }
+        }
+    }
+}
+
+/// Make way for the agent's socket at `path`: remove one left over, and one another agent listens on only
+/// if `takeover`
+#[cfg(unix)]
+async fn clear_socket(path: &Path, takeover: bool) -> Result<(), Error> {
+    if std::fs::symlink_metadata(path).is_err() {
+        return Ok(());
+    }
+    if let Ok(stream) = tokio::net::UnixStream::connect(path).await {
+        // the credentials of whoever listens
+        let listener = stream
+            .peer_cred()
+            .ok()
+            .and_then(|cred| cred.pid())
+            .and_then(|pid| {
+                Some(format!(
+                    "`{}` (pid {})",
+                    crate::ssh_agent::process_command_line(pid)?,
+                    pid
+                ))
+            })
+            .unwrap_or_else(|| "another agent".to_string());
+        if !takeover {
+            return Err(Error::SocketInUse(listener, path.display().to_string()));
+        }
+        println!("taking {} over from {}", path.display(), listener);
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
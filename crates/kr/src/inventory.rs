@@ -0,0 +1,258 @@
+//! `akr inventory`: what a machine has paired and which keys it holds, for MDM and compliance tooling
+//!
+//! With `--json` it's a single document of `SCHEMA_VERSION`, fields are only ever added to it. The command
+//! exits with `NOT_COMPLIANT` when a check of `compliance` fails, apart from the exit codes of errors, so a
+//! fleet agent can act on the exit code alone.
+
+use crate::client::Client;
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::{config, device_binding, keychain, output, profile, team_policy};
+use ansi_term::Colour::{Green, Red};
+use serde::Serialize;
+
+/// of the JSON document
+const SCHEMA_VERSION: u32 = 1;
+/// the exit code when the machine isn't compliant
+pub const NOT_COMPLIANT: i32 = 9;
+
+#[derive(Serialize)]
+struct Inventory {
+    schema_version: u32,
+    generated_at: i64,
+    akr_version: &'static str,
+    protocol_version: &'static str,
+    machine: Machine,
+    profile: String,
+    store: Store,
+    devices: Vec<Device>,
+    keys: Vec<Key>,
+    team_policy: Option<TeamPolicy>,
+    compliance: Compliance,
+}
+
+#[derive(Serialize)]
+struct Machine {
+    hostname: String,
+    user: String,
+    platform: String,
+    os: String,
+}
+
+#[derive(Serialize)]
+struct Store {
+    encrypted: bool,
+    /// "tpm" or "software", see `akr device-binding`
+    binding: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Device {
+    name: String,
+    paired_at: Option<i64>,
+    keys_rotated_at: Option<i64>,
+    last_seen_at: Option<i64>,
+    /// the protocol version of the app, unknown for apps from before the handshake
+    app_protocol_version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Key {
+    fingerprint: String,
+    key_type: &'static str,
+    rp_id: String,
+    label: Option<String>,
+    tags: Vec<String>,
+    created_at: Option<i64>,
+    last_used_at: Option<i64>,
+    /// none for keys the app didn't attest, and those imported or created before akr kept attestations
+    attestation: Option<Attestation>,
+}
+
+#[derive(Serialize)]
+struct Attestation {
+    format: String,
+    self_attested: bool,
+    /// the subject of the attestation certificate
+    attested_by: Option<String>,
+    verified_at: i64,
+}
+
+#[derive(Serialize)]
+struct TeamPolicy {
+    url: String,
+    /// none until the agent fetched the policy
+    serial: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Compliance {
+    compliant: bool,
+    violations: Vec<Violation>,
+}
+
+#[derive(Serialize)]
+struct Violation {
+    /// "paired", "team_policy" or "allowed_rp_ids"
+    check: &'static str,
+    detail: String,
+}
+
+pub fn run() -> Result<(), Error> {
+    let inventory = inventory()?;
+
+    if output::is_json() {
+        output::print_json(&inventory)?;
+    } else {
+        print(&inventory);
+    }
+    if !inventory.compliance.compliant {
+        std::process::exit(NOT_COMPLIANT);
+    }
+    Ok(())
+}
+
+fn inventory() -> Result<Inventory, Error> {
+    let id = StoredIdentity::load_from_disk()?;
+    let pairings = match Client::pairings() {
+        Err(Error::NotPaired) => vec![],
+        pairings => pairings?,
+    };
+    let devices = pairings
+        .iter()
+        .map(|pairing| {
+            let queue_uuid = pairing.queue_uuid().ok();
+            let capabilities = id
+                .app_capabilities
+                .iter()
+                .find(|app| Some(app.queue_uuid) == queue_uuid);
+            Device {
+                name: pairing.device_name.clone(),
+                paired_at: Some(pairing.paired_at).filter(|t| *t > 0),
+                keys_rotated_at: Some(pairing.keys_rotated_at).filter(|t| *t > 0),
+                last_seen_at: Some(pairing.last_seen_at).filter(|t| *t > 0),
+                app_protocol_version: capabilities.map(|app| app.capabilities.version.clone()),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut handles = id.key_pair_handles;
+    handles.extend(StoredIdentity::load_added_key_pair_handles()?);
+    handles.retain(|handle| handle.application.starts_with("ssh:"));
+
+    let mut violations = vec![];
+    if devices.is_empty() {
+        violations.push(Violation {
+            check: "paired",
+            detail: "no phone/tablet is paired".to_string(),
+        });
+    }
+    let policy = match team_policy::current() {
+        Ok(policy) => policy,
+        Err(e) => {
+            violations.push(Violation {
+                check: "team_policy",
+                detail: e.to_string(),
+            });
+            None
+        }
+    };
+
+    let mut keys = vec![];
+    for handle in &handles {
+        if let Some(Err(e)) = policy
+            .as_ref()
+            .map(|policy| policy.check_rp_id(&handle.application))
+        {
+            violations.push(Violation {
+                check: "allowed_rp_ids",
+                detail: format!("{}: {}", handle.fingerprint()?, e),
+            });
+        }
+        let attestation = match StoredIdentity::load_attestation(&handle.key_handle)? {
+            Some(attestation) => Some(Attestation {
+                self_attested: attestation.is_self_attested(),
+                attested_by: attestation.attested_by()?,
+                format: attestation.format,
+                verified_at: attestation.verified_at,
+            }),
+            None => None,
+        };
+        keys.push(Key {
+            fingerprint: handle.fingerprint()?,
+            key_type: handle.key_type.type_id(),
+            rp_id: handle.application.clone(),
+            label: handle.label.clone(),
+            tags: handle.tags.clone(),
+            created_at: handle.created_at,
+            last_used_at: StoredIdentity::load_usage(&handle.key_handle)?.last_used_at,
+            attestation,
+        });
+    }
+
+    let stored_key = keychain::stored_key()?;
+    Ok(Inventory {
+        schema_version: SCHEMA_VERSION,
+        generated_at: chrono::Utc::now().timestamp(),
+        akr_version: env!("CARGO_PKG_VERSION"),
+        protocol_version: PROTOCOL_VERSION,
+        machine: Machine {
+            hostname: whoami::hostname(),
+            user: whoami::username(),
+            platform: whoami::platform().to_string(),
+            os: whoami::distro(),
+        },
+        profile: profile::current().to_string(),
+        store: Store {
+            encrypted: stored_key.is_some(),
+            binding: stored_key
+                .as_deref()
+                .and_then(device_binding::binding_of)
+                .map(|binding| binding.to_string()),
+        },
+        devices,
+        keys,
+        team_policy: config::get().policy_url.as_ref().map(|url| TeamPolicy {
+            url: url.to_string(),
+            serial: policy.as_ref().map(|policy| policy.serial),
+        }),
+        compliance: Compliance {
+            compliant: violations.is_empty(),
+            violations,
+        },
+    })
+}
+
+fn print(inventory: &Inventory) {
+    let machine = &inventory.machine;
+    println!(
+        "akr {} on {} ({}), {}@{}, profile {}",
+        inventory.akr_version,
+        machine.os,
+        machine.platform,
+        machine.user,
+        machine.hostname,
+        inventory.profile
+    );
+    let attested = |key: &&Key| key.attestation.as_ref().is_some_and(|a| !a.self_attested);
+    println!(
+        "{} devices, {} keys ({} attested by a certificate)",
+        inventory.devices.len(),
+        inventory.keys.len(),
+        inventory.keys.iter().filter(attested).count()
+    );
+    if let Some(policy) = &inventory.team_policy {
+        match policy.serial {
+            Some(serial) => println!("Team policy #{} from {}", serial, policy.url),
+            None => println!("Team policy from {}, not fetched", policy.url),
+        }
+    }
+
+    if inventory.compliance.compliant {
+        println!("{}", Green.paint("Compliant"));
+    }
+    for violation in &inventory.compliance.violations {
+        println!("{} {}: {}", Red.paint("✗"), violation.check, violation.detail);
+    }
+}
@@ -0,0 +1,78 @@
+//! FIDO2 keys indexed by the SHA256 fingerprint of their public key, found by their public key, a certificate
+//! of theirs (a sign request for a certificate is for the key it certifies) or the fingerprint `akr list` shows
+
+use crate::crypto;
+use crate::error::Error;
+use crate::ssh_format::{SshFido2KeyPairHandle, SshWirePublicKey};
+use base64::Engine;
+use std::collections::HashMap;
+
+type Digest = [u8; 32];
+
+#[derive(Debug, Default)]
+pub struct KeyIndex {
+    keys: HashMap<Digest, (SshWirePublicKey, SshFido2KeyPairHandle)>,
+}
+
+impl KeyIndex {
+    pub fn from_handles(handles: Vec<SshFido2KeyPairHandle>) -> Result<Self, Error> {
+        let mut index = KeyIndex::default();
+        for handle in handles {
+            index.insert(handle.fmt_public_key()?, handle);
+        }
+        Ok(index)
+    }
+
+    fn digest(public_key: &[u8]) -> Digest {
+        crypto::sha256(public_key)
+    }
+
+    /// Returns the key that was there for `public_key`, if any
+    pub fn insert(
+        &mut self,
+        public_key: SshWirePublicKey,
+        handle: SshFido2KeyPairHandle,
+    ) -> Option<SshFido2KeyPairHandle> {
+        self.keys
+            .insert(Self::digest(&public_key), (public_key, handle))
+            .map(|(_, handle)| handle)
+    }
+
+    pub fn remove(&mut self, public_key: &[u8]) -> Option<SshFido2KeyPairHandle> {
+        self.keys
+            .remove(&Self::digest(public_key))
+            .map(|(_, handle)| handle)
+    }
+
+    /// The key of `blob`, a public key or a certificate of one
+    pub fn get(&self, blob: &[u8]) -> Option<&SshFido2KeyPairHandle> {
+        if let Some((_, handle)) = self.keys.get(&Self::digest(blob)) {
+            return Some(handle);
+        }
+        let certified = SshFido2KeyPairHandle::parse_public_key_from_certificate(blob).ok()?;
+        self.keys.get(&Self::digest(&certified)).map(|(_, handle)| handle)
+    }
+
+    /// The key of a fingerprint like "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s"
+    pub fn get_by_fingerprint(&self, fingerprint: &str) -> Option<&SshFido2KeyPairHandle> {
+        let encoded = fingerprint.strip_prefix("SHA256:")?;
+        let digest = base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(encoded.trim_end_matches('='))
+            .ok()?;
+        self.keys.get(digest.as_slice()).map(|(_, handle)| handle)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SshWirePublicKey, &SshFido2KeyPairHandle)> {
+        self.keys
+            .values()
+            .map(|(public_key, handle)| (public_key, handle))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
@@ -0,0 +1,413 @@
+//! At-rest encryption of the identity store and the pairings, with the key kept by the OS
+//!
+//! The key lives in the macOS Keychain, in the Secret Service on Linux (through `secret-tool`) and, on Windows,
+//! in a file protected with DPAPI. Files written before are plaintext and still read, the store is rewritten
+//! encrypted the first time it's loaded. Without a keychain, or with `--no-keychain`, everything stays plaintext,
+//! unless the key is bound to the machine (see `device_binding`) and can be kept in "~/.akr" instead.
+
+use crate::config;
+use crate::crypto::{self, Key};
+use crate::device_binding::{self, Binding};
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use crate::pairing::Pairing;
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+/// starts every encrypted file, followed by the nonce and the sealed contents
+const MAGIC: &[u8] = b"akr-sealed-v1\n";
+const SERVICE: &str = "akr";
+
+static DISABLED: OnceLock<bool> = OnceLock::new();
+/// `None` once there turned out to be no keychain to keep a new key
+static KEY: OnceLock<Option<Key>> = OnceLock::new();
+
+/// Don't touch the keychain, given `--no-keychain` or `keychain = false` in the config file
+pub fn configure(disabled: bool) {
+    let _ = DISABLED.set(disabled);
+}
+
+pub fn is_enabled() -> bool {
+    !DISABLED.get().copied().unwrap_or(false)
+}
+
+/// the key of the current profile, made and stored first if `create`
+fn key(create: bool) -> Result<Option<&'static Key>, Error> {
+    if let Some(key) = KEY.get() {
+        return Ok(key.as_ref());
+    }
+
+    let binding = config::get().device_binding;
+    let account = account();
+    let key = match load(&account)? {
+        Some(stored) => {
+            let stored = Zeroizing::new(stored);
+            parse_key(&Zeroizing::new(device_binding::unbind(&stored)?))?
+        }
+        // a new key would never open what is already sealed
+        None if !create => return Ok(None),
+        // without the keychain only a bound key can be kept
+        None if !is_enabled() && binding.is_none() => return Ok(None),
+        // nor what was sealed with a key that can't be found now
+        None if sealed_files_exist()? => return Err(Error::IdentityStoreLocked),
+        None => {
+            let key = Key::generate();
+            let hex = Zeroizing::new(hex::encode(key.as_bytes()));
+            let stored = Zeroizing::new(device_binding::bind(&hex, binding)?);
+            if let Err(e) = store(&account, &stored) {
+                tracing::debug!("no keychain, the identity store stays plaintext: {}", e);
+                return Ok(keep(None));
+            }
+            key
+        }
+    };
+    Ok(keep(Some(key)))
+}
+
+/// whether the identity store or a pairing is sealed, a new key would lock them for good
+fn sealed_files_exist() -> Result<bool, Error> {
+    Ok(StoredIdentity::has_sealed_files()? || Pairing::has_sealed_files()?)
+}
+
+/// the key for the rest of the process, a `Key` is kept out of swap
+fn keep(key: Option<Key>) -> Option<&'static Key> {
+    KEY.get_or_init(|| key).as_ref()
+}
+
+fn parse_key(hex: &str) -> Result<Key, Error> {
+    hex::decode(hex.trim())
+        .ok()
+        .map(Zeroizing::new)
+        .and_then(|bytes| Key::from_slice(&bytes))
+        .ok_or_else(|| Error::Keychain("the stored key is malformed".into()))
+}
+
+/// the stored key: from the keychain, or from the file a bound key is kept in without one
+fn load(account: &str) -> Result<Option<String>, Error> {
+    if is_enabled() {
+        if let Some(stored) = platform::load(account)? {
+            return Ok(Some(stored));
+        }
+    }
+    device_binding::load_file(account)
+}
+
+fn store(account: &str, stored: &str) -> Result<(), Error> {
+    let in_keychain = match is_enabled() {
+        true => platform::store(account, stored),
+        false => Err(Error::Keychain("disabled with --no-keychain".into())),
+    };
+    match in_keychain {
+        // useless anywhere else, it can stay next to the store
+        Err(_) if device_binding::binding_of(stored).is_some() => device_binding::store_file(account, stored),
+        result => result,
+    }
+}
+
+/// The key of the current profile as stored, bound or not
+pub fn stored_key() -> Result<Option<String>, Error> {
+    load(&account())
+}
+
+/// Bind the key of the current profile anew, making one first when the store is plaintext
+pub fn rebind(binding: Option<Binding>) -> Result<(), Error> {
+    let account = account();
+    let key = Zeroizing::new(match load(&account)? {
+        Some(stored) => device_binding::unbind(&Zeroizing::new(stored))?,
+        // e.g. given --no-keychain, a new key wouldn't open the store
+        None if sealed_files_exist()? => return Err(Error::IdentityStoreLocked),
+        None => hex::encode(Key::generate().as_bytes()),
+    });
+    let stored = device_binding::bind(key.trim(), binding)?;
+    store(&account, &stored)?;
+    // only one copy stays around
+    if is_enabled() && platform::load(&account)?.is_some_and(|kept| kept.trim() == stored) {
+        device_binding::delete_file(&account)?;
+    }
+    keep(Some(parse_key(&key)?));
+    Ok(())
+}
+
+/// Whether `seal` encrypts, making the key if there is none yet
+pub fn is_available() -> bool {
+    match key(true) {
+        Ok(key) => key.is_some(),
+        Err(e) => {
+            tracing::debug!("keychain: {}", e);
+            false
+        }
+    }
+}
+
+/// one key per profile, they get wiped separately
+fn account() -> String {
+    format!("identity-store-{}", crate::profile::current())
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypt `plaintext` if there is a keychain to hold the key, return it as is otherwise
+pub fn seal(plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = match key(true)? {
+        Some(key) => key,
+        None => return Ok(plaintext.to_vec()),
+    };
+    let mut sealed = MAGIC.to_vec();
+    sealed.extend(crypto::seal_with_key(plaintext, key));
+    Ok(sealed)
+}
+
+/// Decrypt what `seal` wrote, files from before the store was encrypted pass through
+pub fn open(data: &[u8]) -> Result<Vec<u8>, Error> {
+    if !is_sealed(data) {
+        return Ok(data.to_vec());
+    }
+    let key = key(false)?.ok_or(Error::IdentityStoreLocked)?;
+    crypto::open_with_key(&data[MAGIC.len()..], key)
+        .map(|plaintext| plaintext.to_vec())
+        .ok_or(Error::IdentityStoreCorrupted)
+}
+
+/// A key for `purpose` made from the key of the current profile, None without a keychain to keep one
+pub fn derive_key(purpose: &str) -> Result<Option<Zeroizing<[u8; crypto::KEY_LEN]>>, Error> {
+    let key = key(true)?;
+    Ok(key.map(|key| Zeroizing::new(crypto::hmac_sha256(key.as_bytes(), purpose.as_bytes()))))
+}
+
+/// Remove the key of the current profile, e.g. when the profile is wiped
+pub fn forget() -> Result<(), Error> {
+    if is_enabled() {
+        platform::delete(&account())?;
+    }
+    device_binding::delete_file(&account())
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::SERVICE;
+    use crate::error::Error;
+    use security_framework::os::macos::keychain::SecKeychain;
+    use security_framework::os::macos::passwords::find_generic_password;
+
+    /// errSecItemNotFound
+    const NOT_FOUND: i32 = -25300;
+
+    fn keychain_error(e: security_framework::base::Error) -> Error {
+        Error::Keychain(e.to_string())
+    }
+
+    pub fn load(account: &str) -> Result<Option<String>, Error> {
+        match find_generic_password(None, SERVICE, account) {
+            Ok((password, _)) => Ok(Some(String::from_utf8_lossy(&password).to_string())),
+            Err(e) if e.code() == NOT_FOUND => Ok(None),
+            Err(e) => Err(keychain_error(e)),
+        }
+    }
+
+    /// through the Security framework, the secret is never on the command line of a process
+    pub fn store(account: &str, secret: &str) -> Result<(), Error> {
+        SecKeychain::default()
+            .and_then(|keychain| keychain.set_generic_password(SERVICE, account, secret.as_bytes()))
+            .map_err(keychain_error)
+    }
+
+    pub fn delete(account: &str) -> Result<(), Error> {
+        match find_generic_password(None, SERVICE, account) {
+            Ok((_, item)) => {
+                item.delete();
+                Ok(())
+            }
+            Err(e) if e.code() == NOT_FOUND => Ok(()),
+            Err(e) => Err(keychain_error(e)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::SERVICE;
+    use crate::error::Error;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    /// `secret-tool` comes with libsecret, without it or a session bus the store stays plaintext
+    fn secret_tool() -> Command {
+        let mut command = Command::new("secret-tool");
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    pub fn load(account: &str) -> Result<Option<String>, Error> {
+        let output = match secret_tool()
+            .args(["lookup", "service", SERVICE, "account", account])
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // exits with 1 and says nothing when there is no such item, a locked collection or a missing
+        // Secret Service come with a message
+        match output.status.success() {
+            true if !output.stdout.is_empty() => Ok(Some(String::from_utf8_lossy(&output.stdout).to_string())),
+            _ if output.stderr.is_empty() => Ok(None),
+            _ => Err(Error::Keychain(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            )),
+        }
+    }
+
+    pub fn store(account: &str, secret: &str) -> Result<(), Error> {
+        let mut child = match secret_tool()
+            .args([
+                "store",
+                "--label=akr identity store",
+                "service",
+                SERVICE,
+                "account",
+                account,
+            ])
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::Keychain("secret-tool isn't installed".into()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // the secret goes through stdin, never the command line
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(secret.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(Error::Keychain(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            )),
+        }
+    }
+
+    pub fn delete(account: &str) -> Result<(), Error> {
+        match secret_tool()
+            .args(["clear", "service", SERVICE, "account", account])
+            .output()
+        {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use crate::error::Error;
+    use std::path::PathBuf;
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPT_INTEGER_BLOB,
+    };
+    use windows_sys::Win32::System::Memory::LocalFree;
+
+    /// DPAPI doesn't store anything, the key is kept in a file only this Windows user can decrypt
+    fn path(account: &str) -> Result<PathBuf, Error> {
+        Ok(crate::create_home_path()?.join(format!("{}.dpapi", account)))
+    }
+
+    pub fn load(account: &str) -> Result<Option<String>, Error> {
+        let path = path(account)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = unprotect(&std::fs::read(path)?)?;
+        Ok(Some(String::from_utf8_lossy(&data).to_string()))
+    }
+
+    pub fn store(account: &str, secret: &str) -> Result<(), Error> {
+        crate::util::write_atomically(&path(account)?, &protect(secret.as_bytes())?)
+    }
+
+    pub fn delete(account: &str) -> Result<(), Error> {
+        let path = path(account)?;
+        if path.exists() {
+            crate::util::shred(&path)?;
+        }
+        Ok(())
+    }
+
+    fn protect(data: &[u8]) -> Result<Vec<u8>, Error> {
+        dpapi(data, true)
+    }
+
+    fn unprotect(data: &[u8]) -> Result<Vec<u8>, Error> {
+        dpapi(data, false)
+    }
+
+    fn dpapi(data: &[u8], protect: bool) -> Result<Vec<u8>, Error> {
+        let input = CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
+        };
+        let mut output = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
+        use std::ptr::{null, null_mut};
+        let done = unsafe {
+            match protect {
+                true => CryptProtectData(&input, null(), null(), null(), null(), 0, &mut output),
+                false => CryptUnprotectData(&input, null_mut(), null(), null(), null(), 0, &mut output),
+            }
+        };
+        if done == 0 {
+            return Err(Error::Keychain(std::io::Error::last_os_error().to_string()));
+        }
+        let result = unsafe { std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec() };
+        unsafe { LocalFree(output.pbData as isize) };
+        Ok(result)
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux", windows)))]
+use unsupported as platform;
+
+/// No keychain: nothing is found and a key can't be kept, which leaves the store plaintext
+#[cfg(any(test, not(any(target_os = "macos", target_os = "linux", windows))))]
+#[cfg_attr(any(target_os = "macos", target_os = "linux", windows), allow(dead_code))]
+mod unsupported {
+    use crate::error::Error;
+
+    pub fn load(_account: &str) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    pub fn store(_account: &str, _secret: &str) -> Result<(), Error> {
+        Err(Error::KeychainUnavailable)
+    }
+
+    pub fn delete(_account: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_key_is_not_kept_without_a_keychain() {
+        // `key` leaves the store plaintext when storing fails, a key that isn't kept would lock it for good
+        assert!(matches!(unsupported::store("account", "secret"), Err(Error::KeychainUnavailable)));
+        assert_eq!(unsupported::load("account").unwrap(), None);
+    }
+
+    #[test]
+    fn seals_with_a_magic() {
+        assert!(is_sealed(b"akr-sealed-v1\nnonce"));
+        assert!(!is_sealed(b"{\"identity\":{}}"));
+    }
+}
@@ -2,7 +2,24 @@
 
 use crate::error::Error;
 use askama::Template;
+#[cfg(target_os = "linux")]
 use nix::unistd::Uid;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+/// where the user units of every user are
+#[cfg(target_os = "linux")]
+const GLOBAL_UNIT_DIR: &str = "/usr/lib/systemd/user";
+/// where the LaunchAgents of every user are
+#[cfg(target_os = "macos")]
+const GLOBAL_LAUNCH_AGENT_DIR: &str = "/Library/LaunchAgents";
+
+/// the first socket passed by systemd, see sd_listen_fds(3)
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
 
 #[derive(Debug, Clone)]
 pub struct Daemon {
@@ -31,6 +48,197 @@ impl Daemon {
         Ok(self.os_specific().render()?)
     }
 
+    /// Stop the agent running in the background, until the next login or `restart`
+    pub fn stop(self) -> Result<(), Error> {
+        self.os_specific().stop()
+    }
+
+    pub fn restart(self) -> Result<(), Error> {
+        self.os_specific().restart()
+    }
+
+    /// Install and load the LaunchAgent that starts the agent at login
+    #[cfg(target_os = "macos")]
+    pub fn install_launch_agent(self) -> Result<(), Error> {
+        self.install()
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    pub fn install_launch_agent(self) -> Result<(), Error> {
+        Err(Error::Launchd("launchd is only available on macOS".to_string()))
+    }
+
+    /// Install a socket unit listening on `socket`, which starts the agent on the first ssh connection
+    #[cfg(target_os = "linux")]
+    pub fn install_socket_activated(self, socket: &Path) -> Result<(), Error> {
+        SocketActivation::new(self, socket).install()
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn render_socket_activated(self, socket: &Path) -> Result<String, Error> {
+        let activation = SocketActivation::new(self, socket);
+        Ok(format!(
+            "{}\n\n{}",
+            activation.socket.render()?,
+            activation.service.render()?
+        ))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn install_socket_activated(self, _socket: &Path) -> Result<(), Error> {
+        Err(Error::Systemd(
+            "socket activation is only available on Linux".to_string(),
+        ))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn render_socket_activated(self, _socket: &Path) -> Result<String, Error> {
+        Err(Error::Systemd(
+            "socket activation is only available on Linux".to_string(),
+        ))
+    }
+
+    /// Install the service for every user, enabled for their next login. Below `root`, e.g. the staging dir
+    /// of a package, the files are only written. Returns them
+    #[cfg(target_os = "linux")]
+    pub fn install_global(self, socket_activated: bool, root: Option<&Path>) -> Result<Vec<PathBuf>, Error> {
+        let dir = rooted(root, GLOBAL_UNIT_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let (files, unit) = match socket_activated {
+            true => {
+                let activation = SocketActivation::global(self);
+                let unit = format!("{}.socket", activation.unit_name);
+                (activation.write(&dir)?, unit)
+            }
+            false => {
+                let service = SystemdService {
+                    current_user: None,
+                    ..SystemdService::from(self)
+                };
+                (
+                    vec![service.write(&dir)?],
+                    format!("{}.service", service.bin_name),
+                )
+            }
+        };
+        if root.is_none() {
+            systemctl_as("--global", &["enable", &unit])?;
+        }
+        Ok(files)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn install_global(self, socket_activated: bool, root: Option<&Path>) -> Result<Vec<PathBuf>, Error> {
+        if socket_activated {
+            return Err(Error::Systemd(
+                "socket activation is only available on Linux".to_string(),
+            ));
+        }
+        let agent = LaunchAgent::from(self);
+        let path = rooted(root, GLOBAL_LAUNCH_AGENT_DIR).join(format!("{}.plist", agent.label));
+        std::fs::create_dir_all(path.parent().expect("a dir"))?;
+        std::fs::write(&path, agent.render()?)?;
+        Ok(vec![path])
+    }
+
+    #[cfg(windows)]
+    pub fn install_global(
+        self,
+        _socket_activated: bool,
+        _root: Option<&Path>,
+    ) -> Result<Vec<PathBuf>, Error> {
+        Err(Error::ScheduledTask(
+            "the task is only installed for the current user".to_string(),
+        ))
+    }
+
+    /// Stop the agent and remove the service `setup` installed, of either kind. Returns what was removed
+    #[cfg(target_os = "linux")]
+    pub fn uninstall(self) -> Result<Vec<String>, Error> {
+        let dir = user_unit_dir()?;
+        let unit_name = unit_name(&self.bin_name);
+        let mut units = vec![
+            format!("{}.socket", unit_name),
+            format!("{}.service", unit_name),
+            format!("{}.service", self.bin_name),
+        ];
+        units.dedup();
+
+        let mut removed = vec![];
+        for unit in units {
+            let path = dir.join(&unit);
+            if path.exists() {
+                let _ = systemctl(&["disable", "--now", &unit]);
+                std::fs::remove_file(&path)?;
+                removed.push(path.display().to_string());
+            }
+        }
+        if !removed.is_empty() {
+            let _ = systemctl(&["daemon-reload"]);
+        }
+        Ok(removed)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn uninstall(self) -> Result<Vec<String>, Error> {
+        let path = LaunchAgent::from(self).path()?;
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let _ = launchctl(&["unload", "-w", &path.to_string_lossy()]);
+        std::fs::remove_file(&path)?;
+        Ok(vec![path.display().to_string()])
+    }
+
+    #[cfg(windows)]
+    pub fn uninstall(self) -> Result<Vec<String>, Error> {
+        let task = ScheduledTask::from(self);
+        powershell(&format!(
+            "Stop-ScheduledTask -TaskName '{0}'; Unregister-ScheduledTask -TaskName '{0}' -Confirm:$false",
+            task.task_name
+        ))?;
+        Ok(vec![format!("the scheduled task {}", task.task_name)])
+    }
+
+    /// Remove the service of `install_global`, disabled first unless below `root`. Returns what was removed
+    #[cfg(target_os = "linux")]
+    pub fn uninstall_global(self, root: Option<&Path>) -> Result<Vec<String>, Error> {
+        let dir = rooted(root, GLOBAL_UNIT_DIR);
+        let mut removed = vec![];
+        for unit in [
+            format!("{}.socket", self.bin_name),
+            format!("{}.service", self.bin_name),
+        ] {
+            let path = dir.join(&unit);
+            if path.exists() {
+                if root.is_none() {
+                    let _ = systemctl_as("--global", &["disable", &unit]);
+                }
+                std::fs::remove_file(&path)?;
+                removed.push(path.display().to_string());
+            }
+        }
+        Ok(removed)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn uninstall_global(self, root: Option<&Path>) -> Result<Vec<String>, Error> {
+        let agent = LaunchAgent::from(self);
+        let path = rooted(root, GLOBAL_LAUNCH_AGENT_DIR).join(format!("{}.plist", agent.label));
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        std::fs::remove_file(&path)?;
+        Ok(vec![path.display().to_string()])
+    }
+
+    #[cfg(windows)]
+    pub fn uninstall_global(self, _root: Option<&Path>) -> Result<Vec<String>, Error> {
+        Err(Error::ScheduledTask(
+            "the task is only installed for the current user".to_string(),
+        ))
+    }
+
     #[cfg(target_os = "linux")]
     fn os_specific(self) -> SystemdService {
         return SystemdService::from(self);
@@ -40,6 +248,20 @@ impl Daemon {
     fn os_specific(self) -> LaunchAgent {
         return LaunchAgent::from(self);
     }
+
+    #[cfg(windows)]
+    fn os_specific(self) -> ScheduledTask {
+        ScheduledTask::from(self)
+    }
+}
+
+/// `path` below `root`, or as it is without one
+#[cfg(unix)]
+fn rooted(root: Option<&Path>, path: &str) -> PathBuf {
+    match root {
+        Some(root) => root.join(path.trim_start_matches('/')),
+        None => PathBuf::from(path),
+    }
 }
 
 #[cfg(target_os = "macos")]
@@ -63,13 +285,17 @@ impl From<Daemon> for LaunchAgent {
 }
 #[cfg(target_os = "macos")]
 impl LaunchAgent {
-    fn install(&self) -> Result<(), Error> {
+    fn path(&self) -> Result<PathBuf, Error> {
         let dirs = directories::UserDirs::new().ok_or(Error::CannotCreateHomeDir)?;
-        let path = dirs
+        Ok(dirs
             .home_dir()
             .join("Library")
             .join("LaunchAgents")
-            .join(format!("{}.plist", &self.label));
+            .join(format!("{}.plist", &self.label)))
+    }
+
+    fn install(&self) -> Result<(), Error> {
+        let path = self.path()?;
 
         if path.exists() {
             // first unload if already there
@@ -92,6 +318,39 @@ impl LaunchAgent {
 
         Ok(())
     }
+
+    /// the agent is kept alive, `launchctl stop` would only have it started again
+    fn stop(&self) -> Result<(), Error> {
+        launchctl(&["unload", &self.installed_path()?.to_string_lossy()])
+    }
+
+    fn restart(&self) -> Result<(), Error> {
+        let path = self.installed_path()?;
+        let _ = launchctl(&["unload", &path.to_string_lossy()]);
+        launchctl(&["load", "-w", &path.to_string_lossy()])
+    }
+
+    fn installed_path(&self) -> Result<PathBuf, Error> {
+        let path = self.path()?;
+        match path.exists() {
+            true => Ok(path),
+            false => Err(Error::Launchd(format!(
+                "{} isn't installed, run `akr setup --launchd`",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn launchctl(args: &[&str]) -> Result<(), Error> {
+    let output = std::process::Command::new("launchctl").args(args).output()?;
+    // launchctl reports some failures on stderr only
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stderr.trim().is_empty() {
+        return Err(Error::Launchd(stderr.trim().to_string()));
+    }
+    Ok(())
 }
 
 #[cfg(target_os = "linux")]
@@ -101,7 +360,8 @@ struct SystemdService {
     description: String,
     bin_path: String,
     bin_name: String,
-    current_user: String,
+    /// none for the units of every user
+    current_user: Option<String>,
 }
 
 #[cfg(target_os = "linux")]
@@ -111,24 +371,47 @@ impl From<Daemon> for SystemdService {
             bin_name: d.bin_name,
             bin_path: d.bin_path,
             description: env!("CARGO_PKG_DESCRIPTION").to_string(),
-            current_user: whoami::username(),
+            current_user: Some(whoami::username()),
         }
     }
 }
 
 #[cfg(target_os = "linux")]
 impl SystemdService {
-    fn install(&self) -> Result<(), Error> {
-        let dirs = directories::UserDirs::new().ok_or(Error::CannotCreateHomeDir)?;
+    /// the socket unit from `akr setup --systemd` would start the agent again on the next connection
+    fn stop(&self) -> Result<(), Error> {
+        match socket_unit_active() {
+            true => {
+                let unit_name = unit_name(&self.bin_name);
+                let socket_name = format!("{}.socket", unit_name);
+                let service_name = format!("{}.service", unit_name);
+                systemctl(&["stop", &socket_name, &service_name])
+            }
+            false => systemctl(&["stop", &format!("{}.service", &self.bin_name)]),
+        }
+    }
 
-        let path = dirs.home_dir().join(".config").join("systemd").join("user");
+    fn restart(&self) -> Result<(), Error> {
+        let unit_name = match socket_unit_active() {
+            true => unit_name(&self.bin_name),
+            false => self.bin_name.clone(),
+        };
+        systemctl(&["restart", &format!("{}.service", unit_name)])
+    }
+
+    /// Write the unit into `dir`, returns its path
+    fn write(&self, dir: &Path) -> Result<PathBuf, Error> {
+        let path = dir.join(format!("{}.service", &self.bin_name));
+        std::fs::write(&path, self.render()?)?;
+        Ok(path)
+    }
+
+    fn install(&self) -> Result<(), Error> {
+        let path = user_unit_dir()?;
         std::fs::create_dir_all(&path)?;
 
         let service_name = format!("{}.service", &self.bin_name);
-
-        let path_to_write = path.clone().join(&service_name);
-        let contents = self.render()?;
-        std::fs::write(path_to_write, contents)?;
+        self.write(&path)?;
 
         if Uid::effective().is_root() {
 
@@ -152,3 +435,239 @@ impl SystemdService {
         Ok(())
     }
 }
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Template)]
+#[template(path = "linux/systemd.socket", escape = "none")]
+struct SystemdSocket {
+    description: String,
+    socket_path: String,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Template)]
+#[template(path = "linux/systemd-activated.service", escape = "none")]
+struct ActivatedService {
+    bin_name: String,
+    bin_path: String,
+    start_args: String,
+}
+
+/// A socket unit and the service it starts, named after the profile for the named ones
+#[cfg(target_os = "linux")]
+struct SocketActivation {
+    unit_name: String,
+    socket: SystemdSocket,
+    service: ActivatedService,
+}
+
+#[cfg(target_os = "linux")]
+impl SocketActivation {
+    fn new(d: Daemon, socket: &Path) -> Self {
+        let profile = crate::profile::current();
+        let start_args = match crate::profile::is_default(profile) {
+            true => "start".to_string(),
+            false => format!("--profile {} start", profile),
+        };
+        let unit_name = unit_name(&d.bin_name);
+        Self {
+            socket: SystemdSocket {
+                description: env!("CARGO_PKG_DESCRIPTION").to_string(),
+                socket_path: socket.display().to_string(),
+            },
+            service: ActivatedService {
+                bin_name: unit_name.clone(),
+                bin_path: d.bin_path,
+                start_args,
+            },
+            unit_name,
+        }
+    }
+
+    /// The units of every user, with a socket in their runtime dir
+    fn global(d: Daemon) -> Self {
+        Self {
+            socket: SystemdSocket {
+                description: env!("CARGO_PKG_DESCRIPTION").to_string(),
+                // %t is XDG_RUNTIME_DIR, see `instance::runtime_dir`
+                socket_path: format!("%t/akr/{}", crate::SSH_AGENT_PIPE),
+            },
+            service: ActivatedService {
+                bin_name: d.bin_name.clone(),
+                bin_path: d.bin_path,
+                start_args: "start".to_string(),
+            },
+            unit_name: d.bin_name,
+        }
+    }
+
+    /// Write the units into `dir`, returns their paths
+    fn write(&self, dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let service = dir.join(format!("{}.service", &self.unit_name));
+        let socket = dir.join(format!("{}.socket", &self.unit_name));
+        std::fs::write(&service, self.service.render()?)?;
+        std::fs::write(&socket, self.socket.render()?)?;
+        Ok(vec![service, socket])
+    }
+
+    fn install(&self) -> Result<(), Error> {
+        let path = user_unit_dir()?;
+        std::fs::create_dir_all(&path)?;
+        self.write(&path)?;
+
+        let service_name = format!("{}.service", &self.unit_name);
+        let socket_name = format!("{}.socket", &self.unit_name);
+
+        systemctl(&["daemon-reload"])?;
+        // an agent started by the plain service holds the socket, systemd has to bind it now
+        let _ = systemctl(&["disable", "--now", &service_name]);
+        systemctl(&["enable", "--now", &socket_name])
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn user_unit_dir() -> Result<PathBuf, Error> {
+    let dirs = directories::UserDirs::new().ok_or(Error::CannotCreateHomeDir)?;
+    Ok(dirs.home_dir().join(".config").join("systemd").join("user"))
+}
+
+/// "akr", or "akr-<profile>" for a named profile
+fn unit_name(bin_name: &str) -> String {
+    let profile = crate::profile::current();
+    match crate::profile::is_default(profile) {
+        true => bin_name.to_string(),
+        false => format!("{}-{}", bin_name, profile),
+    }
+}
+
+/// Whether the socket unit from `akr setup --systemd` is listening for the agent
+pub fn socket_unit_active() -> bool {
+    let socket_name = format!("{}.socket", unit_name(Daemon::BIN_NAME));
+    std::process::Command::new("systemctl")
+        .args(["--user", "is-active", "--quiet", &socket_name])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(target_os = "linux")]
+fn systemctl(args: &[&str]) -> Result<(), Error> {
+    systemctl_as("--user", args)
+}
+
+/// `systemctl` for the user's own units with "--user", or those of every user with "--global"
+#[cfg(target_os = "linux")]
+fn systemctl_as(manager: &str, args: &[&str]) -> Result<(), Error> {
+    let output = std::process::Command::new("systemctl")
+        .arg(manager)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Systemd(stderr.trim().to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+#[derive(Debug, Clone, Template)]
+#[template(path = "windows/scheduled_task.ps1", escape = "none")]
+struct ScheduledTask {
+    task_name: String,
+    description: String,
+    bin_path: String,
+    current_user: String,
+}
+
+#[cfg(windows)]
+impl From<Daemon> for ScheduledTask {
+    fn from(d: Daemon) -> Self {
+        Self {
+            task_name: d.bin_name,
+            description: env!("CARGO_PKG_DESCRIPTION").to_string(),
+            bin_path: d.bin_path,
+            current_user: whoami::username(),
+        }
+    }
+}
+
+/// A task of the Task Scheduler starting the agent at logon, Windows has no user services
+#[cfg(windows)]
+impl ScheduledTask {
+    fn install(&self) -> Result<(), Error> {
+        powershell(&self.render()?)
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        powershell(&format!("Stop-ScheduledTask -TaskName '{}'", self.task_name))
+    }
+
+    fn restart(&self) -> Result<(), Error> {
+        powershell(&format!(
+            "Stop-ScheduledTask -TaskName '{0}'; Start-ScheduledTask -TaskName '{0}'",
+            self.task_name
+        ))
+    }
+}
+
+#[cfg(windows)]
+fn powershell(script: &str) -> Result<(), Error> {
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::ScheduledTask(stderr.trim().to_string()));
+    }
+    Ok(())
+}
+
+/// The listening socket systemd passed on, when the agent was started by its socket unit
+#[cfg(unix)]
+pub fn inherited_listener() -> Option<UnixListener> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    // they're meant for us, not for the processes we start
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let pid = pid?.parse::<u32>().ok()?;
+    let fds = fds?.parse::<u32>().ok()?;
+    if pid != std::process::id() || fds == 0 {
+        return None;
+    }
+
+    let _ = nix::fcntl::fcntl(
+        SD_LISTEN_FDS_START,
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    );
+    // systemd passed it to us alone, nothing else owns it
+    let listener = unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    listener.set_nonblocking(true).ok()?;
+    Some(listener)
+}
+
+/// Wait for SIGTERM, which systemd and launchd stop the agent with, or SIGINT (ctrl-c on Windows).
+/// Returns which one
+pub async fn stop_requested() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+            (Ok(mut terminate), Ok(mut interrupt)) => tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = interrupt.recv() => "SIGINT",
+            },
+            // not stopping gracefully, but still stopping
+            _ => std::future::pending().await,
+        }
+    }
+    #[cfg(windows)]
+    {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => "ctrl-c",
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! The parts of akr the rest builds on: its errors, the wire formats of the agent and the phone/tablet and the
+//! crypto under them. They're a library of their own so the fuzz targets in "fuzz" can link them, the akr
+//! binary is everything else.
+
+#[macro_use]
+extern crate bitflags;
+
+pub mod crypto;
+pub mod error;
+pub mod memory;
+pub mod prompt;
+pub mod protocol;
+pub mod ssh_format;
+pub mod util;
@@ -0,0 +1,29 @@
+//! Logging to stderr through `tracing`
+//!
+//! Requests to the phone/tablet run in a `request` span carrying the request id, with a `transport` span
+//! for each of the transports it went over, so a sign operation can be followed from the agent handler to
+//! the response. Closing spans log how long they took.
+
+use crate::config;
+use tracing::Level;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+/// Log akr at `level` if given, otherwise as set in `RUST_LOG` or the config file (errors only by default)
+pub fn init(level: Option<Level>) {
+    let for_akr = |level: Level| EnvFilter::new(format!("warn,akr={0},ssh_agent={0}", level));
+    let filter = match level {
+        Some(level) => for_akr(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| match config::get().log_level {
+            Some(level) => for_akr(level),
+            None => EnvFilter::new("error"),
+        }),
+    };
+
+    // only the first call sets up logging
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .try_init();
+}
@@ -6,33 +6,78 @@ use self::cli::*;
 
 mod ssh_agent;
 
+mod add_to;
+mod age;
+mod attestation;
+mod audit;
+mod audit_export;
 mod client;
-
-mod error;
+mod completions;
+
+mod config;
+mod confirmation;
+mod control;
+mod device_binding;
+mod doctor;
+mod git;
+mod host_context;
+mod i18n;
 mod identity;
+mod instance;
+mod inventory;
+mod key_index;
+mod keychain;
 mod launch;
+mod logging;
+mod metrics;
+mod notification;
+mod output;
 mod pairing;
-mod protocol;
+mod policy;
+mod prewarm;
+mod profile;
+mod prune;
+mod qr;
+mod rate_limit;
+mod recorder;
+mod retry;
+mod rotate;
+mod round_trip;
+mod secret;
+mod service;
 mod setup;
-mod ssh_format;
+mod ssh_config;
+mod sshsig;
+mod status;
+mod team_policy;
 mod transport;
-mod util;
+mod update;
+#[cfg(feature = "webauthn-bridge")]
+mod webauthn;
 
+use akr::{crypto, error, memory, prompt, protocol, ssh_format, util};
 use clap::Clap;
 use protocol::UnpairRequest;
-use protocol::{RegisterRequest, RegisterResponse};
+use protocol::{RegisterRequest, RegisterResponse, UserData};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
+#[cfg(unix)]
 use tokio::net::UnixListener;
 
 use crate::client::Client;
+use crate::control::ControlRequest;
 use crate::error::Error;
 use crate::protocol::{
-    Base64Buffer, IdRequest, IdResponse, Request, RequestBody, ResponseBody, PROTOCOL_VERSION,
+    AuthenticateRequest, AuthenticateResponse, Base64Buffer, Capabilities, IdRequest, IdResponse, Request,
+    RequestBody, ResponseBody, PAIRING_TIMEOUT, PROTOCOL_VERSION,
 };
+use crate::retry::RetryPolicy;
+use crate::transport::TransportKind;
 use crate::{
     pairing::{Keypair, Os, Pairing, PairingQr},
-    ssh_format::SshFido2KeyPairHandle,
+    ssh_format::{verify_sk_signature, ExportFormat, SkKeyType, SshFido2KeyPairHandle},
 };
 
 use crate::identity::StoredIdentity;
@@ -41,74 +86,170 @@ use ansi_term::Colour::{Blue, Green, Red, Yellow};
 use base64::Engine;
 use run_script::ScriptOptions;
 
-#[macro_use]
-extern crate bitflags;
-
-mod prompt;
-
 pub const HOME_DIR: &'static str = ".akr";
 const SSH_AGENT_PIPE: &'static str = "akr-ssh-agent.sock";
+/// named pipes live in their own namespace on Windows, not in the home directory
+#[cfg(windows)]
+const WINDOWS_AGENT_PIPE: &str = r"\\.\pipe\akr-ssh-agent";
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-    sodiumoxide::init().map_err(|_| Error::CryptoInit).unwrap();
+    crypto::init().unwrap();
 
-    let result = handle_command().await;
-    if let Err(e) = result {
-        eprintln!("Error: {}", Red.paint(e.to_string()));
+    // invoked by git as `gpg.ssh.program`
+    if git::is_signing_program() {
+        logging::init(None);
+        git::run_signing_program().await;
+    }
+    // invoked by age as `age-plugin-akr`
+    if age::is_plugin_program() {
+        logging::init(None);
+        age::run_plugin_program().await;
+    }
+
+    if let Err(e) = handle_command().await {
+        match output::is_json() {
+            true => output::print_error(&e),
+            false => {
+                eprintln!("{} {}", i18n::tr("error", &[]), Red.paint(e.to_string()));
+                if let Some(hint) = e.hint() {
+                    eprintln!("{}", Yellow.paint(hint));
+                }
+            }
+        }
+        std::process::exit(e.category().exit_code());
     }
 }
 
 async fn handle_command() -> Result<(), Error> {
     let opts: Opts = Opts::parse();
+    profile::select(opts.profile.clone())?;
+    // replays keep away from the pairing, keys and audit log of the profile
+    let sandbox = match &opts.command {
+        Command::Replay(_) => Some(recorder::sandbox()?),
+        _ => None,
+    };
+    // a broken config file can still be fixed with `akr config set`
+    let config = match &opts.command {
+        Command::Config { .. } => config::get(),
+        _ => config::load()?,
+    };
+    logging::init(match &opts.command {
+        Command::Start(args) => args.log_level,
+        _ => None,
+    });
+    transport::select(match &opts.command {
+        // what the phone/tablet answered comes from the recording
+        Command::Replay(_) => TransportKind::Mock,
+        _ => opts
+            .transport
+            .or(config.transport)
+            .unwrap_or(TransportKind::Relay),
+    });
+    transport::proxy::configure(opts.proxy.or_else(|| config.proxy.clone()));
+    output::select_json(opts.json);
+    keychain::configure(opts.no_keychain || config.keychain == Some(false) || sandbox.is_some());
 
     match opts.command {
-        Command::Start => start_daemon().await,
-        Command::Pair { setup } => {
+        Command::Start(args) => start_daemon(args).await,
+        Command::Stop => launch::Daemon::new()?.stop()?,
+        Command::Restart => launch::Daemon::new()?.restart()?,
+        Command::Replay(args) => recorder::replay(args).await?,
+        Command::Pair { setup, renew } => {
             if setup {
                 setup::run(SetupArgs {
                     print_only: false,
+                    dry_run: false,
+                    remove: false,
+                    host: None,
+                    host_context: false,
+                    key: vec![],
                     ssh_config_path: None,
+                    systemd: false,
+                    launchd: false,
                 })
                 .await?
             }
-            pair().await?
+            pair(renew).await?
         }
-        Command::Unpair => unpair().await?,
-        Command::Status => get_pairing_details().await?,
-        Command::Generate { name } => generate(name).await?,
+        Command::Unpair { reset } => unpair(reset).await?,
+        Command::FlushQueue => flush_queue().await?,
+        Command::Repair => repair_identity()?,
+        Command::Reload => control::run(ControlRequest::Reload).await?,
+        Command::Lock => control::run(ControlRequest::Lock).await?,
+        Command::Unlock => control::run(ControlRequest::Unlock).await?,
+        Command::RotateKeys(args) => rotate::run(args).await?,
+        Command::Prune(args) => prune::run(args).await?,
+        Command::Translations(args) => i18n::run(args),
+        Command::Completions(args) => completions::run(args)?,
+        Command::Test(args) => round_trip::run(args).await?,
+        Command::Prewarm(args) => prewarm::run(args).await,
+        Command::HostContext(args) => host_context::run(args),
+        Command::Devices { command } => match command {
+            DevicesCommand::List => list_devices()?,
+            DevicesCommand::Remove { device } => remove_device(device).await?,
+        },
+        Command::Config { command } => configure(command)?,
+        Command::Profile { command } => manage_profiles(command)?,
+        Command::Policy { command } => policy::run(command)?,
+        Command::TeamPolicy(args) => team_policy::run(args).await?,
+        Command::Audit { command } => audit::run(command)?,
+        Command::DeviceBinding { command } => device_binding::run(command)?,
+        Command::Sign(args) => sshsig::run(args).await?,
+        Command::DeriveSecret(args) => secret::run(args).await?,
+        Command::AgePlugin(args) => age::run(args).await?,
+        Command::GitConfig(args) => git::run(args)?,
+        Command::AddTo(args) => add_to::run(args).await?,
+        #[cfg(feature = "webauthn-bridge")]
+        Command::WebauthnBridge(args) => webauthn::run(args).await?,
+        Command::Status => status::run().await?,
+        Command::Generate(args) => generate(args).await?,
         Command::Load => load_keys().await?,
+        Command::LoadCert { file } => load_certificate(file)?,
+        Command::Attestation { key, pem } => show_attestation(key, pem)?,
+        Command::List {
+            tag,
+            public_key,
+            verbose,
+        } => list_keys(tag, public_key, verbose)?,
+        Command::Rename(args) => rename_key(args)?,
+        Command::Delete { key } => delete_key(key).await?,
+        Command::Export { key, format } => export_key(key, format)?,
+        Command::Import { files } => import_keys(files)?,
         Command::Setup(args) => setup::run(args).await?,
+        Command::InstallService(args) => service::install(args)?,
+        Command::UninstallService(args) => service::uninstall(args)?,
         Command::Check => health_check().await?,
+        Command::Doctor => doctor::run().await?,
+        Command::Inventory => inventory::run()?,
+        Command::Update(args) => update::run(args).await?,
     }
 
     Ok(())
 }
 
-async fn pair() -> Result<(), Error> {
+async fn pair(renew: bool) -> Result<(), Error> {
     // check if ssh 8.2+ is installed or not
     check_ssh_version()?;
     let client = Client::new()?;
-    let mut already_paired = false;
-    let mut paired_device_name = "".to_string();
-
-    //IdResponse
-    let id_response_result: Result<IdResponse, Error> = client
-        .send_request(RequestBody::Id(IdRequest {
-            send_sk_accounts: true,
-        }))
-        .await;
-
-    match id_response_result {
-        Ok(id_response) => {
-            already_paired = true;
-            paired_device_name = id_response.data.device_name;
-        }
-        Err(_) => {}
+    let old_pairings = Client::pairings().unwrap_or_default();
+    let paired_device_names = old_pairings
+        .iter()
+        .map(|p| p.device_name.clone())
+        .collect::<Vec<String>>();
+
+    // the keys the reinstalled app has to prove it still holds
+    let kept_handles = match renew {
+        true => StoredIdentity::load_from_disk()
+            .map(|id| id.key_pair_handles)
+            .unwrap_or_default(),
+        false => vec![],
+    };
+    if renew && kept_handles.is_empty() {
+        return Err(Error::NoKeysToRenew);
     }
 
-    let keypair: Keypair = sodiumoxide::crypto::box_::gen_keypair().into();
+    let keypair: Keypair = crypto::gen_keypair().into();
     let qr = PairingQr {
         public_key: keypair.public_key.clone(),
         version: PROTOCOL_VERSION.into(),
@@ -118,6 +259,7 @@ async fn pair() -> Result<(), Error> {
             kind: whoami::distro(),
             device_identifier: global_device_uuid()?,
         },
+        expires_at: Some(chrono::Utc::now().timestamp() + PAIRING_TIMEOUT.as_secs() as i64),
     };
 
     let queue_uuid = keypair.queue_uuid()?;
@@ -128,30 +270,45 @@ async fn pair() -> Result<(), Error> {
         "https://mfa.akamai.com/app#{}",
         base64::engine::general_purpose::STANDARD.encode(serde_json::to_string(&qr)?)
     );
-    qr2term::print_qr(raw).expect("failed to generate a qr code");
-    if already_paired {
-        println!("You are already paired with device {}. \nTo override, scan the above QR code to pair a new device ", Yellow.paint(paired_device_name));
-    } else {
-        println!("{}", Green.paint("Scan the above QR code to pair your device..."));
+    let qr = qr::render(&raw)?;
+    let prompt = match paired_device_names.is_empty() {
+        _ if renew => Green.paint(i18n::tr("pair-scan-renew", &[])).to_string(),
+        true => Green.paint(i18n::tr("pair-scan", &[])).to_string(),
+        false => i18n::tr(
+            "pair-scan-another",
+            &[("devices", &Yellow.paint(paired_device_names.join(", ")))],
+        ),
+    };
+    let fallback = i18n::tr("pair-link", &[("link", &raw)]);
+    // the JSON output stays parseable, the person pairing still sees the code
+    match output::is_json() {
+        true => eprintln!("{}{}\n{}", qr, prompt, fallback),
+        false => println!("{}{}\n{}", qr, prompt, fallback),
     }
 
-    let device_public_key = client
-        .receive(queue_uuid, |messages| {
+    let device_public_key = wait_for_scan(
+        client.receive(queue_uuid, |messages| {
             keypair.open_sealed_public_key(messages.first())
-        })
-        .await?;
+        }),
+        PAIRING_TIMEOUT,
+    )
+    .await?;
+    if !output::is_json() {
+        println!("{}", i18n::tr("pair-answered", &[]));
+    }
 
     let mut pairing = Pairing {
         keypair,
-        device_public_key: device_public_key.0.to_vec().into(),
+        device_public_key: device_public_key.to_vec().into(),
         device_token: None,
         aws_push_id: None,
         device_name: String::new(),
+        paired_at: chrono::Utc::now().timestamp(),
+        keys_rotated_at: 0,
+        last_seen_at: chrono::Utc::now().timestamp(),
     };
 
-    let request = Request::new(RequestBody::Id(IdRequest {
-        send_sk_accounts: true,
-    }));
+    let request = Request::new(RequestBody::Id(IdRequest::new(true)));
     client.send(None, queue_uuid, pairing.seal(&request)?).await?;
     let response = client
         .receive(queue_uuid, |messages| {
@@ -167,152 +324,929 @@ async fn pair() -> Result<(), Error> {
     pairing.device_name = id_response.data.device_name.clone();
     pairing.aws_push_id = response.aws_push_id;
     pairing.device_token = response.device_token;
+    if renew {
+        verify_renewed_pairing(&client, &pairing, &kept_handles).await?;
+    }
     pairing.store_to_disk()?;
 
-    let id = StoredIdentity {
-        device_id: Some(id_response.data.device_identifier),
-        key_pair_handles: id_response
-            .data
-            .sk_accounts
-            .unwrap_or(vec![])
+    // keep the keys of the other paired devices around
+    let mut id = StoredIdentity::load_from_disk().unwrap_or(StoredIdentity {
+        device_id: None,
+        key_pair_handles: vec![],
+        bluetooth_peers: vec![],
+        app_capabilities: vec![],
+    });
+    id.device_id = Some(id_response.data.device_identifier);
+    id.set_bluetooth_peer(queue_uuid, id_response.data.bluetooth_service_uuid);
+    // an app from before the handshake still says which protocol version it speaks
+    let capabilities = id_response.data.capabilities.clone().unwrap_or(Capabilities {
+        version: response.version,
+        features: None,
+    });
+    id.set_app_capabilities(queue_uuid, Some(capabilities));
+    let new_handles = id_response
+        .data
+        .sk_accounts
+        .unwrap_or(vec![])
+        .into_iter()
+        .map(SshFido2KeyPairHandle::from)
+        .filter(|handle| {
+            !id.key_pair_handles
+                .iter()
+                .any(|kept| kept.key_handle == handle.key_handle)
+        })
+        .collect::<Vec<_>>();
+    id.key_pair_handles.extend(new_handles);
+
+    // the pairing with the app before it was reinstalled is dead, the device can't open its messages
+    let mut replaced = vec![];
+    if renew {
+        for old in old_pairings
             .into_iter()
-            .map(|sk| SshFido2KeyPairHandle {
-                application: sk.rp_id,
-                key_handle: sk.key_handle.0,
-                flags: 0x01,
-                public_key: sk.public_key.0,
-            })
-            .collect(),
-    };
+            .filter(|old| old.device_name == pairing.device_name)
+        {
+            id.forget_pairing(old.queue_uuid()?);
+            old.delete_from_disk()?;
+            replaced.push(old.device_name);
+        }
+    }
 
     id.store_to_disk()?;
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "device_name": id_response.data.device_name,
+            "queue_uuid": queue_uuid,
+            "keys": id.key_pair_handles.len(),
+            "renewed": renew,
+            "replaced": replaced.len(),
+        }));
+    }
     println!(
         "\n{} {}.\n",
-        Green.paint("Paired successfully with"),
+        Green.paint(i18n::tr("pair-done", &[])),
         Green.paint(id_response.data.device_name)
     );
+    if renew {
+        let kept = i18n::tr(
+            "pair-kept",
+            &[
+                ("keys", &id.key_pair_handles.len()),
+                ("replaced", &replaced.len()),
+            ],
+        );
+        println!("{}", kept);
+    }
     Ok(())
 }
 
-async fn unpair() -> Result<(), Error> {
+/// Have the newly paired device sign a fresh challenge with one of the keys already loaded here,
+/// so a renewed pairing never ends up with an app that can't use them
+async fn verify_renewed_pairing(
+    client: &Client,
+    pairing: &Pairing,
+    kept_handles: &[SshFido2KeyPairHandle],
+) -> Result<(), Error> {
+    let handle = &kept_handles[0];
+    let challenge = crypto::random_bytes(32);
+    let request = Request::new(RequestBody::Authenticate(AuthenticateRequest {
+        challenge: Base64Buffer(challenge.clone()),
+        rp_id: handle.application.clone(),
+        extensions: None,
+        key_handle: Some(Base64Buffer(handle.key_handle.clone())),
+        key_handles: None,
+    }));
+
+    if !output::is_json() {
+        println!(
+            "Approve the request on {} to show it still holds your keys...",
+            pairing.device_name
+        );
+    }
+    let queue_uuid = pairing.queue_uuid()?;
+    client.send(None, queue_uuid, pairing.seal(&request)?).await?;
+    let response = client
+        .receive(queue_uuid, |messages| {
+            pairing.find_response(&request.id, messages)
+        })
+        .await?;
+    let resp = AuthenticateResponse::try_from(response.body)?;
+
+    let signed = resp.key_handle.0 == handle.key_handle
+        && verify_sk_signature(
+            handle.key_type,
+            &handle.public_key,
+            &resp.authenticator_data.0,
+            &challenge,
+            &resp.signature.0,
+        );
+    match signed {
+        true => Ok(()),
+        false => Err(Error::RenewedDeviceMismatch(pairing.device_name.clone())),
+    }
+}
+
+/// Wait for the phone to answer the pairing QR code, counting down until it expires
+async fn wait_for_scan<T>(
+    response: impl std::future::Future<Output = Result<T, Error>>,
+    timeout: Duration,
+) -> Result<T, Error> {
+    use std::io::{IsTerminal, Write};
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let countdown = !output::is_json() && std::io::stderr().is_terminal();
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    tokio::pin!(response);
+
+    let result = loop {
+        tokio::select! {
+            result = &mut response => break result,
+            _ = tokio::time::sleep_until(deadline) => break Err(Error::PairingExpired),
+            _ = tick.tick(), if countdown => {
+                let left = deadline.saturating_duration_since(tokio::time::Instant::now()).as_secs();
+                eprint!("\rThe code expires in {}:{:02} ", left / 60, left % 60);
+                let _ = std::io::stderr().flush();
+            }
+        }
+    };
+    if countdown {
+        eprint!("\r\x1b[2K");
+    }
+    result
+}
+
+async fn unpair(reset: bool) -> Result<(), Error> {
     // check if ssh 8.2+ is installed or not
     check_ssh_version()?;
     let client = Client::new()?;
-    let pairing = Client::pairing()?;
-    let queue_uuid = pairing.queue_uuid()?;
-    let request = Request::new(RequestBody::Unpair(UnpairRequest {}));
-    let wire_message = pairing.seal(&request)?;
+    let pairings = match Client::pairings() {
+        // nothing to unpair, there may still be keys to wipe
+        Err(Error::NotPaired) if reset => vec![],
+        pairings => pairings?,
+    };
 
-    let _ = client
-        .send(pairing.device_token.clone(), queue_uuid, wire_message)
-        .await?;
+    let mut unpaired = vec![];
+    let mut not_told = vec![];
+    for pairing in pairings {
+        if !unpair_device(&client, &pairing).await? {
+            not_told.push(pairing.device_name.clone());
+        }
+        unpaired.push(pairing.device_name);
+    }
 
-    Pairing::delete_pairing_file()?;
+    if reset {
+        // the agent would write the identity back
+        if let Err(e) = launch::Daemon::new()?.stop() {
+            eprintln!("couldn't stop the agent, stop it by hand: {}", e);
+        }
+        wipe_home()?;
+    }
+
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "unpaired": unpaired, "reset": reset }));
+    }
+    if reset && !not_told.is_empty() {
+        // the queued unpair requests went with the reset
+        eprintln!(
+            "{} {}",
+            Yellow.paint("Remove this machine in the app by hand, these weren't told:"),
+            not_told.join(", ")
+        );
+    }
     println!("\n{}\n", Green.paint("Unpaired successfully!"));
+    if reset {
+        println!("{}", Green.paint("Wiped the keys and pairings from this machine"));
+    }
     Ok(())
 }
 
-async fn get_pairing_details() -> Result<(), Error> {
-    // check if ssh 8.2+ is installed or not
-    check_ssh_version()?;
+/// tell the device we're unpairing and forget about it
+/// false if it couldn't be reached and the request was queued
+async fn unpair_device(client: &Client, pairing: &Pairing) -> Result<bool, Error> {
+    let request = Request::new(RequestBody::Unpair(UnpairRequest {}));
+
+    // the phone/tablet hears about it once the queue is flushed, no need to stay paired until then
+    let told = match client.send_or_queue(pairing, &request).await {
+        Err(e @ Error::RequestQueued(_)) => {
+            eprintln!("{}", e);
+            false
+        }
+        result => result.map(|_| true)?,
+    };
+
+    pairing.delete_from_disk()?;
+    Ok(told)
+}
+
+/// Securely delete everything in the home of the profile: keys, identity, pairings and the queued requests
+fn wipe_home() -> Result<(), Error> {
+    let home = create_home_path()?;
+    let default_profile = profile::is_default(profile::current());
+    keychain::forget()?;
+    for entry in std::fs::read_dir(&home)? {
+        let path = entry?.path();
+        // the other profiles live in the home of the default one
+        if default_profile && path.file_name() == Some(profile::PROFILES_DIR.as_ref()) {
+            continue;
+        }
+        util::shred(&path)?;
+    }
+    if !default_profile {
+        std::fs::remove_dir(&home)?;
+    }
+    Ok(())
+}
 
+fn repair_identity() -> Result<(), Error> {
+    let restored = StoredIdentity::repair()?;
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "restored_from": restored }));
+    }
+
+    match restored {
+        Some(backup) => println!(
+            "{} {}",
+            Green.paint("Restored the identity file from"),
+            backup.display()
+        ),
+        None => println!("The identity file is fine, nothing to repair"),
+    }
+    Ok(())
+}
+
+async fn flush_queue() -> Result<(), Error> {
     let client = Client::new()?;
+    let (sent, remaining) = client.flush_queue().await?;
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "sent": sent, "remaining": remaining }));
+    }
 
-    let id_response: IdResponse = client
-        .send_request(RequestBody::Id(IdRequest {
-            send_sk_accounts: true,
-        }))
-        .await?;
+    println!("Sent {} queued request(s)", sent);
+    if remaining > 0 {
+        println!("{} request(s) still queued", Yellow.paint(remaining.to_string()));
+    }
+    Ok(())
+}
+
+fn configure(command: ConfigCommand) -> Result<(), Error> {
+    match command {
+        ConfigCommand::Get { key } => {
+            let value = config::get_value(&key)?;
+            match output::is_json() {
+                true => output::print_json(&serde_json::json!({ "key": key, "value": value }))?,
+                false => println!("{}", value.unwrap_or_default()),
+            }
+        }
+        ConfigCommand::Set { key, value } => config::set_value(&key, &value)?,
+        ConfigCommand::Unset { key } => config::unset_value(&key)?,
+        ConfigCommand::List => {
+            let values = config::values()?;
+            if output::is_json() {
+                return output::print_json(&values.into_iter().collect::<BTreeMap<_, _>>());
+            }
+            for (key, value) in values {
+                println!("{} = {}", key, value);
+            }
+        }
+    }
+    Ok(())
+}
 
-    println!("Paired with {}", Green.bold().paint(id_response.data.device_name));
+fn manage_profiles(command: ProfileCommand) -> Result<(), Error> {
+    match command {
+        ProfileCommand::List => {
+            let current = profile::current();
+            let profiles = profile::list()?;
+            if output::is_json() {
+                return output::print_json(&serde_json::json!({ "current": current, "profiles": profiles }));
+            }
+            for name in profiles {
+                match name == current {
+                    true => println!("* {}", Green.paint(name)),
+                    false => println!("  {}", name),
+                }
+            }
+        }
+        ProfileCommand::Create { name } => {
+            let dir = profile::create(&name)?;
+            if output::is_json() {
+                return output::print_json(&serde_json::json!({ "profile": name, "path": dir }));
+            }
+            println!("{} {}", Green.paint("Created profile"), name);
+            println!("Pair it with `akr --profile {} pair`", name);
+        }
+        ProfileCommand::Switch { name } => {
+            profile::switch(&name)?;
+            if output::is_json() {
+                return output::print_json(&serde_json::json!({ "profile": name }));
+            }
+            println!("{} {}", Green.paint("Switched to profile"), name);
+            println!("Run `akr setup` to point ssh at the agent of this profile");
+        }
+    }
     Ok(())
 }
 
-async fn generate(name: String) -> Result<(), Error> {
+fn list_devices() -> Result<(), Error> {
+    let app_capabilities = StoredIdentity::load_from_disk()
+        .map(|id| id.app_capabilities)
+        .unwrap_or_default();
+    let capabilities = |pairing: &Pairing| {
+        let queue_uuid = pairing.queue_uuid().ok();
+        app_capabilities
+            .iter()
+            .find(|app| Some(app.queue_uuid) == queue_uuid)
+            .map(|app| app.capabilities.clone())
+    };
+
+    if output::is_json() {
+        let devices = Client::pairings()?
+            .iter()
+            .enumerate()
+            .map(|(i, pairing)| {
+                serde_json::json!({
+                    "number": i + 1,
+                    "device_name": pairing.device_name,
+                    "paired_at": Some(pairing.paired_at).filter(|t| *t > 0),
+                    "last_seen_at": Some(pairing.last_seen_at).filter(|t| *t > 0),
+                    "capabilities": capabilities(pairing),
+                })
+            })
+            .collect::<Vec<_>>();
+        return output::print_json(&devices);
+    }
+
+    for (i, pairing) in Client::pairings()?.iter().enumerate() {
+        let paired_at = chrono::NaiveDateTime::from_timestamp_opt(pairing.paired_at, 0)
+            .filter(|_| pairing.paired_at > 0)
+            .map(|t| format!("paired {}", t.format("%Y-%m-%d %H:%M")))
+            .unwrap_or_default();
+        let version = capabilities(pairing)
+            .map(|capabilities| format!(", protocol {}", capabilities.version))
+            .unwrap_or_default();
+        println!(
+            "{}. {} {}{}",
+            i + 1,
+            Green.paint(&pairing.device_name),
+            paired_at,
+            version
+        );
+    }
+    Ok(())
+}
+
+/// remove a device by its number in `akr devices list` or by its name
+async fn remove_device(device: String) -> Result<(), Error> {
+    let client = Client::new()?;
+    let pairing = Pairing::find(&device)?;
+
+    unpair_device(&client, &pairing).await?;
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "unpaired": [pairing.device_name] }));
+    }
+    println!(
+        "\n{} {}\n",
+        Green.paint("Removed"),
+        Green.paint(&pairing.device_name)
+    );
+    Ok(())
+}
+
+async fn generate(args: GenerateArgs) -> Result<(), Error> {
     // check if ssh 8.2+ is installed or not
     check_ssh_version()?;
 
     let client = Client::new()?;
-    let name = format!("ssh:{}", name);
+    let name = match args.rp_id {
+        // sshd only accepts sk keys of "ssh:" applications
+        Some(rp_id) if !rp_id.starts_with("ssh:") => return Err(Error::InvalidRpId(rp_id)),
+        Some(rp_id) => rp_id,
+        None => {
+            let name = args
+                .name
+                .or_else(|| config::get().default_rp_id.clone())
+                .ok_or(Error::MissingKeyName)?;
+            format!("ssh:{}", name.strip_prefix("ssh:").unwrap_or(&name))
+        }
+    };
+    let resident = match (args.resident, args.no_resident) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => config::get().resident_keys.unwrap_or(false),
+    };
+    // like ssh-keygen, the user id is the name padded with zeros
+    let user = args.user.map(|user| {
+        let mut id = user.as_bytes().to_vec();
+        id.resize(id.len().max(32), 0);
+        UserData {
+            id: id.into(),
+            display_name: user,
+        }
+    });
+
+    // before the phone makes a key that would then have nowhere to go
+    let write = args.write.as_deref().map(util::expand_home);
+    if let Some(path) = write.as_ref().filter(|path| path.exists()) {
+        return Err(Error::KeyFileExists(path.display().to_string()));
+    }
+
+    let challenge = crypto::random_bytes(32);
     let resp: RegisterResponse = client
         .send_request(RequestBody::Register(RegisterRequest {
-            challenge: sodiumoxide::randombytes::randombytes(32).into(),
+            challenge: challenge.clone().into(),
             rp_id: name.clone(),
             rp_name: None,
-            user: None,
+            user,
             is_webauthn: true,
+            resident_key: resident,
+            user_verification_required: args.uv_required,
+            extensions: None,
         }))
         .await?;
+    // don't keep a key whose attestation is bogus
+    let attestation = attestation::verify(&resp, &name, &challenge)?;
 
     let key_pair = SshFido2KeyPairHandle {
         application: name,
         key_handle: resp.key_handle.0,
+        key_type: SkKeyType::from_public_key(&resp.public_key.0),
         public_key: resp.public_key.0,
-        flags: 0x01,
+        flags: key_flags(resident, args.uv_required),
+        comment: String::new(),
+        label: None,
+        tags: vec![],
+        created_at: Some(chrono::Utc::now().timestamp()),
     };
 
     StoredIdentity::store_key_pair_handle(&key_pair)?;
+    if let Some(attestation) = &attestation {
+        StoredIdentity::store_attestation(&key_pair.key_handle, attestation)?;
+    }
+    let written = match &write {
+        Some(path) => Some(key_pair.write_key_files(path)?),
+        None => None,
+    };
 
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "rp_id": key_pair.application,
+            "public_key": key_pair.authorized_public_key()?,
+            "attestation": attestation.as_ref().map(|attestation| &attestation.format),
+            "resident": resident,
+            "uv_required": args.uv_required,
+            "key_file": write,
+        }));
+    }
     println!("{}", key_pair.authorized_public_key()?);
+    if let (Some(path), Some(pub_path)) = (&write, &written) {
+        eprintln!(
+            "{} {} and {}",
+            Green.paint("Wrote the key to"),
+            path.display(),
+            pub_path.display()
+        );
+    }
+    match &attestation {
+        Some(attestation) if !attestation.is_self_attested() => eprintln!(
+            "{} {}",
+            Green.paint("Attested by"),
+            attestation.attested_by()?.unwrap_or_default()
+        ),
+        Some(_) => eprintln!(
+            "{}",
+            Yellow.paint("Self attested, it can't prove where the key lives")
+        ),
+        None => eprintln!("{}", Yellow.paint("The app didn't attest the key")),
+    }
 
     Ok(())
 }
 
+/// the flags sshd checks signatures against, see `SshFido2KeyPairHandle`
+fn key_flags(resident: bool, uv_required: bool) -> u8 {
+    let mut flags = SshFido2KeyPairHandle::SSH_SK_USER_PRESENCE_REQD;
+    if resident {
+        flags |= SshFido2KeyPairHandle::SSH_SK_RESIDENT_KEY;
+    }
+    if uv_required {
+        flags |= SshFido2KeyPairHandle::SSH_SK_USER_VERIFICATION_REQD;
+    }
+    flags
+}
+
 async fn load_keys() -> Result<(), Error> {
     // check if ssh 8.2+ is installed or not
     check_ssh_version()?;
     let client = Client::new()?;
 
-    let id_response: IdResponse = client
-        .send_request(RequestBody::Id(IdRequest {
-            send_sk_accounts: true,
-        }))
-        .await?;
+    // collect the keys of every paired device
+    let requests = Client::pairings()?.into_iter().map(|pairing| {
+        let device_name = pairing.device_name.clone();
+        let queue_uuid = pairing.queue_uuid();
+        let request =
+            client.send_request_to_device::<IdResponse>(pairing, RequestBody::Id(IdRequest::new(true)));
+        async move { (device_name, queue_uuid, request.await) }
+    });
+
+    // devices that don't answer keep their bluetooth details and capabilities
+    let stored = StoredIdentity::load_from_disk().ok();
+    let mut id = StoredIdentity {
+        device_id: None,
+        key_pair_handles: vec![],
+        bluetooth_peers: stored
+            .as_ref()
+            .map(|id| id.bluetooth_peers.clone())
+            .unwrap_or_default(),
+        app_capabilities: stored.map(|id| id.app_capabilities).unwrap_or_default(),
+    };
+    let mut responded = false;
+    for (device_name, queue_uuid, id_response) in futures::future::join_all(requests).await {
+        let id_response = match id_response {
+            Ok(id_response) => id_response,
+            Err(e) => {
+                eprintln!("{} {}: {}", Red.paint("Couldn't load keys from"), device_name, e);
+                continue;
+            }
+        };
 
-    let id = StoredIdentity {
-        device_id: Some(id_response.data.device_identifier),
-        key_pair_handles: id_response
-            .data
-            .sk_accounts
-            .unwrap_or(vec![])
-            .into_iter()
-            .map(|sk| SshFido2KeyPairHandle {
-                application: sk.rp_id,
-                key_handle: sk.key_handle.0,
-                flags: 0x01,
-                public_key: sk.public_key.0,
+        responded = true;
+        id.device_id = id.device_id.or(Some(id_response.data.device_identifier));
+        if let Ok(queue_uuid) = queue_uuid {
+            id.set_bluetooth_peer(queue_uuid, id_response.data.bluetooth_service_uuid);
+            id.set_app_capabilities(queue_uuid, id_response.data.capabilities.clone());
+        }
+        id.key_pair_handles.extend(
+            id_response
+                .data
+                .sk_accounts
+                .unwrap_or(vec![])
+                .into_iter()
+                .map(SshFido2KeyPairHandle::from),
+        );
+    }
+
+    // don't wipe the stored keys if no device could be reached
+    if !responded {
+        return Err(Error::ResponseTimedOut);
+    }
+
+    id.store_to_disk()?;
+
+    let keys = id
+        .key_pair_handles
+        .iter()
+        .filter(|k| k.application.starts_with("ssh:"))
+        .map(|k| k.authorized_public_key())
+        .collect::<Result<Vec<_>, Error>>()?;
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "keys": keys }));
+    }
+    for key in keys {
+        println!("{}", Blue.paint(key));
+    }
+
+    Ok(())
+}
+
+fn list_keys(tag: Option<String>, public_key: bool, verbose: bool) -> Result<(), Error> {
+    let mut handles = StoredIdentity::load_from_disk()?.key_pair_handles;
+    handles.extend(StoredIdentity::load_added_key_pair_handles()?);
+    handles.retain(|handle| handle.application.starts_with("ssh:"));
+    if let Some(tag) = &tag {
+        handles.retain(|handle| handle.tags.contains(tag));
+    }
+
+    // ready to append to authorized_keys
+    if public_key {
+        for handle in &handles {
+            println!("{}", handle.authorized_public_key()?);
+        }
+        return Ok(());
+    }
+
+    if output::is_json() {
+        let keys = handles
+            .iter()
+            .map(|handle| {
+                let usage = StoredIdentity::load_usage(&handle.key_handle)?;
+                Ok(serde_json::json!({
+                    "fingerprint": handle.fingerprint()?,
+                    "md5_fingerprint": handle.md5_fingerprint()?,
+                    "key_type": handle.key_type.type_id(),
+                    "label": handle.label,
+                    "rp_id": handle.application,
+                    "tags": handle.tags,
+                    "created_at": handle.created_at,
+                    "last_used_at": usage.last_used_at,
+                    "sign_count": usage.count,
+                    "last_host": usage.last_host,
+                    "public_key": handle.authorized_public_key()?,
+                }))
             })
-            .collect(),
+            .collect::<Result<Vec<_>, Error>>()?;
+        return output::print_json(&keys);
+    }
+
+    let date = |timestamp: Option<i64>| {
+        timestamp
+            .and_then(|t| chrono::NaiveDateTime::from_timestamp_opt(t, 0))
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "never".to_string())
     };
+    for handle in &handles {
+        let tags = match handle.tags.is_empty() {
+            true => String::new(),
+            false => format!(" [{}]", handle.tags.join(", ")),
+        };
+        println!(
+            "{} {}{}",
+            Green.paint(handle.key_comment()),
+            handle.key_type.type_id(),
+            Yellow.paint(tags)
+        );
+        println!("  {}", handle.fingerprint()?);
+        println!("  {}", handle.md5_fingerprint()?);
+        let usage = StoredIdentity::load_usage(&handle.key_handle)?;
+        println!(
+            "  rp id {}, created {}, last used {}",
+            handle.application,
+            match handle.created_at {
+                Some(_) => date(handle.created_at),
+                None => "unknown".to_string(),
+            },
+            date(usage.last_used_at)
+        );
+        if verbose {
+            let host = match &usage.last_host {
+                Some(host) => format!(", last login to {}", host),
+                None => String::new(),
+            };
+            println!("  signed {} times{}", usage.count, host);
+        }
+    }
+    Ok(())
+}
 
-    id.store_to_disk()?;
+fn import_keys(files: Vec<String>) -> Result<(), Error> {
+    let mut imported = vec![];
+    for file in &files {
+        let mut handle = ssh_format::parse_openssh_sk_private_key(&std::fs::read_to_string(file)?)?;
+        handle.created_at = std::fs::metadata(file)?
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since| since.as_secs() as i64);
+        // kept with the keys added through ssh-add, `akr load` doesn't touch those
+        StoredIdentity::store_added_key_pair_handle(&handle)?;
+        imported.push(handle);
+    }
 
-    for k in id.key_pair_handles {
-        if !k.application.starts_with("ssh:") {
-            continue;
+    if output::is_json() {
+        let keys = imported
+            .iter()
+            .map(|handle| {
+                Ok(serde_json::json!({
+                    "fingerprint": handle.fingerprint()?,
+                    "rp_id": handle.application,
+                    "comment": handle.comment,
+                }))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        return output::print_json(&keys);
+    }
+    for (file, handle) in files.iter().zip(&imported) {
+        println!(
+            "{} {} from {}",
+            Green.paint("Imported"),
+            handle.key_comment(),
+            file
+        );
+    }
+    Ok(())
+}
+
+fn export_key(key: Option<String>, format: ExportFormat) -> Result<(), Error> {
+    let handle = sshsig::find_key_pair_handle(key.as_deref())?;
+    let exported = handle.export(format)?;
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "fingerprint": handle.fingerprint()?,
+            "key": exported,
+        }));
+    }
+    println!("{}", exported.trim_end());
+    Ok(())
+}
+
+fn rename_key(args: RenameArgs) -> Result<(), Error> {
+    let mut handle = sshsig::find_key_pair_handle(Some(&args.key))?;
+    if args.clear {
+        handle.label = None;
+    }
+    if let Some(label) = args.label {
+        handle.label = Some(label).filter(|label| !label.is_empty());
+    }
+    handle.tags.retain(|tag| !args.untag.contains(tag));
+    for tag in args.tag {
+        if !handle.tags.contains(&tag) {
+            handle.tags.push(tag);
+        }
+    }
+    StoredIdentity::update_key_pair_handle(&handle)?;
+
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "fingerprint": handle.fingerprint()?,
+            "label": handle.label,
+            "tags": handle.tags,
+        }));
+    }
+    println!("{} {}", Green.paint("Renamed to"), handle.key_comment());
+    Ok(())
+}
+
+async fn delete_key(key: String) -> Result<(), Error> {
+    let handle = sshsig::find_key_pair_handle(Some(&key))?;
+    let client = Client::new()?;
+    prune::delete(Some(&client), &handle).await?;
+    prune::reload_agent().await?;
+
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "fingerprint": handle.fingerprint()?,
+            "deleted": true,
+        }));
+    }
+    println!(
+        "{} {}, your phone/tablet deletes it too",
+        Green.paint("Deleted"),
+        handle.key_comment()
+    );
+    Ok(())
+}
+
+fn show_attestation(key: Option<String>, pem: bool) -> Result<(), Error> {
+    let handle = sshsig::find_key_pair_handle(key.as_deref())?;
+    let attestation = StoredIdentity::load_attestation(&handle.key_handle)?.ok_or(Error::NoAttestation)?;
+    let chain = attestation.certificate_chain()?;
+
+    if pem {
+        for certificate in &chain {
+            print!("{}", String::from_utf8_lossy(&certificate.to_pem()?));
         }
-        println!("{}", Blue.paint(k.authorized_public_key()?));
+        return Ok(());
+    }
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "key": handle.key_comment(),
+            "format": attestation.format,
+            "self_attested": attestation.is_self_attested(),
+            "attested_by": attestation.attested_by()?,
+            "verified_at": attestation.verified_at,
+            "certificates": attestation.certificates,
+        }));
     }
 
+    println!("{}: {}", Blue.paint("Key"), handle.key_comment());
+    println!("{}: {}", Blue.paint("Format"), attestation.format);
+    match attestation.attested_by()? {
+        Some(attested_by) => println!("{}: {}", Blue.paint("Attested by"), attested_by),
+        None => println!(
+            "{}: {}",
+            Blue.paint("Attested by"),
+            Yellow.paint("the key itself, it can't prove where the key lives")
+        ),
+    }
+    if let Some(verified_at) = chrono::NaiveDateTime::from_timestamp_opt(attestation.verified_at, 0) {
+        println!(
+            "{}: {}",
+            Blue.paint("Verified"),
+            verified_at.format("%Y-%m-%d %H:%M")
+        );
+    }
+    for (i, certificate) in chain.iter().enumerate() {
+        println!(
+            "  {}. not after {}, serial {}",
+            i + 1,
+            certificate.not_after(),
+            certificate.serial_number().to_bn()?.to_hex_str()?
+        );
+    }
     Ok(())
 }
 
-async fn start_daemon() {
+fn load_certificate(file: String) -> Result<(), Error> {
+    // <cert type> <base64 certificate> [comment]
+    let contents = std::fs::read_to_string(&file)?;
+    let certificate = contents
+        .split_whitespace()
+        .nth(1)
+        .ok_or(Error::InvalidCertificate)?;
+    let certificate = base64::engine::general_purpose::STANDARD.decode(certificate)?;
+    let certified = SshFido2KeyPairHandle::parse_public_key_from_certificate(&certificate)?;
+
+    let mut handles = StoredIdentity::load_from_disk()?.key_pair_handles;
+    handles.extend(StoredIdentity::load_added_key_pair_handles()?);
+    let handle = handles
+        .into_iter()
+        .find(|handle| handle.fmt_public_key().ok().as_ref() == Some(&certified))
+        .ok_or(Error::UnknownKey)?;
+
+    StoredIdentity::store_certificate(&certified, &certificate)?;
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "key": handle.key_comment() }));
+    }
+    println!(
+        "{} {}",
+        Green.paint("Certificate attached to"),
+        handle.key_comment()
+    );
+
+    Ok(())
+}
+
+async fn start_daemon(args: StartArgs) {
     // check if ssh 8.2+ is installed or not
     check_ssh_version()
         .expect("Failed to check ssh version. Please make sure OpenSSH 8.2+ is installed to use akr");
 
-    let home = create_home_path().expect("failed to create home dir");
-    let pipe = home.join(SSH_AGENT_PIPE);
+    if !args.allow_core_dumps {
+        if let Err(e) = memory::disable_core_dumps() {
+            eprintln!("couldn't disable core dumps: {}", e);
+        }
+    }
+
+    if args.all_profiles {
+        if let Err(e) = profile::start_all().await {
+            eprintln!("couldn't start the agents: {}", e);
+        }
+        return;
+    }
+
+    #[cfg(windows)]
+    let pipe = agent_socket_path().expect("failed to create home dir");
+    #[cfg(not(windows))]
+    if args.pageant {
+        eprintln!("Pageant clients are only served on Windows");
+    }
 
-    if std::fs::metadata(&pipe).is_ok() {
-        if let Ok(_) = std::fs::remove_file(&pipe) {
-            println!("Pipe deleted");
+    let _instance = match instance::acquire(args.takeover).await {
+        Ok(instance) => instance,
+        Err(e) => return eprintln!("{}", Red.paint(e.to_string())),
+    };
+    let allow_other_users = args.allow_other_users || config::get().allow_other_users.unwrap_or(false);
+
+    // started by `akr setup --systemd`'s socket unit, which keeps the socket across restarts
+    #[cfg(unix)]
+    let (listener, socket) = match launch::inherited_listener() {
+        Some(listener) => {
+            println!("listening on the socket from systemd");
+            (UnixListener::from_std(listener).expect("failed to listen"), None)
+        }
+        None => {
+            let pipe = agent_socket_path().expect("failed to create home dir");
+            println!("binding to {}", pipe.display());
+            match instance::bind(&pipe, args.takeover, allow_other_users).await {
+                Ok((listener, socket)) => (listener, Some(socket)),
+                Err(e) => return eprintln!("{}", Red.paint(e.to_string())),
+            }
+        }
+    };
+    if let Some(dir) = &args.record {
+        match recorder::start(dir) {
+            Ok(()) => println!("recording to {}", dir.display()),
+            Err(e) => eprintln!("couldn't record to {}: {}", dir.display(), e),
         }
     }
-    println!("binding to {}", pipe.display());
-    let listener = UnixListener::bind(pipe);
-    let mut handler = ssh_agent::Agent::new(Client::new().expect("failed to startup client"));
+    let mut client = Client::new().expect("failed to startup client");
+    let config = config::get();
+    let retry = RetryPolicy::default();
+    client.set_retry_policy(RetryPolicy {
+        max_attempts: args
+            .retry_attempts
+            .or(config.retry_attempts)
+            .unwrap_or(retry.max_attempts),
+        initial_backoff: args
+            .retry_backoff
+            .or(config.retry_backoff)
+            .map(Duration::from_millis)
+            .unwrap_or(retry.initial_backoff),
+        idempotent_only: !args.retry_non_idempotent,
+        ..retry
+    });
+    let mut handler = ssh_agent::Agent::new(client);
+    handler.watch_store();
+    if let Err(e) = audit_export::start() {
+        eprintln!("couldn't ship the audit log: {}", e);
+    }
+    team_policy::start();
+
+    if let Some(address) = args.metrics_address {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(address).await {
+                eprintln!("couldn't serve metrics on {}: {}", address, e);
+            }
+        });
+    }
 
     if let Some(mut dir) = dirs::home_dir() {
         dir.push(".ssh");
@@ -321,34 +1255,65 @@ async fn start_daemon() {
         eprintln!("couldn't determine user home dir, no keys will be preloaded");
     }
 
-    SshAgent::run(handler, listener.unwrap()).await;
+    if args.local_keys {
+        handler.enable_local_keys();
+    }
+    let sign_timeout = args.sign_timeout.or(config.sign_timeout).map(Duration::from_secs);
+    if let Some(sign_timeout) = sign_timeout {
+        handler.set_sign_timeout(sign_timeout);
+    }
+    // the requests being answered on shutdown get as long as they would have anyway
+    let grace = sign_timeout.unwrap_or(ssh_agent::Agent::DEFAULT_SIGN_TIMEOUT);
+    handler.set_strict_sign_counter(args.strict_sign_counter);
+    handler.set_allow_other_users(allow_other_users);
+    handler.set_local_confirmation(args.local_confirmation.or(config.local_confirmation));
+    if let Some(ttl) = args.refresh_keys {
+        handler.enable_device_keys_refresh(Duration::from_secs(ttl));
+    }
+
+    let stop = async {
+        let signal = launch::stop_requested().await;
+        println!("{}, shutting down once the requests being answered are", signal);
+    };
+    #[cfg(unix)]
+    let drained = SshAgent::run(handler, listener, stop, grace).await;
+    #[cfg(windows)]
+    let drained = {
+        println!("serving {}", pipe.display());
+        let served = match args.pageant {
+            true => SshAgent::run_named_pipe_and_pageant(handler, &pipe.to_string_lossy(), stop, grace).await,
+            false => SshAgent::run_named_pipe(handler, &pipe.to_string_lossy(), stop, grace).await,
+        };
+        match served {
+            Ok(drained) => drained,
+            Err(e) => return eprintln!("couldn't serve {}: {}", pipe.display(), e),
+        }
+    };
+
+    if !drained {
+        eprintln!("stopped with requests still being answered");
+    }
+    #[cfg(unix)]
+    if let Some(socket) = socket {
+        socket.remove();
+    }
+    if !audit_export::finish().await {
+        eprintln!("couldn't ship the rest of the audit log");
+    }
 }
 
 async fn health_check() -> Result<(), Error> {
     let client = Client::new()?;
-    let mut errors_encountered = false;
+    let mut problems = vec![];
 
     // check if queues are working properly or not
-    match client.pz_health_check().await? {
-        error::QueueEvaluation::Allow => {}
-        error::QueueEvaluation::Deny(reason) => {
-            eprintln!("{}", Red.paint(reason.to_string()));
-            errors_encountered = true;
-        }
-    }
-    match client.aws_health_check().await? {
-        error::QueueEvaluation::Allow => {}
-        error::QueueEvaluation::Deny(reason) => {
-            eprintln!("{}", Red.paint(reason.to_string()));
-            errors_encountered = true;
-        }
-    }
-
-    match client.azure_health_check().await? {
-        error::QueueEvaluation::Allow => {}
-        error::QueueEvaluation::Deny(reason) => {
-            eprintln!("{}", Red.paint(reason.to_string()));
-            errors_encountered = true;
+    for evaluation in [
+        client.pz_health_check().await?,
+        client.aws_health_check().await?,
+        client.azure_health_check().await?,
+    ] {
+        if let error::QueueEvaluation::Deny(reason) = evaluation {
+            problems.push(reason.to_string());
         }
     }
 
@@ -356,44 +1321,72 @@ async fn health_check() -> Result<(), Error> {
     check_ssh_version()?;
 
     //check if the user has any keys
-    let id_response: IdResponse = client
-        .send_request(RequestBody::Id(IdRequest {
-            send_sk_accounts: true,
-        }))
-        .await?;
+    let id_response: IdResponse = client.send_request(RequestBody::Id(IdRequest::new(true))).await?;
 
     let id_filtered: Vec<SshFido2KeyPairHandle> = id_response
         .data
         .sk_accounts
         .unwrap_or(vec![])
         .into_iter()
-        .map(|sk| SshFido2KeyPairHandle {
-            application: sk.rp_id,
-            key_handle: sk.key_handle.0,
-            flags: 0x01,
-            public_key: sk.public_key.0,
-        })
+        .map(SshFido2KeyPairHandle::from)
         .collect::<Vec<SshFido2KeyPairHandle>>()
         .into_iter()
         .filter(|x| x.application.starts_with("ssh:"))
         .collect();
 
     if id_filtered.is_empty() {
-        eprintln!("{}", Red.paint("You do not have any keys loaded in your agent. Please generate one using `akr generate --name <key_name>`"));
-        errors_encountered = true;
+        problems.push("You do not have any keys loaded in your agent. Please generate one using `akr generate --name <key_name>`".to_string());
     }
 
-    if !errors_encountered {
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "ok": problems.is_empty(), "problems": problems }));
+    }
+    for problem in &problems {
+        eprintln!("{}", Red.paint(problem));
+    }
+    if problems.is_empty() {
         println!("{}", Green.paint("You're all set! "));
     }
 
     Ok(())
 }
 
+/// where the agent listens unless set in the config file: "akr-ssh-agent.sock" in the runtime directory
+/// ("akr-ssh-agent-<profile>.sock" for another profile), or in the home of the profile without one
+fn agent_socket_path() -> Result<PathBuf, Error> {
+    match &config::get().socket_path {
+        Some(path) => Ok(util::expand_home(path)),
+        #[cfg(unix)]
+        None => match instance::runtime_dir() {
+            Some(dir) => {
+                let name = profile::current();
+                match profile::is_default(name) {
+                    true => Ok(dir.join(SSH_AGENT_PIPE)),
+                    false => Ok(dir.join(format!("akr-ssh-agent-{}.sock", name))),
+                }
+            }
+            None => Ok(create_home_path()?.join(SSH_AGENT_PIPE)),
+        },
+        #[cfg(windows)]
+        None => {
+            let name = profile::current();
+            match profile::is_default(name) {
+                true => Ok(PathBuf::from(WINDOWS_AGENT_PIPE)),
+                false => Ok(PathBuf::from(format!("{}-{}", WINDOWS_AGENT_PIPE, name))),
+            }
+        }
+    }
+}
+
+/// the home of the current profile, "~/.akr" for the default one
 fn create_home_path() -> Result<PathBuf, Error> {
-    let dirs = directories::UserDirs::new().ok_or(Error::CannotCreateHomeDir)?;
-    let home = dirs.home_dir().join(HOME_DIR);
+    let name = profile::current();
+    let home = profile::dir(name)?;
     if !home.exists() {
+        // named profiles have to be created first, so a typo doesn't pair a new one
+        if !profile::is_default(name) {
+            return Err(Error::UnknownProfile(name.to_string()));
+        }
         std::fs::create_dir(&home)?;
     }
     Ok(home)
@@ -403,7 +1396,7 @@ pub fn global_device_uuid() -> Result<Base64Buffer, Error> {
     let path = create_home_path()?.join("global_device.uuid");
 
     if !std::fs::metadata(&path).is_ok() {
-        let uuid: Base64Buffer = sodiumoxide::randombytes::randombytes(32).into();
+        let uuid: Base64Buffer = crypto::random_bytes(32).into();
         std::fs::write(path, uuid.to_string())?;
         return Ok(uuid);
     }
@@ -0,0 +1,110 @@
+//! Keeping secrets out of swap and core dumps
+//!
+//! `Secret` holds the pairing keys, derived secrets and the like: its pages are locked in memory where the
+//! platform allows it and it's zeroed once dropped. The agent also turns off core dumps when it starts,
+//! unless `akr start --allow-core-dumps`, a core dump would hold everything the agent has in memory.
+
+use crate::error::Error;
+use base64::Engine;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::Deref;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Bytes that are zeroed when dropped and kept out of swap while alive, serialized as base64
+pub struct Secret(Box<[u8]>);
+
+impl Secret {
+    /// Takes over `bytes` and zeroes them, a `Vec` can't be turned into a box without a copy
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        let secret = Self::from(bytes.as_slice());
+        bytes.zeroize();
+        secret
+    }
+}
+
+impl From<&[u8]> for Secret {
+    fn from(bytes: &[u8]) -> Self {
+        let secret = Box::<[u8]>::from(bytes);
+        lock(&secret);
+        Self(secret)
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Self {
+        Self::from(&self.0[..])
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        unlock(&self.0);
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let encoded = Zeroizing::new(base64::engine::general_purpose::STANDARD.encode(&self.0));
+        serializer.serialize_str(&encoded)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = Zeroizing::new(String::deserialize(deserializer)?);
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.as_bytes())
+            .map_err(serde::de::Error::custom)?;
+        Ok(Self::new(decoded))
+    }
+}
+
+/// Keep the pages of `secret` out of swap, best effort: the limit of locked memory is low on some systems
+pub fn lock(secret: &[u8]) {
+    #[cfg(unix)]
+    if !secret.is_empty() {
+        // SAFETY: locking a live allocation doesn't touch its contents
+        if let Err(e) = unsafe { nix::sys::mman::mlock(secret.as_ptr().cast(), secret.len()) } {
+            tracing::debug!("couldn't lock a secret in memory: {}", e);
+        }
+    }
+}
+
+fn unlock(secret: &[u8]) {
+    #[cfg(unix)]
+    if !secret.is_empty() {
+        // SAFETY: as for `lock`
+        let _ = unsafe { nix::sys::mman::munlock(secret.as_ptr().cast(), secret.len()) };
+    }
+}
+
+/// No core dumps of this process, and on linux no other process of the user reading its memory either
+pub fn disable_core_dumps() -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use nix::sys::resource::{setrlimit, Resource};
+        setrlimit(Resource::RLIMIT_CORE, 0, 0).map_err(std::io::Error::from)?;
+    }
+    #[cfg(target_os = "linux")]
+    // SAFETY: only sets a flag of this process
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
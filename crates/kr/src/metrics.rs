@@ -0,0 +1,194 @@
+//! Counters of the agent daemon, served in the Prometheus text format
+//! by `akr start --metrics-address <ip:port>` on `/metrics`
+
+use crate::error::Error;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// upper bounds in seconds of the round trip latency buckets, approvals take a human a few seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+/// requests with a longer head than this aren't for us
+const MAX_REQUEST_HEAD_LEN: usize = 8 * 1024;
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    sign_requests: AtomicU64,
+    approvals: AtomicU64,
+    denials: AtomicU64,
+    timeouts: AtomicU64,
+    throttled: AtomicU64,
+    identities: AtomicU64,
+    latency: Histogram,
+}
+
+struct Histogram {
+    /// per bucket, not cumulative, the last one counting everything above the largest bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            sign_requests: AtomicU64::new(0),
+            approvals: AtomicU64::new(0),
+            denials: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            identities: AtomicU64::new(0),
+            latency: Histogram {
+                buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+                sum_micros: AtomicU64::new(0),
+            },
+        }
+    }
+
+    pub fn sign_requested(&self) {
+        self.sign_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sign_approved(&self) {
+        self.approvals.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sign_denied(&self) {
+        self.denials.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sign_timed_out(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sign_throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_identities(&self, identities: usize) {
+        self.identities.store(identities as u64, Ordering::Relaxed);
+    }
+
+    /// record how long a request took from sending it until its response arrived
+    pub fn observe_round_trip(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency
+            .sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// See: https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "akr_sign_requests_total",
+                "Sign requests received by the agent",
+                &self.sign_requests,
+            ),
+            (
+                "akr_sign_approvals_total",
+                "Sign requests approved on a phone/tablet",
+                &self.approvals,
+            ),
+            (
+                "akr_sign_denials_total",
+                "Sign requests denied on a phone/tablet",
+                &self.denials,
+            ),
+            (
+                "akr_sign_timeouts_total",
+                "Sign requests that weren't answered in time",
+                &self.timeouts,
+            ),
+            (
+                "akr_sign_throttled_total",
+                "Sign requests refused by the rate limits",
+                &self.throttled,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        let name = "akr_transport_round_trip_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Time from sending a request until its response arrived",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut count = 0;
+        for (i, bucket) in self.latency.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS
+                .get(i)
+                .map(|bound| bound.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let sum = self.latency.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+
+        let name = "akr_identities";
+        let _ = writeln!(out, "# HELP {} Identities the agent listed to ssh last", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, self.identities.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Answer scrapes on `address` until the daemon exits
+pub async fn serve(address: SocketAddr) -> Result<(), Error> {
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                tracing::debug!(error = %e, "couldn't answer metrics request");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> Result<(), Error> {
+    let mut head = vec![];
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_HEAD_LEN {
+            return Ok(());
+        }
+        head.extend_from_slice(&buffer[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&head);
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", METRICS.render()),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Desktop notifications about sign requests, through notify-rust (the notification center on macOS)
+//!
+//! One when a request goes to the phone/tablet, a reminder when the approval takes a while and one with the
+//! outcome. `notifications = false` in the config file turns them all off, `notify_results = false` only the
+//! outcomes. They're in the language of the locale, see `i18n`.
+
+use crate::config;
+use crate::i18n::tr;
+use std::time::Duration;
+
+pub enum Outcome {
+    Approved,
+    Denied,
+    TimedOut(Duration),
+}
+
+fn enabled() -> bool {
+    config::get().notifications != Some(false)
+}
+
+/// `what` is the command asking, e.g. "ssh user@host", or the rp id
+pub fn request_sent(what: &str, rp_id: &str) {
+    if enabled() {
+        show(tr("notify-approve", &[("what", &what)]), Some(rp_id.to_string()));
+    }
+}
+
+pub fn still_waiting(what: &str, device_names: &str) {
+    if enabled() {
+        show(
+            tr("notify-approve", &[("what", &what)]),
+            Some(tr("notify-waiting", &[("devices", &device_names)])),
+        );
+    }
+}
+
+pub fn outcome(what: &str, outcome: Outcome) {
+    if !enabled() || config::get().notify_results == Some(false) {
+        return;
+    }
+    let summary = match outcome {
+        Outcome::Approved => tr("notify-approved", &[("what", &what)]),
+        Outcome::Denied => tr("notify-denied", &[("what", &what)]),
+        Outcome::TimedOut(timeout) => tr(
+            "notify-timed-out",
+            &[("secs", &timeout.as_secs()), ("what", &what)],
+        ),
+    };
+    show(summary, None);
+}
+
+/// sign requests are refused for `retry_in`, there were too many
+pub fn throttled(what: &str, retry_in: Duration) {
+    if enabled() {
+        show(
+            tr("notify-throttled", &[("what", &what)]),
+            Some(tr("notify-throttled-body", &[("secs", &retry_in.as_secs())])),
+        );
+    }
+}
+
+/// showing can block on D-Bus, keep it off the agent's tasks
+fn show(summary: String, body: Option<String>) {
+    let show = move || {
+        let mut notification = notify_rust::Notification::new();
+        notification.summary(&summary);
+        if let Some(body) = &body {
+            notification.body(body);
+        }
+        if let Err(e) = notification.show() {
+            tracing::debug!("couldn't show a notification: {}", e);
+        }
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => drop(runtime.spawn_blocking(show)),
+        Err(_) => show(),
+    }
+}
@@ -0,0 +1,35 @@
+//! Machine readable output for scripts, with the global `--json` flag
+//!
+//! Commands then print a single JSON document to stdout instead of their usual text. What's only of use to a
+//! person, like the pairing QR code, goes to stderr.
+
+use crate::error::Error;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+static JSON: OnceLock<bool> = OnceLock::new();
+
+/// Switch every command to JSON output
+pub fn select_json(json: bool) {
+    let _ = JSON.set(json);
+}
+
+pub fn is_json() -> bool {
+    JSON.get().copied().unwrap_or(false)
+}
+
+/// Print `value` as the output of the command
+pub fn print_json<T: Serialize>(value: &T) -> Result<(), Error> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Print the error a command failed with
+pub fn print_error(error: &Error) {
+    let _ = print_json(&serde_json::json!({
+        "error": error.to_string(),
+        "code": error.code(),
+        "category": error.category().name(),
+        "hint": error.hint(),
+    }));
+}
@@ -1,47 +1,137 @@
+use crate::crypto::{self, PublicKey};
 use crate::error::Error;
+use crate::keychain;
+use crate::memory::Secret;
 use crate::protocol::{Base64Buffer, Request, Response, ResponseBody, WireMessage};
+use crate::util::write_atomically;
 use serde::{Deserialize, Serialize};
-use sodiumoxide::crypto::box_::{PublicKey, SecretKey, NONCEBYTES};
 
 use std::path::PathBuf;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Pairing {
     pub device_public_key: Base64Buffer,
     pub device_name: String,
     pub aws_push_id: Option<String>,
     pub device_token: Option<String>,
+    /// unix time of the pairing, used to keep devices in enrollment order
+    #[serde(default)]
+    pub paired_at: i64,
+    /// unix time the keys were last rotated with `akr rotate-keys`, 0 if never
+    #[serde(default)]
+    pub keys_rotated_at: i64,
+    /// unix time of the last response from the device, 0 if never
+    #[serde(default)]
+    pub last_seen_at: i64,
     #[serde(flatten)]
     pub keypair: Keypair,
 }
 
 impl Pairing {
-    fn path() -> Result<PathBuf, Error> {
+    /// legacy single device pairing file
+    fn legacy_path() -> Result<PathBuf, Error> {
         let path = super::create_home_path()?.join("pairing.json");
         Ok(path)
     }
 
-    pub fn load_from_disk() -> Result<Self, Error> {
-        let path = Self::path()?;
+    fn dir_path() -> Result<PathBuf, Error> {
+        let path = super::create_home_path()?.join("pairings");
+        if !path.exists() {
+            std::fs::create_dir_all(&path)?;
+        }
+        Ok(path)
+    }
+
+    fn path(&self) -> Result<PathBuf, Error> {
+        Ok(Self::dir_path()?.join(format!("{}.json", self.queue_uuid()?)))
+    }
 
+    /// move a pairing from before multi device support into the pairings dir
+    fn migrate_legacy_pairing() -> Result<(), Error> {
+        let path = Self::legacy_path()?;
         if !std::fs::metadata(&path).is_ok() {
+            return Ok(());
+        }
+
+        let contents = Zeroizing::new(std::fs::read_to_string(&path)?);
+        let pairing: Pairing = serde_json::from_str(&contents)?;
+        pairing.store_to_disk()?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// load every paired device, oldest pairing first
+    pub fn load_all_from_disk() -> Result<Vec<Self>, Error> {
+        Self::migrate_legacy_pairing()?;
+
+        let mut pairings = vec![];
+        for entry in std::fs::read_dir(Self::dir_path()?)? {
+            let path = entry?.path();
+            // skips directories and leftover temporary files
+            if path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+            let contents = match std::fs::read(&path) {
+                Ok(contents) => Zeroizing::new(contents),
+                Err(_) => continue,
+            };
+            // a locked keychain or a pairing bound to another machine isn't the same as not being paired
+            let json = Zeroizing::new(keychain::open(&contents)?);
+            let pairing = match serde_json::from_slice::<Pairing>(&json) {
+                Ok(pairing) => pairing,
+                Err(_) => continue,
+            };
+            // pairings from before they were encrypted are sealed the first time they're loaded
+            if !keychain::is_sealed(&contents) && keychain::is_available() {
+                pairing.store_to_disk()?;
+            }
+            pairings.push(pairing);
+        }
+
+        if pairings.is_empty() {
             return Err(Error::NotPaired);
         }
 
-        let contents = std::fs::read_to_string(path)?;
-        Ok(serde_json::from_str(&contents)?)
+        pairings.sort_by_key(|p| p.paired_at);
+        Ok(pairings)
+    }
+
+    /// Whether a pairing is sealed with the key of the keychain
+    pub fn has_sealed_files() -> Result<bool, Error> {
+        Ok(std::fs::read_dir(Self::dir_path()?)?.any(|entry| {
+            entry
+                .ok()
+                .and_then(|entry| std::fs::read(entry.path()).ok())
+                .is_some_and(|contents| keychain::is_sealed(&contents))
+        }))
+    }
+
+    /// a device by its number in `akr devices list` or by its name
+    pub fn find(device: &str) -> Result<Self, Error> {
+        let pairings = Self::load_all_from_disk()?;
+
+        match device.parse::<usize>() {
+            Ok(i) if i >= 1 && i <= pairings.len() => pairings.into_iter().nth(i - 1),
+            _ => pairings.into_iter().find(|p| p.device_name == device),
+        }
+        .ok_or_else(|| Error::UnknownDevice(device.to_string()))
     }
 
     pub fn store_to_disk(&self) -> Result<(), Error> {
-        let path = Self::path()?;
-        std::fs::write(&path, serde_json::to_string_pretty(&self)?)?;
-        Ok(())
+        let path = self.path()?;
+        let contents = Zeroizing::new(serde_json::to_string_pretty(&self)?);
+        // sealed like the identity store, a copy of "~/.akr" on another machine doesn't clone the pairing
+        write_atomically(&path, &keychain::seal(contents.as_bytes())?)
     }
 
-    pub fn delete_pairing_file() -> Result<(), Error> {
-        let path = Self::path()?;
-        std::fs::remove_file(path)?;
+    pub fn delete_from_disk(&self) -> Result<(), Error> {
+        let path = self.path()?;
+        // the pairing keys decrypt everything exchanged with the phone/tablet, don't leave them on disk
+        if path.exists() {
+            crate::util::shred(&path)?;
+        }
         Ok(())
     }
 
@@ -50,15 +140,16 @@ impl Pairing {
     }
 
     pub fn device_public_key(&self) -> Result<PublicKey, Error> {
-        PublicKey::from_slice(&self.device_public_key.0).ok_or(Error::InvalidPairingKeys)
+        let key = self.device_public_key.0.as_slice();
+        key.try_into().map_err(|_| Error::InvalidPairingKeys)
     }
 
     pub fn seal(&self, request: &Request) -> Result<WireMessage, Error> {
-        self.keypair.seal(self.device_public_key()?, request)
+        self.keypair.seal(&self.device_public_key()?, request)
     }
 
     fn open(&self, wire_message: &WireMessage) -> Result<Response, Error> {
-        self.keypair.open(self.device_public_key()?, wire_message)
+        self.keypair.open(&self.device_public_key()?, wire_message)
     }
 
     pub fn find_response(
@@ -71,7 +162,7 @@ impl Pairing {
 
             // special case to handle unpairing
             if let ResponseBody::Unpair(_) = response.body {
-                Pairing::delete_pairing_file()?;
+                self.delete_from_disk()?;
                 return Err(Error::NotPaired);
             }
 
@@ -93,6 +184,9 @@ pub struct PairingQr {
     pub version: String,
     #[serde(flatten)]
     pub os: Os,
+    /// unix time after which akr stops waiting for the code to be scanned
+    #[serde(rename = "e", default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -105,64 +199,53 @@ pub struct Os {
     pub version: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Keypair {
     #[serde(rename = "WorkstationPublicKey")]
     pub public_key: Base64Buffer,
     #[serde(rename = "WorkstationSecretKey")]
-    secret_key: Base64Buffer,
+    secret_key: Secret,
 }
 
-impl From<(PublicKey, SecretKey)> for Keypair {
-    fn from(kp: (PublicKey, SecretKey)) -> Self {
+impl From<(PublicKey, Secret)> for Keypair {
+    fn from((public_key, secret_key): (PublicKey, Secret)) -> Self {
         Self {
-            public_key: kp.0 .0.to_vec().into(),
-            secret_key: kp.1 .0.to_vec().into(),
+            public_key: public_key.to_vec().into(),
+            secret_key,
         }
     }
 }
 
 impl Keypair {
     pub fn queue_uuid(&self) -> Result<Uuid, Error> {
-        let hash_prefix = sodiumoxide::crypto::hash::sha256::hash(self.public_key.0.as_slice()).0;
+        let hash_prefix = crypto::sha256(self.public_key.0.as_slice());
         let uuid = Uuid::from_slice(&hash_prefix[..16])?;
         Ok(uuid)
     }
 
-    fn public_key(&self) -> Result<PublicKey, Error> {
-        PublicKey::from_slice(&self.public_key.0).ok_or(Error::InvalidPairingKeys)
-    }
-
-    fn secret_key(&self) -> Result<SecretKey, Error> {
-        SecretKey::from_slice(&self.secret_key.0).ok_or(Error::InvalidPairingKeys)
+    /// check the keys are well formed and the public key belongs to the secret key
+    pub fn verify(&self) -> Result<(), Error> {
+        match self.public_key.0 == crypto::public_key_of(&self.secret_key)? {
+            true => Ok(()),
+            false => Err(Error::InvalidPairingKeys),
+        }
     }
 
-    fn seal(&self, device_pk: PublicKey, request: &Request) -> Result<WireMessage, Error> {
+    fn seal(&self, device_pk: &PublicKey, request: &Request) -> Result<WireMessage, Error> {
         let message = serde_json::to_vec(&request)?;
-        let nonce = sodiumoxide::crypto::box_::gen_nonce();
-        let ctxt =
-            sodiumoxide::crypto::box_::seal(&message, &nonce, &device_pk, &self.secret_key()?);
-        Ok(WireMessage::SealedMessage(
-            vec![nonce.0.to_vec(), ctxt].concat(),
-        ))
+        Ok(WireMessage::SealedMessage(crypto::seal(
+            &message,
+            device_pk,
+            &self.secret_key,
+        )?))
     }
 
-    fn open(&self, device_pk: PublicKey, wire_message: &WireMessage) -> Result<Response, Error> {
+    fn open(&self, device_pk: &PublicKey, wire_message: &WireMessage) -> Result<Response, Error> {
         let sealed = match wire_message {
             WireMessage::SealedMessage(data) => data.as_slice(),
             _ => return Err(Error::InvalidWireProtocol),
         };
-
-        if sealed.len() < NONCEBYTES {
-            return Err(Error::InvalidCiphertext);
-        }
-        let nonce = sodiumoxide::crypto::box_::Nonce::from_slice(&sealed[0..NONCEBYTES])
-            .ok_or(Error::InvalidCiphertext)?;
-        let ctxt = &sealed[NONCEBYTES..];
-        let plaintext =
-            sodiumoxide::crypto::box_::open(ctxt, &nonce, &device_pk, &self.secret_key()?)
-                .map_err(|_| Error::UnsealFailed)?;
-        Ok(serde_json::from_slice(&plaintext)?)
+        Response::parse(&crypto::open(sealed, device_pk, &self.secret_key)?)
     }
 
     pub fn open_sealed_public_key(
@@ -175,9 +258,7 @@ impl Keypair {
             _ => return Err(Error::InvalidWireProtocol),
         };
 
-        let device_public_key =
-            sodiumoxide::crypto::sealedbox::open(&sealed, &self.public_key()?, &self.secret_key()?)
-                .map_err(|_| Error::UnsealFailed)?;
-        Ok(PublicKey::from_slice(&device_public_key))
+        let device_public_key = crypto::open_sealed(sealed, &self.public_key.0, &self.secret_key)?;
+        Ok(device_public_key.as_slice().try_into().ok())
     }
 }
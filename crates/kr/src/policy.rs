@@ -0,0 +1,448 @@
+//! Approval windows: once a login to a host was approved, the phone/tablet may approve the next ones for a while
+//!
+//! A signature can't be reused, every login needs a fresh one from the phone/tablet. The rules of `akr policy`
+//! go along with each sign request as the "akr_approval_window" extension instead, apps that support it approve
+//! the requests for the same host and user without a prompt until the window closes. The rules live in
+//! "~/.akr/policy.json".
+//!
+//! The file also says what to do with the sign requests that come through a forwarded agent, which ssh tells
+//! with session-bind@openssh.com: sign them, refuse them, or ask the phone/tablet every time, window or not.
+
+use crate::cli::PolicyCommand;
+use crate::error::Error;
+use crate::output;
+use crate::util::write_atomically;
+use ansi_term::Colour::{Green, Yellow};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// key of the `AuthenticateRequest` extension
+pub const EXTENSION: &str = "akr_approval_window";
+const FILE: &str = "policy.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// a host name, where "*" matches any part of it, or the "SHA256:" fingerprint of a host key
+    pub host: String,
+    /// only logins as this user on the host, any user when `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// how long after an approval the next requests don't need one
+    pub minutes: u32,
+}
+
+/// What the agent does with a sign request that was forwarded from another host
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardingAction {
+    Allow,
+    Deny,
+    /// always ask the phone/tablet, even within an approval window
+    FreshApproval,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ForwardingRule {
+    /// the "SHA256:" fingerprint of a key, its rp id, e.g. "ssh:work", or "*" for every key
+    pub key: String,
+    pub action: ForwardingAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwarding: Vec<ForwardingRule>,
+}
+
+impl FromStr for ForwardingAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(ForwardingAction::Allow),
+            "deny" => Ok(ForwardingAction::Deny),
+            "fresh_approval" | "fresh-approval" => Ok(ForwardingAction::FreshApproval),
+            _ => Err(Error::UnknownForwardingAction(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for ForwardingAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardingAction::Allow => write!(f, "allow"),
+            ForwardingAction::Deny => write!(f, "deny"),
+            ForwardingAction::FreshApproval => write!(f, "fresh_approval"),
+        }
+    }
+}
+
+/// Where a sign request is going, as far as the agent can tell, sent as the "akr_target" extension with logins
+#[derive(Serialize, Debug, Default)]
+pub struct Target {
+    /// from the `akr host-context` of ssh, or its command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// the fingerprint of the host key the connection was bound to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_key: Option<String>,
+    /// from the userauth request being signed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// the HostName of the ssh config for `host`, from `akr host-context`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+impl Target {
+    /// key of the `AuthenticateRequest` extension carrying this
+    pub const EXTENSION: &'static str = "akr_target";
+}
+
+/// What the phone/tablet is asked to honor, sent as the `EXTENSION`
+#[derive(Serialize, Debug)]
+pub struct ApprovalWindow {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub seconds: u64,
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
+        }
+        let host = self.host.as_ref().or(self.host_key.as_ref());
+        write!(f, "{}", host.map(String::as_str).unwrap_or("an unknown host"))
+    }
+}
+
+impl Rule {
+    fn matches(&self, target: &Target) -> bool {
+        let host = match self.host.starts_with("SHA256:") {
+            true => target.host_key.as_deref() == Some(self.host.as_str()),
+            false => target.host.as_deref().is_some_and(|host| glob(&self.host, host)),
+        };
+        let user = match &self.user {
+            Some(user) => target.user.as_ref() == Some(user),
+            None => true,
+        };
+        host && user
+    }
+}
+
+impl Policy {
+    fn path() -> Result<PathBuf, Error> {
+        Ok(crate::create_home_path()?.join(FILE))
+    }
+
+    pub fn load() -> Result<Policy, Error> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Policy::default());
+        }
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn store(&self) -> Result<(), Error> {
+        write_atomically(&Self::path()?, &serde_json::to_vec_pretty(self)?)
+    }
+
+    /// the window of the first rule for `target`, a rule of a specific user before one for any user
+    pub fn window(&self, target: &Target) -> Option<ApprovalWindow> {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(target))
+            .max_by_key(|rule| rule.user.is_some())?;
+        Some(ApprovalWindow {
+            host: target.host.clone().or_else(|| target.host_key.clone())?,
+            user: target.user.clone(),
+            seconds: rule.minutes as u64 * 60,
+        })
+    }
+
+    /// what to do with a forwarded request for a key, a rule naming the key before one for "*"
+    pub fn forwarding(&self, fingerprint: &str, rp_id: Option<&str>) -> ForwardingAction {
+        let names_key = |rule: &&ForwardingRule| rule.key == fingerprint || Some(rule.key.as_str()) == rp_id;
+        self.forwarding
+            .iter()
+            .find(names_key)
+            .or_else(|| self.forwarding.iter().find(|rule| rule.key == "*"))
+            .map(|rule| rule.action)
+            .unwrap_or(ForwardingAction::Allow)
+    }
+}
+
+/// Whether the `denied_hosts` and `allowed_hosts` of the config file and the team policy let the agent sign a
+/// login to `target`, what refused it otherwise. Once there is an allow-list, logins to unknown hosts are refused
+pub fn check_destination(target: &Target) -> Result<(), String> {
+    match crate::team_policy::current() {
+        Ok(Some(team_policy)) if !team_policy.allows_destination(target) => {
+            return Err("the allowed_hosts of the team policy".to_string());
+        }
+        Err(e) => return Err(e.to_string()),
+        _ => {}
+    }
+
+    let config = crate::config::get();
+    let denied = config.denied_hosts.iter().flatten();
+    if let Some(pattern) = denied.into_iter().find(|pattern| denies(pattern, target)) {
+        return Err(format!("denied_hosts {}", pattern));
+    }
+    match &config.allowed_hosts {
+        Some(allowed) if !allowed.iter().any(|pattern| allows(pattern, target)) => {
+            Err("allowed_hosts".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether a denied pattern refuses `target`: the host as given to ssh or the HostName it resolves to, an
+/// alias doesn't get around a pattern of the real name
+pub fn denies(pattern: &str, target: &Target) -> bool {
+    destination_matches(pattern, target, target.host.as_deref())
+        || destination_matches(pattern, target, target.hostname.as_deref())
+}
+
+/// Whether an allowed pattern lets a login to `target` through: the HostName ssh connects to once `akr
+/// host-context` resolved it, an alias can point anywhere. The host as given otherwise
+pub fn allows(pattern: &str, target: &Target) -> bool {
+    destination_matches(
+        pattern,
+        target,
+        target.hostname.as_deref().or(target.host.as_deref()),
+    )
+}
+
+/// a "[user@]host" pattern, where "*" matches any part of either, against `host`, or the fingerprint of a host key
+fn destination_matches(pattern: &str, target: &Target, host: Option<&str>) -> bool {
+    if pattern.starts_with("SHA256:") {
+        return target.host_key.as_deref() == Some(pattern);
+    }
+    let (user, host_pattern) = match pattern.rsplit_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, pattern),
+    };
+    let user_matches = user.is_none_or(|user| target.user.as_deref().is_some_and(|name| glob(user, name)));
+    user_matches && host.is_some_and(|name| glob(host_pattern, name))
+}
+
+/// case insensitive, "*" matches any run of characters
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts = parts.collect::<Vec<_>>();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// The `[user@]host` an ssh command line connects to, e.g. "git@github.com" from git's
+/// `ssh -o SendEnv=GIT_PROTOCOL git@github.com git-upload-pack 'repo'`
+pub fn ssh_destination(command: &str) -> Option<(Option<String>, String)> {
+    // the options of ssh that take an argument
+    const WITH_ARGUMENT: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+    let mut args = command.split_whitespace();
+    let program = args.next()?;
+    let name = program.rsplit(['/', '\\']).next()?;
+    if name != "ssh" && name != "ssh.exe" {
+        return None;
+    }
+
+    let mut login = None;
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix('-') {
+            Some(flags) if !flags.is_empty() => {
+                // the argument is either the rest of this one or the next one, e.g. "-p22" or "-p 22"
+                if let Some(at) = flags.find(|c| WITH_ARGUMENT.contains(c)) {
+                    let value = match &flags[at + 1..] {
+                        "" => args.next()?,
+                        value => value,
+                    };
+                    if flags[at..].starts_with('l') {
+                        login = Some(value.to_string());
+                    }
+                }
+            }
+            _ => {
+                let destination = arg.strip_prefix("ssh://").unwrap_or(arg);
+                let (user, host) = match destination.rsplit_once('@') {
+                    Some((user, host)) => (Some(user.to_string()), host),
+                    None => (login, destination),
+                };
+                // ssh://host:port
+                let host = match arg.starts_with("ssh://") {
+                    true => host.split(':').next().unwrap_or(host),
+                    false => host,
+                };
+                return Some((user, host.to_string()));
+            }
+        }
+    }
+    None
+}
+
+/// `akr policy`
+pub fn run(command: PolicyCommand) -> Result<(), Error> {
+    let mut policy = Policy::load()?;
+    match command {
+        PolicyCommand::List => {
+            if output::is_json() {
+                return output::print_json(&policy);
+            }
+            if policy.rules.is_empty() {
+                println!("No rules, every request needs an approval on your phone/tablet");
+            }
+            for rule in &policy.rules {
+                println!(
+                    "{}{}: {} minutes",
+                    rule.user
+                        .as_ref()
+                        .map(|user| format!("{}@", user))
+                        .unwrap_or_default(),
+                    rule.host,
+                    rule.minutes
+                );
+            }
+            for rule in &policy.forwarding {
+                println!("forwarded requests for {}: {}", rule.key, rule.action);
+            }
+        }
+        PolicyCommand::Add { host, user, minutes } => {
+            let rule = Rule { host, user, minutes };
+            policy
+                .rules
+                .retain(|old| old.host != rule.host || old.user != rule.user);
+            policy.rules.push(rule.clone());
+            policy.store()?;
+            if output::is_json() {
+                return output::print_json(&rule);
+            }
+            println!(
+                "{} {}{}, {} minutes after each approval",
+                Green.paint("Added an approval window for"),
+                rule.user
+                    .as_ref()
+                    .map(|user| format!("{}@", user))
+                    .unwrap_or_default(),
+                rule.host,
+                rule.minutes
+            );
+            println!(
+                "{}",
+                Yellow.paint("Only phones/tablets whose app supports approval windows honor it")
+            );
+        }
+        PolicyCommand::Remove { host, user } => {
+            let count = policy.rules.len();
+            policy
+                .rules
+                .retain(|rule| rule.host != host || (user.is_some() && rule.user != user));
+            if policy.rules.len() == count {
+                return Err(Error::UnknownPolicyRule(host));
+            }
+            policy.store()?;
+            if output::is_json() {
+                return output::print_json(&serde_json::json!({ "removed": count - policy.rules.len() }));
+            }
+            println!("{} for {}", Green.paint("Removed the rules"), host);
+        }
+        PolicyCommand::Forwarding { key, action } => {
+            policy.forwarding.retain(|rule| rule.key != key);
+            // signing forwarded requests is what happens without a rule
+            if action != ForwardingAction::Allow {
+                policy.forwarding.push(ForwardingRule {
+                    key: key.clone(),
+                    action,
+                });
+            }
+            policy.store()?;
+            if output::is_json() {
+                return output::print_json(&policy.forwarding);
+            }
+            let done = match action {
+                ForwardingAction::Allow => "signed like any other",
+                ForwardingAction::Deny => "refused",
+                ForwardingAction::FreshApproval => "always approved on your phone/tablet",
+            };
+            println!("{} for {} are {}", Green.paint("Forwarded requests"), key, done);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str, hostname: Option<&str>) -> Target {
+        Target {
+            host: Some(host.to_string()),
+            user: Some("git".to_string()),
+            hostname: hostname.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn denied_by_host_or_hostname() {
+        let aliased = target("work", Some("prod.example.com"));
+        assert!(denies("*.example.com", &aliased));
+        assert!(denies("work", &aliased));
+        assert!(denies("git@prod.*", &aliased));
+        assert!(!denies("root@*", &aliased));
+        assert!(!denies("*.example.org", &aliased));
+    }
+
+    #[test]
+    fn allowed_by_hostname_only() {
+        let aliased = target("work.corp.example.com", Some("evil.example.org"));
+        assert!(!allows("*.corp.example.com", &aliased));
+        assert!(allows("*.example.org", &aliased));
+        // without `akr host-context` there is only the host
+        assert!(allows(
+            "*.corp.example.com",
+            &target("git.corp.example.com", None)
+        ));
+    }
+
+    #[test]
+    fn host_key_fingerprints() {
+        let target = Target {
+            host_key: Some("SHA256:abc".to_string()),
+            ..Default::default()
+        };
+        assert!(denies("SHA256:abc", &target));
+        assert!(allows("SHA256:abc", &target));
+        assert!(!allows("SHA256:abd", &target));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("*.Example.com", "git.example.COM"));
+        assert!(glob("a*b*c", "aXbYc"));
+        assert!(!glob("a*b*c", "aXbYcd"));
+        assert!(glob("*", ""));
+        assert!(!glob("example.com", "example.com.evil"));
+    }
+}
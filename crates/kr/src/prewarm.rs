@@ -0,0 +1,86 @@
+//! `akr prewarm <host>` has the phone/tablet open the approval of a login before ssh asks the agent for the
+//! signature, so the screen is up by the time the sign request lands. It's meant for a `Match exec` of the
+//! ssh config, which ssh runs before it connects:
+//!
+//! ```text
+//! Match host prod-* exec "akr prewarm %n --user %r"
+//! ```
+//!
+//! The request goes out from a process of its own, ssh doesn't wait for it, and the command always succeeds
+//! so the `Match` holds. ssh may read its config twice for a connection, the same login is prewarmed once.
+
+use crate::cli::PrewarmArgs;
+use crate::client::Client;
+use crate::error::Error;
+use crate::protocol::{Capabilities, PrewarmRequest, RequestBody};
+use crate::{profile, util};
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::time::Duration;
+
+const FILE: &str = "prewarm.json";
+/// the same login within this long is the same connection
+const DEDUPE_SECS: i64 = 10;
+/// how long the request may take to go out
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The login prewarmed last
+#[derive(Serialize, Deserialize)]
+struct Prewarmed {
+    host: String,
+    user: Option<String>,
+    at: i64,
+}
+
+/// `akr prewarm`, run by ssh. Quiet, ssh would show whatever it prints with every connection
+pub async fn run(args: PrewarmArgs) {
+    let result = match args.wait {
+        true => send(args).await,
+        false => spawn(args),
+    };
+    if let Err(e) = result {
+        tracing::debug!("couldn't prewarm the approval: {}", e);
+    }
+}
+
+/// Start `akr prewarm --wait` in the background, unless the login was just prewarmed
+fn spawn(args: PrewarmArgs) -> Result<(), Error> {
+    let path = crate::create_home_path()?.join(FILE);
+    let now = chrono::Utc::now().timestamp();
+    let last = std::fs::read(&path)
+        .ok()
+        .and_then(|contents| serde_json::from_slice::<Prewarmed>(&contents).ok());
+    let same = |last: &Prewarmed| last.host == args.host && last.user == args.user;
+    if last.is_some_and(|last| same(&last) && now - last.at < DEDUPE_SECS) {
+        return Ok(());
+    }
+    let prewarmed = Prewarmed {
+        host: args.host.clone(),
+        user: args.user.clone(),
+        at: now,
+    };
+    util::write_atomically(&path, &serde_json::to_vec(&prewarmed)?)?;
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(["--profile", profile::current(), "prewarm", "--wait", &args.host])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    if let Some(user) = &args.user {
+        command.args(["--user", user]);
+    }
+    command.spawn()?;
+    Ok(())
+}
+
+async fn send(args: PrewarmArgs) -> Result<(), Error> {
+    let client = Client::new()?;
+    let request = RequestBody::Prewarm(PrewarmRequest {
+        host: args.host,
+        user: args.user,
+    });
+    tokio::time::timeout(SEND_TIMEOUT, client.notify(request, Capabilities::PREWARM))
+        .await
+        .map_err(|_| Error::ResponseTimedOut)?
+}
@@ -0,0 +1,161 @@
+//! Named profiles, e.g. "work" and "personal", each with its own pairings, keys and agent socket
+//!
+//! The default profile lives in "~/.akr" as it always did, the others in "~/.akr/profiles/<name>". The
+//! profile is `--profile` if given, otherwise `AKR_PROFILE` or the one picked with `akr profile switch`.
+
+use crate::config::Config;
+use crate::error::Error;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+pub const DEFAULT: &str = "default";
+pub const PROFILES_DIR: &str = "profiles";
+const MAX_NAME_LEN: usize = 64;
+
+static PROFILE: OnceLock<String> = OnceLock::new();
+/// where the profiles live instead of "~/.akr", see `use_root`
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use the profile given with `--profile`, if any
+pub fn select(name: Option<String>) -> Result<(), Error> {
+    if let Some(name) = name {
+        validate(&name)?;
+        let _ = PROFILE.set(name);
+    }
+    Ok(())
+}
+
+pub fn current() -> &'static str {
+    PROFILE.get_or_init(|| {
+        std::env::var("AKR_PROFILE")
+            .ok()
+            .filter(|name| !name.is_empty())
+            .or_else(|| Config::read_file().ok()?.profile)
+            .unwrap_or_else(|| DEFAULT.to_string())
+    })
+}
+
+pub fn is_default(name: &str) -> bool {
+    name == DEFAULT
+}
+
+/// names end up in paths, keep them to a single plain component
+fn validate(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidProfileName(name.to_string())),
+    }
+}
+
+/// Keep every profile in `dir` instead of "~/.akr", `akr replay` runs in a temporary one
+pub fn use_root(dir: PathBuf) {
+    let _ = ROOT.set(dir);
+}
+
+/// For tests: a temporary root of the default profile, without the keychain, the same for all of them
+#[cfg(test)]
+pub fn use_temporary_home() {
+    static HOME: OnceLock<tempfile::TempDir> = OnceLock::new();
+    HOME.get_or_init(|| {
+        let home = tempfile::tempdir().unwrap();
+        use_root(home.path().to_path_buf());
+        select(Some(DEFAULT.to_string())).unwrap();
+        crate::keychain::configure(true);
+        home
+    });
+}
+
+fn root() -> Result<PathBuf, Error> {
+    if let Some(root) = ROOT.get() {
+        return Ok(root.clone());
+    }
+    let dirs = directories::UserDirs::new().ok_or(Error::CannotCreateHomeDir)?;
+    Ok(dirs.home_dir().join(crate::HOME_DIR))
+}
+
+/// "~/.akr" for the default profile, "~/.akr/profiles/<name>" for the others
+pub fn dir(name: &str) -> Result<PathBuf, Error> {
+    validate(name)?;
+    match is_default(name) {
+        true => root(),
+        false => Ok(root()?.join(PROFILES_DIR).join(name)),
+    }
+}
+
+pub fn exists(name: &str) -> Result<bool, Error> {
+    Ok(is_default(name) || dir(name)?.is_dir())
+}
+
+/// the default profile followed by the others by name
+pub fn list() -> Result<Vec<String>, Error> {
+    let mut names = vec![];
+    if let Ok(entries) = std::fs::read_dir(root()?.join(PROFILES_DIR)) {
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() && validate(&name).is_ok() && !is_default(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    names.insert(0, DEFAULT.to_string());
+    Ok(names)
+}
+
+pub fn create(name: &str) -> Result<PathBuf, Error> {
+    if exists(name)? {
+        return Err(Error::ProfileExists(name.to_string()));
+    }
+    let dir = dir(name)?;
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Make `name` the profile used without `--profile`
+pub fn switch(name: &str) -> Result<(), Error> {
+    if !exists(name)? {
+        return Err(Error::UnknownProfile(name.to_string()));
+    }
+    match is_default(name) {
+        true => crate::config::unset_value("profile"),
+        false => crate::config::set_value("profile", name),
+    }
+}
+
+/// Run an agent for every profile, each on its own socket, as `akr --profile <name> <args>`
+/// with the arguments of `akr start --all-profiles` minus the profile ones
+pub async fn start_all() -> Result<(), Error> {
+    let mut args = vec![];
+    let mut given = std::env::args().skip(1);
+    while let Some(arg) = given.next() {
+        match arg.as_str() {
+            "--all-profiles" => {}
+            "--profile" => {
+                given.next();
+            }
+            _ if arg.starts_with("--profile=") => {}
+            _ => args.push(arg),
+        }
+    }
+
+    let exe = std::env::current_exe()?;
+    let mut agents = vec![];
+    for name in list()? {
+        let mut command = tokio::process::Command::new(&exe);
+        command.arg("--profile").arg(&name).args(&args);
+        agents.push(async move {
+            match command.status().await {
+                Ok(status) => eprintln!("the agent of profile {} exited with {}", name, status),
+                Err(e) => eprintln!("couldn't start the agent of profile {}: {}", name, e),
+            }
+        });
+    }
+    futures::future::join_all(agents).await;
+    Ok(())
+}
@@ -2,77 +2,151 @@
 use std::cmp;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use zeroize::Zeroizing;
+
+#[cfg(target_os = "macos")]
+const PINENTRY: &str = "pinentry-mac";
+#[cfg(not(target_os = "macos"))]
+const PINENTRY: &str = "pinentry";
 
 pub struct PasswordPrompt {
     key_name: String,
+    description: Option<String>,
+    title: Option<String>,
+    prompt: Option<String>,
 }
 
 impl PasswordPrompt {
     pub fn new(key_name: String) -> Self {
-        PasswordPrompt { key_name: key_name }
+        PasswordPrompt {
+            key_name: key_name,
+            description: None,
+            title: None,
+            prompt: None,
+        }
     }
 
-    /// Invokes the password prompt and puts the entered password into `password_buffer`.
-    ///
-    /// Returns the number of bytes input into the buffer.
-    pub fn invoke(&self, password_buffer: &mut [u8]) -> usize {
-        #[cfg(target_os = "macos")]
-        let commmand_str = "pinentry-mac";
+    /// Ask for something else than the password of an SSH key, e.g. "GitHub token" and "Token:"
+    pub fn with_title(mut self, title: String, prompt: String) -> Self {
+        self.title = Some(title);
+        self.prompt = Some(prompt);
+        self
+    }
 
-        #[cfg(target_os = "linux")]
-        let commmand_str = "pinentry";
+    /// Override the default "unlock" description shown in the dialog
+    pub fn with_description(mut self, description: String) -> Self {
+        self.description = Some(description);
+        self
+    }
 
-        let mut pinentry = Command::new(commmand_str)
+    /// Invokes the password prompt and puts the entered password into `password_buffer`.
+    ///
+    /// Returns the number of bytes input into the buffer, an error when pinentry couldn't be started.
+    pub fn invoke(&self, password_buffer: &mut [u8]) -> std::io::Result<usize> {
+        let mut pinentry = Command::new(PINENTRY)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .spawn()
-            .expect("pinentry command failed to start");
+            .spawn()?;
+
+        let len = self.get_pin(&mut pinentry, password_buffer);
+        // reap it, the agent runs for long
+        let _ = pinentry.kill();
+        pinentry.wait()?;
+        len
+    }
+
+    fn get_pin(&self, pinentry: &mut Child, password_buffer: &mut [u8]) -> std::io::Result<usize> {
+        let (Some(mut pincmd), Some(message)) = (pinentry.stdin.take(), pinentry.stdout.take()) else {
+            return Ok(0);
+        };
 
         // Configure pinentry
-        let pincmd = pinentry.stdin.take();
+        let title = self.title.as_deref().unwrap_or("Unlock SSH key");
+        let prompt = self.prompt.as_deref().unwrap_or("Password:");
+        writeln!(pincmd, "SETTITLE {}", title)?;
+        writeln!(pincmd, "SETPROMPT {}", prompt)?;
+        match &self.description {
+            Some(description) => writeln!(pincmd, "SETDESC {}", description),
+            None => writeln!(
+                pincmd,
+                "SETDESC Enter the password for unlocking the SSH key '{}'",
+                self.key_name
+            ),
+        }?;
+        writeln!(pincmd, "GETPIN")?;
 
-        match pincmd {
-            Some(mut pincmd) => {
-                writeln!(pincmd, "SETTITLE Unlock SSH key").expect("failed to write to pinentry");
-                writeln!(pincmd, "SETPROMPT Password:").expect("failed to write to pinentry");
-                writeln!(
-                    pincmd,
-                    "SETDESC Enter the password for unlocking the SSH key '{}'",
-                    self.key_name
-                )
-                .expect("failed to write to pinentry");
-                writeln!(pincmd, "GETPIN").expect("failed to write to pinentry");
+        // Read until we get an "ERR" or "D" line
+        let out = BufReader::new(message);
+        for line in out.lines() {
+            let line = Zeroizing::new(line?);
+            if line.starts_with("ERR ") {
+                return Ok(0); // Abort!
+            } else if line.starts_with("D ") {
+                let bytes = &line.as_bytes()[2..];
+                for (byte, target) in bytes.iter().zip(password_buffer.iter_mut()) {
+                    *target = *byte;
+                }
+                return Ok(cmp::min(bytes.len(), password_buffer.len()));
+            }
+        }
+        Ok(0)
+    }
+}
 
-                let message = pinentry.stdout.take();
+/// GUI confirmation using pinentry's CONFIRM, an OK/Cancel dialog
+pub struct ConfirmPrompt {
+    description: String,
+}
 
-                match message {
-                    Some(message) => {
-                        // Read until we get an "ERR" or "D" line
-                        let out = BufReader::new(message);
-                        for line in out.lines() {
-                            let line = line.expect("failed to read line from pinentry");
-                            if line.starts_with("ERR ") {
-                                pinentry.kill().expect("failed to kill pinentry");
-                                return 0; // Abort!
-                            } else if line.starts_with("D ") {
-                                let bytes = &line.as_bytes()[2..];
-                                for (byte, target) in bytes.iter().zip(password_buffer.iter_mut()) {
-                                    *target = *byte;
-                                }
+impl ConfirmPrompt {
+    pub fn new(description: String) -> Self {
+        ConfirmPrompt { description }
+    }
 
-                                pinentry.kill().expect("failed to kill pinentry");
-                                return cmp::min(bytes.len(), password_buffer.len());
-                            }
-                        }
-                    }
-                    None => return 0,
-                }
+    /// Whether the user clicked OK, false when pinentry couldn't be started either
+    pub fn invoke(&self) -> bool {
+        let mut pinentry = match Command::new(PINENTRY)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(pinentry) => pinentry,
+            Err(e) => {
+                eprintln!("couldn't start {}: {}", PINENTRY, e);
+                return false;
             }
-            None => return 0,
+        };
+        let (Some(mut pincmd), Some(message)) = (pinentry.stdin.take(), pinentry.stdout.take()) else {
+            return false;
+        };
+
+        // assuan escapes line breaks and percent signs
+        let description = self.description.replace('%', "%25").replace('\n', "%0A");
+        let written = writeln!(pincmd, "SETTITLE Confirm sign request")
+            .and_then(|_| writeln!(pincmd, "SETDESC {}", description))
+            .and_then(|_| writeln!(pincmd, "CONFIRM"));
+        if written.is_err() {
+            let _ = pinentry.kill();
+            let _ = pinentry.wait();
+            return false;
         }
 
-        pinentry.kill().expect("failed to kill pinentry");
-        return 0;
+        // the greeting, SETTITLE and SETDESC are answered with OK too, CONFIRM with the fourth one
+        let mut oks = 0;
+        for line in BufReader::new(message).lines() {
+            match line {
+                Ok(line) if line.starts_with("OK") => oks += 1,
+                Ok(line) if line.starts_with("ERR") => break,
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+            if oks == 4 {
+                break;
+            }
+        }
+        let _ = pinentry.kill();
+        let _ = pinentry.wait();
+        oks == 4
     }
 }
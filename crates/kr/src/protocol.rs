@@ -1,10 +1,13 @@
+use crate::crypto;
 use crate::error::Error;
 use base64::Engine;
 use base64_serde::base64_serde_type;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, convert::TryFrom};
+use std::{collections::BTreeMap, convert::TryFrom, time::Duration};
 
 pub const PROTOCOL_VERSION: &'static str = "3.0.0";
+/// how long a pairing QR code stays valid, the phone rejects it after that too
+pub const PAIRING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
 base64_serde_type!(Base64Format, base64::prelude::BASE64_STANDARD);
 
@@ -46,7 +49,7 @@ pub struct Request {
 impl Request {
     pub fn new(body: RequestBody) -> Self {
         Self {
-            id: base64::engine::general_purpose::STANDARD.encode(sodiumoxide::randombytes::randombytes(32)),
+            id: base64::engine::general_purpose::STANDARD.encode(crypto::random_bytes(32)),
             send_ack: false,
             unix_seconds: chrono::Utc::now().timestamp(),
             version: PROTOCOL_VERSION.to_string(),
@@ -69,11 +72,126 @@ pub enum RequestBody {
 
     #[serde(rename = "unpair_request")]
     Unpair(UnpairRequest),
+
+    #[serde(rename = "list_keys_request")]
+    ListKeys(ListKeysRequest),
+
+    #[serde(rename = "rotate_pairing_keys_request")]
+    RotatePairingKeys(RotatePairingKeysRequest),
+
+    #[serde(rename = "ping_request")]
+    Ping(PingRequest),
+
+    #[serde(rename = "delete_key_request")]
+    DeleteKey(DeleteKeyRequest),
+
+    #[serde(rename = "prewarm_request")]
+    Prewarm(PrewarmRequest),
+
+    #[serde(rename = "test_request")]
+    Test(TestRequest),
+}
+
+impl RequestBody {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestBody::Id(_) => "me",
+            RequestBody::Register(_) => "register",
+            RequestBody::Authenticate(_) => "authenticate",
+            RequestBody::Unpair(_) => "unpair",
+            RequestBody::ListKeys(_) => "list keys",
+            RequestBody::RotatePairingKeys(_) => "rotate pairing keys",
+            RequestBody::Ping(_) => "ping",
+            RequestBody::DeleteKey(_) => "delete key",
+            RequestBody::Prewarm(_) => "prewarm",
+            RequestBody::Test(_) => "test",
+        }
+    }
+
+    /// Whether the request can wait for the network to come back, see `akr flush-queue`.
+    /// Anything someone is waiting on to approve or use right away can't
+    pub fn is_queueable(&self) -> bool {
+        match self {
+            RequestBody::Unpair(_) | RequestBody::ListKeys(_) | RequestBody::DeleteKey(_) => true,
+            RequestBody::Id(_)
+            | RequestBody::Register(_)
+            | RequestBody::Authenticate(_)
+            | RequestBody::RotatePairingKeys(_)
+            | RequestBody::Ping(_)
+            | RequestBody::Prewarm(_)
+            | RequestBody::Test(_) => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IdRequest {
     pub send_sk_accounts: bool,
+    /// what this akr speaks, the app answers with what it speaks in `IdData`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+}
+
+impl IdRequest {
+    pub fn new(send_sk_accounts: bool) -> Self {
+        IdRequest {
+            send_sk_accounts,
+            capabilities: Some(Capabilities::ours()),
+        }
+    }
+}
+
+/// The protocol version and features one side speaks, exchanged with the `IdRequest` at pairing and `akr load`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub version: String,
+    /// the features beyond those of protocol 3.0.0, unknown for apps from before the handshake, which are
+    /// sent everything like before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+}
+
+impl Capabilities {
+    /// deriving secrets from a credential
+    pub const HMAC_SECRET: &'static str = "hmac-secret";
+    /// the `DeleteKeyRequest`
+    pub const DELETE_KEY: &'static str = "delete_key";
+    /// the "akr_" extensions of sign requests: approval windows, who asks, the login, forwarding
+    pub const AKR_EXTENSIONS: &'static str = "akr_extensions";
+    /// the `PrewarmRequest`
+    pub const PREWARM: &'static str = "prewarm";
+    /// the `TestRequest`
+    pub const TEST: &'static str = "test";
+
+    pub fn ours() -> Self {
+        Capabilities {
+            version: PROTOCOL_VERSION.to_string(),
+            features: Some(
+                [
+                    Self::HMAC_SECRET,
+                    Self::DELETE_KEY,
+                    Self::AKR_EXTENSIONS,
+                    Self::PREWARM,
+                    Self::TEST,
+                ]
+                .map(String::from)
+                .to_vec(),
+            ),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features
+            .as_ref()
+            .is_none_or(|features| features.iter().any(|f| f == feature))
+    }
+}
+
+/// "3.0.0" as (3, 0, 0), missing parts are 0
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version.split('.').map(str::parse::<u32>);
+    let mut next = || parts.next().unwrap_or(Ok(0));
+    Some((next().ok()?, next().ok()?, next().ok()?))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,6 +203,14 @@ pub struct RegisterRequest {
     pub user: Option<UserData>,
     #[serde(rename = "webauthn")]
     pub is_webauthn: bool,
+    /// a discoverable credential, which `ssh-keygen -K` can download from the authenticator
+    #[serde(default)]
+    pub resident_key: bool,
+    /// verify the user, e.g. with a fingerprint, every time the credential is used
+    #[serde(default)]
+    pub user_verification_required: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Extensions>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -98,14 +224,144 @@ pub struct AuthenticateRequest {
     pub challenge: Base64Buffer,
     #[serde(rename = "app_id")]
     pub rp_id: String,
-    pub extensions: Option<BTreeMap<String, serde_json::Value>>,
+    pub extensions: Option<Extensions>,
     pub key_handle: Option<Base64Buffer>,
     pub key_handles: Option<Vec<Base64Buffer>>,
 }
 
+/// The extensions of a register or authenticate request: the FIDO2 ones, named as in CTAP 2.1, and those of
+/// akr, starting with "akr_", which stay untyped
+/// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#sctn-defined-extensions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Extensions {
+    #[serde(rename = "hmac-secret", default, skip_serializing_if = "Option::is_none")]
+    pub hmac_secret: Option<HmacSecret>,
+    #[serde(rename = "credProtect", default, skip_serializing_if = "Option::is_none")]
+    pub cred_protect: Option<CredProtect>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, serde_json::Value>,
+}
+
+impl Extensions {
+    pub fn is_empty(&self) -> bool {
+        self.hmac_secret.is_none() && self.cred_protect.is_none() && self.other.is_empty()
+    }
+
+    pub fn insert(&mut self, name: &str, value: serde_json::Value) {
+        self.other.insert(name.to_string(), value);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HmacSecret {
+    /// when registering, whether the credential should be able to derive secrets
+    Enable(bool),
+    /// when authenticating, the one or two 32 byte salts to derive the secrets from
+    Salts {
+        salt1: Base64Buffer,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        salt2: Option<Base64Buffer>,
+    },
+}
+
+/// When the authenticator uses a credential without verifying the user, the policies of credProtect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CredProtect {
+    #[serde(rename = "userVerificationOptional")]
+    Optional,
+    /// only when the credential id is given, it isn't discoverable otherwise
+    #[serde(rename = "userVerificationOptionalWithCredentialIDList")]
+    OptionalWithCredentialIdList,
+    #[serde(rename = "userVerificationRequired")]
+    Required,
+}
+
+impl CredProtect {
+    /// the credProtect value of CTAP
+    pub fn level(self) -> i128 {
+        match self {
+            CredProtect::Optional => 1,
+            CredProtect::OptionalWithCredentialIdList => 2,
+            CredProtect::Required => 3,
+        }
+    }
+
+    pub fn from_level(level: i128) -> Option<Self> {
+        match level {
+            1 => Some(CredProtect::Optional),
+            2 => Some(CredProtect::OptionalWithCredentialIdList),
+            3 => Some(CredProtect::Required),
+            _ => None,
+        }
+    }
+}
+
+/// What the authenticator answered to the `Extensions` of a request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtensionOutputs {
+    #[serde(rename = "hmac-secret", default, skip_serializing_if = "Option::is_none")]
+    pub hmac_secret: Option<HmacSecretOutput>,
+    #[serde(rename = "credProtect", default, skip_serializing_if = "Option::is_none")]
+    pub cred_protect: Option<CredProtect>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HmacSecretOutput {
+    /// when registering, whether the credential can derive secrets
+    Enabled(bool),
+    /// when authenticating, the secrets derived from `salt1` and `salt2` with the credential
+    Secrets {
+        output1: Base64Buffer,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        output2: Option<Base64Buffer>,
+    },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnpairRequest {}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListKeysRequest {}
+
+/// Check that the device is reachable, answered right away without asking the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingRequest {}
+
+/// Delete a credential, answered the same whether the device had it or not
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteKeyRequest {
+    pub key_handle: Base64Buffer,
+    #[serde(rename = "app_id")]
+    pub rp_id: String,
+}
+
+/// Open the approval of a login to `host` ahead of its sign request, while ssh is still connecting.
+/// Not answered, the sign request that follows is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrewarmRequest {
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// A prompt for `akr test` to show and have answered, which approves nothing. It checks a request gets to
+/// the device and the user sees it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestRequest {
+    /// the machine asking, for the prompt
+    pub host: String,
+}
+
+/// Replace the keys of a pairing, the device answers with its own new public key
+/// and drops the old keys once it gets a request sealed with the new ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotatePairingKeysRequest {
+    /// the new workstation public key, which also determines the new queue
+    pub public_key: Base64Buffer,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Response {
     pub request_id: String,
@@ -120,6 +376,32 @@ pub struct Response {
     pub body: ResponseBody,
 }
 
+impl Response {
+    /// A response that doesn't parse from an app speaking another major version says which to update
+    pub fn parse(contents: &[u8]) -> Result<Self, Error> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            #[serde(rename = "v")]
+            version: String,
+        }
+
+        serde_json::from_slice(contents).map_err(|e| {
+            let Ok(Versioned { version }) = serde_json::from_slice(contents) else {
+                return e.into();
+            };
+            match (parse_version(&version), parse_version(PROTOCOL_VERSION)) {
+                (Some(theirs), Some(ours)) if theirs.0 > ours.0 => {
+                    Error::ProtocolMismatch(version, PROTOCOL_VERSION, "akr")
+                }
+                (Some(theirs), Some(ours)) if theirs.0 < ours.0 => {
+                    Error::ProtocolMismatch(version, PROTOCOL_VERSION, "the Akamai MFA app")
+                }
+                _ => Error::UnexpectedResponseFormat(version, e.to_string()),
+            }
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct ClientResult<T> {
@@ -128,6 +410,15 @@ pub struct ClientResult<T> {
     error: Option<String>,
 }
 
+impl<T> ClientResult<T> {
+    pub fn ok(contents: T) -> Self {
+        Self {
+            contents: Some(contents),
+            error: None,
+        }
+    }
+}
+
 impl<T> Into<Result<T, Error>> for ClientResult<T> {
     fn into(self) -> Result<T, Error> {
         match (self.contents, self.error) {
@@ -152,6 +443,21 @@ pub enum ResponseBody {
 
     #[serde(rename = "unpair_response")]
     Unpair(ClientResult<UnpairResponse>),
+
+    #[serde(rename = "list_keys_response")]
+    ListKeys(ClientResult<ListKeysResponse>),
+
+    #[serde(rename = "rotate_pairing_keys_response")]
+    RotatePairingKeys(ClientResult<RotatePairingKeysResponse>),
+
+    #[serde(rename = "ping_response")]
+    Ping(ClientResult<PingResponse>),
+
+    #[serde(rename = "delete_key_response")]
+    DeleteKey(ClientResult<DeleteKeyResponse>),
+
+    #[serde(rename = "test_response")]
+    Test(ClientResult<TestResponse>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -167,6 +473,12 @@ pub struct IdData {
     pub device_identifier: Base64Buffer,
     /// security key accounts (keyhandle + public key)
     pub sk_accounts: Option<Vec<SkAccount>>,
+    /// the GATT service the device advertises, if it can be reached over bluetooth LE
+    #[serde(default)]
+    pub bluetooth_service_uuid: Option<uuid::Uuid>,
+    /// missing from apps from before the handshake
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -181,7 +493,20 @@ pub struct SkAccount {
 pub struct RegisterResponse {
     pub public_key: Base64Buffer,
     pub key_handle: Base64Buffer,
+    /// the authenticator data, with the attested credential data of the new key
     pub attestation_data: Option<Base64Buffer>,
+    /// the attestation statement format, e.g. "packed", missing from apps that don't attest
+    #[serde(default, rename = "attestation_fmt")]
+    pub attestation_format: Option<String>,
+    /// over `attestation_data || challenge`
+    #[serde(default)]
+    pub attestation_signature: Option<Base64Buffer>,
+    /// DER encoded, the attestation certificate first
+    #[serde(default)]
+    pub attestation_certificates: Option<Vec<Base64Buffer>>,
+    /// missing from apps that don't support any
+    #[serde(default)]
+    pub extensions: Option<ExtensionOutputs>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -200,9 +525,16 @@ pub struct AuthenticateResponse {
     /// https://www.w3.org/TR/webauthn-2/#sctn-authenticator-data
     #[serde(rename = "auth_data")]
     pub authenticator_data: Base64Buffer,
+    #[serde(default)]
+    pub extensions: Option<ExtensionOutputs>,
 }
 
 impl AuthenticateResponse {
+    /// user present
+    pub const AUTH_FLAG_UP: u8 = 0x01;
+    /// user verified
+    pub const AUTH_FLAG_UV: u8 = 0x04;
+
     pub fn get_auth_flags(&self) -> Result<u8, Error> {
         if self.authenticator_data.0.len() < 33 {
             return Err(Error::BadAuthenticatorData);
@@ -210,11 +542,53 @@ impl AuthenticateResponse {
 
         Ok(self.authenticator_data.0[32])
     }
+
+    /// the counter covered by the signature, as opposed to the `counter` field
+    pub fn get_sign_counter(&self) -> Result<u32, Error> {
+        if self.authenticator_data.0.len() < 37 {
+            return Err(Error::BadAuthenticatorData);
+        }
+
+        let mut counter = [0u8; 4];
+        counter.copy_from_slice(&self.authenticator_data.0[33..37]);
+        Ok(u32::from_be_bytes(counter))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnpairResponse {}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PingResponse {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteKeyResponse {}
+
+/// The answer to a `TestRequest`, with how long the app took by its own clock
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestResponse {
+    /// approved rather than declined
+    pub approved: bool,
+    /// from getting the request to showing the prompt, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_ms: Option<u64>,
+    /// from showing the prompt to its answer, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotatePairingKeysResponse {
+    /// the device's new public key
+    pub public_key: Base64Buffer,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListKeysResponse {
+    /// the credentials currently on the device, for ssh and for the websites of the WebAuthn bridge
+    pub sk_accounts: Vec<SkAccount>,
+}
+
 impl TryFrom<ResponseBody> for IdResponse {
     type Error = crate::error::Error;
 
@@ -259,6 +633,61 @@ impl TryFrom<ResponseBody> for UnpairResponse {
     }
 }
 
+impl TryFrom<ResponseBody> for ListKeysResponse {
+    type Error = crate::error::Error;
+
+    fn try_from(value: ResponseBody) -> Result<Self, Error> {
+        match value {
+            ResponseBody::ListKeys(resp) => resp.into(),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+}
+
+impl TryFrom<ResponseBody> for RotatePairingKeysResponse {
+    type Error = crate::error::Error;
+
+    fn try_from(value: ResponseBody) -> Result<Self, Error> {
+        match value {
+            ResponseBody::RotatePairingKeys(resp) => resp.into(),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+}
+
+impl TryFrom<ResponseBody> for PingResponse {
+    type Error = crate::error::Error;
+
+    fn try_from(value: ResponseBody) -> Result<Self, Error> {
+        match value {
+            ResponseBody::Ping(resp) => resp.into(),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+}
+
+impl TryFrom<ResponseBody> for DeleteKeyResponse {
+    type Error = crate::error::Error;
+
+    fn try_from(value: ResponseBody) -> Result<Self, Error> {
+        match value {
+            ResponseBody::DeleteKey(resp) => resp.into(),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+}
+
+impl TryFrom<ResponseBody> for TestResponse {
+    type Error = crate::error::Error;
+
+    fn try_from(value: ResponseBody) -> Result<Self, Error> {
+        match value {
+            ResponseBody::Test(resp) => resp.into(),
+            _ => Err(Error::UnexpectedResponse),
+        }
+    }
+}
+
 // Wire protocols
 #[derive(Debug, Clone)]
 pub enum WireMessage {
@@ -291,3 +720,227 @@ impl WireMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::rand::rngs::StdRng;
+    use ::rand::{Rng, SeedableRng};
+
+    /// the properties hold for this many inputs of a fixed seed, proptest isn't a dependency
+    const CASES: usize = 500;
+
+    fn bytes(rng: &mut StdRng, max: usize) -> Base64Buffer {
+        let len = rng.gen_range(0..=max);
+        Base64Buffer((0..len).map(|_| rng.gen()).collect())
+    }
+
+    fn text(rng: &mut StdRng, max: usize) -> String {
+        let len = rng.gen_range(0..=max);
+        (0..len).map(|_| rng.gen::<char>()).collect()
+    }
+
+    fn maybe<T>(rng: &mut StdRng, value: impl FnOnce(&mut StdRng) -> T) -> Option<T> {
+        match rng.gen() {
+            true => Some(value(rng)),
+            false => None,
+        }
+    }
+
+    fn extensions(rng: &mut StdRng) -> Extensions {
+        let mut extensions = Extensions {
+            hmac_secret: match rng.gen_range(0..3) {
+                0 => None,
+                1 => Some(HmacSecret::Enable(rng.gen())),
+                _ => Some(HmacSecret::Salts {
+                    salt1: bytes(rng, 32),
+                    salt2: maybe(rng, |rng| bytes(rng, 32)),
+                }),
+            },
+            cred_protect: maybe(rng, |rng| CredProtect::from_level(rng.gen_range(1..=3)).unwrap()),
+            other: BTreeMap::new(),
+        };
+        if rng.gen() {
+            extensions.insert("akr_login", serde_json::json!({ "user": text(rng, 10) }));
+        }
+        extensions
+    }
+
+    fn request_body(rng: &mut StdRng) -> RequestBody {
+        match rng.gen_range(0..10) {
+            0 => RequestBody::Id(IdRequest::new(rng.gen())),
+            1 => RequestBody::Register(RegisterRequest {
+                challenge: bytes(rng, 32),
+                rp_id: text(rng, 20),
+                rp_name: maybe(rng, |rng| text(rng, 20)),
+                user: maybe(rng, |rng| UserData {
+                    id: bytes(rng, 64),
+                    display_name: text(rng, 20),
+                }),
+                is_webauthn: rng.gen(),
+                resident_key: rng.gen(),
+                user_verification_required: rng.gen(),
+                extensions: maybe(rng, extensions),
+            }),
+            2 => RequestBody::Authenticate(AuthenticateRequest {
+                challenge: bytes(rng, 32),
+                rp_id: text(rng, 20),
+                extensions: maybe(rng, extensions),
+                key_handle: maybe(rng, |rng| bytes(rng, 300)),
+                key_handles: maybe(rng, |rng| {
+                    (0..rng.gen_range(0..4)).map(|_| bytes(rng, 300)).collect()
+                }),
+            }),
+            3 => RequestBody::Unpair(UnpairRequest {}),
+            4 => RequestBody::ListKeys(ListKeysRequest {}),
+            5 => RequestBody::RotatePairingKeys(RotatePairingKeysRequest {
+                public_key: bytes(rng, 32),
+            }),
+            6 => RequestBody::Ping(PingRequest {}),
+            7 => RequestBody::DeleteKey(DeleteKeyRequest {
+                key_handle: bytes(rng, 300),
+                rp_id: text(rng, 20),
+            }),
+            8 => RequestBody::Prewarm(PrewarmRequest {
+                host: text(rng, 20),
+                user: maybe(rng, |rng| text(rng, 10)),
+            }),
+            _ => RequestBody::Test(TestRequest { host: text(rng, 20) }),
+        }
+    }
+
+    fn result<T>(rng: &mut StdRng, contents: impl FnOnce(&mut StdRng) -> T) -> ClientResult<T> {
+        match rng.gen_range(0..4) {
+            0 => ClientResult {
+                contents: None,
+                error: Some(text(rng, 20)),
+            },
+            _ => ClientResult::ok(contents(rng)),
+        }
+    }
+
+    fn sk_account(rng: &mut StdRng) -> SkAccount {
+        SkAccount {
+            public_key: bytes(rng, 65),
+            key_handle: bytes(rng, 300),
+            rp_id: text(rng, 20),
+        }
+    }
+
+    fn extension_outputs(rng: &mut StdRng) -> ExtensionOutputs {
+        ExtensionOutputs {
+            hmac_secret: match rng.gen_range(0..3) {
+                0 => None,
+                1 => Some(HmacSecretOutput::Enabled(rng.gen())),
+                _ => Some(HmacSecretOutput::Secrets {
+                    output1: bytes(rng, 32),
+                    output2: maybe(rng, |rng| bytes(rng, 32)),
+                }),
+            },
+            cred_protect: maybe(rng, |rng| CredProtect::from_level(rng.gen_range(1..=3)).unwrap()),
+        }
+    }
+
+    fn response_body(rng: &mut StdRng) -> ResponseBody {
+        match rng.gen_range(0..9) {
+            0 => ResponseBody::Id(result(rng, |rng| IdResponse {
+                data: IdData {
+                    device_name: text(rng, 20),
+                    device_identifier: bytes(rng, 32),
+                    sk_accounts: maybe(rng, |rng| {
+                        (0..rng.gen_range(0..4)).map(|_| sk_account(rng)).collect()
+                    }),
+                    bluetooth_service_uuid: maybe(rng, |rng| uuid::Uuid::from_bytes(rng.gen())),
+                    capabilities: maybe(rng, |_| Capabilities::ours()),
+                },
+            })),
+            1 => ResponseBody::Register(result(rng, |rng| RegisterResponse {
+                public_key: bytes(rng, 65),
+                key_handle: bytes(rng, 300),
+                attestation_data: maybe(rng, |rng| bytes(rng, 200)),
+                attestation_format: maybe(rng, |rng| text(rng, 10)),
+                attestation_signature: maybe(rng, |rng| bytes(rng, 72)),
+                attestation_certificates: maybe(rng, |rng| vec![bytes(rng, 500)]),
+                extensions: maybe(rng, extension_outputs),
+            })),
+            2 => ResponseBody::Authenticate(result(rng, |rng| AuthenticateResponse {
+                public_key: bytes(rng, 65),
+                counter: rng.gen(),
+                signature: bytes(rng, 72),
+                key_handle: bytes(rng, 300),
+                user_handle: maybe(rng, |rng| bytes(rng, 64)),
+                authenticator_data: bytes(rng, 100),
+                extensions: maybe(rng, extension_outputs),
+            })),
+            3 => ResponseBody::Unpair(result(rng, |_| UnpairResponse {})),
+            4 => ResponseBody::ListKeys(result(rng, |rng| ListKeysResponse {
+                sk_accounts: (0..rng.gen_range(0..4)).map(|_| sk_account(rng)).collect(),
+            })),
+            5 => ResponseBody::RotatePairingKeys(result(rng, |rng| RotatePairingKeysResponse {
+                public_key: bytes(rng, 32),
+            })),
+            6 => ResponseBody::Ping(result(rng, |_| PingResponse {})),
+            7 => ResponseBody::DeleteKey(result(rng, |_| DeleteKeyResponse {})),
+            _ => ResponseBody::Test(result(rng, |rng| TestResponse {
+                approved: rng.gen(),
+                display_ms: maybe(rng, |rng| rng.gen()),
+                answer_ms: maybe(rng, |rng| rng.gen()),
+            })),
+        }
+    }
+
+    /// the types don't implement PartialEq, their JSON values are compared instead
+    fn json(value: &impl Serialize) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..CASES {
+            let mut request = Request::new(request_body(&mut rng));
+            request.send_ack = rng.gen();
+            let parsed: Request = serde_json::from_slice(&serde_json::to_vec(&request).unwrap()).unwrap();
+            assert_eq!(json(&parsed), json(&request));
+            assert_eq!(parsed.body.name(), request.body.name());
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..CASES {
+            let response = Response {
+                request_id: text(&mut rng, 44),
+                aws_push_id: maybe(&mut rng, |rng| text(rng, 40)),
+                device_token: maybe(&mut rng, |rng| text(rng, 40)),
+                version: PROTOCOL_VERSION.to_string(),
+                body: response_body(&mut rng),
+            };
+            let parsed = Response::parse(&serde_json::to_vec(&response).unwrap()).unwrap();
+            assert_eq!(json(&parsed), json(&response));
+        }
+    }
+
+    #[test]
+    fn cut_or_random_input_is_an_error() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..CASES {
+            let response = Response {
+                request_id: text(&mut rng, 44),
+                aws_push_id: None,
+                device_token: None,
+                version: PROTOCOL_VERSION.to_string(),
+                body: response_body(&mut rng),
+            };
+            let encoded = serde_json::to_vec(&response).unwrap();
+            assert!(Response::parse(&encoded[..rng.gen_range(0..encoded.len())]).is_err());
+
+            // anything goes, as long as it doesn't panic
+            let random = bytes(&mut rng, 200).0;
+            let _ = Response::parse(&random);
+            let _ = serde_json::from_slice::<Request>(&random);
+            let _ = WireMessage::new(random);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! `akr prune --unused-for 90d` deletes the keys that haven't signed for that long, the ones that never did
+//! once they're that old. Keys made elsewhere and never used here have no age and are kept.
+//!
+//! With `--revoke` the phones/tablets are asked to delete the credentials first, a key they may still have
+//! is kept on this machine if that fails. Without it, a pruned key can come back with `akr load`.
+
+use crate::cli::PruneArgs;
+use crate::client::Client;
+use crate::control::{self, ControlRequest};
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use crate::output;
+use crate::protocol::{Base64Buffer, Capabilities, DeleteKeyRequest, DeleteKeyResponse, RequestBody};
+use crate::ssh_format::SshFido2KeyPairHandle;
+use ansi_term::Colour::{Green, Red, Yellow};
+
+pub async fn run(args: PruneArgs) -> Result<(), Error> {
+    let unused_for = parse_duration(&args.unused_for)?;
+    let cutoff = chrono::Utc::now().timestamp() - unused_for;
+
+    let mut handles = StoredIdentity::load_from_disk()?.key_pair_handles;
+    handles.extend(StoredIdentity::load_added_key_pair_handles()?);
+    handles.retain(|handle| handle.application.starts_with("ssh:"));
+
+    let mut unused = vec![];
+    for handle in handles {
+        let last_used_at = StoredIdentity::load_usage(&handle.key_handle)?.last_used_at;
+        if last_used_at.or(handle.created_at).is_some_and(|t| t < cutoff) {
+            unused.push((handle, last_used_at));
+        }
+    }
+
+    let client = match args.revoke && !args.dry_run {
+        true => Some(Client::new()?),
+        false => None,
+    };
+    let mut results = vec![];
+    for (handle, last_used_at) in &unused {
+        let result = match args.dry_run {
+            true => Ok(false),
+            false => delete(client.as_ref(), handle).await,
+        };
+        match &result {
+            _ if output::is_json() => {}
+            _ if args.dry_run => println!(
+                "{} {}",
+                Yellow.paint("Would delete"),
+                describe(handle, *last_used_at)
+            ),
+            Ok(revoked) => println!(
+                "{} {}{}",
+                Green.paint("Deleted"),
+                describe(handle, *last_used_at),
+                match revoked {
+                    true => ", your phone/tablet deletes it too",
+                    false => "",
+                }
+            ),
+            Err(e) => eprintln!("{} {}: {}", Red.paint("Couldn't delete"), handle.key_comment(), e),
+        }
+        results.push(serde_json::json!({
+            "fingerprint": handle.fingerprint()?,
+            "rp_id": handle.application,
+            "last_used_at": last_used_at,
+            "deleted": !args.dry_run && result.is_ok(),
+            "revoked": result.as_ref().is_ok_and(|revoked| *revoked),
+            "error": result.err().map(|e| e.to_string()),
+        }));
+    }
+
+    if output::is_json() {
+        return output::print_json(&results);
+    }
+    if unused.is_empty() {
+        println!("No key has been unused for {}", args.unused_for);
+    } else if !args.dry_run {
+        reload_agent().await?;
+    }
+    Ok(())
+}
+
+/// the agent would keep offering deleted keys until it reloads, if it runs
+pub async fn reload_agent() -> Result<(), Error> {
+    let _ = control::send(&crate::agent_socket_path()?, ControlRequest::Reload).await;
+    Ok(())
+}
+
+/// Delete a key from this machine, from the phones/tablets first with a `client`, also for `akr delete`.
+/// Returns whether they were asked to, a request queued while offline counts
+pub async fn delete(client: Option<&Client>, handle: &SshFido2KeyPairHandle) -> Result<bool, Error> {
+    if let Some(client) = client {
+        client.require(Capabilities::DELETE_KEY)?;
+        let request = RequestBody::DeleteKey(DeleteKeyRequest {
+            key_handle: Base64Buffer(handle.key_handle.clone()),
+            rp_id: handle.application.clone(),
+        });
+        match client.send_request::<DeleteKeyResponse>(request).await {
+            Ok(_) => {}
+            Err(e @ Error::RequestQueued(_)) => eprintln!("{}", e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    StoredIdentity::remove_key_pair_handle(handle)?;
+    StoredIdentity::remove_added_key_pair_handle(handle)?;
+    StoredIdentity::remove_certificate(&handle.fmt_public_key()?)?;
+    StoredIdentity::remove_identity_file(handle)?;
+    StoredIdentity::unmark_removed(&handle.fmt_public_key()?)?;
+    Ok(client.is_some())
+}
+
+fn describe(handle: &SshFido2KeyPairHandle, last_used_at: Option<i64>) -> String {
+    let last_used = last_used_at
+        .and_then(|t| chrono::NaiveDateTime::from_timestamp_opt(t, 0))
+        .map(|t| t.format("last used %Y-%m-%d").to_string())
+        .unwrap_or_else(|| "never used".to_string());
+    format!("{} ({})", handle.key_comment(), last_used)
+}
+
+/// "90d", "12w" or "36h" in seconds
+fn parse_duration(duration: &str) -> Result<i64, Error> {
+    let invalid = || Error::InvalidDuration(duration.to_string());
+    let unit_at = duration.len() - duration.chars().last().map_or(0, char::len_utf8);
+    let (count, unit) = duration.split_at(unit_at);
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    let unit = match unit {
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    count
+        .checked_mul(unit)
+        .filter(|secs| *secs > 0)
+        .ok_or_else(invalid)
+}
@@ -0,0 +1,36 @@
+//! Pairing QR codes drawn in the terminal
+//!
+//! Every character cell holds two modules with an upper half block, the top one in the foreground colour
+//! and the bottom one in the background colour. The colours are set explicitly so the code scans on dark
+//! and light terminals alike.
+
+use crate::error::Error;
+use qrcode::{Color, QrCode};
+
+/// light modules around the code, scanners need some to find it
+const QUIET_ZONE: isize = 2;
+
+const DARK_FG: &str = "30";
+const LIGHT_FG: &str = "97";
+const DARK_BG: &str = "40";
+const LIGHT_BG: &str = "107";
+
+pub fn render(data: &str) -> Result<String, Error> {
+    let code = QrCode::new(data.as_bytes())?;
+    let width = code.width() as isize;
+    let colors = code.to_colors();
+    let dark = |x: isize, y: isize| {
+        (0..width).contains(&x) && (0..width).contains(&y) && colors[(y * width + x) as usize] == Color::Dark
+    };
+
+    let mut out = String::new();
+    for y in (-QUIET_ZONE..width + QUIET_ZONE).step_by(2) {
+        for x in -QUIET_ZONE..width + QUIET_ZONE {
+            let fg = if dark(x, y) { DARK_FG } else { LIGHT_FG };
+            let bg = if dark(x, y + 1) { DARK_BG } else { LIGHT_BG };
+            out.push_str(&format!("\x1b[{};{}m▀", fg, bg));
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    Ok(out)
+}
@@ -0,0 +1,198 @@
+//! Rate limits on sign requests, so a rogue local process can't flood the phone/tablet with prompts
+//!
+//! Each key may be asked to sign `sign_rate_limit` times a minute, all keys together `sign_rate_limit_global`
+//! times. Going over locks the key, or every key, out for `BASE_LOCKOUT`, doubled for every time it happens
+//! again before a quiet minute went by. 0 turns a limit off. Keys the agent doesn't hold only count against
+//! the global limit, so made up keys can't grow the buckets, and buckets are dropped once quiet.
+
+use crate::config;
+use crate::crypto;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const DEFAULT_PER_KEY: u32 = 10;
+pub const DEFAULT_GLOBAL: u32 = 30;
+const WINDOW: Duration = Duration::from_secs(60);
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Default)]
+struct Bucket {
+    /// the requests let through within the last `WINDOW`
+    requests: VecDeque<Instant>,
+    /// lockouts since the last quiet `WINDOW`
+    strikes: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Why a request was refused
+pub struct Throttled {
+    /// whether all keys are locked out, not only the one asked for
+    pub global: bool,
+    pub retry_in: Duration,
+    /// whether this request started the lockout, the user is only told once
+    pub started: bool,
+}
+
+pub struct RateLimiter {
+    per_key_limit: u32,
+    global_limit: u32,
+    /// by the sha256 of the key
+    keys: HashMap<[u8; crypto::SHA256_LEN], Bucket>,
+    global: Bucket,
+}
+
+impl Bucket {
+    fn lockout(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// nothing within the last `WINDOW` that the next request would still count
+    fn is_idle(&self, now: Instant) -> bool {
+        self.requests.back().is_none_or(|at| now - *at > WINDOW)
+            && self.locked_until.is_none_or(|until| now > until + WINDOW)
+    }
+
+    /// count a request, the lockout it earned when over `limit`
+    fn take(&mut self, limit: u32, now: Instant) -> Option<Duration> {
+        while self.requests.front().is_some_and(|at| now - *at > WINDOW) {
+            self.requests.pop_front();
+        }
+        if self.locked_until.is_some_and(|until| now > until + WINDOW) {
+            self.strikes = 0;
+            self.locked_until = None;
+        }
+        if self.requests.len() < limit as usize {
+            self.requests.push_back(now);
+            return None;
+        }
+
+        let lockout = BASE_LOCKOUT
+            .saturating_mul(2u32.saturating_pow(self.strikes))
+            .min(MAX_LOCKOUT);
+        self.strikes += 1;
+        self.locked_until = Some(now + lockout);
+        self.requests.clear();
+        Some(lockout)
+    }
+}
+
+impl RateLimiter {
+    /// with the limits of the config file
+    pub fn from_config() -> Self {
+        let config = config::get();
+        Self::new(
+            config.sign_rate_limit.unwrap_or(DEFAULT_PER_KEY),
+            config.sign_rate_limit_global.unwrap_or(DEFAULT_GLOBAL),
+        )
+    }
+
+    fn new(per_key_limit: u32, global_limit: u32) -> Self {
+        RateLimiter {
+            per_key_limit,
+            global_limit,
+            keys: HashMap::new(),
+            global: Bucket::default(),
+        }
+    }
+
+    /// Count a sign request, refusing it while every key or `key` is locked out. `key` is the sha256 of a key
+    /// the agent holds, None for any other
+    pub fn check(&mut self, key: Option<[u8; crypto::SHA256_LEN]>) -> Result<(), Throttled> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&mut self, key: Option<[u8; crypto::SHA256_LEN]>, now: Instant) -> Result<(), Throttled> {
+        let throttled = |global, retry_in, started| Throttled {
+            global,
+            retry_in,
+            started,
+        };
+        if let Some(retry_in) = self.global.lockout(now) {
+            return Err(throttled(true, retry_in, false));
+        }
+        if self.global_limit > 0 {
+            if let Some(retry_in) = self.global.take(self.global_limit, now) {
+                return Err(throttled(true, retry_in, true));
+            }
+        }
+
+        self.keys.retain(|_, bucket| !bucket.is_idle(now));
+        let Some(key) = key else {
+            return Ok(());
+        };
+        let bucket = self.keys.entry(key).or_default();
+        if let Some(retry_in) = bucket.lockout(now) {
+            return Err(throttled(false, retry_in, false));
+        }
+        if self.per_key_limit > 0 {
+            if let Some(retry_in) = bucket.take(self.per_key_limit, now) {
+                return Err(throttled(false, retry_in, true));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_a_key_out() {
+        let mut limiter = RateLimiter::new(2, 0);
+        let now = Instant::now();
+        let key = Some([1; crypto::SHA256_LEN]);
+        assert!(limiter.check_at(key, now).is_ok());
+        assert!(limiter.check_at(key, now).is_ok());
+        let throttled = limiter.check_at(key, now).unwrap_err();
+        assert!(!throttled.global && throttled.started);
+        assert_eq!(throttled.retry_in, BASE_LOCKOUT);
+        // other keys go on
+        assert!(limiter.check_at(Some([2; crypto::SHA256_LEN]), now).is_ok());
+        let throttled = limiter.check_at(key, now + Duration::from_secs(1)).unwrap_err();
+        assert!(!throttled.started);
+        assert!(limiter
+            .check_at(key, now + BASE_LOCKOUT + Duration::from_secs(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn global_limit_comes_first() {
+        let mut limiter = RateLimiter::new(10, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(None, now).is_ok());
+        }
+        let throttled = limiter.check_at(Some([1; crypto::SHA256_LEN]), now).unwrap_err();
+        assert!(throttled.global);
+        // refused before a bucket was made for the key
+        assert!(limiter.keys.is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_get_no_bucket() {
+        let mut limiter = RateLimiter::new(1, 0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check_at(None, now).is_ok());
+        }
+        assert!(limiter.keys.is_empty());
+    }
+
+    #[test]
+    fn prunes_quiet_buckets() {
+        let mut limiter = RateLimiter::new(5, 0);
+        let now = Instant::now();
+        for i in 0..50 {
+            assert!(limiter.check_at(Some([i; crypto::SHA256_LEN]), now).is_ok());
+        }
+        assert_eq!(limiter.keys.len(), 50);
+        let later = now + WINDOW + Duration::from_secs(1);
+        assert!(limiter.check_at(Some([0; crypto::SHA256_LEN]), later).is_ok());
+        assert_eq!(limiter.keys.len(), 1);
+    }
+}
@@ -0,0 +1,258 @@
+//! `akr start --record <dir>` writes every request to the agent and every exchange with the phone/tablet to the
+//! directory, one JSON object per line in "agent.jsonl" and "transport.jsonl", and `akr replay <dir>` runs the
+//! requests through an agent again, answered by the mock transport from what the phone/tablet answered then
+//!
+//! The keys added with ssh-add, the passphrases of lock and unlock and the secrets derived with hmac-secret are
+//! left out, so a recording can go with a bug report. What was signed stays, a sign request can't be replayed
+//! without it.
+//!
+//! A replay runs in a temporary home with the audit log and the keychain off, remove and control requests are
+//! skipped, so neither the pairing and keys of the profile nor the keys in "~/.ssh" are touched.
+
+use crate::cli::ReplayArgs;
+use crate::client::Client;
+use crate::error::Error;
+use crate::output;
+use crate::protocol::{self, Base64Buffer};
+use crate::transport::mock::{self, Exchange};
+use ansi_term::Colour::{Green, Red, Yellow};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use ssh_agent::error::HandleResult;
+use ssh_agent::{ConnectionId, Reply, Request, Response};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+const AGENT_FILE: &str = "agent.jsonl";
+const TRANSPORT_FILE: &str = "transport.jsonl";
+
+/// the directory recorded to, if any
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+/// keeps the lines of concurrent requests apart
+static WRITES: Mutex<()> = Mutex::new(());
+
+/// A request to the agent and how it was answered
+#[derive(Debug, Serialize, Deserialize)]
+struct AgentEntry {
+    at: i64,
+    connection: ConnectionId,
+    request: String,
+    /// the message without its length, missing for requests with secrets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<Base64Buffer>,
+    /// the answer without its length, missing if the agent hung up on an error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<Base64Buffer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub fn start(dir: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    let _ = DIR.set(dir.to_path_buf());
+    Ok(())
+}
+
+/// Append a line, not being able to is reported but never fails the request
+fn append(file: &str, entry: &impl Serialize) {
+    let Some(dir) = DIR.get() else {
+        return;
+    };
+    let appended = serde_json::to_vec(entry)
+        .map_err(Error::from)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            let _writing = WRITES.lock().unwrap_or_else(|e| e.into_inner());
+            let mut options = std::fs::OpenOptions::new();
+            options.create(true).append(true);
+            // what was signed is in there
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            Ok(options.open(dir.join(file))?.write_all(&line)?)
+        });
+    if let Err(e) = appended {
+        eprintln!("couldn't record to {}: {}", dir.display(), e);
+    }
+}
+
+/// A request to the agent being answered
+pub struct Recording(AgentEntry);
+
+impl Recording {
+    /// None if the agent isn't recording
+    pub fn start(connection: ConnectionId, request: &Request) -> Option<Self> {
+        DIR.get()?;
+        let message = match request {
+            Request::AddIdentity { .. }
+            | Request::AddSmartcardKey { .. }
+            | Request::RemoveSmartcardKey { .. }
+            | Request::Lock { .. }
+            | Request::Unlock { .. } => None,
+            request => request.encode(),
+        };
+        Some(Recording(AgentEntry {
+            at: chrono::Utc::now().timestamp(),
+            connection,
+            request: request.name().to_string(),
+            message: message.map(Base64Buffer),
+            response: None,
+            error: None,
+        }))
+    }
+
+    /// Record `reply` once it's there, it's handed back as is
+    pub async fn finish(self, reply: HandleResult<Reply>) -> HandleResult<Reply> {
+        match reply {
+            Ok(Reply::Later(pending)) => Ok(Reply::Later(Box::pin(async move {
+                let response = pending.await;
+                self.record(response.as_ref().map_err(|e| e.details.as_str()))
+                    .await;
+                response
+            }))),
+            Ok(Reply::Now(response)) => {
+                self.record(Ok(&response)).await;
+                Ok(Reply::Now(response))
+            }
+            Err(e) => {
+                self.record(Err(&e.details)).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn record(mut self, response: Result<&Response, &str>) {
+        match response {
+            Ok(response) => {
+                let mut message = vec![];
+                if response.write(&mut message).await.is_ok() {
+                    self.0.response = Some(Base64Buffer(message.split_off(4)));
+                }
+            }
+            Err(e) => self.0.error = Some(e.to_string()),
+        }
+        append(AGENT_FILE, &self.0);
+    }
+}
+
+/// Record an exchange with the phone/tablet, as a line of the mock transport's script
+pub fn exchange(request: &protocol::Request, response: &Result<protocol::Response, Error>) {
+    if DIR.get().is_none() {
+        return;
+    }
+    let exchange = match response {
+        Ok(response) => Exchange {
+            request: request.body.name().to_string(),
+            response: Some(response.body.clone()),
+            ..Default::default()
+        },
+        Err(Error::DeviceError(e)) => Exchange {
+            request: request.body.name().to_string(),
+            error: Some(e.clone()),
+            ..Default::default()
+        },
+        Err(e) => Exchange {
+            request: request.body.name().to_string(),
+            error: Some(e.to_string()),
+            ..Default::default()
+        },
+    };
+
+    let Ok(mut entry) = serde_json::to_value(&exchange) else {
+        return;
+    };
+    if let Some(extensions) = entry
+        .pointer_mut("/response/u2f_authenticate_response/extensions")
+        .and_then(serde_json::Value::as_object_mut)
+    {
+        extensions.remove("hmac-secret");
+    }
+    append(TRANSPORT_FILE, &entry);
+}
+
+fn load<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Error> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+/// The temporary home of `akr replay`, set up before anything reads the profile and removed when dropped
+pub fn sandbox() -> Result<tempfile::TempDir, Error> {
+    let dir = tempfile::Builder::new().prefix("akr-replay-").tempdir()?;
+    crate::profile::use_root(dir.path().to_path_buf());
+    std::fs::create_dir_all(crate::profile::dir(crate::profile::current())?)?;
+    crate::audit::disable();
+    Ok(dir)
+}
+
+/// requests that would take keys out of the agent or lock it, which a replay doesn't need to answer
+fn is_skipped(message: &[u8]) -> bool {
+    match Request::parse(message) {
+        Ok(Request::RemoveIdentity { .. } | Request::RemoveAllIdentities) => true,
+        Ok(Request::Extension { extension_type, .. }) => extension_type == crate::control::EXTENSION,
+        _ => false,
+    }
+}
+
+/// `akr replay <dir>`, the transport is already the mock one and the home the one of `sandbox`
+pub async fn replay(args: ReplayArgs) -> Result<(), Error> {
+    let requests: Vec<AgentEntry> = load(&args.dir.join(AGENT_FILE))?;
+    mock::set_script(load(&args.dir.join(TRANSPORT_FILE))?);
+
+    let agent = crate::ssh_agent::Agent::new(Client::new()?);
+    let agent = Arc::new(tokio::sync::Mutex::new(agent));
+
+    let mut connections = HashMap::new();
+    let mut results = vec![];
+    for entry in requests {
+        let Some(message) = entry.message.filter(|message| !is_skipped(&message.0)) else {
+            results.push((entry.request, "skipped"));
+            continue;
+        };
+        let client = connections.entry(entry.connection).or_insert_with(|| {
+            ssh_agent::Client::new(::ssh_agent::Agent::connect_in_process(
+                agent.clone(),
+                entry.connection,
+            ))
+        });
+
+        let answer = client.request(message.0).await;
+        let outcome = match (&answer, &entry.response) {
+            (Ok(answer), Some(recorded)) if *answer == recorded.0 => "as recorded",
+            // the agent hung up on an error both times
+            (Err(_), None) => "as recorded",
+            _ => "differently",
+        };
+        if answer.is_err() {
+            connections.remove(&entry.connection);
+        }
+        results.push((entry.request, outcome));
+    }
+
+    if output::is_json() {
+        let results = results
+            .iter()
+            .map(|(request, outcome)| serde_json::json!({ "request": request, "answered": outcome }))
+            .collect::<Vec<_>>();
+        return output::print_json(&results);
+    }
+    for (request, outcome) in &results {
+        let outcome = match *outcome {
+            "as recorded" => Green.paint(*outcome),
+            "skipped" => Yellow.paint(*outcome),
+            _ => Red.paint(*outcome),
+        };
+        println!("{}: {}", request, outcome);
+    }
+    Ok(())
+}
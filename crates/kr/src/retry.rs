@@ -0,0 +1,107 @@
+//! Retrying requests to the phone/tablet that failed on a transient error,
+//! e.g. a relay queue that didn't answer, with exponential backoff and jitter
+
+use crate::crypto;
+use crate::error::{Error, ErrorKind};
+use crate::protocol::RequestBody;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// attempts in total, including the first one
+    pub max_attempts: u32,
+    /// wait before the second attempt, doubled for every one after it
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// fraction of each backoff that is randomized, so agents don't retry in lockstep
+    pub jitter: f64,
+    /// only retry requests that are safe to repeat, see `RetryPolicy::applies_to`
+    pub idempotent_only: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(4),
+            jitter: 0.5,
+            idempotent_only: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether `request` may be sent again
+    /// a retried registration could leave a second key on the phone/tablet and a retried unpair could
+    /// hit a re-pairing, whereas a sign request only asks for another approval of the same challenge
+    pub fn applies_to(&self, request: &RequestBody) -> bool {
+        if !self.idempotent_only {
+            return true;
+        }
+
+        match request {
+            RequestBody::Id(_)
+            | RequestBody::ListKeys(_)
+            | RequestBody::Authenticate(_)
+            | RequestBody::Ping(_)
+            | RequestBody::DeleteKey(_)
+            | RequestBody::Prewarm(_)
+            | RequestBody::Test(_) => true,
+            RequestBody::Register(_) | RequestBody::Unpair(_) | RequestBody::RotatePairingKeys(_) => false,
+        }
+    }
+
+    /// the wait after failed attempt number `attempt` (starting at 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let random = crypto::random_u32() as f64 / u32::MAX as f64;
+        backoff.mul_f64(1.0 - jitter * random)
+    }
+
+    /// Run `attempt` until it succeeds, fails fatally or the attempts are used up,
+    /// leaving off early when the backoff would pass `deadline`
+    pub async fn run<T, F, Fut>(
+        &self,
+        request: &RequestBody,
+        deadline: Option<Instant>,
+        mut attempt: F,
+    ) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let max_attempts = match self.applies_to(request) {
+            true => self.max_attempts.max(1),
+            false => 1,
+        };
+
+        let mut attempts = 1;
+        loop {
+            let error = match attempt().await {
+                Err(e) if e.kind() == ErrorKind::Retryable && attempts < max_attempts => e,
+                result => return result,
+            };
+
+            let backoff = self.backoff(attempts);
+            if deadline.is_some_and(|d| Instant::now() + backoff >= d) {
+                return Err(error);
+            }
+
+            eprintln!(
+                "request failed ({}), retrying in {}ms",
+                error,
+                backoff.as_millis()
+            );
+            tokio::time::sleep(backoff).await;
+            attempts += 1;
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Rotate the keys that seal the messages between this machine and a paired phone/tablet
+//!
+//! The new workstation public key goes to the device sealed with the old keys, which answers with its
+//! own new public key. A request sealed with the new keys then proves they work, and tells the device
+//! to drop the old ones. Only after that the new pairing replaces the old one on disk.
+
+use crate::cli::RotateKeysArgs;
+use crate::client::Client;
+use crate::crypto;
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use crate::output;
+use crate::pairing::{Keypair, Pairing};
+use crate::protocol::{
+    IdRequest, IdResponse, RequestBody, RotatePairingKeysRequest, RotatePairingKeysResponse,
+};
+use ansi_term::Colour::{Green, Red, Yellow};
+
+/// suggest rotating keys older than this in the audit
+const MAX_KEY_AGE_DAYS: i64 = 365;
+
+pub async fn run(args: RotateKeysArgs) -> Result<(), Error> {
+    let pairings = match &args.device {
+        Some(device) => vec![Pairing::find(device)?],
+        None => Client::pairings()?,
+    };
+
+    if args.audit && output::is_json() {
+        return output::print_json(&pairings.iter().map(audit_json).collect::<Vec<_>>());
+    }
+    if args.audit {
+        pairings.iter().for_each(audit);
+        return Ok(());
+    }
+
+    let client = Client::new()?;
+    let mut results = vec![];
+    for pairing in pairings {
+        let device_name = pairing.device_name.clone();
+        let result = rotate(&client, pairing).await;
+        match &result {
+            _ if output::is_json() => {}
+            Ok(()) => println!("{} {}", Green.paint("Rotated the pairing keys of"), device_name),
+            Err(e) => eprintln!(
+                "{} {}: {}",
+                Red.paint("Couldn't rotate the keys of"),
+                device_name,
+                e
+            ),
+        }
+        results.push(serde_json::json!({
+            "device_name": device_name,
+            "rotated": result.is_ok(),
+            "error": result.err().map(|e| e.to_string()),
+        }));
+    }
+
+    if output::is_json() {
+        return output::print_json(&results);
+    }
+    Ok(())
+}
+
+async fn rotate(client: &Client, pairing: Pairing) -> Result<(), Error> {
+    let old_queue_uuid = pairing.queue_uuid()?;
+    let keypair: Keypair = crypto::gen_keypair().into();
+    let new_queue_uuid = keypair.queue_uuid()?;
+    client.create_queue(new_queue_uuid).await?;
+
+    let response: RotatePairingKeysResponse = client
+        .send_request_to_device(
+            pairing.clone(),
+            RequestBody::RotatePairingKeys(RotatePairingKeysRequest {
+                public_key: keypair.public_key.clone(),
+            }),
+        )
+        .await?;
+
+    let rotated = Pairing {
+        keypair,
+        device_public_key: response.public_key,
+        keys_rotated_at: chrono::Utc::now().timestamp(),
+        ..pairing.clone()
+    };
+    // make sure the device sent a usable key before sealing anything with it
+    let _ = rotated.device_public_key()?;
+
+    // stores the new pairing once the device answers with the new keys
+    let _: IdResponse = client
+        .send_request_to_device(rotated, RequestBody::Id(IdRequest::new(false)))
+        .await?;
+
+    if let Ok(mut id) = StoredIdentity::load_from_disk() {
+        id.move_pairing(old_queue_uuid, new_queue_uuid);
+        id.store_to_disk()?;
+    }
+    pairing.delete_from_disk()
+}
+
+fn fingerprint(key: &[u8]) -> String {
+    hex::encode(&crypto::sha256(key)[..8])
+}
+
+/// the unix time the keys of `pairing` were made, and whether that was at the pairing or a rotation
+fn keys_created_at(pairing: &Pairing) -> (&'static str, i64) {
+    match pairing.keys_rotated_at {
+        0 => ("paired", pairing.paired_at),
+        rotated_at => ("rotated", rotated_at),
+    }
+}
+
+fn audit_json(pairing: &Pairing) -> serde_json::Value {
+    let (_, created_at) = keys_created_at(pairing);
+    let age_days = Some(created_at)
+        .filter(|t| *t > 0)
+        .map(|t| (chrono::Utc::now().timestamp() - t) / (24 * 60 * 60));
+
+    serde_json::json!({
+        "device_name": pairing.device_name,
+        "queue_uuid": pairing.queue_uuid().ok(),
+        "workstation_key": pairing.keypair.verify().ok().map(|_| fingerprint(&pairing.keypair.public_key.0)),
+        "device_key": pairing.device_public_key().ok().map(|key| fingerprint(&key)),
+        "keys_age_days": age_days,
+        "needs_rotation": age_days.is_some_and(|age| age > MAX_KEY_AGE_DAYS),
+    })
+}
+
+fn audit(pairing: &Pairing) {
+    println!("{}", Green.bold().paint(&pairing.device_name));
+
+    match pairing.queue_uuid() {
+        Ok(queue_uuid) => println!("  queue: {}", queue_uuid),
+        Err(e) => println!("  queue: {}", Red.paint(e.to_string())),
+    }
+
+    match pairing.keypair.verify() {
+        Ok(()) => println!(
+            "  workstation key: {}",
+            fingerprint(&pairing.keypair.public_key.0)
+        ),
+        Err(e) => println!("  workstation key: {}", Red.paint(e.to_string())),
+    }
+    match pairing.device_public_key() {
+        Ok(key) => println!("  device key: {}", fingerprint(&key)),
+        Err(e) => println!("  device key: {}", Red.paint(e.to_string())),
+    }
+
+    let (since, created_at) = keys_created_at(pairing);
+    if created_at > 0 {
+        let age_days = (chrono::Utc::now().timestamp() - created_at) / (24 * 60 * 60);
+        let age = format!("{} {} days ago", since, age_days);
+        match age_days > MAX_KEY_AGE_DAYS {
+            true => println!("  keys: {}, consider `akr rotate-keys`", Yellow.paint(age)),
+            false => println!("  keys: {}", age),
+        }
+    }
+}
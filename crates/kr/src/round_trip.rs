@@ -0,0 +1,225 @@
+//! `akr test`: a request to every paired phone/tablet and back, to tell a network problem from one of the app
+//!
+//! A ping first shows whether the request got out to a relay and whether the app got it and answered. Then
+//! the app shows a test prompt that approves nothing. Its answer says how long the prompt took to show up and
+//! to be answered, the rest of the round trip is the network's. Apps without the test prompt are only pinged.
+
+use crate::cli::TestArgs;
+use crate::client::Client;
+use crate::error::Error;
+use crate::pairing::Pairing;
+use crate::protocol::{Capabilities, PingRequest, PingResponse, RequestBody, TestRequest, TestResponse};
+use crate::ssh_agent::Agent;
+use crate::status::{self, RelayStatus};
+use crate::{config, output};
+use ansi_term::Colour::{Green, Red, Yellow};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// how long to wait for the app to answer the ping
+const PING_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize)]
+struct Report {
+    devices: Vec<DeviceReport>,
+    relays: Vec<RelayStatus>,
+}
+
+#[derive(Serialize, Default)]
+struct DeviceReport {
+    name: String,
+    /// a relay took the request
+    sent: bool,
+    /// the app answered the ping
+    received: bool,
+    /// the app showed the test prompt, none for apps without it
+    displayed: Option<bool>,
+    /// the prompt was answered, approved or declined
+    answered: Option<bool>,
+    approved: Option<bool>,
+    ping_ms: Option<u128>,
+    /// of the test prompt, from sending it to its answer
+    round_trip_ms: Option<u128>,
+    /// as the app measured it
+    display_ms: Option<u64>,
+    answer_ms: Option<u64>,
+    /// the round trip without the time the app took
+    network_ms: Option<u128>,
+    error: Option<String>,
+    /// what went wrong, in words
+    diagnosis: Option<String>,
+}
+
+pub async fn run(args: TestArgs) -> Result<(), Error> {
+    let client = Client::new()?;
+    let pairings = match &args.device {
+        Some(device) => vec![Pairing::find(device)?],
+        None => Client::pairings()?,
+    };
+    let prompt_timeout = config::get()
+        .sign_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(Agent::DEFAULT_SIGN_TIMEOUT);
+
+    let (devices, relays) = futures::future::join(
+        futures::future::join_all(
+            pairings
+                .into_iter()
+                .map(|pairing| test_device(&client, pairing, prompt_timeout)),
+        ),
+        status::relay_statuses(&client),
+    )
+    .await;
+    let mut report = Report { devices, relays };
+    let relays_down = report.relays.iter().all(|relay| !relay.reachable);
+    for device in &mut report.devices {
+        device.diagnosis = diagnose(device, relays_down, prompt_timeout);
+    }
+
+    if output::is_json() {
+        return output::print_json(&report);
+    }
+    print(&report);
+    Ok(())
+}
+
+async fn test_device(client: &Client, pairing: Pairing, prompt_timeout: Duration) -> DeviceReport {
+    let mut report = DeviceReport {
+        name: pairing.device_name.clone(),
+        ..Default::default()
+    };
+
+    let started = Instant::now();
+    let ping = client
+        .send_request_to_device_with_timeout::<PingResponse>(
+            pairing.clone(),
+            RequestBody::Ping(PingRequest {}),
+            PING_TIMEOUT,
+        )
+        .await;
+    match ping {
+        Ok(_) => {
+            report.sent = true;
+            report.received = true;
+            report.ping_ms = Some(started.elapsed().as_millis());
+        }
+        // the relays took it, the app didn't answer
+        Err(e @ Error::ResponseTimedOut) => {
+            report.sent = true;
+            report.error = Some(e.to_string());
+            return report;
+        }
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    }
+    if !client.pairing_supports(&pairing, Capabilities::TEST) {
+        return report;
+    }
+
+    let started = Instant::now();
+    let request = RequestBody::Test(TestRequest {
+        host: whoami::hostname(),
+    });
+    let response = client
+        .send_request_to_device_with_timeout::<TestResponse>(pairing, request, prompt_timeout)
+        .await;
+    let round_trip = started.elapsed().as_millis();
+    match response {
+        Ok(response) => {
+            let in_app = response.display_ms.unwrap_or_default() + response.answer_ms.unwrap_or_default();
+            report.displayed = Some(true);
+            report.answered = Some(true);
+            report.approved = Some(response.approved);
+            report.round_trip_ms = Some(round_trip);
+            report.display_ms = response.display_ms;
+            report.answer_ms = response.answer_ms;
+            report.network_ms = Some(round_trip.saturating_sub(in_app as u128));
+        }
+        // the app answers a declined prompt with an error
+        Err(Error::DeviceError(_)) => {
+            report.displayed = Some(true);
+            report.answered = Some(true);
+            report.approved = Some(false);
+            report.round_trip_ms = Some(round_trip);
+        }
+        Err(e @ Error::ResponseTimedOut) => {
+            report.answered = Some(false);
+            report.error = Some(e.to_string());
+        }
+        Err(e) => report.error = Some(e.to_string()),
+    }
+    report
+}
+
+fn diagnose(device: &DeviceReport, relays_down: bool, prompt_timeout: Duration) -> Option<String> {
+    let diagnosis = match (device.sent, device.received, device.answered, device.approved) {
+        (false, ..) if relays_down => "no relay is reachable, check the network and proxy of this machine",
+        (false, ..) => "the request didn't get out to a relay",
+        (true, false, ..) => {
+            "the relays took the request but the app didn't answer: open it and check it's online"
+        }
+        (true, true, Some(false), _) => {
+            return Some(format!(
+                "the app got the request but the prompt wasn't answered within {}s: check its notifications",
+                prompt_timeout.as_secs()
+            ))
+        }
+        (true, true, Some(true), Some(false)) => "the prompt was declined on the phone/tablet",
+        (true, true, None, _) if device.error.is_some() => "the app answered the ping but not the prompt",
+        _ => return None,
+    };
+    Some(diagnosis.to_string())
+}
+
+fn print(report: &Report) {
+    if report.devices.is_empty() {
+        println!("{}", Yellow.paint("No phone/tablet is paired, run `akr pair`"));
+    }
+    for device in &report.devices {
+        let ping = match device.ping_ms {
+            Some(ms) => format!("answered in {}ms", ms),
+            None => "didn't answer".to_string(),
+        };
+        match (device.approved, device.diagnosis.as_deref()) {
+            (Some(true), _) => {
+                println!("{} {}: {}", Green.paint("✓"), device.name, ping);
+                let ms = |ms: Option<u64>| {
+                    ms.map(|ms| format!("{}ms", ms))
+                        .unwrap_or_else(|| "?".to_string())
+                };
+                println!(
+                    "  prompt approved in {}ms: {} to show it, {} to answer, {}ms on the network",
+                    device.round_trip_ms.unwrap_or_default(),
+                    ms(device.display_ms),
+                    ms(device.answer_ms),
+                    device.network_ms.unwrap_or_default()
+                );
+            }
+            (None, None) => {
+                println!("{} {}: {}", Green.paint("✓"), device.name, ping);
+                println!("  the app is too old for the test prompt, update it to test approving");
+            }
+            (_, diagnosis) => {
+                println!("{} {}: {}", Red.paint("✗"), device.name, ping);
+                if let Some(diagnosis) = diagnosis {
+                    println!("  {}", diagnosis);
+                }
+                if let Some(error) = &device.error {
+                    println!("  ({})", error);
+                }
+            }
+        }
+    }
+
+    let relays = report
+        .relays
+        .iter()
+        .map(|relay| match relay.reachable {
+            true => format!("{} {}", relay.name, Green.paint("up")),
+            false => format!("{} {}", relay.name, Red.paint("down")),
+        })
+        .collect::<Vec<_>>();
+    println!("Relays: {}", relays.join(", "));
+}
@@ -0,0 +1,103 @@
+//! `akr derive-secret`, a 32 byte secret derived by one of your keys on the phone/tablet with hmac-secret
+//!
+//! The same key and salt always give the same secret, which makes it a key file for LUKS, age or the like that
+//! only exists while the phone approves. A different salt gives an unrelated secret, one key can unlock many
+//! things. The salt travels over the encrypted pairing, there is no PIN protocol key agreement like over USB.
+
+use crate::cli::DeriveSecretArgs;
+use crate::client::Client;
+use crate::config;
+use crate::crypto;
+use crate::error::Error;
+use crate::memory::Secret;
+use crate::output;
+use crate::protocol::{
+    AuthenticateRequest, AuthenticateResponse, Base64Buffer, Capabilities, Extensions, HmacSecret,
+    HmacSecretOutput, RequestBody,
+};
+use crate::ssh_agent::Agent;
+use crate::ssh_format::{verify_sk_signature, SshFido2KeyPairHandle};
+use crate::sshsig;
+use std::io::Write;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// hmac-secret takes salts and gives secrets of 32 bytes
+pub const SECRET_LEN: usize = 32;
+
+pub async fn run(args: DeriveSecretArgs) -> Result<(), Error> {
+    let salt = hex::decode(args.salt.trim())
+        .ok()
+        .filter(|salt| salt.len() == SECRET_LEN)
+        .ok_or(Error::InvalidSalt)?;
+    let handle = sshsig::find_key_pair_handle(Some(&args.key))?;
+    eprintln!(
+        "Approve deriving a secret with {} on your phone",
+        handle.key_comment()
+    );
+    let secret = derive(&handle, salt).await?;
+
+    let hex = Zeroizing::new(hex::encode(&*secret));
+    match args.output {
+        Some(path) => write_key_file(&path, &secret)?,
+        None if output::is_json() => output::print_json(&serde_json::json!({ "secret": hex.as_str() }))?,
+        None => println!("{}", hex.as_str()),
+    }
+    Ok(())
+}
+
+/// The secret `handle` derives from the 32 byte `salt`, once the phone approved
+pub async fn derive(handle: &SshFido2KeyPairHandle, salt: Vec<u8>) -> Result<Secret, Error> {
+    // the assertion proves the secret comes from the key asked for
+    let challenge = crypto::random_bytes(32);
+    let client = Client::new()?;
+    client.require(Capabilities::HMAC_SECRET)?;
+    let response: AuthenticateResponse = client
+        .send_request_with_timeout(
+            RequestBody::Authenticate(AuthenticateRequest {
+                challenge: Base64Buffer(challenge.clone()),
+                rp_id: handle.application.clone(),
+                extensions: Some(Extensions {
+                    hmac_secret: Some(HmacSecret::Salts {
+                        salt1: Base64Buffer(salt),
+                        salt2: None,
+                    }),
+                    ..Extensions::default()
+                }),
+                key_handle: Some(Base64Buffer(handle.key_handle.clone())),
+                key_handles: None,
+            }),
+            config::get()
+                .sign_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(Agent::DEFAULT_SIGN_TIMEOUT),
+        )
+        .await?;
+    if response.key_handle.0 != handle.key_handle
+        || !verify_sk_signature(
+            handle.key_type,
+            &handle.public_key,
+            &response.authenticator_data.0,
+            &challenge,
+            &response.signature.0,
+        )
+    {
+        return Err(Error::SignatureVerificationFailed(handle.application.clone()));
+    }
+    match response.extensions.and_then(|extensions| extensions.hmac_secret) {
+        Some(HmacSecretOutput::Secrets { output1, .. }) if output1.0.len() == SECRET_LEN => {
+            Ok(Secret::new(output1.0))
+        }
+        _ => Err(Error::NoDerivedSecret),
+    }
+}
+
+/// the raw bytes, readable by the user only, e.g. for `cryptsetup --key-file`
+fn write_key_file(path: &str, secret: &[u8]) -> Result<(), Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(secret)?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+//! `akr install-service` and `akr uninstall-service`, what the install and removal scripts of packages call
+//! instead of doing what `akr setup` does by hand
+//!
+//! For the current user it installs the service of the agent like `setup`, makes the dir of the agent's
+//! socket and a config file to fill in. `--global` installs the service for every user instead, run as root
+//! from e.g. a deb postinst, and `--root` only writes its files below a package's staging dir. Neither
+//! touches the ssh config, `akr setup` is still for that.
+
+use crate::cli::{ServiceArgs, UninstallServiceArgs};
+use crate::config::Config;
+use crate::error::Error;
+use crate::launch::Daemon;
+use crate::output;
+use ansi_term::Colour::Green;
+use std::path::PathBuf;
+
+const CONFIG_SKELETON: &str = "\
+# Settings of akr, `akr config set <key> <value>` changes them and `akr config list` shows them
+# e.g. sign_timeout = 60
+";
+
+pub fn install(args: ServiceArgs) -> Result<(), Error> {
+    let created = match args.global || args.root.is_some() {
+        true => Daemon::new()?.install_global(args.systemd, args.root.as_deref())?,
+        false => install_for_user(args.systemd, args.launchd)?,
+    };
+    let created = created
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "created": created }));
+    }
+    for path in &created {
+        println!("Created {}", path);
+    }
+    println!("{} the service of the agent", Green.paint("Installed"));
+    Ok(())
+}
+
+/// Install the service of the agent for the current user, with what it needs. Returns the files and dirs
+/// that weren't there
+pub fn install_for_user(systemd: bool, launchd: bool) -> Result<Vec<PathBuf>, Error> {
+    let mut created = vec![];
+
+    if let Some(dir) = crate::agent_socket_path()?.parent().filter(|dir| !dir.exists()) {
+        std::fs::create_dir_all(dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        created.push(dir.to_path_buf());
+    }
+    let config = Config::path()?;
+    if !config.exists() {
+        std::fs::create_dir_all(config.parent().expect("a dir"))?;
+        std::fs::write(&config, CONFIG_SKELETON)?;
+        created.push(config);
+    }
+
+    match (systemd, launchd) {
+        (true, _) => Daemon::new()?.install_socket_activated(&crate::agent_socket_path()?)?,
+        (_, true) => Daemon::new()?.install_launch_agent()?,
+        _ => Daemon::new()?.install()?,
+    }
+    Ok(created)
+}
+
+/// Stop the agent and remove its service, the config and the keys stay
+pub fn uninstall(args: UninstallServiceArgs) -> Result<(), Error> {
+    let removed = match args.global || args.root.is_some() {
+        true => Daemon::new()?.uninstall_global(args.root.as_deref())?,
+        false => Daemon::new()?.uninstall()?,
+    };
+
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({ "removed": removed }));
+    }
+    if removed.is_empty() {
+        println!("The service of the agent isn't installed");
+    }
+    for what in &removed {
+        println!("Removed {}", what);
+    }
+    Ok(())
+}
@@ -1,101 +1,189 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use super::SetupArgs;
-use crate::{error::Error, launch::Daemon};
+use crate::identity::StoredIdentity;
+use crate::ssh_config::{self, ConfigFile};
+use crate::{error::Error, host_context, launch::Daemon, service, sshsig};
+use ansi_term::Colour::Yellow;
 
 pub async fn run(args: SetupArgs) -> Result<(), Error> {
     if args.print_only {
-        return print_config();
+        return print_config(args.systemd);
     }
 
-    update_ssh_config(args.ssh_config_path).await?;
-    Daemon::new()?.install()
+    let ssh_config_path = ssh_config_path(args.ssh_config_path)?;
+    let host = match args.host_context {
+        true => Some(ssh_config::HOST_CONTEXT.to_string()),
+        false => args.host,
+    };
+    if args.remove {
+        return remove_ssh_config(&ssh_config_path, host.as_deref(), args.dry_run);
+    }
+    if args.host_context {
+        return update_host_context(&ssh_config_path, args.dry_run);
+    }
+    if let Some(host) = &host {
+        return update_host_config(&ssh_config_path, host, &args.key, args.dry_run);
+    }
+    update_ssh_config(&ssh_config_path, args.dry_run)?;
+    if args.dry_run {
+        return Ok(());
+    }
+    service::install_for_user(args.systemd, args.launchd)?;
+    Ok(())
 }
 
 /// print out config changes
-pub fn print_config() -> Result<(), Error> {
+pub fn print_config(systemd: bool) -> Result<(), Error> {
     println!(
         "== SSH Config Additions ==\n{}\n",
         create_ssh_config_stanza()?
     ); //TODO:ssh config
-    println!("==  Background Service  ==\n{}\n", Daemon::new()?.render()?);
+    let daemon = match systemd {
+        true => Daemon::new()?.render_socket_activated(&crate::agent_socket_path()?)?,
+        false => Daemon::new()?.render()?,
+    };
+    println!("==  Background Service  ==\n{}\n", daemon);
     Ok(())
 }
 
-const BEGIN_CONFIG_STANZA: &'static str = "# Begin Akamai MFA SSH Config";
-const END_CONFIG_STANZA: &'static str = "# End Akamai MFA SSH Config";
+/// A path as the ssh config has it
+fn config_path(path: &Path) -> String {
+    let path = path.display().to_string();
+    // ssh takes backslashes in its config as escapes, named pipes work with forward slashes as well
+    #[cfg(windows)]
+    let path = path.replace('\\', "/");
+    path
+}
 
-const BEGIN_KR_STANZA: &'static str = "# Added by Krypton";
-const KR_PROXY_COMMAND_STANZA: &'static str = "krssh %h %p";
+/// The socket of the agent as the ssh config has it
+fn config_socket_path() -> Result<String, Error> {
+    Ok(config_path(&crate::agent_socket_path()?))
+}
 
 fn create_ssh_config_stanza() -> Result<String, Error> {
-    let agent_socket_path = crate::create_home_path()?
-        .join(crate::SSH_AGENT_PIPE)
-        .display()
-        .to_string();
-
-    // create the new config
-    let mut stanza = String::new();
-    stanza.push_str("\n");
-    stanza.push_str(BEGIN_CONFIG_STANZA);
-    stanza.push_str("\n");
-    stanza.push_str("Host *\n");
-    stanza.push_str("\tIdentityAgent ");
-    stanza.push_str(&agent_socket_path);
-    stanza.push_str("\n");
-    stanza.push_str(END_CONFIG_STANZA);
-    stanza.push_str("\n");
-
-    Ok(stanza)
+    Ok(ssh_config::block(&config_socket_path()?).join("\n"))
 }
 
-/// add our host stanza to the config file
-pub async fn update_ssh_config(custom_path: Option<String>) -> Result<(), Error> {
-    let path = if let Some(custom) = custom_path {
-        Path::new(&custom).into()
-    } else {
-        directories::UserDirs::new()
+fn ssh_config_path(custom_path: Option<String>) -> Result<PathBuf, Error> {
+    match custom_path {
+        Some(custom) => Ok(PathBuf::from(custom)),
+        None => Ok(directories::UserDirs::new()
             .ok_or(Error::CannotReadHomeDir)?
             .home_dir()
             .join(".ssh")
-            .join("config")
-    };
+            .join("config")),
+    }
+}
 
-    let ssh_config = std::fs::read_to_string(&path)?;
-
-    // clear any existing config by us
-    let mut lines: Vec<&str> = ssh_config.split("\n").collect();
- 
-    let start = lines
-        .iter_mut()
-        .position(|s| s.as_bytes() == BEGIN_CONFIG_STANZA.as_bytes());
-    let end = lines
-        .iter_mut()
-        .position(|s| s.as_bytes() == END_CONFIG_STANZA.as_bytes());
-
-        let clean_akr_config_lines = match (start, end) {
-        (Some(start), Some(end)) => vec![&lines[..start], &lines[(end + 1)..]]
-            .concat()
-            .join("\n"),
-        _ => lines.join("\n"),
-    };
+/// Put our host stanza in the config, or update it where it is: in the config or a file it includes
+pub fn update_ssh_config(path: &Path, dry_run: bool) -> Result<(), Error> {
+    let block = ssh_config::block(&config_socket_path()?);
+    let mut files = ssh_config::load_all(path)?;
+    for file in &files {
+        for (line, directive) in file.unmanaged_directives() {
+            if directive.keyword == "identityagent" {
+                eprintln!(
+                    "{} {}:{} sets IdentityAgent {}, ssh uses that agent for its hosts if it reads it first",
+                    Yellow.paint("Note:"),
+                    file.path.display(),
+                    line,
+                    directive.args.join(" ")
+                );
+            }
+        }
+    }
+
+    manage(&mut files, None, &block, false);
+    save(&files, dry_run)
+}
 
-    // clean up old kr stanza if any 
-    let mut lines_updated: Vec<&str> = clean_akr_config_lines.split("\n").collect();
-    let kr_start = lines_updated
-        .iter_mut()
-        .position(|s| s.as_bytes() == BEGIN_KR_STANZA.as_bytes());
-    let kr_end = lines_updated
-        .iter_mut()
-        .position(|s| s.contains(KR_PROXY_COMMAND_STANZA));
-
-    let mut clean_config = match (kr_start, kr_end) {
-        (Some(kr_start), Some(kr_end)) => vec![&lines_updated[..kr_start], &lines_updated[(kr_end + 1)..]]
-            .concat()
-            .join("\n"),
-        _ => lines_updated.join("\n"),
+/// Write the stanza for `host` that offers it only `keys`, or the one key there is, so a server doesn't
+/// give up with "Too many authentication failures" before ssh gets to the right one of many
+pub fn update_host_config(path: &Path, host: &str, keys: &[String], dry_run: bool) -> Result<(), Error> {
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(Error::InvalidHostPattern(host.to_string()));
+    }
+    let handles = match keys.is_empty() {
+        true => vec![sshsig::find_key_pair_handle(None)?],
+        false => keys
+            .iter()
+            .map(|key| sshsig::find_key_pair_handle(Some(key)))
+            .collect::<Result<Vec<_>, _>>()?,
     };
+    let mut identity_files = vec![];
+    for handle in &handles {
+        let path = match dry_run {
+            true => StoredIdentity::identity_file_path(handle)?,
+            false => StoredIdentity::store_identity_file(handle)?,
+        };
+        identity_files.push(config_path(&path));
+    }
+
+    let block = ssh_config::host_block(host, &config_socket_path()?, &identity_files);
+    let mut files = ssh_config::load_all(path)?;
+    manage(&mut files, Some(host), &block, false);
+    save(&files, dry_run)
+}
+
+/// Have ssh run `akr host-context` before it connects, which tells the agent the host, user and port the
+/// phone/tablet shows with the sign request
+pub fn update_host_context(path: &Path, dry_run: bool) -> Result<(), Error> {
+    let akr = config_path(&std::env::current_exe()?);
+    // ssh runs it with the user's shell
+    #[cfg(unix)]
+    let akr = format!("'{}'", akr.replace('\'', "'\\''"));
+    let command = format!("{} {}", akr, host_context::COMMAND);
+
+    let block = ssh_config::host_context_block(&command);
+    let mut files = ssh_config::load_all(path)?;
+    manage(&mut files, Some(ssh_config::HOST_CONTEXT), &block, true);
+    save(&files, dry_run)
+}
+
+/// Put `block` where the one for `host` is, in the config or a file it includes, and take it out of the others.
+/// A new one goes at the top of the config, or at its end if `last`
+fn manage(files: &mut [ConfigFile], host: Option<&str>, block: &[String], last: bool) {
+    let managing = files.iter().position(|file| file.has_managed(host)).unwrap_or(0);
+    for (i, file) in files.iter_mut().enumerate() {
+        match i == managing {
+            true if last => file.set_managed_last(host, block),
+            true => file.set_managed(host, block),
+            false => {
+                file.remove_managed(host);
+            }
+        }
+    }
+}
+
+/// Take our stanzas out of the config and the files it includes, only the one for `host` if given
+pub fn remove_ssh_config(path: &Path, host: Option<&str>, dry_run: bool) -> Result<(), Error> {
+    let mut files = ssh_config::load_all(path)?;
+    let mut removed = false;
+    for file in &mut files {
+        removed |= match host {
+            Some(host) => file.remove_managed(Some(host)),
+            None => file.remove_all_managed(),
+        };
+    }
+    match host {
+        _ if removed => {}
+        Some(host) => println!("{} has no akr config for {}", path.display(), host),
+        None => println!("{} has no akr config", path.display()),
+    }
+    save(&files, dry_run)
+}
 
-    clean_config.push_str(&create_ssh_config_stanza()?);
-    Ok(std::fs::write(&path, clean_config)?)
+/// Write the files that changed, or print how they would with `dry_run`
+fn save(files: &[ConfigFile], dry_run: bool) -> Result<(), Error> {
+    for file in files.iter().filter(|file| file.changed()) {
+        match dry_run {
+            true => print!("{}", file.diff()),
+            false => file.save()?,
+        }
+    }
+    if dry_run && !files.iter().any(ConfigFile::changed) {
+        println!("The ssh config is up to date");
+    }
+    Ok(())
 }
@@ -1,29 +1,54 @@
+use crate::audit;
 use crate::client::Client;
+use crate::config;
+use crate::confirmation::LocalConfirmation;
+use crate::control::{self, AgentState, ControlRequest};
+use crate::crypto::{self, PassphraseHash};
+use crate::host_context::HostContext;
+use crate::key_index::KeyIndex;
+use crate::metrics::METRICS;
+use crate::notification::{self, Outcome};
+use crate::policy::{self, ApprovalWindow, ForwardingAction, Policy, Target};
 use crate::prompt::PasswordPrompt;
-use crate::protocol::{AuthenticateRequest, AuthenticateResponse, Base64Buffer, RequestBody};
-use crate::ssh_format::{SshKey, SshWirePublicKey};
+use crate::protocol::{
+    AuthenticateRequest, AuthenticateResponse, Base64Buffer, Capabilities, Extensions, ListKeysRequest,
+    ListKeysResponse, RequestBody,
+};
+use crate::rate_limit::{RateLimiter, Throttled};
+use crate::ssh_format::{verify_sk_signature, verify_ssh_signature, SkKeyType, SshKey, SshWirePublicKey};
+use crate::team_policy::{self, TeamPolicy};
 use crate::{
     error::*,
-    util::{read_data, read_string},
+    util::{read_data, read_string, read_string_at_most, MAX_COMMENT_LEN, MAX_NAME_LEN},
 };
-use crate::{identity::StoredIdentity, ssh_format::SshFido2KeyPairHandle};
+use crate::{identity::StoredIdentity, recorder::Recording, ssh_format::SshFido2KeyPairHandle};
 use async_trait::async_trait;
+use base64::Engine;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use eagre_asn1::der::DER;
 use eagre_asn1::der_sequence;
+use futures::FutureExt;
 use osshkeys::PrivateParts;
 use ssh_agent::error::HandleResult;
 use ssh_agent::Identity;
 use ssh_agent::Response;
 use ssh_agent::SSHAgentHandler;
-use std::collections::HashMap;
+use ssh_agent::{ConnectionId, PeerCredentials, Reply, Request};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{
     io::{Cursor, Write},
     vec,
 };
+use tokio::task::{JoinError, JoinHandle};
+use tokio::{sync::Mutex, time::Instant};
+use tracing::Instrument;
+use zeroize::Zeroizing;
 
 #[derive(Debug)]
 struct ECDSASign {
@@ -37,19 +62,298 @@ eagre_asn1::der_sequence! {
         s: NOTAG TYPE Vec<u8>,
 }
 
+/// Constraints that can follow the key in SSH_AGENTC_ADD_ID_CONSTRAINED
+/// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#section-4.2.6
+#[derive(Debug)]
+enum KeyConstraint {
+    Lifetime(Duration),
+    Confirm,
+    Extension(String),
+}
+
+impl KeyConstraint {
+    const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+    const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+    const SSH_AGENT_CONSTRAIN_EXTENSION: u8 = 255;
+
+    /// read all the constraints remaining in the buffer
+    fn read_all(cursor: &mut Cursor<Vec<u8>>) -> Result<Vec<Self>, Error> {
+        let mut constraints = vec![];
+        while (cursor.position() as usize) < cursor.get_ref().len() {
+            let constraint = match cursor.read_u8()? {
+                Self::SSH_AGENT_CONSTRAIN_LIFETIME => {
+                    KeyConstraint::Lifetime(Duration::from_secs(cursor.read_u32::<BigEndian>()? as u64))
+                }
+                Self::SSH_AGENT_CONSTRAIN_CONFIRM => KeyConstraint::Confirm,
+                Self::SSH_AGENT_CONSTRAIN_EXTENSION => {
+                    let name = read_string_at_most(cursor, MAX_NAME_LEN)?;
+                    let _details = read_data(cursor)?;
+                    KeyConstraint::Extension(name)
+                }
+                other => return Err(Error::UnsupportedKeyConstraint(other)),
+            };
+            constraints.push(constraint);
+        }
+        Ok(constraints)
+    }
+}
+
+/// The SSH_MSG_USERAUTH_REQUEST ssh has the agent sign for a "publickey" login
+/// https://datatracker.ietf.org/doc/html/rfc4252#section-7
+#[derive(Debug, Clone)]
+struct UserauthRequest {
+    session_id: Vec<u8>,
+    user: String,
+    /// "ssh-connection" for a login
+    service: String,
+    method: String,
+    /// the public key algorithm, for the "publickey" method
+    algorithm: Option<String>,
+}
+
+impl UserauthRequest {
+    /// key of the `AuthenticateRequest` extension carrying this
+    const EXTENSION: &'static str = "akr_userauth";
+    const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+
+    /// None for data that isn't a userauth request, e.g. an SSHSIG for git
+    fn parse(data: &[u8]) -> Option<Self> {
+        let mut cursor = Cursor::new(data.to_vec());
+        let session_id = read_data(&mut cursor).ok()?;
+        if cursor.read_u8().ok()? != Self::SSH_MSG_USERAUTH_REQUEST {
+            return None;
+        }
+        let user = read_string(&mut cursor).ok()?;
+        let service = read_string(&mut cursor).ok()?;
+        let method = read_string(&mut cursor).ok()?;
+        // boolean TRUE, then the algorithm and the public key
+        let algorithm = match method.as_str() {
+            "publickey" => cursor.read_u8().ok().and_then(|_| read_string(&mut cursor).ok()),
+            _ => None,
+        };
+        Some(UserauthRequest {
+            session_id,
+            user,
+            service,
+            method,
+            algorithm,
+        })
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "user": self.user,
+            "service": self.service,
+            "method": self.method,
+            "algorithm": self.algorithm,
+            "session_id": hex::encode(&self.session_id),
+        })
+    }
+}
+
+/// A connection bound to an ssh session with session-bind@openssh.com
+/// https://github.com/openssh/openssh-portable/blob/master/PROTOCOL.agent
+#[derive(Debug)]
+struct SessionBind {
+    host_key: Vec<u8>,
+    session_id: Vec<u8>,
+    forwarded: bool,
+}
+
+impl SessionBind {
+    const EXTENSION: &'static str = "session-bind@openssh.com";
+    /// key of the `AuthenticateRequest` extension telling a request was forwarded
+    const FORWARDED_EXTENSION: &'static str = "akr_forwarded";
+    /// same limit as OpenSSH's agent
+    const MAX_PER_CONNECTION: usize = 16;
+
+    fn read(cursor: &mut Cursor<Vec<u8>>) -> Result<(Self, Vec<u8>), Error> {
+        let host_key = read_data(cursor)?;
+        let session_id = read_data(cursor)?;
+        let signature = read_data(cursor)?;
+        let forwarded = cursor.read_u8()? != 0;
+
+        Ok((
+            SessionBind {
+                host_key,
+                session_id,
+                forwarded,
+            },
+            signature,
+        ))
+    }
+}
+
+/// The local process behind a connection, shown on the phone with sign requests
+#[derive(Debug, Clone, serde::Serialize)]
+struct Requester {
+    pid: Option<i32>,
+    uid: u32,
+    command: Option<String>,
+    hostname: String,
+}
+
+impl Requester {
+    /// key of the `AuthenticateRequest` extension carrying this
+    const EXTENSION: &'static str = "akr_requester";
+
+    fn new(peer: PeerCredentials) -> Self {
+        Requester {
+            pid: peer.pid,
+            uid: peer.uid,
+            command: peer.pid.and_then(process_command_line),
+            hostname: whoami::hostname(),
+        }
+    }
+}
+
+/// The lock of `ssh-add -x`, shared with the hashing of passphrases that runs without holding the agent
+#[derive(Default)]
+struct PassphraseLock {
+    locked: bool,
+    /// the passphrase it was locked with, None while it's still being hashed
+    hash: Option<PassphraseHash>,
+    /// an `ssh-add -X` passphrase is being checked, the others are refused until it's done
+    checking: bool,
+    /// wrong passphrases given to `ssh-add -X` in a row, and when the next one is looked at
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
 pub struct Agent {
-    pub client: Client,
-    identities: HashMap<SshWirePublicKey, SshFido2KeyPairHandle>,
+    pub client: Arc<Client>,
+    identities: KeyIndex,
+    /// the keys of the store on disk and those added with ssh-add, as last loaded
+    store_keys: Vec<SshFido2KeyPairHandle>,
+    /// a load of the store that didn't finish yet, see `refresh_identities`
+    store_load: Option<JoinHandle<Result<Vec<SshFido2KeyPairHandle>, Error>>>,
+    /// identities added with a lifetime, these only live in memory
+    /// and get dropped by a background task once they expire
+    constrained_identities: Arc<Mutex<HashMap<SshWirePublicKey, (SshFido2KeyPairHandle, Instant)>>>,
     ssh_keys: Vec<SshKey>,
+    /// local keys stored by the pending replies of `add_local_key`, moved into `ssh_keys` by the next request
+    added_local_keys: Arc<std::sync::Mutex<Vec<SshKey>>>,
+    /// audit events of the request being handled, written once the handler is unlocked, see `audited`
+    deferred_audit: Vec<(&'static str, &'static str, serde_json::Value)>,
+    /// whether software keys added with ssh-add are kept (see `enable_local_keys`)
+    local_keys: bool,
+    /// how long to wait for a sign request to be approved on the phone
+    sign_timeout: Duration,
+    /// refuse signatures whose counter didn't increase, instead of only warning
+    strict_sign_counter: bool,
+    /// ask at this computer before sending a sign request to the phone
+    local_confirmation: Option<LocalConfirmation>,
+    /// how long the keys listed by the phone stay fresh, `None` to never ask
+    device_keys_ttl: Option<Duration>,
+    /// the keys last listed by the phone and when they were asked for
+    device_keys: Option<(Instant, Vec<SshFido2KeyPairHandle>)>,
+    /// the phone didn't answer when last asked for its keys
+    device_keys_failed: bool,
+    /// the phone is being asked for its keys
+    device_keys_refresh: Option<JoinHandle<Result<Vec<SshFido2KeyPairHandle>, Error>>>,
+    /// serializes the phone round trips of each key
+    sign_queues: HashMap<SshWirePublicKey, Arc<Mutex<()>>>,
+    /// who is on the other end of the open connections
+    requesters: HashMap<ConnectionId, Requester>,
+    /// accept connections from processes of other users, see `connection_opened`
+    allow_other_users: bool,
+    /// session bindings of the open connections, in the order they were made
+    session_binds: HashMap<ConnectionId, Vec<SessionBind>>,
+    /// locked by `ssh-add -x`, which only `ssh-add -X` lifts
+    passphrase_lock: Arc<std::sync::Mutex<PassphraseLock>>,
+    /// locked by `akr lock`, which only `akr unlock` lifts, see `control`
+    control_locked: bool,
+    /// set once the identity store on disk changed, see `watch_store`
+    store_changed: Arc<AtomicBool>,
+    /// the keys `ssh-add -D` removed, they stay on disk but out of the agent until added again, see
+    /// `StoredIdentity::mark_removed`
+    unloaded: HashSet<SshWirePublicKey>,
+    /// unix time, for `akr status`
+    started_at: i64,
+    rate_limiter: RateLimiter,
 }
 
 impl Agent {
+    pub const DEFAULT_SIGN_TIMEOUT: Duration = Duration::from_secs(60);
+    /// remind the user to look at their phone when approval takes longer than this
+    const APPROVAL_REMINDER_DELAY: Duration = Duration::from_secs(5);
+    /// don't keep `ssh-add -l` hanging on an unreachable phone
+    const LIST_KEYS_TIMEOUT: Duration = Duration::from_secs(10);
+    /// how often `watch_store` looks at the identity store
+    const STORE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+    /// how long `ssh-add -l` waits for the store, e.g. behind a locked keychain, before it gets the keys
+    /// loaded before
+    const STORE_LOAD_TIMEOUT: Duration = Duration::from_secs(1);
+    /// how long `ssh-add -X` is refused after a wrong passphrase, doubled for every one after it
+    const UNLOCK_BASE_DELAY: Duration = Duration::from_secs(1);
+    const UNLOCK_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+    /// added to the comments of listed keys that may be out of date
+    const STALE_COMMENT: &'static str = " (stale)";
+    /// the providers `ssh-add -s` reloads the keys of akr with, e.g. `ssh-add -s "$(command -v akr)"`: the akr
+    /// binary or its PKCS#11 module, ssh-add only sends paths of files that exist
+    const SMARTCARD_PROVIDERS: &'static [&'static str] = &[
+        "akr",
+        "akr.exe",
+        "libakr_pkcs11.so",
+        "libakr_pkcs11.dylib",
+        "akr_pkcs11.dll",
+    ];
+
     pub fn new(client: Client) -> Self {
-        Agent {
-            client,
-            identities: HashMap::new(),
+        let mut agent = Agent {
+            client: Arc::new(client),
+            identities: KeyIndex::default(),
+            store_keys: Vec::new(),
+            store_load: None,
+            constrained_identities: Arc::new(Mutex::new(HashMap::new())),
             ssh_keys: Vec::new(),
+            added_local_keys: Arc::new(std::sync::Mutex::new(Vec::new())),
+            deferred_audit: Vec::new(),
+            local_keys: false,
+            sign_timeout: Self::DEFAULT_SIGN_TIMEOUT,
+            strict_sign_counter: false,
+            local_confirmation: None,
+            device_keys_ttl: None,
+            device_keys: None,
+            device_keys_failed: false,
+            device_keys_refresh: None,
+            sign_queues: HashMap::new(),
+            requesters: HashMap::new(),
+            allow_other_users: false,
+            session_binds: HashMap::new(),
+            passphrase_lock: Arc::default(),
+            control_locked: false,
+            // the store is loaded when the keys are first needed
+            store_changed: Arc::new(AtomicBool::new(true)),
+            unloaded: HashSet::new(),
+            started_at: chrono::Utc::now().timestamp(),
+            rate_limiter: RateLimiter::from_config(),
+        };
+
+        match StoredIdentity::load_removed_keys() {
+            Ok(removed) => agent.unloaded = removed,
+            Err(e) => eprintln!("couldn't load the keys removed with ssh-add -D: {}", e),
         }
+
+        // reload the identities previously added with ssh-add
+        match StoredIdentity::load_added_key_pair_handles() {
+            Ok(handles) => {
+                for handle in handles {
+                    match handle.fmt_public_key() {
+                        Ok(pubkey) => {
+                            agent.store_keys.push(handle.clone());
+                            if !agent.unloaded.contains(&pubkey) {
+                                agent.identities.insert(pubkey, handle);
+                            }
+                        }
+                        Err(e) => eprintln!("couldn't load added identity: {}", e),
+                    }
+                }
+            }
+            Err(e) => eprintln!("couldn't load added identities: {}", e),
+        }
+
+        agent
     }
 
     pub fn preload_user_keys_from_dir<P: AsRef<Path>>(&mut self, key_dir: P) {
@@ -81,6 +385,13 @@ impl Agent {
                 let priv_path = path.with_extension("");
                 match priv_path.metadata() {
                     Ok(_) => match SshKey::from_paths(&path, priv_path) {
+                        Ok(key) if self.unloaded.contains(key.pub_key_blob()) => {
+                            println!(
+                                "skipped '{}' from {}, removed with ssh-add -D",
+                                key.comment(),
+                                path.display()
+                            );
+                        }
                         Ok(key) => {
                             println!(
                                 "successfully preloaded public key '{}' from {}",
@@ -94,11 +405,7 @@ impl Agent {
                         }
                     },
                     Err(e) => {
-                        eprintln!(
-                            "{} has no associated private key file ({})",
-                            path.display(),
-                            e
-                        );
+                        eprintln!("{} has no associated private key file ({})", path.display(), e);
                     }
                 }
             }
@@ -111,89 +418,687 @@ impl Agent {
         );
     }
 
-    async fn sign_fido2(
-        &mut self,
-        pubkey: Vec<u8>,
-        data: Vec<u8>,
-        _flags: u32,
-    ) -> HandleResult<Response> {
-        // try to find the matching key handle
-        let id = self
-            .identities
-            .iter()
-            .filter(|(pk, _)| pk.as_slice() == pubkey.as_slice())
-            .next()
-            .map(|id| id.1);
-        let rp_id = if let Some(ref id) = &id {
-            id.application.clone()
-        } else {
-            // parse the rp_id from the public key
-            let rp_id = SshFido2KeyPairHandle::parse_application_from_public_key(pubkey)?;
-            if !rp_id.starts_with("ssh:") {
-                return Err(Error::BadRpPrefix)?;
-            }
-            rp_id
-        };
+    pub fn set_sign_timeout(&mut self, sign_timeout: Duration) {
+        self.sign_timeout = sign_timeout;
+    }
 
-        //pop a notification
-        let rp_id_clone = rp_id.clone();
+    pub fn set_allow_other_users(&mut self, allow_other_users: bool) {
+        self.allow_other_users = allow_other_users;
+    }
+
+    pub fn set_strict_sign_counter(&mut self, strict_sign_counter: bool) {
+        self.strict_sign_counter = strict_sign_counter;
+    }
+
+    pub fn set_local_confirmation(&mut self, local_confirmation: Option<LocalConfirmation>) {
+        self.local_confirmation = local_confirmation;
+    }
+
+    /// Reload the keys when the identity store on disk changes, e.g. after `akr load` or `akr rename`,
+    /// so the agent doesn't have to be restarted
+    pub fn watch_store(&self) {
+        let store_changed = self.store_changed.clone();
         tokio::spawn(async move {
-            show_notification(&rp_id_clone);
+            let mut stamp = StoredIdentity::change_stamp();
+            loop {
+                tokio::time::sleep(Self::STORE_POLL_INTERVAL).await;
+                let current = StoredIdentity::change_stamp();
+                if current != stamp {
+                    tracing::debug!("the identity store changed, reloading the keys");
+                    store_changed.store(true, Ordering::Relaxed);
+                    stamp = current;
+                }
+            }
         });
+    }
 
-        let challenge_hash = sodiumoxide::crypto::hash::sha256::hash(data.as_slice())
-            .0
-            .to_vec();
+    /// the keys of the store on disk and those added with ssh-add
+    fn load_store() -> Result<Vec<SshFido2KeyPairHandle>, Error> {
+        let mut ids = StoredIdentity::load_from_disk()?.key_pair_handles;
+        ids.extend(StoredIdentity::load_added_key_pair_handles()?);
+        Ok(ids)
+    }
 
-        // get the signature from the client
-        let resp: AuthenticateResponse = self
-            .client
-            .send_request(RequestBody::Authenticate(AuthenticateRequest {
-                challenge: Base64Buffer(challenge_hash),
-                rp_id,
-                extensions: None,
-                key_handle: id.map(|id| id.key_handle.clone()).map(Base64Buffer),
-                key_handles: None,
-            }))
-            .await?;
+    /// replace the keys of the store with what is on disk now, plus `device_keys`
+    fn load_identities(&mut self, device_keys: Vec<SshFido2KeyPairHandle>) -> Result<(), Error> {
+        self.store_changed.store(false, Ordering::Relaxed);
+        self.store_keys = Self::load_store()?;
+        self.index_identities(device_keys)
+    }
 
-        let flags = resp.get_auth_flags()?;
-        /* parse the asn.1 signature into ssh format
+    fn index_identities(&mut self, device_keys: Vec<SshFido2KeyPairHandle>) -> Result<(), Error> {
+        let mut ids = self.store_keys.clone();
+        ids.extend(device_keys);
+        self.identities = KeyIndex::from_handles(ids)?;
+        for pubkey in &self.unloaded {
+            self.identities.remove(pubkey);
+        }
+        Ok(())
+    }
 
-           ecdsa signature
-               mpint		r
-               mpint		s
-        */
-        let asn1_sig = ECDSASign::der_from_bytes(resp.signature.0)?;
-        let mut signature: Vec<u8> = Vec::new();
+    /// Bring the keys up to date for a listing without waiting on the phone or a locked keychain: the store
+    /// is loaded in the background and only waited for `STORE_LOAD_TIMEOUT`, the phone is asked for its keys
+    /// in the background. Returns whether the keys may be out of date
+    async fn refresh_identities(&mut self) -> Result<bool, Error> {
+        let (device_keys_changed, device_keys_stale) = self.poll_device_keys();
+        if self.store_load.is_none() && self.store_changed.swap(false, Ordering::Relaxed) {
+            self.store_load = Some(tokio::task::spawn_blocking(Self::load_store));
+        }
 
-        signature.write_u32::<BigEndian>(asn1_sig.r.len() as u32)?;
-        signature.write_all(asn1_sig.r.as_slice())?;
+        let mut store_stale = false;
+        let mut store_loaded = false;
+        if let Some(load) = self.store_load.as_mut() {
+            match tokio::time::timeout(Self::STORE_LOAD_TIMEOUT, load).await {
+                Ok(loaded) => {
+                    self.store_load = None;
+                    match joined(loaded) {
+                        Ok(keys) => {
+                            self.store_keys = keys;
+                            store_loaded = true;
+                        }
+                        Err(e) => {
+                            eprintln!("couldn't load the identity store: {}", e);
+                            // try again with the next listing
+                            self.store_changed.store(true, Ordering::Relaxed);
+                            store_stale = true;
+                        }
+                    }
+                }
+                Err(_) => store_stale = true,
+            }
+        }
 
-        signature.write_u32::<BigEndian>(asn1_sig.s.len() as u32)?;
-        signature.write_all(asn1_sig.s.as_slice())?;
+        // a change of the store still to load may be missing from `store_keys`, its load brings the keys
+        // of the phone along
+        let store_pending = self.store_load.is_some() || self.store_changed.load(Ordering::Relaxed);
+        if store_loaded || (device_keys_changed && !store_pending) {
+            let device_keys = self
+                .device_keys
+                .as_ref()
+                .map(|(_, keys)| keys.clone())
+                .unwrap_or_default();
+            self.index_identities(device_keys)?;
+        }
+        Ok(store_stale || device_keys_stale)
+    }
 
-        /*
-           string		"sk-ecdsa-sha2-nistp256@openssh.com"
-           string		ecdsa_signature
-           byte		    flags
-           uint32		counter
+    fn is_akr_provider(provider: &str) -> bool {
+        let name = Path::new(provider).file_name().and_then(OsStr::to_str);
+        name.is_some_and(|name| Self::SMARTCARD_PROVIDERS.contains(&name))
+    }
 
-           https://github.com/openssh/openssh-portable/blob/master/PROTOCOL.u2f
-        */
-        let mut data: Vec<u8> = vec![];
+    /// pick up the changes `watch_store` noticed, with the keys last listed by the phone
+    fn reload_identities_if_changed(&mut self) {
+        if !self.store_changed.load(Ordering::Relaxed) {
+            return;
+        }
+        let device_keys = self
+            .device_keys
+            .as_ref()
+            .map(|(_, keys)| keys.clone())
+            .unwrap_or_default();
+        if let Err(e) = self.load_identities(device_keys) {
+            eprintln!("couldn't reload the identity store: {}", e);
+        }
+    }
+
+    /// Ask the paired devices for their current keys when ssh lists identities,
+    /// reusing the answer for `ttl`
+    pub fn enable_device_keys_refresh(&mut self, ttl: Duration) {
+        self.device_keys_ttl = Some(ttl);
+    }
 
-        const SIG_TYPE_ID: &'static str = "sk-ecdsa-sha2-nistp256@openssh.com";
-        data.write_u32::<BigEndian>(SIG_TYPE_ID.len() as u32)?;
-        data.write_all(SIG_TYPE_ID.as_bytes())?;
+    /// Take the keys the phone listed in the background, and ask it again once the ones it listed are older
+    /// than the ttl. Returns whether they changed, and whether they may be out of date
+    fn poll_device_keys(&mut self) -> (bool, bool) {
+        let ttl = match self.device_keys_ttl {
+            Some(ttl) => ttl,
+            None => return (false, false),
+        };
 
-        data.write_u32::<BigEndian>(signature.len() as u32)?;
-        data.write_all(&signature)?;
+        let mut changed = false;
+        if let Some(listed) = self
+            .device_keys_refresh
+            .as_mut()
+            .and_then(|refresh| refresh.now_or_never())
+        {
+            self.device_keys_refresh = None;
+            let keys = match joined(listed) {
+                Ok(keys) => {
+                    changed = true;
+                    keys
+                }
+                Err(e) => {
+                    // keep the previous answer, and don't retry before the ttl is up again
+                    eprintln!("couldn't list keys from device: {}", e);
+                    self.device_keys.take().map(|(_, keys)| keys).unwrap_or_default()
+                }
+            };
+            self.device_keys_failed = !changed;
+            self.device_keys = Some((Instant::now(), keys));
+        }
 
-        data.write_u8(flags)?;
-        data.write_u32::<BigEndian>(resp.counter)?;
+        let fresh = matches!(&self.device_keys, Some((fetched_at, _)) if fetched_at.elapsed() < ttl);
+        if !fresh && self.device_keys_refresh.is_none() {
+            let client = self.client.clone();
+            self.device_keys_refresh = Some(tokio::spawn(async move {
+                let resp = client
+                    .send_request_with_timeout::<ListKeysResponse>(
+                        RequestBody::ListKeys(ListKeysRequest {}),
+                        Self::LIST_KEYS_TIMEOUT,
+                    )
+                    .await?;
+                // the phone lists its WebAuthn credentials too
+                Ok(resp
+                    .sk_accounts
+                    .into_iter()
+                    .filter(|account| account.rp_id.starts_with("ssh:"))
+                    .map(SshFido2KeyPairHandle::from)
+                    .collect())
+            }));
+        }
+        (changed, !fresh || self.device_keys_failed)
+    }
+
+    /// Accept regular ed25519/ecdsa keys from ssh-add and sign with them locally.
+    /// They get encrypted with a passphrase from pinentry and stored in "~/.akr/local"
+    pub fn enable_local_keys(&mut self) {
+        self.local_keys = true;
+
+        match StoredIdentity::local_keys_dir_path() {
+            Ok(dir) if dir.exists() => self.preload_user_keys_from_dir(&dir),
+            Ok(_) => {}
+            Err(e) => eprintln!("couldn't determine local keys dir: {}", e),
+        }
+    }
+
+    /// Encrypt and store a software key sent with SSH_AGENTC_ADD_IDENTITY, it is kept unlocked in memory until
+    /// the agent restarts. pinentry asks for the password once the handler is unlocked, so other connections
+    /// are still served meanwhile
+    fn add_local_key(&mut self, key_type: String, cursor: &mut Cursor<Vec<u8>>) -> Result<Reply, Error> {
+        let (pub_blob, priv_key) = crate::ssh_format::parse_software_key(&key_type, cursor)?;
+        let priv_key = Zeroizing::new(priv_key);
+        let comment = read_string_at_most(cursor, MAX_COMMENT_LEN)?;
+
+        // constraints can't be honored for a key that lives on disk
+        if !KeyConstraint::read_all(cursor)?.is_empty() {
+            eprintln!("add error: constraints are not supported for local keys");
+            return Ok(Response::Failure.into());
+        }
+
+        let added_local_keys = self.added_local_keys.clone();
+        Ok(Reply::Later(Box::pin(async move {
+            let stored = tokio::task::spawn_blocking(move || {
+                Self::store_local_key(key_type, pub_blob, &priv_key, comment)
+            })
+            .await;
+            match joined(stored)? {
+                Some(key) => {
+                    added_local_keys.lock().unwrap().push(key);
+                    Ok(Response::Success)
+                }
+                None => Ok(Response::Failure),
+            }
+        })))
+    }
+
+    /// ask for a password, encrypt the key with it into "~/.akr/local" and unlock it, None without a password
+    fn store_local_key(
+        key_type: String,
+        pub_blob: Vec<u8>,
+        priv_key: &[u8],
+        comment: String,
+    ) -> Result<Option<SshKey>, Error> {
+        let pem = Zeroizing::new(crate::ssh_format::openssh_private_key_pem(
+            &pub_blob, priv_key, &comment,
+        )?);
+        let keypair = osshkeys::KeyPair::from_keystr(&pem, None)?;
+
+        let mut password_buffer = Zeroizing::new([0u8; 128]);
+        let len = PasswordPrompt::new(comment.clone())
+            .with_description(format!(
+                "Enter a password to encrypt the SSH key '{}' stored by akr",
+                comment
+            ))
+            .invoke(&mut *password_buffer)?;
+        let passphrase = Zeroizing::new(String::from_utf8(password_buffer[..len].to_vec())?);
+        let passphrase = passphrase.trim();
+        if passphrase.is_empty() {
+            eprintln!("add error: a password is required to store a local key");
+            return Ok(None);
+        }
+
+        let encrypted = match keypair.keytype() {
+            osshkeys::KeyType::ED25519 => {
+                keypair.serialize_openssh(Some(passphrase), osshkeys::cipher::Cipher::Aes256_Ctr)?
+            }
+            _ => keypair.serialize_pem(Some(passphrase))?,
+        };
+        let (pub_path, priv_path) =
+            StoredIdentity::store_local_key(&pub_blob, &encrypted, &keypair.serialize_publickey()?)?;
+        StoredIdentity::unmark_removed(&pub_blob)?;
+
+        let mut key = SshKey::from_paths(pub_path, priv_path)?;
+        match keypair.keytype() {
+            osshkeys::KeyType::ED25519 => key.keypair = Some(keypair),
+            _ => {
+                key.unlock_with(
+                    |buf| {
+                        let len = passphrase.len().min(buf.len());
+                        buf[..len].copy_from_slice(&passphrase.as_bytes()[..len]);
+                        Ok(len)
+                    },
+                    key_type,
+                )?;
+            }
+        }
+        Ok(Some(key))
+    }
+
+    /// take the local keys `add_local_key` stored since the last request into `ssh_keys`
+    fn take_added_local_keys(&mut self) {
+        let added = std::mem::take(&mut *self.added_local_keys.lock().unwrap());
+        for key in added {
+            self.unloaded.remove(key.pub_key_blob());
+            self.ssh_keys
+                .retain(|existing| existing.pub_key_blob() != key.pub_key_blob());
+            self.ssh_keys.push(key);
+        }
+    }
+
+    /// Record a session-bind@openssh.com binding for the connection
+    /// the rules follow OpenSSH's process_ext_session_bind
+    fn session_bind(&mut self, connection: ConnectionId, contents: Vec<u8>) -> Result<Response, Error> {
+        let (bind, signature) = SessionBind::read(&mut Cursor::new(contents))?;
+        if !verify_ssh_signature(&bind.host_key, &bind.session_id, &signature)? {
+            eprintln!("session bind error: bad host key signature");
+            return Ok(Response::ExtensionFailure);
+        }
+
+        let binds = self.session_binds.entry(connection).or_default();
+        if let Some(existing) = binds.iter().find(|b| b.session_id == bind.session_id) {
+            if existing.host_key == bind.host_key && existing.forwarded == bind.forwarded {
+                return Ok(Response::Success);
+            }
+            eprintln!("session bind error: session id bound to another host key");
+            return Ok(Response::ExtensionFailure);
+        }
+        // a connection that ssh used to authenticate must not be reused for another session
+        if binds.last().is_some_and(|last| !last.forwarded) {
+            eprintln!("session bind error: connection already used for authentication");
+            return Ok(Response::ExtensionFailure);
+        }
+        if binds.len() >= SessionBind::MAX_PER_CONNECTION {
+            eprintln!("session bind error: too many bindings");
+            return Ok(Response::ExtensionFailure);
+        }
 
-        Ok(Response::SignResponse { signature: data })
+        binds.push(bind);
+        Ok(Response::Success)
+    }
+
+    /// refuse to sign an authentication request for a different session than
+    /// the one this connection was bound to, i.e. a hijacked forwarded agent
+    fn session_permits(&self, connection: ConnectionId, data: &[u8]) -> bool {
+        let last = match self.session_binds.get(&connection).and_then(|b| b.last()) {
+            Some(last) if !last.forwarded => last,
+            _ => return true,
+        };
+
+        match userauth_session_id(data) {
+            Some(session_id) => session_id == last.session_id,
+            None => true,
+        }
+    }
+
+    /// Whether requests on `connection` come from another host, through a forwarded agent. Only ssh clients
+    /// that send session-bind@openssh.com (OpenSSH 8.9 and later) tell
+    fn is_forwarded(&self, connection: ConnectionId) -> bool {
+        self.session_binds
+            .get(&connection)
+            .is_some_and(|binds| binds.iter().any(|bind| bind.forwarded))
+    }
+
+    /// What `akr policy forwarding` says about forwarded requests for `pubkey`
+    fn forwarding_action(pubkey: &[u8]) -> ForwardingAction {
+        let rp_id = SshFido2KeyPairHandle::parse_application_from_public_key(pubkey.to_vec()).ok();
+        match Policy::load() {
+            Ok(policy) => policy.forwarding(&fingerprint(pubkey), rp_id.as_deref()),
+            // the rules may be there to refuse these
+            Err(e) => {
+                eprintln!("couldn't read the policy, refusing the forwarded request: {}", e);
+                ForwardingAction::Deny
+            }
+        }
+    }
+
+    /// tell that `akr policy forwarding` refuses a forwarded request for `pubkey`, the details for the audit log
+    fn forwarding_denied(pubkey: &[u8]) -> serde_json::Value {
+        eprintln!(
+            "sign error: {} doesn't sign forwarded requests, see `akr policy list`",
+            fingerprint(pubkey)
+        );
+        serde_json::json!({ "key": fingerprint(pubkey), "forwarded": true })
+    }
+
+    /// The approval window of `akr policy` for the host and user of a login, if any
+    fn approval_window(target: &Target) -> Option<ApprovalWindow> {
+        let policy = match Policy::load() {
+            Ok(policy) if !policy.rules.is_empty() => policy,
+            Ok(_) => return None,
+            Err(e) => {
+                eprintln!("couldn't read the approval policy: {}", e);
+                return None;
+            }
+        };
+        let window = policy.window(target)?;
+        // the team policy may allow shorter ones, or none
+        match team_policy::current() {
+            Ok(Some(TeamPolicy {
+                max_session_minutes: Some(minutes),
+                ..
+            })) => Some(ApprovalWindow {
+                seconds: window.seconds.min(u64::from(minutes) * 60),
+                ..window
+            })
+            .filter(|window| window.seconds > 0),
+            Ok(_) => Some(window),
+            Err(_) => None,
+        }
+    }
+
+    /// the host and user a login is for, from `akr host-context` or the ssh command line, the session bind and
+    /// the userauth request
+    fn target(&self, connection: ConnectionId, data: &[u8]) -> Target {
+        let requester = self.requesters.get(&connection);
+        let command = requester.and_then(|requester| requester.command.as_deref());
+        let context = requester
+            .and_then(|requester| Some((requester.pid?, requester.command.as_deref()?)))
+            .and_then(|(pid, command)| HostContext::load(pid, command));
+        let destination = command.and_then(policy::ssh_destination);
+        let host_key = self
+            .session_binds
+            .get(&connection)
+            .and_then(|binds| binds.last())
+            .map(|bind| fingerprint(&bind.host_key));
+        let userauth = UserauthRequest::parse(data);
+        let (destination_user, destination_host) = destination.unzip();
+        match context {
+            Some(context) => Target {
+                user: userauth.map(|userauth| userauth.user).or(Some(context.user)),
+                hostname: Some(context.hostname).filter(|hostname| *hostname != context.host),
+                host: Some(context.host),
+                host_key,
+                port: context.port,
+            },
+            None => Target {
+                user: userauth
+                    .map(|userauth| userauth.user)
+                    .or(destination_user.flatten()),
+                host: destination_host,
+                host_key,
+                hostname: None,
+                port: None,
+            },
+        }
+    }
+
+    /// the queue of the sign requests for `pubkey`, the queues of keys with none pending are dropped
+    fn sign_queue(&mut self, pubkey: &[u8]) -> Arc<Mutex<()>> {
+        self.sign_queues.retain(|_, queue| Arc::strong_count(queue) > 1);
+        self.sign_queues.entry(pubkey.to_vec()).or_default().clone()
+    }
+
+    /// locked with `ssh-add -x` or `akr lock`, each is lifted on its own
+    fn is_locked(&self) -> bool {
+        self.passphrase_lock.lock().unwrap().locked || self.control_locked
+    }
+
+    /// Whether `connection` is from a process of this user on this machine, not through ssh
+    fn is_local_peer(&self, connection: ConnectionId) -> bool {
+        #[cfg(unix)]
+        let same_user = self
+            .requesters
+            .get(&connection)
+            .is_some_and(|requester| requester.uid == nix::unistd::getuid().as_raw());
+        // only the user opens the named pipe
+        #[cfg(not(unix))]
+        let same_user = true;
+        same_user && !self.session_binds.contains_key(&connection)
+    }
+
+    /// Carry out a request of the CLI, see `control`. Only for local clients of the same user
+    async fn control(&mut self, connection: ConnectionId, contents: Vec<u8>) -> Result<Response, Error> {
+        if !self.is_local_peer(connection) {
+            tracing::warn!("refused a control request from another user or through ssh");
+            return Ok(Response::Failure);
+        }
+
+        match ControlRequest::parse(&contents)? {
+            ControlRequest::Status => {}
+            ControlRequest::Reload => {
+                let device_keys = self
+                    .device_keys
+                    .as_ref()
+                    .map(|(_, keys)| keys.clone())
+                    .unwrap_or_default();
+                self.load_identities(device_keys)?;
+            }
+            // a lock of ssh-add stays until its passphrase is given
+            ControlRequest::Lock => self.control_locked = true,
+            ControlRequest::Unlock => self.control_locked = false,
+        }
+
+        let state = AgentState {
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            profile: crate::profile::current().to_string(),
+            started_at: self.started_at,
+            locked: self.is_locked(),
+            identities: self.identities.len()
+                + self.constrained_identities.lock().await.len()
+                + self.ssh_keys.len(),
+        };
+        Ok(Response::Extension(state.to_reply()?))
+    }
+
+    /// The sha256 of the key `pubkey` is, or certifies, when the agent holds it
+    async fn held_key_digest(&mut self, pubkey: &[u8]) -> Option<[u8; crypto::SHA256_LEN]> {
+        self.reload_identities_if_changed();
+        let key_type = read_string(&mut Cursor::new(pubkey.to_vec())).unwrap_or_default();
+        let key = match SkKeyType::from_cert_type_id(&key_type) {
+            Some(_) => SshFido2KeyPairHandle::parse_public_key_from_certificate(pubkey).ok()?,
+            None => pubkey.to_vec(),
+        };
+        let held = self.identities.get(&key).is_some()
+            || self.constrained_identities.lock().await.contains_key(&key)
+            || self
+                .ssh_keys
+                .iter()
+                .any(|ssh_key| ssh_key.pub_key_blob() == key.as_slice());
+        held.then(|| crypto::sha256(&key))
+    }
+
+    fn throttled(&mut self, connection: ConnectionId, pubkey: &[u8], throttled: Throttled) -> Response {
+        METRICS.sign_throttled();
+        let what = match throttled.global {
+            true => "all keys".to_string(),
+            false => fingerprint(pubkey),
+        };
+        eprintln!(
+            "sign error: too many requests for {}, refusing them for {}s",
+            what,
+            throttled.retry_in.as_secs()
+        );
+        if throttled.started {
+            let requested_by = self
+                .requesters
+                .get(&connection)
+                .and_then(|requester| requester.command.as_deref());
+            notification::throttled(requested_by.unwrap_or(&what), throttled.retry_in);
+            self.defer_audit(
+                "sign",
+                "throttled",
+                serde_json::json!({
+                    "key": fingerprint(pubkey),
+                    "global": throttled.global,
+                    "retry_in": throttled.retry_in.as_secs(),
+                }),
+            );
+        }
+        Response::Failure
+    }
+
+    /// what the audit log records about `request`, `None` for those it leaves out, e.g. session binds
+    fn audit_event(
+        &self,
+        connection: ConnectionId,
+        request: &Request,
+    ) -> Option<(&'static str, serde_json::Value)> {
+        let mut details = serde_json::Map::new();
+        let operation = match request {
+            Request::RequestIdentities => "list",
+            Request::AddIdentity { key_type, .. } => {
+                details.insert("key_type".into(), key_type.clone().into());
+                "add"
+            }
+            Request::SignRequest { pubkey_blob, .. } => {
+                details.insert("key".into(), fingerprint(pubkey_blob).into());
+                details.insert("forwarded".into(), self.is_forwarded(connection).into());
+                "sign"
+            }
+            Request::RemoveIdentity { pubkey_blob } => {
+                details.insert("key".into(), fingerprint(pubkey_blob).into());
+                "remove"
+            }
+            Request::RemoveAllIdentities => "remove_all",
+            Request::Lock { .. } => "lock",
+            Request::Unlock { .. } => "unlock",
+            Request::Extension { extension_type, .. } if extension_type == control::EXTENSION => "control",
+            _ => return None,
+        };
+        if let Some(requester) = self.requesters.get(&connection) {
+            details.insert("pid".into(), requester.pid.into());
+            details.insert("command".into(), requester.command.clone().into());
+        }
+        Some((operation, details.into()))
+    }
+
+    /// record an audit event of the request being handled once the handler is unlocked
+    fn defer_audit(&mut self, operation: &'static str, outcome: &'static str, details: serde_json::Value) {
+        self.deferred_audit.push((operation, outcome, details));
+    }
+
+    async fn audited(&mut self, connection: ConnectionId, request: Request) -> HandleResult<Reply> {
+        self.take_added_local_keys();
+        let event = self.audit_event(connection, &request);
+        let reply = ssh_agent::dispatch(self, connection, request).await;
+        let deferred = std::mem::take(&mut self.deferred_audit);
+        if event.is_none() && deferred.is_empty() {
+            return reply;
+        }
+        // the log is written after the handler is unlocked, other connections don't wait on the disk
+        Ok(Reply::Later(Box::pin(async move {
+            for (operation, outcome, details) in deferred {
+                audit::record(operation, outcome, details);
+            }
+            let response = match reply {
+                Ok(Reply::Now(response)) => Ok(response),
+                Ok(Reply::Later(pending)) => pending.await,
+                Err(e) => Err(e),
+            };
+            if let Some((operation, details)) = event {
+                audit::record(operation, audit_outcome(&response), details);
+            }
+            response
+        })))
+    }
+
+    async fn sign_fido2(
+        &mut self,
+        connection: ConnectionId,
+        pubkey: Vec<u8>,
+        data: Vec<u8>,
+        _flags: u32,
+        key_type: SkKeyType,
+    ) -> HandleResult<Reply> {
+        self.reload_identities_if_changed();
+        // try to find the matching key handle
+        let constrained_id = self
+            .constrained_identities
+            .lock()
+            .await
+            .get(&pubkey)
+            .map(|(id, _)| id.clone());
+        // only the keys the agent holds go to the phone, not those removed with ssh-add -D or any blob a
+        // client makes up
+        let Some(id) = self.identities.get(&pubkey).cloned().or(constrained_id) else {
+            eprintln!("sign error: the agent doesn't hold key {}", fingerprint(&pubkey));
+            return Ok(Response::Failure.into());
+        };
+        let rp_id = id.application.clone();
+
+        let challenge_hash = crypto::sha256(data.as_slice()).to_vec();
+
+        // let the phone show which process is asking
+        let requester = self.requesters.get(&connection).cloned();
+        if let Some(Requester {
+            command: Some(command),
+            ..
+        }) = &requester
+        {
+            eprintln!("sign request for {} from `{}`", rp_id, command);
+        }
+        tracing::info!(rp_id = %rp_id, "fido2 sign request");
+        let requested_by = requester.as_ref().and_then(|requester| requester.command.clone());
+        let mut extensions = Extensions::default();
+        let forwarded = self.is_forwarded(connection);
+        let target = self.target(connection, &data);
+        if forwarded {
+            extensions.insert(SessionBind::FORWARDED_EXTENSION, true.into());
+        }
+        if let Some(requester) = requester {
+            extensions.insert(
+                Requester::EXTENSION,
+                serde_json::to_value(requester).map_err(Error::from)?,
+            );
+        }
+        // what the phone/tablet can show about the login
+        let mut login = None;
+        if let Some(userauth) = UserauthRequest::parse(&data) {
+            extensions.insert(UserauthRequest::EXTENSION, userauth.to_json());
+            if target.host.is_some() || target.host_key.is_some() {
+                extensions.insert(
+                    Target::EXTENSION,
+                    serde_json::to_value(&target).map_err(Error::from)?,
+                );
+                login = Some(target.to_string());
+            }
+        }
+
+        // wait for the phone, and read the policy, without holding the agent, so other shells are still served
+        let pending = PendingFido2Sign {
+            client: self.client.clone(),
+            queue: self.sign_queue(&pubkey),
+            sign_timeout: self.sign_timeout,
+            strict_sign_counter: self.strict_sign_counter,
+            local_confirmation: self.local_confirmation,
+            key_type,
+            pubkey,
+            id: Some(id),
+            rp_id,
+            challenge_hash,
+            extensions: Some(extensions),
+            forwarded,
+            window_target: Some(target),
+            requested_by,
+            login,
+        };
+
+        // the signature is waited for after the handler returned, keep it in the sign request's span
+        Ok(Reply::Later(Box::pin(
+            pending.run().instrument(tracing::Span::current()),
+        )))
     }
 
     async fn sign_rsa(
@@ -203,23 +1108,18 @@ impl Agent {
         flags: u32,
         pubkey_type: String,
     ) -> HandleResult<Response> {
-        let key = self
-            .ssh_keys
-            .iter_mut()
-            .find(|key| key.pub_key_blob() == pubkey);
+        let key = self.ssh_keys.iter_mut().find(|key| key.pub_key_blob() == pubkey);
         match key {
             Some(key) => {
                 let comment = key.comment().to_string();
                 let (signature, algo) = {
-                    let (priv_key, _ecdsa_key_pair) = match key.unlock_with(
-                        |buf| Ok(PasswordPrompt::new(comment).invoke(buf)),
-                        pubkey_type,
-                    ) {
-                        Ok(p) => p,
-                        Err(_e) => {
-                            return Ok(Response::Failure);
-                        }
-                    };
+                    let (priv_key, _ecdsa_key_pair) =
+                        match key.unlock_with(|buf| ask_password(comment, buf), pubkey_type) {
+                            Ok(p) => p,
+                            Err(_e) => {
+                                return Ok(Response::Failure);
+                            }
+                        };
 
                     match priv_key.sign_rsa(&data, &flags) {
                         Ok((signature, algo)) => (signature, algo),
@@ -245,23 +1145,18 @@ impl Agent {
         flags: u32,
         pubkey_type: String,
     ) -> HandleResult<Response> {
-        let key = self
-            .ssh_keys
-            .iter_mut()
-            .find(|key| key.pub_key_blob() == pubkey);
+        let key = self.ssh_keys.iter_mut().find(|key| key.pub_key_blob() == pubkey);
         match key {
             Some(key) => {
                 let comment = key.comment().to_string();
                 let signature = {
-                    let (priv_key, ecdsa_key_pair) = match key.unlock_with(
-                        |buf| Ok(PasswordPrompt::new(comment).invoke(buf)),
-                        pubkey_type.clone(),
-                    ) {
-                        Ok(p) => p,
-                        Err(_e) => {
-                            return Ok(Response::Failure);
-                        }
-                    };
+                    let (priv_key, ecdsa_key_pair) =
+                        match key.unlock_with(|buf| ask_password(comment, buf), pubkey_type.clone()) {
+                            Ok(p) => p,
+                            Err(_e) => {
+                                return Ok(Response::Failure);
+                            }
+                        };
 
                     match priv_key.sign_ecdsa(&data, &flags, ecdsa_key_pair) {
                         Ok(signature) => signature,
@@ -296,64 +1191,367 @@ impl Agent {
         _flags: u32,
         pubkey_type: String,
     ) -> HandleResult<Response> {
-        let key = self
-            .ssh_keys
-            .iter_mut()
-            .find(|key| key.pub_key_blob() == pubkey);
+        let key = self.ssh_keys.iter_mut().find(|key| key.pub_key_blob() == pubkey);
         match key {
-            Some(key) => match &key.keypair {
-                Some(keypair) => {
-                    let signature = keypair.sign(&data.as_slice()).unwrap();
-
-                    Ok(Response::SignResponse2 {
+            Some(key) => {
+                let unlocked;
+                let keypair = match &key.keypair {
+                    Some(keypair) => keypair,
+                    None => match key.unlock_ed25519_key()? {
+                        Some(keypair) => {
+                            unlocked = keypair;
+                            &unlocked
+                        }
+                        None => return Ok(Response::Failure),
+                    },
+                };
+                match keypair.sign(&data.as_slice()) {
+                    Ok(signature) => Ok(Response::SignResponse2 {
                         algo_name: pubkey_type,
                         signature,
-                    })
+                    }),
+                    Err(e) => {
+                        eprintln!("sign error: {}", e);
+                        Ok(Response::Failure)
+                    }
                 }
-                None => {
-                    let keypair = key.unlock_ed25519_key()?;
+            }
+            None => Ok(Response::Failure),
+        }
+    }
+}
 
-                    match keypair {
-                        Some(kp) => {
-                            let signature = kp.sign(&data.as_slice()).unwrap();
+/// The part of a FIDO2 signature that waits on the phone
+pub struct PendingFido2Sign {
+    client: Arc<Client>,
+    /// sign requests for the same key are answered one at a time
+    queue: Arc<Mutex<()>>,
+    sign_timeout: Duration,
+    strict_sign_counter: bool,
+    local_confirmation: Option<LocalConfirmation>,
+    key_type: SkKeyType,
+    pubkey: SshWirePublicKey,
+    id: Option<SshFido2KeyPairHandle>,
+    rp_id: String,
+    challenge_hash: Vec<u8>,
+    extensions: Option<Extensions>,
+    /// through a forwarded agent, `akr policy forwarding` says what to do with it
+    forwarded: bool,
+    /// the login to look up an approval window of `akr policy` for, None to always ask
+    window_target: Option<Target>,
+    /// the command line of the process asking, for the notifications
+    requested_by: Option<String>,
+    /// "user@host" of the login signed for, for `akr list --verbose`
+    login: Option<String>,
+}
 
-                            Ok(Response::SignResponse2 {
-                                algo_name: pubkey_type,
-                                signature,
-                            })
-                        }
-                        None => {
-                            return Ok(Response::Failure);
-                        }
-                    }
+impl PendingFido2Sign {
+    /// sign `data` with a key handle outside of the agent, e.g. for `akr sign`
+    pub fn for_key_pair_handle(
+        client: Client,
+        handle: SshFido2KeyPairHandle,
+        data: &[u8],
+    ) -> Result<Self, Error> {
+        Ok(PendingFido2Sign {
+            client: Arc::new(client),
+            queue: Arc::new(Mutex::new(())),
+            sign_timeout: config::get()
+                .sign_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(Agent::DEFAULT_SIGN_TIMEOUT),
+            strict_sign_counter: false,
+            // `akr sign` is run by the user at the keyboard
+            local_confirmation: None,
+            key_type: handle.key_type,
+            pubkey: handle.fmt_public_key()?,
+            rp_id: handle.application.clone(),
+            id: Some(handle),
+            challenge_hash: crypto::sha256(data).to_vec(),
+            extensions: None,
+            forwarded: false,
+            window_target: None,
+            requested_by: None,
+            login: None,
+        })
+    }
+
+    async fn run(mut self) -> HandleResult<Response> {
+        if self.forwarded {
+            match Agent::forwarding_action(&self.pubkey) {
+                ForwardingAction::Deny => {
+                    audit::record("sign", "denied", Agent::forwarding_denied(&self.pubkey));
+                    return Ok(Response::Failure);
                 }
-            },
-            None => Ok(Response::Failure),
+                ForwardingAction::FreshApproval => self.window_target = None,
+                ForwardingAction::Allow => {}
+            }
         }
+        Ok(Response::SignResponse {
+            signature: self.sign().await?,
+        })
+    }
+
+    /// wait for the phone and return the sk signature blob
+    pub async fn sign(self) -> Result<Vec<u8>, Error> {
+        let PendingFido2Sign {
+            client,
+            queue,
+            sign_timeout,
+            strict_sign_counter,
+            local_confirmation,
+            key_type,
+            pubkey,
+            id,
+            rp_id,
+            challenge_hash,
+            mut extensions,
+            forwarded: _,
+            window_target,
+            requested_by,
+            login,
+        } = self;
+        let team_policy = team_policy::current()?;
+        if let Some(team_policy) = &team_policy {
+            if let Err(e) = team_policy.check_rp_id(&rp_id) {
+                eprintln!("sign error: {}", e);
+                return Err(e);
+            }
+        }
+        if let Some(extensions) = extensions.as_mut() {
+            if let Some(window) = window_target.as_ref().and_then(Agent::approval_window) {
+                extensions.insert(policy::EXTENSION, serde_json::to_value(window)?);
+            }
+            // an app that doesn't know them may not approve the request at all, it's asked without them
+            if !client.supports(Capabilities::AKR_EXTENSIONS) {
+                extensions.other.clear();
+            }
+        }
+        let extensions = extensions.filter(|extensions| !extensions.is_empty());
+        let _queued = queue.lock_owned().await;
+        let what = requested_by.unwrap_or_else(|| rp_id.clone());
+        if let Some(local_confirmation) = local_confirmation {
+            if let Err(e) = local_confirmation.confirm(&what, sign_timeout).await {
+                eprintln!(
+                    "sign error: the request for {} wasn't confirmed on this computer",
+                    rp_id
+                );
+                return Err(e);
+            }
+        }
+        notification::request_sent(&what, &rp_id);
+
+        // tell the user where to look if the approval takes a while,
+        // the loopback and mock transports answer without a pairing
+        let device_names = match Client::pairings() {
+            Ok(pairings) => pairings
+                .into_iter()
+                .map(|p| p.device_name)
+                .collect::<Vec<String>>()
+                .join(", "),
+            Err(_) => "the authenticator".to_string(),
+        };
+        let rp_id_clone = rp_id.clone();
+        let what_clone = what.clone();
+        let reminder = tokio::spawn(async move {
+            tokio::time::sleep(Agent::APPROVAL_REMINDER_DELAY).await;
+            eprintln!("waiting for approval of {} on {}", rp_id_clone, device_names);
+            notification::still_waiting(&what_clone, &device_names);
+        });
+
+        // get the signature from the client
+        let resp = client
+            .send_request_with_timeout::<AuthenticateResponse>(
+                RequestBody::Authenticate(AuthenticateRequest {
+                    challenge: Base64Buffer(challenge_hash.clone()),
+                    rp_id: rp_id.clone(),
+                    extensions,
+                    key_handle: id.as_ref().map(|id| id.key_handle.clone()).map(Base64Buffer),
+                    key_handles: None,
+                }),
+                sign_timeout,
+            )
+            .await;
+        reminder.abort();
+        tracing::debug!(approved = resp.is_ok(), "device answered the sign request");
+
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(Error::ResponseTimedOut) => {
+                METRICS.sign_timed_out();
+                notification::outcome(&what, Outcome::TimedOut(sign_timeout));
+                eprintln!("sign error: no approval for {} within {:?}", rp_id, sign_timeout);
+                return Err(Error::ApprovalTimedOut(sign_timeout.as_secs()))?;
+            }
+            Err(e @ Error::DeviceError(_)) => {
+                METRICS.sign_denied();
+                notification::outcome(&what, Outcome::Denied);
+                return Err(e)?;
+            }
+            Err(e) => return Err(e)?,
+        };
+        METRICS.sign_approved();
+        notification::outcome(&what, Outcome::Approved);
+
+        // make sure the phone signed our challenge with the requested key, otherwise
+        // the handshake only fails later with a confusing message from the server
+        let public_key = match &id {
+            Some(id) => id.public_key.clone(),
+            None => SshFido2KeyPairHandle::parse_key_from_public_key(pubkey)?,
+        };
+        if !verify_sk_signature(
+            key_type,
+            &public_key,
+            &resp.authenticator_data.0,
+            &challenge_hash,
+            &resp.signature.0,
+        ) {
+            eprintln!(
+                "sign error: signature from the device did not verify for {}",
+                rp_id
+            );
+            return Err(Error::SignatureVerificationFailed(rp_id))?;
+        }
+
+        // sshd checks these against the key, so report what the authenticator actually did
+        let flags = resp.get_auth_flags()?;
+        let mut key_flags = id
+            .as_ref()
+            .map(|id| id.flags)
+            .unwrap_or(SshFido2KeyPairHandle::SSH_SK_USER_PRESENCE_REQD);
+        if team_policy.is_some_and(|team_policy| team_policy.require_user_verification) {
+            key_flags |= SshFido2KeyPairHandle::SSH_SK_USER_VERIFICATION_REQD;
+        }
+        if key_flags & SshFido2KeyPairHandle::SSH_SK_USER_PRESENCE_REQD != 0
+            && flags & AuthenticateResponse::AUTH_FLAG_UP == 0
+        {
+            return Err(Error::KeyFlagsNotSatisfied("presence"))?;
+        }
+        if key_flags & SshFido2KeyPairHandle::SSH_SK_USER_VERIFICATION_REQD != 0
+            && flags & AuthenticateResponse::AUTH_FLAG_UV == 0
+        {
+            return Err(Error::KeyFlagsNotSatisfied("verification"))?;
+        }
+        let counter = resp.get_sign_counter()?;
+        check_sign_counter(&resp.key_handle.0, counter, strict_sign_counter)?;
+        // only for `akr list`, never fail a signature over it
+        let _ = StoredIdentity::record_use(&resp.key_handle.0, login);
+        let signature = match key_type {
+            SkKeyType::EcdsaP256 => {
+                /* parse the asn.1 signature into ssh format
+
+                   ecdsa signature
+                       mpint		r
+                       mpint		s
+                */
+                let asn1_sig = ECDSASign::der_from_bytes(resp.signature.0)?;
+                let mut signature: Vec<u8> = Vec::new();
+
+                signature.write_u32::<BigEndian>(asn1_sig.r.len() as u32)?;
+                signature.write_all(asn1_sig.r.as_slice())?;
+
+                signature.write_u32::<BigEndian>(asn1_sig.s.len() as u32)?;
+                signature.write_all(asn1_sig.s.as_slice())?;
+                signature
+            }
+            // ed25519 signatures are already the raw 64 byte R || S
+            SkKeyType::Ed25519 => resp.signature.0,
+        };
+
+        /*
+           string		"sk-ecdsa-sha2-nistp256@openssh.com" / "sk-ssh-ed25519@openssh.com"
+           string		ecdsa_signature / ed25519_signature
+           byte		    flags
+           uint32		counter
+
+           https://github.com/openssh/openssh-portable/blob/master/PROTOCOL.u2f
+        */
+        let mut data: Vec<u8> = vec![];
+
+        let sig_type_id = key_type.type_id();
+        data.write_u32::<BigEndian>(sig_type_id.len() as u32)?;
+        data.write_all(sig_type_id.as_bytes())?;
+
+        data.write_u32::<BigEndian>(signature.len() as u32)?;
+        data.write_all(&signature)?;
+
+        data.write_u8(flags)?;
+        data.write_u32::<BigEndian>(counter)?;
+
+        Ok(data)
     }
 }
 
+/// The result of a background task, or why it has none
+fn joined<T>(result: Result<Result<T, Error>, JoinError>) -> Result<T, String> {
+    match result {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The password of a local key for openssl, failing the unlock when pinentry couldn't be started
+fn ask_password(comment: String, buf: &mut [u8]) -> Result<usize, openssl::error::ErrorStack> {
+    PasswordPrompt::new(comment).invoke(buf).map_err(|e| {
+        eprintln!("couldn't start pinentry: {}", e);
+        openssl::error::ErrorStack::get()
+    })
+}
+
 #[async_trait]
 impl SSHAgentHandler for Agent {
+    /// every request goes into the audit log with its outcome, sign requests once the phone answered,
+    /// and into the recording of `akr start --record`
+    async fn handle_request(&mut self, connection: ConnectionId, request: Request) -> HandleResult<Reply> {
+        match Recording::start(connection, &request) {
+            Some(recording) => recording.finish(self.audited(connection, request).await).await,
+            None => self.audited(connection, request).await,
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
     async fn identities(&mut self) -> HandleResult<Response> {
-        let ids = StoredIdentity::load_from_disk()?.key_pair_handles;
-        self.identities = ids
-            .into_iter()
-            .map(|kp| Ok((kp.fmt_public_key()?, kp)))
-            .collect::<Result<Vec<_>, Error>>()?
-            .into_iter()
-            .collect();
+        // a locked agent answers with an empty list, just like OpenSSH's
+        if self.is_locked() {
+            return Ok(Response::Identities(vec![]));
+        }
 
+        // served from what's loaded, ssh-add -l doesn't wait on the phone
+        let stale = self.refresh_identities().await?;
+        let comment = |kp: &SshFido2KeyPairHandle| match stale {
+            true => format!("{}{}", kp.key_comment(), Self::STALE_COMMENT),
+            false => kp.key_comment().to_string(),
+        };
+
+        let constrained_identities = self.constrained_identities.lock().await;
         let mut identities = self
             .identities
             .iter()
-            .map(|(pubkey, kp)| {
+            .map(|(pubkey, kp)| (pubkey, comment(kp)))
+            .chain(
+                constrained_identities
+                    .iter()
+                    .map(|(pubkey, (kp, _))| (pubkey, kp.key_comment().to_string())),
+            )
+            .map(|(pubkey, key_comment)| {
                 Ok(Identity {
-                    key_comment: kp.application.clone(),
+                    key_comment,
                     key_blob: pubkey.clone(),
                 })
             })
             .collect::<Result<Vec<Identity>, Error>>()?;
+        drop(constrained_identities);
+
+        // advertise attached certificates next to their keys
+        let mut certificates = vec![];
+        for identity in &identities {
+            if let Some(certificate) = StoredIdentity::load_certificate(&identity.key_blob)? {
+                certificates.push(Identity {
+                    key_comment: identity.key_comment.clone(),
+                    key_blob: certificate,
+                });
+            }
+        }
+        identities.extend(certificates);
 
         let keys = self
             .ssh_keys
@@ -369,6 +1567,7 @@ impl SSHAgentHandler for Agent {
         // push keys to ids
         identities.extend(keys);
 
+        METRICS.set_identities(identities.len());
         let ids = identities
             .iter()
             .map(|id| {
@@ -383,65 +1582,338 @@ impl SSHAgentHandler for Agent {
         Ok(ids)
     }
 
-    async fn add_identity(
-        &mut self,
-        key_type: String,
-        key_blob: Vec<u8>,
-    ) -> HandleResult<Response> {
-        if key_type.as_str() != SshFido2KeyPairHandle::TYPE_ID {
-            eprintln!("add error: not a fido2 ssh keypair");
-            return Ok(Response::Success);
+    #[tracing::instrument(skip_all, fields(key_type = %key_type))]
+    async fn add_identity(&mut self, key_type: String, key_blob: Vec<u8>) -> HandleResult<Reply> {
+        if self.is_locked() {
+            return Ok(Response::Failure.into());
         }
-        /*
-           string		curve name
-           ec_point	Q
-           string		application (user-specified, but typically "ssh:")
-           uint8		flags
-           string		key_handle
-           string		reserved
-        */
+
         let mut cursor = Cursor::new(key_blob);
-        let _curve_name = read_string(&mut cursor)?;
-        let public_key = read_data(&mut cursor)?;
-        let application = read_string(&mut cursor)?;
-        let flags = cursor.read_u8()?;
-        let key_handle = read_data(&mut cursor)?;
-
-        let identity = SshFido2KeyPairHandle {
-            application,
-            key_handle,
-            public_key,
-            flags,
+        let sk_key_type = match SkKeyType::from_type_id(&key_type) {
+            Some(sk_key_type) => sk_key_type,
+            None if self.local_keys => return Ok(self.add_local_key(key_type, &mut cursor)?),
+            None => {
+                eprintln!("add error: not a fido2 ssh keypair");
+                return Ok(Response::Failure.into());
+            }
         };
-        self.identities.insert(identity.fmt_public_key()?, identity);
 
+        let mut identity = SshFido2KeyPairHandle::parse_private_key_blob(sk_key_type, &mut cursor)?;
+        identity.comment = read_string_at_most(&mut cursor, MAX_COMMENT_LEN)?;
+
+        let mut lifetime = None;
+        for constraint in KeyConstraint::read_all(&mut cursor)? {
+            match constraint {
+                KeyConstraint::Lifetime(duration) if !duration.is_zero() => lifetime = Some(duration),
+                KeyConstraint::Lifetime(_) => {}
+                // every signature is already confirmed on the phone
+                KeyConstraint::Confirm => {}
+                KeyConstraint::Extension(name) => {
+                    eprintln!("add error: unsupported constraint extension {}", name);
+                    return Ok(Response::Failure.into());
+                }
+            }
+        }
+
+        let pubkey = identity.fmt_public_key()?;
+        match lifetime {
+            Some(lifetime) => {
+                // don't let a previously persisted copy outlive the constraint
+                if let Some(persisted) = self.identities.remove(&pubkey) {
+                    StoredIdentity::remove_added_key_pair_handle(&persisted)?;
+                    self.store_changed.store(true, Ordering::Relaxed);
+                }
+                let expires_at = Instant::now() + lifetime;
+                self.constrained_identities
+                    .lock()
+                    .await
+                    .insert(pubkey.clone(), (identity, expires_at));
+
+                let constrained_identities = self.constrained_identities.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep_until(expires_at).await;
+                    let mut constrained_identities = constrained_identities.lock().await;
+                    // the key may have been re-added with a later deadline since
+                    if let Some((_, deadline)) = constrained_identities.get(&pubkey) {
+                        if *deadline <= Instant::now() {
+                            constrained_identities.remove(&pubkey);
+                        }
+                    }
+                });
+            }
+            None => {
+                StoredIdentity::store_added_key_pair_handle(&identity)?;
+                StoredIdentity::unmark_removed(&pubkey)?;
+                self.store_changed.store(true, Ordering::Relaxed);
+                self.constrained_identities.lock().await.remove(&pubkey);
+                self.unloaded.remove(&pubkey);
+                self.identities.insert(pubkey, identity);
+            }
+        }
+
+        Ok(Response::Success.into())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn remove_identity(&mut self, pubkey: Vec<u8>) -> HandleResult<Response> {
+        if self.is_locked() {
+            return Ok(Response::Failure);
+        }
+        self.reload_identities_if_changed();
+
+        // removing a certificate keeps the key it certifies
+        let key_type = read_string(&mut Cursor::new(pubkey.clone())).unwrap_or_default();
+        if SkKeyType::from_cert_type_id(&key_type).is_some() {
+            let certified = SshFido2KeyPairHandle::parse_public_key_from_certificate(&pubkey)?;
+            if StoredIdentity::remove_certificate(&certified)? {
+                return Ok(Response::Success);
+            }
+            return Ok(Response::Failure);
+        }
+
+        if self.constrained_identities.lock().await.remove(&pubkey).is_some() {
+            return Ok(Response::Success);
+        }
+
+        // a key removed with ssh-add -D is only left on disk
+        let removed = self.unloaded.remove(&pubkey);
+        if removed {
+            StoredIdentity::unmark_removed(&pubkey)?;
+        }
+
+        let stored = removed
+            .then(|| {
+                self.store_keys
+                    .iter()
+                    .find(|handle| handle.fmt_public_key().is_ok_and(|stored| stored == pubkey))
+                    .cloned()
+            })
+            .flatten();
+        if let Some(identity) = self.identities.remove(&pubkey).or(stored) {
+            // drop it from disk too, otherwise the next listing reloads it
+            StoredIdentity::remove_key_pair_handle(&identity)?;
+            StoredIdentity::remove_added_key_pair_handle(&identity)?;
+            StoredIdentity::remove_certificate(&pubkey)?;
+            self.store_changed.store(true, Ordering::Relaxed);
+            return Ok(Response::Success);
+        }
+
+        let key_count = self.ssh_keys.len();
+        self.ssh_keys
+            .retain(|key| key.pub_key_blob() != pubkey.as_slice());
+        if self.ssh_keys.len() < key_count || removed {
+            StoredIdentity::remove_local_key(&pubkey)?;
+            return Ok(Response::Success);
+        }
+
+        Ok(Response::Failure)
+    }
+
+    async fn remove_all_identities(&mut self) -> HandleResult<Response> {
+        if self.is_locked() {
+            return Ok(Response::Failure);
+        }
+
+        // the key handles and key files stay on disk, marked so they aren't loaded again until they're added
+        // again, `ssh-add -d` and `akr remove` delete them
+        self.reload_identities_if_changed();
+        let removed: Vec<SshWirePublicKey> = self
+            .identities
+            .iter()
+            .map(|(pubkey, _)| pubkey.clone())
+            .chain(self.ssh_keys.iter().map(|key| key.pub_key_blob().to_vec()))
+            .collect();
+        for pubkey in removed {
+            StoredIdentity::mark_removed(&pubkey)?;
+            self.unloaded.insert(pubkey);
+        }
+        self.identities.clear();
+        self.constrained_identities.lock().await.clear();
+        self.ssh_keys.clear();
+
+        Ok(Response::Success)
+    }
+
+    async fn add_smartcard_key(&mut self, provider: String, pin: Vec<u8>) -> HandleResult<Response> {
+        // no PIN to check, the phone approves
+        let _pin = Zeroizing::new(pin);
+        if self.is_locked() {
+            return Ok(Response::Failure);
+        }
+        if !Self::is_akr_provider(&provider) {
+            eprintln!(
+                "add error: akr doesn't load PKCS#11 providers like {}, its keys are on your phone; \
+                 `ssh-add -s \"$(command -v akr)\"` reloads them",
+                provider
+            );
+            return Ok(Response::Failure);
+        }
+
+        // the keys removed with ssh-add -D stay out
+        let device_keys = self
+            .device_keys
+            .as_ref()
+            .map(|(_, keys)| keys.clone())
+            .unwrap_or_default();
+        self.load_identities(device_keys)?;
         Ok(Response::Success)
     }
 
+    async fn remove_smartcard_key(&mut self, provider: String, pin: Vec<u8>) -> HandleResult<Response> {
+        let _pin = Zeroizing::new(pin);
+        match Self::is_akr_provider(&provider) {
+            true => eprintln!("remove error: `ssh-add -d` or `akr remove` removes the keys of akr"),
+            false => eprintln!("remove error: akr has no keys of PKCS#11 provider {}", provider),
+        }
+        Ok(Response::Failure)
+    }
+
+    /// locks right away, the passphrase is hashed without holding the agent
+    async fn lock(&mut self, passphrase: Vec<u8>) -> HandleResult<Reply> {
+        let passphrase = Zeroizing::new(passphrase);
+        if self.is_locked() {
+            return Ok(Response::Failure.into());
+        }
+        *self.passphrase_lock.lock().unwrap() = PassphraseLock {
+            locked: true,
+            ..Default::default()
+        };
+
+        let passphrase_lock = self.passphrase_lock.clone();
+        Ok(Reply::Later(Box::pin(async move {
+            let hashed = tokio::task::spawn_blocking(move || PassphraseHash::new(&passphrase)).await;
+            let mut passphrase_lock = passphrase_lock.lock().unwrap();
+            match hashed {
+                Ok(hash) => {
+                    passphrase_lock.hash = Some(hash);
+                    Ok(Response::Success)
+                }
+                Err(e) => {
+                    // nothing could unlock it
+                    eprintln!("lock error: {}", e);
+                    *passphrase_lock = PassphraseLock::default();
+                    Ok(Response::Failure)
+                }
+            }
+        })))
+    }
+
+    /// the passphrase is checked without holding the agent, one at a time
+    async fn unlock(&mut self, passphrase: Vec<u8>) -> HandleResult<Reply> {
+        let passphrase = Zeroizing::new(passphrase);
+        let hash = {
+            let mut passphrase_lock = self.passphrase_lock.lock().unwrap();
+            let hash = match &passphrase_lock.hash {
+                Some(hash) if !passphrase_lock.checking => hash.clone(),
+                _ => return Ok(Response::Failure.into()),
+            };
+
+            // anything that can reach the socket could guess, every wrong passphrase makes it wait longer
+            let now = Instant::now();
+            if let Some(retry_at) = passphrase_lock.retry_at.filter(|retry_at| *retry_at > now) {
+                eprintln!(
+                    "unlock error: too many wrong passphrases, try again in {}s",
+                    (retry_at - now).as_secs() + 1
+                );
+                return Ok(Response::Failure.into());
+            }
+            passphrase_lock.checking = true;
+            hash
+        };
+
+        let passphrase_lock = self.passphrase_lock.clone();
+        Ok(Reply::Later(Box::pin(async move {
+            let verified = tokio::task::spawn_blocking(move || hash.verify(&passphrase)).await;
+            let mut passphrase_lock = passphrase_lock.lock().unwrap();
+            passphrase_lock.checking = false;
+            if let Ok(true) = verified {
+                *passphrase_lock = PassphraseLock::default();
+                return Ok(Response::Success);
+            }
+
+            let delay = Self::UNLOCK_BASE_DELAY
+                .saturating_mul(1 << passphrase_lock.failures.min(16))
+                .min(Self::UNLOCK_MAX_DELAY);
+            passphrase_lock.failures += 1;
+            passphrase_lock.retry_at = Some(Instant::now() + delay);
+            Ok(Response::Failure)
+        })))
+    }
+
+    #[tracing::instrument(skip_all, fields(connection = connection))]
     async fn sign_request(
         &mut self,
+        connection: ConnectionId,
         pubkey: Vec<u8>,
         data: Vec<u8>,
         flags: u32,
-    ) -> HandleResult<Response> {
-        /* data:
-         Packet Format (SSH_MSG_USERAUTH_REQUEST):
-         string    session identifier
-         byte      SSH_MSG_USERAUTH_REQUEST
-         string    user name
-         string    service name
-         string    "publickey"
-         boolean   TRUE
-         string    public key algorithm name
-         string    public key to be used for authentication
-        */
+    ) -> HandleResult<Reply> {
+        METRICS.sign_requested();
+        if self.is_locked() {
+            return Ok(Response::Failure.into());
+        }
+
+        let held = self.held_key_digest(&pubkey).await;
+        if let Err(throttled) = self.rate_limiter.check(held) {
+            return Ok(self.throttled(connection, &pubkey, throttled).into());
+        }
+
+        if !self.session_permits(connection, &data) {
+            eprintln!("sign error: request is for a different session than the connection is bound to");
+            return Ok(Response::Failure.into());
+        }
+
+        // the hosts of the config file only concern logins, not e.g. git signatures
+        if userauth_session_id(&data).is_some() {
+            let target = self.target(connection, &data);
+            if let Err(refused_by) = policy::check_destination(&target) {
+                eprintln!("sign error: the login to {} is refused by {}", target, refused_by);
+                self.defer_audit(
+                    "sign",
+                    "denied",
+                    serde_json::json!({ "destination": target.to_string(), "refused_by": refused_by }),
+                );
+                return Ok(Response::Failure.into());
+            }
+        }
+
+        let userauth = UserauthRequest::parse(&data);
+        if let Some(userauth) = &userauth {
+            tracing::info!(user = %userauth.user, service = %userauth.service, "userauth request");
+        }
+        self.defer_audit(
+            "sign",
+            "requested",
+            serde_json::json!({
+                "key": fingerprint(&pubkey),
+                "userauth": userauth.as_ref().map(UserauthRequest::to_json),
+            }),
+        );
 
         let mut cursor = Cursor::new(pubkey.clone());
-        let pubkey_type = read_string(&mut cursor)?;
+        let mut pubkey_type = read_string_at_most(&mut cursor, MAX_NAME_LEN)?;
+
+        // certificates are signed for with the key they certify
+        let mut pubkey = pubkey;
+        if let Some(sk_key_type) = SkKeyType::from_cert_type_id(&pubkey_type) {
+            pubkey = SshFido2KeyPairHandle::parse_public_key_from_certificate(&pubkey)?;
+            pubkey_type = sk_key_type.type_id().to_string();
+        }
+
+        // the pending sign of a FIDO2 key reads `akr policy` itself, after the handler is unlocked
+        if let Some(sk_key_type) = SkKeyType::from_type_id(&pubkey_type) {
+            return self
+                .sign_fido2(connection, pubkey, data, flags, sk_key_type)
+                .await;
+        }
 
-        if pubkey_type == "sk-ecdsa-sha2-nistp256@openssh.com".to_string() {
-            self.sign_fido2(pubkey, data, flags).await
-        } else if pubkey_type.contains("ssh-rsa") {
+        if self.is_forwarded(connection) && Self::forwarding_action(&pubkey) == ForwardingAction::Deny {
+            let details = Self::forwarding_denied(&pubkey);
+            self.defer_audit("sign", "denied", details);
+            return Ok(Response::Failure.into());
+        }
+
+        let response = if pubkey_type.contains("ssh-rsa") {
             self.sign_rsa(pubkey, data, flags, pubkey_type).await
         } else if pubkey_type.contains("ecdsa") {
             self.sign_ecdsa(pubkey, data, flags, pubkey_type).await
@@ -449,16 +1921,299 @@ impl SSHAgentHandler for Agent {
             self.sign_ed25519(pubkey, data, flags, pubkey_type).await
         } else {
             Ok(Response::Failure)
+        };
+        response.map(Reply::Now)
+    }
+
+    async fn extension(
+        &mut self,
+        connection: ConnectionId,
+        extension_type: String,
+        contents: Vec<u8>,
+    ) -> HandleResult<Response> {
+        let response = match extension_type.as_str() {
+            SessionBind::EXTENSION => self.session_bind(connection, contents),
+            control::EXTENSION => self.control(connection, contents).await,
+            _ => return Ok(Response::Failure),
+        };
+        response.or_else(|e| {
+            eprintln!("{} error: {}", extension_type, e);
+            Ok(Response::ExtensionFailure)
+        })
+    }
+
+    async fn connection_opened(&mut self, connection: ConnectionId, peer: Option<PeerCredentials>) -> bool {
+        let Some(peer) = peer else {
+            // in process, or a named pipe only the user can open
+            return true;
+        };
+        #[cfg(unix)]
+        if !self.allow_other_users && peer.uid != nix::unistd::getuid().as_raw() {
+            let requester = Requester::new(peer);
+            eprintln!(
+                "refused a connection from uid {} ({}), `akr start --allow-other-users` lets other users in",
+                peer.uid,
+                requester.command.as_deref().unwrap_or("unknown process")
+            );
+            audit::record(
+                "connection",
+                "denied",
+                serde_json::json!({ "requester": requester }),
+            );
+            return false;
         }
+        self.requesters.insert(connection, Requester::new(peer));
+        true
+    }
+
+    async fn connection_closed(&mut self, connection: ConnectionId) {
+        self.requesters.remove(&connection);
+        self.session_binds.remove(&connection);
     }
 }
 
-/// show a desktop notification about the pending request
-fn show_notification(rp_id: &str) {
-    #[cfg(target_os = "macos")]
-    //open issue https://github.com/h4llow3En/mac-notification-sys/issues/8
-    // let _ = mac_notification_sys::set_application(&"com.akamai.pushzero");
-    let _ = notify_rust::Notification::new()
-        .summary(format!("Login Request: {}", rp_id).as_str())
-        .show();
+/// Compare a signature counter against the last one seen for the key handle and remember it.
+/// A counter that doesn't increase can mean the credential was cloned,
+/// see https://www.w3.org/TR/webauthn-2/#sctn-sign-counter
+fn check_sign_counter(key_handle: &[u8], counter: u32, strict: bool) -> Result<(), Error> {
+    let previous = StoredIdentity::load_sign_counter(key_handle)?;
+    match previous {
+        // authenticators without a counter always report 0
+        Some(0) | None if counter == 0 => return Ok(()),
+        Some(previous) if counter <= previous => {
+            eprintln!(
+                "warning: signature counter went from {} to {}, the credential may be cloned",
+                previous, counter
+            );
+            if strict {
+                return Err(Error::CounterRollback {
+                    previous,
+                    received: counter,
+                });
+            }
+        }
+        _ => {}
+    }
+
+    StoredIdentity::store_sign_counter(key_handle, counter.max(previous.unwrap_or(0)))
+}
+
+/// the command line of a local process, e.g. "ssh user@example.com"
+pub fn process_command_line(pid: i32) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    let command = fs::read(format!("/proc/{}/cmdline", pid)).ok().map(|cmdline| {
+        cmdline
+            .split(|b| *b == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect::<Vec<String>>()
+            .join(" ")
+    });
+
+    #[cfg(not(target_os = "linux"))]
+    let command = std::process::Command::new("ps")
+        .args(["-o", "command=", "-p", &pid.to_string()])
+        .output()
+        .ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+
+    command.filter(|command| !command.is_empty())
+}
+
+/// the session identifier of an SSH_MSG_USERAUTH_REQUEST, None for any other data
+fn userauth_session_id(data: &[u8]) -> Option<Vec<u8>> {
+    const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+
+    let mut cursor = Cursor::new(data.to_vec());
+    let session_id = read_data(&mut cursor).ok()?;
+    match cursor.read_u8() {
+        Ok(SSH_MSG_USERAUTH_REQUEST) => Some(session_id),
+        _ => None,
+    }
+}
+
+fn audit_outcome(response: &HandleResult<Response>) -> &'static str {
+    match response {
+        Ok(Response::Failure | Response::ExtensionFailure) => "failure",
+        Ok(_) => "success",
+        Err(_) => "error",
+    }
+}
+
+/// like `ssh-keygen -l`, of a public key blob
+fn fingerprint(key_blob: &[u8]) -> String {
+    format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(crypto::sha256(key_blob))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{self, Exchange};
+    use crate::transport::{self, TransportKind};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED};
+
+    /// a string of an ssh message, its length in front
+    fn string(buf: &mut &[u8]) -> Vec<u8> {
+        let len = buf.read_u32::<BigEndian>().unwrap() as usize;
+        let (data, rest) = buf.split_at(len);
+        *buf = rest;
+        data.to_vec()
+    }
+
+    /// the end-to-end tests share the transport, the script of the mock and the store on disk
+    static END_TO_END: Mutex<()> = Mutex::const_new(());
+
+    /// an agent in this process, and a client of one of its connections. Holds the others off until dropped,
+    /// the phone answering from a script that is empty at first
+    async fn connected_agent() -> (
+        tokio::sync::MutexGuard<'static, ()>,
+        ssh_agent::Client<tokio::io::DuplexStream>,
+    ) {
+        let serial = END_TO_END.lock().await;
+        crate::profile::use_temporary_home();
+        transport::select(TransportKind::Mock);
+        mock::set_script(vec![]);
+        (serial, start_agent())
+    }
+
+    /// a client of a new agent, as if it was restarted
+    fn start_agent() -> ssh_agent::Client<tokio::io::DuplexStream> {
+        let agent = Arc::new(Mutex::new(Agent::new(Client::new().unwrap())));
+        ssh_agent::Client::new(::ssh_agent::Agent::connect_in_process(agent, 0))
+    }
+
+    /// a P-256 key of the phone for ssh, with its private key as PKCS#8
+    fn p256_handle(comment: &str) -> (SshFido2KeyPairHandle, Vec<u8>) {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new()).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        let handle = SshFido2KeyPairHandle {
+            application: "ssh:".to_string(),
+            public_key: key.public_key().as_ref().to_vec(),
+            key_handle: crypto::sha256(key.public_key().as_ref()).to_vec(),
+            flags: SshFido2KeyPairHandle::SSH_SK_USER_PRESENCE_REQD,
+            key_type: SkKeyType::EcdsaP256,
+            comment: comment.to_string(),
+            label: None,
+            tags: vec![],
+            created_at: None,
+        };
+        (handle, pkcs8.as_ref().to_vec())
+    }
+
+    /// ssh-add of `handle`, with `-t lifetime` if given
+    async fn add(
+        client: &mut ssh_agent::Client<tokio::io::DuplexStream>,
+        handle: &SshFido2KeyPairHandle,
+        lifetime: Option<u32>,
+    ) -> bool {
+        let mut contents = handle.fmt_private_key().unwrap();
+        let key_type = String::from_utf8(string(&mut &contents[..])).unwrap();
+        contents.drain(..4 + key_type.len());
+        contents
+            .write_u32::<BigEndian>(handle.comment.len() as u32)
+            .unwrap();
+        contents.write_all(handle.comment.as_bytes()).unwrap();
+        if let Some(lifetime) = lifetime {
+            contents
+                .write_u8(KeyConstraint::SSH_AGENT_CONSTRAIN_LIFETIME)
+                .unwrap();
+            contents.write_u32::<BigEndian>(lifetime).unwrap();
+        }
+        client.add_identity(&key_type, &contents).await.unwrap()
+    }
+
+    /// ssh-add and ssh through a connection of the agent in this process, the phone answering from a script
+    /// with a P-256 key
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lists_and_signs_through_the_mock_transport() {
+        let (_serial, mut client) = connected_agent().await;
+        let (handle, pkcs8) = p256_handle("mock");
+        let pubkey = handle.fmt_public_key().unwrap();
+        mock::set_script(vec![Exchange {
+            request: "authenticate".to_string(),
+            key: Some(Base64Buffer(pkcs8)),
+            ..Default::default()
+        }]);
+
+        // with a lifetime, so it stays out of the store the other tests share
+        assert!(add(&mut client, &handle, Some(3600)).await);
+
+        let identities = client.request_identities().await.unwrap();
+        let identity = identities
+            .iter()
+            .find(|identity| identity.key_blob == pubkey)
+            .unwrap();
+        assert_eq!(identity.key_comment, "mock");
+
+        let data = b"the session identifier and userauth request";
+        let blob = client.sign(&pubkey, data, 0).await.unwrap().unwrap();
+        let mut blob = &blob[..];
+        assert_eq!(string(&mut blob), SkKeyType::EcdsaP256.type_id().as_bytes());
+        let signature = string(&mut blob);
+        let flags = blob.read_u8().unwrap();
+        let counter = blob.read_u32::<BigEndian>().unwrap();
+        assert!(blob.is_empty());
+        assert_ne!(flags & AuthenticateResponse::AUTH_FLAG_UP, 0);
+
+        // the mpints r and s as the fixed size r || s, over the authenticator data and the hash of `data`
+        let mut signature = &signature[..];
+        let mut fixed = vec![];
+        for mpint in [string(&mut signature), string(&mut signature)] {
+            let mpint = &mpint[mpint.len().saturating_sub(32)..];
+            fixed.resize(fixed.len() + 32 - mpint.len(), 0);
+            fixed.extend_from_slice(mpint);
+        }
+        let mut message = crypto::sha256(handle.application.as_bytes()).to_vec();
+        message.push(flags);
+        message.extend_from_slice(&counter.to_be_bytes());
+        message.extend_from_slice(&crypto::sha256(data));
+        ring::signature::UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &handle.public_key)
+            .verify(&message, &fixed)
+            .unwrap();
+    }
+
+    /// ssh-add -D removes a key added without constraints for good, its handle stays on disk until it's added
+    /// again
+    #[tokio::test(flavor = "multi_thread")]
+    async fn remove_all_keeps_the_keys_on_disk() {
+        let (_serial, mut client) = connected_agent().await;
+        let (handle, _) = p256_handle("unloaded");
+        let pubkey = handle.fmt_public_key().unwrap();
+        assert!(add(&mut client, &handle, None).await);
+        let listed =
+            |identities: Vec<Identity>| identities.iter().any(|identity| identity.key_blob == pubkey);
+        assert!(listed(client.request_identities().await.unwrap()));
+
+        assert!(client.remove_all_identities().await.unwrap());
+        assert!(!listed(client.request_identities().await.unwrap()));
+        assert!(client.sign(&pubkey, b"data", 0).await.unwrap().is_none());
+        let stored = StoredIdentity::load_added_key_pair_handles().unwrap();
+        assert!(stored.iter().any(|stored| stored.key_handle == handle.key_handle));
+
+        let mut restarted = start_agent();
+        assert!(!listed(restarted.request_identities().await.unwrap()));
+        assert!(add(&mut restarted, &handle, None).await);
+        assert!(listed(restarted.request_identities().await.unwrap()));
+        assert!(listed(start_agent().request_identities().await.unwrap()));
+
+        // leave the store the other tests share as it was
+        assert!(StoredIdentity::remove_added_key_pair_handle(&handle).unwrap());
+    }
+
+    /// a wrong passphrase for ssh-add -X holds off the next try, even the right one
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backs_off_after_a_wrong_unlock_passphrase() {
+        let (_serial, mut client) = connected_agent().await;
+
+        assert!(client.lock(b"passphrase").await.unwrap());
+        assert!(!client.unlock(b"guess").await.unwrap());
+        assert!(!client.unlock(b"passphrase").await.unwrap());
+        tokio::time::sleep(Agent::UNLOCK_BASE_DELAY).await;
+        assert!(client.unlock(b"passphrase").await.unwrap());
+    }
 }
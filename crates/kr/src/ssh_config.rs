@@ -0,0 +1,473 @@
+//! Reading and editing ssh configs line by line, every line stays as it was but for the block akr manages:
+//!
+//! ```text
+//! # BEGIN akr
+//! Host *
+//!     IdentityAgent ~/.akr/akr-ssh-agent.sock
+//! # END akr
+//! ```
+//!
+//! ssh takes the first value it finds for a keyword, so a new block goes at the top of the config. One that's
+//! already there is updated where it is, also in a file the config includes, and the blocks of older versions
+//! ("# Begin Akamai MFA SSH Config", Krypton's "krssh" proxy) are replaced by it.
+//!
+//! `akr setup --host` writes a block of its own per host, between "# BEGIN akr <host>" and "# END akr <host>".
+//! The one of `akr setup --host-context` goes at the end instead, its `Match` would take in the lines below.
+
+use crate::error::Error;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+pub const BEGIN: &str = "# BEGIN akr";
+pub const END: &str = "# END akr";
+/// the name of the block of `akr setup --host-context`, in place of a host
+pub const HOST_CONTEXT: &str = "host-context";
+
+/// The first line of the blocks written before, and what their last one has
+const LEGACY_BLOCKS: &[(&str, &str)] = &[
+    ("# Begin Akamai MFA SSH Config", "# End Akamai MFA SSH Config"),
+    ("# Added by Krypton", "krssh %h %p"),
+];
+
+/// how deep ssh follows Include
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A keyword and its arguments, e.g. `IdentityAgent "~/my agent.sock"` or `Host=*`
+pub struct Directive {
+    /// lowercase, ssh's keywords aren't case sensitive
+    pub keyword: String,
+    pub args: Vec<String>,
+}
+
+impl Directive {
+    pub fn parse(line: &str) -> Option<Directive> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let split = line
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(line.len());
+        let (keyword, rest) = line.split_at(split);
+        let rest = rest.trim_start();
+        let rest = rest.strip_prefix('=').unwrap_or(rest);
+
+        let mut args = vec![];
+        let mut arg = String::new();
+        let mut quoted = false;
+        for c in rest.chars() {
+            match c {
+                '"' => quoted = !quoted,
+                '#' if !quoted && arg.is_empty() => break,
+                c if c.is_whitespace() && !quoted => {
+                    if !arg.is_empty() {
+                        args.push(std::mem::take(&mut arg));
+                    }
+                }
+                c => arg.push(c),
+            }
+        }
+        if !arg.is_empty() {
+            args.push(arg);
+        }
+        Some(Directive {
+            keyword: keyword.to_lowercase(),
+            args,
+        })
+    }
+}
+
+/// A change to a file, at a line of it as it was read
+struct Edit {
+    at: usize,
+    removed: Vec<String>,
+    added: Vec<String>,
+}
+
+pub struct ConfigFile {
+    pub path: PathBuf,
+    lines: Vec<String>,
+    /// the lines as they were read, the edits are against them
+    original: Vec<String>,
+    edits: Vec<Edit>,
+}
+
+impl ConfigFile {
+    /// A file that doesn't exist yet is empty
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let lines: Vec<String> = text.lines().map(str::to_string).collect();
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            original: lines.clone(),
+            lines,
+            edits: vec![],
+        })
+    }
+
+    /// Each directive with its line number, from 1
+    pub fn directives(&self) -> impl Iterator<Item = (usize, Directive)> + '_ {
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| Some((i + 1, Directive::parse(line)?)))
+    }
+
+    /// The directives outside the managed blocks, with their line numbers
+    pub fn unmanaged_directives(&self) -> Vec<(usize, Directive)> {
+        let managed = self.all_managed();
+        self.directives()
+            .filter(|(line, _)| !managed.iter().any(|block| block.contains(&(line - 1))))
+            .collect()
+    }
+
+    /// The files of the Include directives, in the order ssh reads them
+    pub fn includes(&self) -> Vec<PathBuf> {
+        let ssh_dir = dirs::home_dir().unwrap_or_default().join(".ssh");
+        self.directives()
+            .filter(|(_, directive)| directive.keyword == "include")
+            .flat_map(|(_, directive)| directive.args)
+            .flat_map(|pattern| {
+                // relative to ~/.ssh, as for the user's config
+                glob(&ssh_dir.join(crate::util::expand_home(Path::new(&pattern))))
+            })
+            .collect()
+    }
+
+    /// The lines of akr's blocks for `host`, or of its block for every host with the ones of older versions
+    fn managed(&self, host: Option<&str>) -> Vec<Range<usize>> {
+        let markers = match host {
+            Some(host) => vec![(format!("{} {}", BEGIN, host), format!("{} {}", END, host))],
+            None => std::iter::once((BEGIN, END))
+                .chain(LEGACY_BLOCKS.iter().copied())
+                .map(|(begin, end)| (begin.to_string(), end.to_string()))
+                .collect(),
+        };
+        let mut blocks = vec![];
+        for (begin, end) in markers {
+            let mut from = 0;
+            while let Some(start) = self.lines[from..].iter().position(|line| line.trim() == begin) {
+                let start = from + start;
+                // Krypton's block ended with its proxy command, akr's end with their marker
+                let is_end = |line: &String| match host.is_some() || begin == BEGIN {
+                    true => line.trim() == end,
+                    false => line.contains(&end),
+                };
+                let Some(len) = self.lines[start..].iter().position(is_end) else {
+                    break;
+                };
+                blocks.push(start..start + len + 1);
+                from = start + len + 1;
+            }
+        }
+        blocks.sort_by_key(|block| block.start);
+        blocks
+    }
+
+    /// The hosts akr wrote a block for with `akr setup --host`
+    pub fn managed_hosts(&self) -> Vec<String> {
+        let prefix = format!("{} ", BEGIN);
+        let mut hosts = self
+            .lines
+            .iter()
+            .filter_map(|line| line.trim().strip_prefix(&prefix))
+            .map(|host| host.trim().to_string())
+            .collect::<Vec<_>>();
+        hosts.dedup();
+        hosts
+    }
+
+    /// Every block akr wrote
+    fn all_managed(&self) -> Vec<Range<usize>> {
+        let mut blocks = self.managed(None);
+        for host in self.managed_hosts() {
+            blocks.extend(self.managed(Some(&host)));
+        }
+        blocks.sort_by_key(|block| block.start);
+        blocks
+    }
+
+    pub fn has_managed(&self, host: Option<&str>) -> bool {
+        !self.managed(host).is_empty()
+    }
+
+    /// Replace `range` of the lines with `added`, `range` of them as they are now
+    fn splice(&mut self, range: Range<usize>, added: Vec<String>) {
+        // lines before `range.start` that edits added or removed
+        let shift: isize = self
+            .edits
+            .iter()
+            .filter(|edit| edit.at <= range.start)
+            .map(|edit| edit.added.len() as isize - edit.removed.len() as isize)
+            .sum();
+        let removed = self.lines.splice(range.clone(), added.clone()).collect();
+        self.edits.push(Edit {
+            at: (range.start as isize - shift) as usize,
+            removed,
+            added,
+        });
+    }
+
+    /// Remove the managed blocks for `host`, or for every host. Returns whether there was one
+    pub fn remove_managed(&mut self, host: Option<&str>) -> bool {
+        let blocks = self.managed(host);
+        self.remove(&blocks)
+    }
+
+    /// Remove all the blocks akr wrote, the ones for single hosts too
+    pub fn remove_all_managed(&mut self) -> bool {
+        let blocks = self.all_managed();
+        self.remove(&blocks)
+    }
+
+    /// Remove `blocks`, with the empty line that set each apart
+    fn remove(&mut self, blocks: &[Range<usize>]) -> bool {
+        for block in blocks.iter().rev() {
+            let mut block = block.clone();
+            let before_blank = block.start == 0 || self.lines[block.start - 1].trim().is_empty();
+            if before_blank
+                && self
+                    .lines
+                    .get(block.end)
+                    .is_some_and(|line| line.trim().is_empty())
+            {
+                block.end += 1;
+            } else if block.start > 0 && before_blank && block.end == self.lines.len() {
+                block.start -= 1;
+            }
+            self.splice(block, vec![]);
+        }
+        !blocks.is_empty()
+    }
+
+    /// Put `block` in place of the first managed block for `host`, or at the top if there's none
+    pub fn set_managed(&mut self, host: Option<&str>, block: &[String]) {
+        self.put_managed(host, block, false)
+    }
+
+    /// Put `block` in place of the first managed block for `host`, or at the end if there's none
+    pub fn set_managed_last(&mut self, host: Option<&str>, block: &[String]) {
+        self.put_managed(host, block, true)
+    }
+
+    fn put_managed(&mut self, host: Option<&str>, block: &[String], last: bool) {
+        let mut blocks = self.managed(host);
+        let first = match blocks.is_empty() {
+            true => None,
+            false => Some(blocks.remove(0)),
+        };
+        for other in blocks.iter().rev() {
+            self.splice(other.clone(), vec![]);
+        }
+        match first {
+            Some(first) if self.lines[first.clone()] == *block => {}
+            Some(first) => self.splice(first, block.to_vec()),
+            None if last => {
+                let mut added = block.to_vec();
+                let end = self.lines.len();
+                if self.lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    added.insert(0, String::new());
+                }
+                self.splice(end..end, added);
+            }
+            None => {
+                let mut added = block.to_vec();
+                if !self.lines.is_empty() {
+                    added.push(String::new());
+                }
+                self.splice(0..0, added);
+            }
+        }
+    }
+
+    pub fn changed(&self) -> bool {
+        self.lines != self.original
+    }
+
+    /// The edits as a diff, e.g. for `akr setup --dry-run`
+    pub fn diff(&self) -> String {
+        let mut edits = self.edits.iter().collect::<Vec<_>>();
+        edits.sort_by_key(|edit| edit.at);
+        let mut diff = format!("--- {}\n+++ {}\n", self.path.display(), self.path.display());
+        // how many lines the edits before added, the new file is numbered with them
+        let mut shift = 0isize;
+        for edit in edits {
+            // a side without lines is numbered by the line before
+            let start = |at: usize, len: usize| at + (len > 0) as usize;
+            let new_at = (edit.at as isize + shift) as usize;
+            diff.push_str(&format!(
+                "@@ -{},{} +{},{} @@\n",
+                start(edit.at, edit.removed.len()),
+                edit.removed.len(),
+                start(new_at, edit.added.len()),
+                edit.added.len()
+            ));
+            shift += edit.added.len() as isize - edit.removed.len() as isize;
+            for line in &edit.removed {
+                diff.push_str(&format!("-{}\n", line));
+            }
+            for line in &edit.added {
+                diff.push_str(&format!("+{}\n", line));
+            }
+        }
+        diff
+    }
+
+    /// Write the file if it changed, through a symlink to it (dotfiles are often linked) and with the
+    /// permissions it had
+    pub fn save(&self) -> Result<(), Error> {
+        if !self.changed() {
+            return Ok(());
+        }
+        let path = std::fs::canonicalize(&self.path).unwrap_or_else(|_| self.path.clone());
+        let permissions = std::fs::metadata(&path).map(|metadata| metadata.permissions());
+        if let Some(dir) = path.parent() {
+            if !dir.exists() {
+                std::fs::create_dir_all(dir)?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+                }
+            }
+        }
+
+        let mut text = self.lines.join("\n");
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        crate::util::write_atomically(&path, text.as_bytes())?;
+        if let Ok(permissions) = permissions {
+            std::fs::set_permissions(&path, permissions)?;
+        }
+        Ok(())
+    }
+}
+
+/// The config at `path` and the files it includes, in the order ssh reads them
+pub fn load_all(path: &Path) -> Result<Vec<ConfigFile>, Error> {
+    let mut files = vec![];
+    let mut seen = vec![];
+    load_into(path, 0, &mut files, &mut seen)?;
+    Ok(files)
+}
+
+fn load_into(
+    path: &Path,
+    depth: usize,
+    files: &mut Vec<ConfigFile>,
+    seen: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let canonical = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if depth > MAX_INCLUDE_DEPTH || seen.contains(&canonical) {
+        return Ok(());
+    }
+    seen.push(canonical);
+    let file = ConfigFile::load(path)?;
+    let includes = file.includes();
+    files.push(file);
+    for include in includes {
+        load_into(&include, depth + 1, files, seen)?;
+    }
+    Ok(())
+}
+
+/// An argument with spaces in quotes
+fn quote(arg: &str) -> String {
+    match arg.contains(char::is_whitespace) {
+        true => format!("\"{}\"", arg),
+        false => arg.to_string(),
+    }
+}
+
+/// The lines of akr's block for the agent on `socket`
+pub fn block(socket: &str) -> Vec<String> {
+    vec![
+        BEGIN.to_string(),
+        "Host *".to_string(),
+        format!("\tIdentityAgent {}", quote(socket)),
+        END.to_string(),
+    ]
+}
+
+/// The lines of akr's block for `host`, which offers it only the keys of `identity_files`
+pub fn host_block(host: &str, socket: &str, identity_files: &[String]) -> Vec<String> {
+    let mut block = vec![format!("{} {}", BEGIN, host), format!("Host {}", host)];
+    block.push(format!("\tIdentityAgent {}", quote(socket)));
+    for identity_file in identity_files {
+        block.push(format!("\tIdentityFile {}", quote(identity_file)));
+    }
+    block.push("\tIdentitiesOnly yes".to_string());
+    block.push(format!("{} {}", END, host));
+    block
+}
+
+/// The lines of the block that has ssh run `command`, e.g. "'/usr/bin/akr' host-context", with the host, user
+/// and port of each connection
+pub fn host_context_block(command: &str) -> Vec<String> {
+    vec![
+        format!("{} {}", BEGIN, HOST_CONTEXT),
+        format!("Match exec \"{} %n %h %r %p\"", command),
+        format!("{} {}", END, HOST_CONTEXT),
+    ]
+}
+
+/// The files matching `pattern`, with `*` and `?` in any of its parts, sorted like glob(3) does
+fn glob(pattern: &Path) -> Vec<PathBuf> {
+    let mut matches = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let part = component.as_os_str().to_string_lossy();
+        if !part.contains(['*', '?']) {
+            matches.iter_mut().for_each(|path| path.push(component));
+            continue;
+        }
+        let mut expanded = vec![];
+        for dir in &matches {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            let mut names = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| !name.starts_with('.') && wildcard_match(&part, name))
+                .collect::<Vec<_>>();
+            names.sort();
+            expanded.extend(names.into_iter().map(|name| dir.join(name)));
+        }
+        matches = expanded;
+    }
+    matches.retain(|path| path.is_file());
+    matches
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    // the positions in `pattern` and `name` to go back to when a `*` has to match one more character
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,182 @@
+use std::io::{Cursor, Read, Write};
+
+use super::SignArgs;
+use crate::client::Client;
+use crate::crypto;
+use crate::identity::StoredIdentity;
+use crate::key_index::KeyIndex;
+use crate::ssh_agent::PendingFido2Sign;
+use crate::ssh_format::{verify_ssh_signature, SshFido2KeyPairHandle};
+use crate::util::{read_data, read_string, write_data};
+use crate::{error::Error, protocol::Base64Buffer};
+use base64::Engine;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// OpenSSH signature format used by `ssh-keygen -Y sign` and git
+/// See: https://github.com/openssh/openssh-portable/blob/master/PROTOCOL.sshsig
+const MAGIC_PREAMBLE: &[u8] = b"SSHSIG";
+const SIG_VERSION: u32 = 0x01;
+const HASH_ALGORITHM: &str = "sha512";
+const BEGIN_SIGNATURE: &str = "-----BEGIN SSH SIGNATURE-----";
+const END_SIGNATURE: &str = "-----END SSH SIGNATURE-----";
+/// same line length as ssh-keygen
+const ARMOR_LINE_LEN: usize = 70;
+
+pub async fn run(args: SignArgs) -> Result<(), Error> {
+    let handle = find_key_pair_handle(args.key.as_deref())?;
+
+    let mut message = vec![];
+    match &args.file {
+        Some(file) => message = std::fs::read(file)?,
+        None => {
+            std::io::stdin().read_to_end(&mut message)?;
+        }
+    }
+
+    let signature = sign(handle, &args.namespace, &message).await?;
+    match &args.file {
+        // like ssh-keygen, signing a file writes "<file>.sig"
+        Some(file) => std::fs::write(format!("{}.sig", file), signature)?,
+        None => std::io::stdout().write_all(signature.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+/// Produce an armored SSHSIG signature of `message` with a key on the phone
+pub async fn sign(handle: SshFido2KeyPairHandle, namespace: &str, message: &[u8]) -> Result<String, Error> {
+    let public_key = handle.fmt_public_key()?;
+    eprintln!("Approve the signature for {} on your phone", handle.key_comment());
+
+    let signed_data = signed_data(namespace, message)?;
+    let signature = PendingFido2Sign::for_key_pair_handle(Client::new()?, handle, &signed_data)?
+        .sign()
+        .await?;
+
+    /*
+       byte[6]   MAGIC_PREAMBLE
+       uint32    SIG_VERSION
+       string    publickey
+       string    namespace
+       string    reserved
+       string    hash_algorithm
+       string    signature
+    */
+    let mut blob = MAGIC_PREAMBLE.to_vec();
+    blob.write_u32::<BigEndian>(SIG_VERSION)?;
+    write_data(&mut blob, &public_key)?;
+    write_data(&mut blob, namespace.as_bytes())?;
+    write_data(&mut blob, &[])?;
+    write_data(&mut blob, HASH_ALGORITHM.as_bytes())?;
+    write_data(&mut blob, &signature)?;
+
+    Ok(armor(&blob))
+}
+
+/// The data the key actually signs
+///    byte[6]   MAGIC_PREAMBLE
+///    string    namespace
+///    string    reserved
+///    string    hash_algorithm
+///    string    H(message)
+fn signed_data(namespace: &str, message: &[u8]) -> Result<Vec<u8>, Error> {
+    let hash = crypto::sha512(message);
+    signed_hash(namespace, HASH_ALGORITHM, hash.as_ref())
+}
+
+fn signed_hash(namespace: &str, hash_algorithm: &str, hash: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut data = MAGIC_PREAMBLE.to_vec();
+    write_data(&mut data, namespace.as_bytes())?;
+    write_data(&mut data, &[])?;
+    write_data(&mut data, hash_algorithm.as_bytes())?;
+    write_data(&mut data, hash)?;
+    Ok(data)
+}
+
+/// Check an armored signature of `ssh-keygen -Y sign` over `message`, made for `namespace` with the wire
+/// format `public_key`, e.g. the one of the team policy
+pub fn verify(armored: &str, namespace: &str, message: &[u8], public_key: &[u8]) -> Result<bool, Error> {
+    let encoded = armored
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && *line != BEGIN_SIGNATURE && *line != END_SIGNATURE)
+        .collect::<String>();
+    let Ok(blob) = base64::engine::general_purpose::STANDARD.decode(encoded) else {
+        return Ok(false);
+    };
+
+    let mut cursor = Cursor::new(blob);
+    let mut magic = [0u8; 6];
+    cursor.read_exact(&mut magic)?;
+    if magic != MAGIC_PREAMBLE || cursor.read_u32::<BigEndian>()? != SIG_VERSION {
+        return Ok(false);
+    }
+    let signer = read_data(&mut cursor)?;
+    let signed_namespace = read_data(&mut cursor)?;
+    let _reserved = read_data(&mut cursor)?;
+    let hash_algorithm = read_string(&mut cursor)?;
+    let signature = read_data(&mut cursor)?;
+    if signer != public_key || signed_namespace != namespace.as_bytes() {
+        return Ok(false);
+    }
+
+    let hash = match hash_algorithm.as_str() {
+        "sha512" => crypto::sha512(message).to_vec(),
+        "sha256" => crypto::sha256(message).to_vec(),
+        _ => return Ok(false),
+    };
+    verify_ssh_signature(
+        public_key,
+        &signed_hash(namespace, &hash_algorithm, &hash)?,
+        &signature,
+    )
+}
+
+fn armor(blob: &[u8]) -> String {
+    let encoded = Base64Buffer(blob.to_vec()).to_string();
+
+    let mut armored = format!("{}\n", BEGIN_SIGNATURE);
+    for line in encoded.as_bytes().chunks(ARMOR_LINE_LEN) {
+        armored.push_str(&String::from_utf8_lossy(line));
+        armored.push('\n');
+    }
+    armored.push_str(END_SIGNATURE);
+    armored.push('\n');
+    armored
+}
+
+/// Pick the key to sign with: a public key file, an "ssh-..." public key line, a fingerprint,
+/// a key label/comment/application, or the only ssh key there is. A certificate picks the key it certifies
+pub fn find_key_pair_handle(key: Option<&str>) -> Result<SshFido2KeyPairHandle, Error> {
+    let mut handles = StoredIdentity::load_from_disk()?.key_pair_handles;
+    handles.extend(StoredIdentity::load_added_key_pair_handles()?);
+    handles.retain(|handle| handle.application.starts_with("ssh:"));
+
+    let key = match key {
+        Some(key) => key,
+        None if handles.len() == 1 => return Ok(handles.remove(0)),
+        None => return Err(Error::UnknownKey),
+    };
+    let index = KeyIndex::from_handles(handles)?;
+
+    // "<key type> <base64 public key> [comment]"
+    let public_key_line = match std::fs::read_to_string(key) {
+        Ok(contents) => contents,
+        Err(_) => key.to_string(),
+    };
+    let public_key = public_key_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded).ok());
+
+    let found = match &public_key {
+        Some(public_key) => index.get(public_key),
+        None => index.get_by_fingerprint(key).or_else(|| {
+            index
+                .iter()
+                .map(|(_, handle)| handle)
+                .find(|handle| handle.key_comment() == key || handle.application == key)
+        }),
+    };
+    found.cloned().ok_or(Error::UnknownKey)
+}
@@ -0,0 +1,231 @@
+//! `akr status`: whether the agent is running, the paired phones/tablets answer and the relays are up
+
+use crate::client::Client;
+use crate::control::{self, AgentState, ControlRequest};
+use crate::error::{Error, QueueEvaluation};
+use crate::output;
+use crate::protocol::{PingRequest, PingResponse, RequestBody};
+use ansi_term::Colour::{Green, Red, Yellow};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// how long to wait for a device to answer a ping
+const PING_TIMEOUT: Duration = Duration::from_secs(15);
+const AGENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+
+#[derive(Serialize)]
+struct Status {
+    agent: AgentStatus,
+    devices: Vec<DeviceStatus>,
+    relays: Vec<RelayStatus>,
+    push_channel: String,
+}
+
+#[derive(Serialize)]
+struct AgentStatus {
+    socket: PathBuf,
+    running: bool,
+    /// the identities the agent lists to ssh
+    identities: Option<u32>,
+    /// what the agent says about itself, agents older than the control messages don't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<AgentState>,
+}
+
+#[derive(Serialize)]
+struct DeviceStatus {
+    name: String,
+    /// unix time of the last response
+    last_seen_at: Option<i64>,
+    reachable: bool,
+    round_trip_ms: Option<u128>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RelayStatus {
+    pub name: &'static str,
+    pub reachable: bool,
+}
+
+pub async fn run() -> Result<(), Error> {
+    let client = Client::new()?;
+    let socket = crate::agent_socket_path()?;
+
+    let (agent, devices, relays) = futures::future::join3(
+        agent_status(socket),
+        device_statuses(&client),
+        relay_statuses(&client),
+    )
+    .await;
+    let status = Status {
+        agent,
+        devices: devices?,
+        relays,
+        push_channel: client.push_state().to_string(),
+    };
+
+    if output::is_json() {
+        return output::print_json(&status);
+    }
+    print(&status);
+    Ok(())
+}
+
+fn print(status: &Status) {
+    let agent = &status.agent;
+    match (agent.running, agent.identities) {
+        (true, Some(identities)) => println!(
+            "Agent: {} on {} with {} identities",
+            Green.paint("running"),
+            agent.socket.display(),
+            identities
+        ),
+        _ => println!("Agent: {} ({})", Red.paint("not running"), agent.socket.display()),
+    }
+    if let Some(state) = &agent.state {
+        print!("  ");
+        control::print_state(state);
+    }
+
+    if status.devices.is_empty() {
+        println!("Devices: {}", Yellow.paint("none paired, run `akr pair`"));
+    } else {
+        println!("Devices:");
+    }
+    for device in &status.devices {
+        let last_seen = device
+            .last_seen_at
+            .and_then(|t| chrono::NaiveDateTime::from_timestamp_opt(t, 0))
+            .map(|t| format!("last seen {}", t.format("%Y-%m-%d %H:%M")))
+            .unwrap_or_else(|| "never seen".to_string());
+        match (&device.round_trip_ms, &device.error) {
+            (Some(ms), _) => println!(
+                "  {}: {} in {}ms, {}",
+                device.name,
+                Green.paint("reachable"),
+                ms,
+                last_seen
+            ),
+            (None, error) => println!(
+                "  {}: {} ({}), {}",
+                device.name,
+                Red.paint("unreachable"),
+                error.as_deref().unwrap_or_default(),
+                last_seen
+            ),
+        }
+    }
+
+    let relays = status
+        .relays
+        .iter()
+        .map(|relay| match relay.reachable {
+            true => format!("{} {}", relay.name, Green.paint("up")),
+            false => format!("{} {}", relay.name, Red.paint("down")),
+        })
+        .collect::<Vec<_>>();
+    println!("Relays: {}", relays.join(", "));
+    println!("Push channel: {}", status.push_channel);
+}
+
+async fn agent_status(socket: PathBuf) -> AgentStatus {
+    let identities = tokio::time::timeout(AGENT_TIMEOUT, agent_identities(&socket))
+        .await
+        .ok()
+        .and_then(Result::ok);
+    let state = match identities {
+        Some(_) => control::send(&socket, ControlRequest::Status).await.ok(),
+        None => None,
+    };
+    AgentStatus {
+        socket,
+        running: identities.is_some(),
+        identities,
+        state,
+    }
+}
+
+/// Ask the agent listening on `socket` how many identities it has, like `ssh-add -l`
+pub async fn agent_identities(socket: &Path) -> Result<u32, Error> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(socket).await?;
+    #[cfg(windows)]
+    let stream = tokio::net::windows::named_pipe::ClientOptions::new().open(socket)?;
+    count_identities(stream).await
+}
+
+async fn count_identities<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> Result<u32, Error> {
+    stream.write_u32(1).await?;
+    stream.write_u8(SSH_AGENTC_REQUEST_IDENTITIES).await?;
+
+    let len = stream.read_u32().await?;
+    let mut reply = vec![0; (len as usize).min(1024 * 1024)];
+    stream.read_exact(&mut reply).await?;
+
+    match reply.as_slice() {
+        [SSH_AGENT_IDENTITIES_ANSWER, a, b, c, d, ..] => Ok(u32::from_be_bytes([*a, *b, *c, *d])),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+async fn device_statuses(client: &Client) -> Result<Vec<DeviceStatus>, Error> {
+    let pairings = match Client::pairings() {
+        Err(Error::NotPaired) => vec![],
+        pairings => pairings?,
+    };
+    let pings = pairings.into_iter().map(|pairing| async move {
+        let name = pairing.device_name.clone();
+        let last_seen_at = Some(pairing.last_seen_at).filter(|t| *t > 0);
+        let started = Instant::now();
+        let ping = client.send_request_to_device::<PingResponse>(pairing, RequestBody::Ping(PingRequest {}));
+
+        let (round_trip_ms, error) = match tokio::time::timeout(PING_TIMEOUT, ping).await {
+            Ok(Ok(_)) => (Some(started.elapsed().as_millis()), None),
+            Ok(Err(e)) => (None, Some(e.to_string())),
+            Err(_) => (None, Some(Error::ResponseTimedOut.to_string())),
+        };
+        DeviceStatus {
+            name,
+            // answering the ping counts as being seen
+            last_seen_at: round_trip_ms
+                .map(|_| chrono::Utc::now().timestamp())
+                .or(last_seen_at),
+            reachable: round_trip_ms.is_some(),
+            round_trip_ms,
+            error,
+        }
+    });
+    Ok(futures::future::join_all(pings).await)
+}
+
+pub async fn relay_statuses(client: &Client) -> Vec<RelayStatus> {
+    let reachable =
+        |evaluation: Result<QueueEvaluation, Error>| matches!(evaluation, Ok(QueueEvaluation::Allow));
+    let (pzq, aws, azure) = futures::future::join3(
+        client.pz_health_check(),
+        client.aws_health_check(),
+        client.azure_health_check(),
+    )
+    .await;
+
+    vec![
+        RelayStatus {
+            name: "pzqueue",
+            reachable: reachable(pzq),
+        },
+        RelayStatus {
+            name: "aws",
+            reachable: reachable(aws),
+        },
+        RelayStatus {
+            name: "azure",
+            reachable: reachable(azure),
+        },
+    ]
+}
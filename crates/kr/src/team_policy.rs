@@ -0,0 +1,193 @@
+//! The policy of a team's admin, enforced on top of the settings of the machine: which keys may sign, whether
+//! the user has to be verified, which hosts logins may go to and how long approval windows last
+//!
+//! The admin signs the document with `ssh-keygen -Y sign -f <key> -n akr-policy policy.json` and serves it at
+//! `policy_url` of the config, with the signature at the same URL and ".sig" appended. A document is only
+//! taken when it's signed with `policy_public_key`, the admin's public key as in authorized_keys, and its
+//! `serial` isn't older than the one's before. The agent fetches it at start and every `REFRESH`, and keeps
+//! the last one in "~/.akr/team_policy.json" for when it can't. With a `policy_url` nothing is signed until
+//! there was one.
+//!
+//! ```json
+//! {
+//!     "serial": 7,
+//!     "allowed_rp_ids": ["ssh:corp-*"],
+//!     "require_user_verification": true,
+//!     "allowed_hosts": ["*.corp.example.com", "git@github.com"],
+//!     "max_session_minutes": 60
+//! }
+//! ```
+
+use crate::cli::TeamPolicyArgs;
+use crate::error::Error;
+use crate::policy::{self, Target};
+use crate::{config, output, sshsig, transport, util};
+use ansi_term::Colour::Green;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// the namespace of the signature
+pub const NAMESPACE: &str = "akr-policy";
+const FILE: &str = "team_policy.json";
+const SIGNATURE_FILE: &str = "team_policy.json.sig";
+/// how often the agent fetches the policy
+const REFRESH: Duration = Duration::from_secs(15 * 60);
+
+/// the policy in force, once it was fetched or read from disk
+static POLICY: RwLock<Option<TeamPolicy>> = RwLock::new(None);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TeamPolicy {
+    /// raised with every change, an older document doesn't replace a newer one
+    pub serial: u64,
+    /// the rp ids of the keys that may sign, where "*" matches any part of one, e.g. "ssh:corp-*"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_rp_ids: Option<Vec<String>>,
+    /// a signature only counts when the phone/tablet verified the user, e.g. with a fingerprint
+    #[serde(default)]
+    pub require_user_verification: bool,
+    /// the only "[user@]host" patterns logins may go to, like `allowed_hosts` of the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_hosts: Option<Vec<String>>,
+    /// the longest approval window of `akr policy`, 0 for none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_session_minutes: Option<u32>,
+}
+
+impl TeamPolicy {
+    /// Whether keys of `rp_id` may sign
+    pub fn check_rp_id(&self, rp_id: &str) -> Result<(), Error> {
+        match &self.allowed_rp_ids {
+            Some(allowed) if !allowed.iter().any(|pattern| policy::glob(pattern, rp_id)) => Err(
+                Error::RefusedByTeamPolicy(format!("{} isn't one of its allowed_rp_ids", rp_id)),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a login may go to `target`, by its resolved HostName as with `allowed_hosts` of the config
+    pub fn allows_destination(&self, target: &Target) -> bool {
+        self.allowed_hosts
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|pattern| policy::allows(pattern, target)))
+    }
+}
+
+/// The policy in force, none without a `policy_url`. Commands other than the agent go with the one it stored
+pub fn current() -> Result<Option<TeamPolicy>, Error> {
+    if config::get().policy_url.is_none() {
+        return Ok(None);
+    }
+    if let Some(policy) = POLICY.read().unwrap().clone() {
+        return Ok(Some(policy));
+    }
+    let policy = load_stored()?.ok_or(Error::NoTeamPolicy)?;
+    *POLICY.write().unwrap() = Some(policy.clone());
+    Ok(Some(policy))
+}
+
+/// The policy stored by the last fetch, checked again, a changed file doesn't count
+fn load_stored() -> Result<Option<TeamPolicy>, Error> {
+    let dir = crate::create_home_path()?;
+    let (Ok(document), Ok(signature)) = (
+        std::fs::read(dir.join(FILE)),
+        std::fs::read(dir.join(SIGNATURE_FILE)),
+    ) else {
+        return Ok(None);
+    };
+    verify(&document, &signature).map(Some)
+}
+
+/// The wire format of `policy_public_key`, "<key type> <base64 public key> [comment]"
+fn public_key() -> Result<Vec<u8>, Error> {
+    let invalid = |reason: &str| Error::InvalidConfig("policy_public_key".to_string(), reason.to_string());
+    let line = config::get()
+        .policy_public_key
+        .as_deref()
+        .ok_or_else(|| invalid("it isn't set, the policy of policy_url can't be checked"))?;
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|encoded| base64::engine::general_purpose::STANDARD.decode(encoded).ok())
+        .ok_or_else(|| invalid("expected a public key as in authorized_keys"))
+}
+
+fn verify(document: &[u8], signature: &[u8]) -> Result<TeamPolicy, Error> {
+    let signature = String::from_utf8_lossy(signature);
+    if !sshsig::verify(&signature, NAMESPACE, document, &public_key()?)? {
+        return Err(Error::InvalidTeamPolicy(
+            "its signature doesn't verify with policy_public_key".to_string(),
+        ));
+    }
+    serde_json::from_slice(document).map_err(|e| Error::InvalidTeamPolicy(e.to_string()))
+}
+
+/// Fetch the policy from `policy_url` and put it in force, unless it's older than the one that is
+pub async fn fetch() -> Result<TeamPolicy, Error> {
+    let url = config::get()
+        .policy_url
+        .clone()
+        .ok_or_else(|| Error::InvalidConfig("policy_url".to_string(), "it isn't set".to_string()))?;
+    let mut signature_url = url.clone();
+    signature_url.set_path(&format!("{}.sig", url.path()));
+
+    let client = transport::proxy::reqwest_client()?;
+    let document = get(&client, url).await?;
+    let signature = get(&client, signature_url).await?;
+    let policy = verify(&document, &signature)?;
+
+    let in_force = current().ok().flatten();
+    if let Some(in_force) = in_force.filter(|in_force| in_force.serial > policy.serial) {
+        return Err(Error::InvalidTeamPolicy(format!(
+            "serial {} is older than {} of the policy in force",
+            policy.serial, in_force.serial
+        )));
+    }
+    let dir = crate::create_home_path()?;
+    util::write_atomically(&dir.join(FILE), &document)?;
+    util::write_atomically(&dir.join(SIGNATURE_FILE), &signature)?;
+    *POLICY.write().unwrap() = Some(policy.clone());
+    Ok(policy)
+}
+
+async fn get(client: &reqwest::Client, url: reqwest::Url) -> Result<Vec<u8>, Error> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Keep the policy up to date, in the agent. Does nothing without a `policy_url`
+pub fn start() {
+    if config::get().policy_url.is_none() {
+        return;
+    }
+    tokio::spawn(async {
+        loop {
+            match fetch().await {
+                Ok(policy) => tracing::info!(serial = policy.serial, "fetched the team policy"),
+                Err(e) => eprintln!("couldn't fetch the team policy: {}", e),
+            }
+            tokio::time::sleep(REFRESH).await;
+        }
+    });
+}
+
+/// `akr team-policy`
+pub async fn run(args: TeamPolicyArgs) -> Result<(), Error> {
+    let policy = match args.fetch {
+        true => Some(fetch().await?),
+        false => current()?,
+    };
+    if output::is_json() {
+        return output::print_json(&policy);
+    }
+    match policy {
+        Some(policy) => {
+            println!("{} #{}", Green.paint("Team policy"), policy.serial);
+            println!("{}", serde_json::to_string_pretty(&policy)?);
+        }
+        None => println!("No team policy, policy_url isn't set"),
+    }
+    Ok(())
+}
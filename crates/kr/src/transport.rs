@@ -1,12 +1,27 @@
+use crate::crypto;
 use crate::error::Error;
 use crate::protocol::Base64Buffer;
-use crate::protocol::WireMessage;
+use crate::protocol::{Request, Response, WireMessage};
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tokio::time::Instant;
 use urlencoding::encode;
 use uuid::Uuid;
 
+pub mod bluetooth;
+mod cbor;
+mod hid;
+pub mod local_network;
+pub mod loopback;
+mod mailbox;
+pub mod mock;
+pub mod outbox;
+pub mod proxy;
+pub mod push;
+
 #[async_trait]
 pub trait Transport {
     async fn create_queue(&self, queue_uuid: Uuid) -> Result<(), Error>;
@@ -18,18 +33,64 @@ pub trait Transport {
     ) -> Result<(), Error>;
     async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
     where
-        F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send;
+        F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync;
 
     async fn health_check(&self) -> Result<(), Error>;
 }
 
+/// Carries a whole request to an authenticator and brings back its response,
+/// unlike `Transport`, which only moves sealed messages through the relay queues
+#[async_trait]
+pub trait DeviceTransport: Send + Sync {
+    async fn exchange(&self, request: &Request, deadline: Option<Instant>) -> Result<Response, Error>;
+}
+
+/// How requests reach an authenticator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// the paired phones/tablets, through the cloud relay
+    Relay,
+    /// a FIDO2 security key attached to this machine
+    Loopback,
+    /// the script of `AKR_MOCK_SCRIPT`, see `mock`
+    Mock,
+}
+
+impl FromStr for TransportKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relay" => Ok(TransportKind::Relay),
+            "loopback" | "usb" => Ok(TransportKind::Loopback),
+            "mock" => Ok(TransportKind::Mock),
+            _ => Err(Error::UnknownTransport(s.to_string())),
+        }
+    }
+}
+
+static SELECTED_TRANSPORT: OnceLock<TransportKind> = OnceLock::new();
+
+/// Pick the transport for every `Client` created afterwards
+pub fn select(kind: TransportKind) {
+    let _ = SELECTED_TRANSPORT.set(kind);
+}
+
+pub fn selected() -> TransportKind {
+    SELECTED_TRANSPORT.get().copied().unwrap_or(TransportKind::Relay)
+}
+
 pub mod pzqueue {
+    use super::push::{PushChannel, PushState};
     use super::*;
+    use std::time::Duration;
     use uuid::Uuid;
 
     #[derive(Clone)]
     pub struct PZQueueClient {
         client: reqwest::Client,
+        /// responses are pushed over this when the relay supports it, polled for otherwise
+        push: PushChannel,
     }
 
     pub struct QueueName(Uuid);
@@ -47,10 +108,16 @@ pub mod pzqueue {
     impl PZQueueClient {
         const URL: &'static str = "https://mfa.akamai.com/api/v1/device/krypton/channel";
 
-        pub fn new() -> Self {
-            Self {
-                client: reqwest::Client::new(),
-            }
+        pub fn new() -> Result<Self, Error> {
+            let client = proxy::reqwest_client()?;
+            Ok(Self {
+                push: PushChannel::new(client.clone()),
+                client,
+            })
+        }
+
+        pub fn push_state(&self) -> PushState {
+            self.push.state()
         }
 
         async fn send_inner(
@@ -71,15 +138,18 @@ pub mod pzqueue {
 
         async fn receive_inner<T, F>(&self, queue_name: &str, on_messages: F) -> Result<T, Error>
         where
-            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send,
+            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
         {
-            let url = format!("{}/{}?poll_wait_secs=10", Self::URL, queue_name);
+            let url = format!("{}/{}", Self::URL, queue_name);
 
             // only try for 60s
-            let timeout = 60i64;
-            let mut duration = 0i64;
-            while duration < timeout {
-                let now = chrono::Utc::now().timestamp();
+            let deadline = Instant::now() + Duration::from_secs(60);
+            if let Some(res) = self.push.receive(&url, deadline, &on_messages).await? {
+                return Ok(res);
+            }
+
+            let url = format!("{}?poll_wait_secs=10", url);
+            while Instant::now() < deadline {
                 let res: Res<Messages> = self.client.get(&url).send().await?.json().await?;
                 let wire: Vec<WireMessage> = res
                     .result
@@ -88,8 +158,6 @@ pub mod pzqueue {
                     .filter_map(|m| WireMessage::new(m.0).ok())
                     .collect();
 
-                duration += chrono::Utc::now().timestamp() - now;
-
                 if let Some(res) = on_messages(&wire)? {
                     return Ok(res);
                 }
@@ -127,7 +195,7 @@ pub mod pzqueue {
 
         async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
         where
-            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send,
+            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
         {
             let queue = QueueName(queue_uuid);
             self.receive_inner(&queue.receive(), on_messages).await
@@ -136,7 +204,7 @@ pub mod pzqueue {
         async fn health_check(&self) -> Result<(), Error> {
             let queue_uuid = Uuid::new_v4();
             self.create_queue(queue_uuid).await?;
-            let fake_message: Vec<u8> = sodiumoxide::randombytes::randombytes(4);
+            let fake_message: Vec<u8> = crypto::random_bytes(4);
             let msg = WireMessage::SealedMessage(fake_message.clone());
 
             let queue = QueueName(queue_uuid);
@@ -161,6 +229,7 @@ pub mod krypton_aws {
     use super::*;
     use base64::Engine;
     use rusoto_core::credential::StaticProvider;
+    use rusoto_core::request::HttpConfig;
     use rusoto_core::{HttpClient, Region};
     use rusoto_sns::{PublishInput, Sns, SnsClient};
     use rusoto_sqs::{
@@ -196,7 +265,7 @@ pub mod krypton_aws {
 
         async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
         where
-            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send,
+            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
         {
             self.create_queue(queue_uuid).await?;
             let queue = QueueName(queue_uuid);
@@ -204,7 +273,7 @@ pub mod krypton_aws {
         }
 
         async fn health_check(&self) -> Result<(), Error> {
-            let fake_message: Vec<u8> = sodiumoxide::randombytes::randombytes(4);
+            let fake_message: Vec<u8> = crypto::random_bytes(4);
 
             let queue_uuid = Uuid::new_v4();
             self.create_queue(queue_uuid).await?;
@@ -251,8 +320,13 @@ pub mod krypton_aws {
 
         pub fn new() -> Result<Self, Error> {
             let provider = StaticProvider::new(Self::ACCESS_KEY.into(), Self::SECRET_KEY.into(), None, None);
-            let sqs = SqsClient::new_with(HttpClient::new()?, provider.clone(), Region::UsEast1);
-            let sns = SnsClient::new_with(HttpClient::new()?, provider.clone(), Region::UsEast1);
+            // one pool of connections for both, kept open between requests
+            let mut config = HttpConfig::new();
+            config.pool_idle_timeout(proxy::POOL_IDLE_TIMEOUT);
+            let http_client = HttpClient::from_connector_with_config(proxy::https_connector(), config);
+            let client = rusoto_core::Client::new_with(provider, http_client);
+            let sqs = SqsClient::new_with_client(client.clone(), Region::UsEast1);
+            let sns = SnsClient::new_with_client(client, Region::UsEast1);
             Ok(Self { sqs, sns })
         }
 
@@ -336,7 +410,7 @@ pub mod krypton_aws {
 
         async fn receive_inner<T, F>(&self, queue_name: &str, on_messages: F) -> Result<T, Error>
         where
-            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send,
+            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
         {
             // only try for 60s
             let timeout = 60i64;
@@ -540,21 +614,20 @@ pub mod krypton_azure {
             Ok(())
         }
 
-        /// check if token has crossed se time
+        /// check if token has crossed se time, a time that doesn't parse gets the token refreshed
         pub fn is_token_expired(&self) -> bool {
-            let se = chrono::DateTime::parse_from_rfc3339(&self.result.params.se).unwrap();
             let now = chrono::Utc::now();
-            se < now
+            chrono::DateTime::parse_from_rfc3339(&self.result.params.se).map_or(true, |se| se < now)
         }
     }
 
     impl AzureQueueClient {
         const TOKEN_URL: &'static str = "https://mfa.akamai.com/api/v1/device/krypton/azq/token";
 
-        pub fn new() -> Self {
-            Self {
-                client: reqwest::Client::new(),
-            }
+        pub fn new() -> Result<Self, Error> {
+            Ok(Self {
+                client: proxy::reqwest_client()?,
+            })
         }
 
         async fn create_queue_inner(&self, queue_uuid: Uuid) -> Result<(), Error> {
@@ -647,7 +720,7 @@ pub mod krypton_azure {
 
         async fn receive_inner<T, F>(&self, queue_name: &str, on_messages: F) -> Result<T, Error>
         where
-            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send,
+            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
         {
             //check whether token is about to expire or not
             // if yes, fetch new token or re-use previous token
@@ -683,18 +756,17 @@ pub mod krypton_azure {
                     res.replace("\u{feff}<?xml version=\"1.0\" encoding=\"utf-8\"?>", "")
                         .as_bytes(),
                 )
-                .expect("Couldn't parse xml response");
+                .map_err(|_| Error::UnexpectedResponse)?;
 
                 // continue if there are messages
-                if data.queue_message.is_some() {
-                    let message = base64::engine::general_purpose::STANDARD
-                        .decode(data.queue_message.clone().unwrap().message_text)?;
+                if let Some(queue_message) = data.queue_message {
+                    let message =
+                        base64::engine::general_purpose::STANDARD.decode(&queue_message.message_text)?;
 
                     //delete the message from the queue
-                    self.delete_message(queue_name, &data.queue_message.unwrap())
-                        .await?;
+                    self.delete_message(queue_name, &queue_message).await?;
 
-                    let wire: Vec<WireMessage> = vec![WireMessage::new(message).unwrap()];
+                    let wire: Vec<WireMessage> = vec![WireMessage::new(message)?];
 
                     duration += chrono::Utc::now().timestamp() - now;
 
@@ -752,7 +824,7 @@ pub mod krypton_azure {
 
         async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
         where
-            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send,
+            F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
         {
             self.create_queue(queue_uuid).await?;
             let queue = QueueName(queue_uuid);
@@ -762,7 +834,7 @@ pub mod krypton_azure {
         async fn health_check(&self) -> Result<(), Error> {
             let queue_uuid = Uuid::new_v4();
             self.create_queue(queue_uuid).await?;
-            let fake_message: Vec<u8> = sodiumoxide::randombytes::randombytes(4);
+            let fake_message: Vec<u8> = crypto::random_bytes(4);
             let msg = WireMessage::SealedMessage(fake_message.clone());
 
             let queue = QueueName(queue_uuid);
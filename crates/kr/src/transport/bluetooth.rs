@@ -0,0 +1,348 @@
+//! Exchange sealed messages with a paired phone/tablet directly over bluetooth LE
+//!
+//! The phone/tablet advertises a GATT service (its UUID is sent with the pairing's `me` response)
+//! with a single characteristic: requests are written to it and responses come back as
+//! notifications. Messages are the same sealed `WireMessage`s the relay queues carry, split into
+//! chunks that start with the number of chunks still to come.
+
+use super::mailbox::Mailboxes;
+use super::Transport;
+use crate::error::Error;
+use crate::identity::BluetoothPeer;
+use crate::protocol::WireMessage;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+/// bytes of message per chunk, small enough for a single ATT write on most phones
+const CHUNK_LEN: usize = 180;
+
+#[derive(Clone)]
+pub struct BluetoothClient {
+    peers: Vec<BluetoothPeer>,
+    mailboxes: Mailboxes,
+}
+
+impl BluetoothClient {
+    pub fn new(peers: Vec<BluetoothPeer>) -> Self {
+        Self {
+            peers,
+            mailboxes: Mailboxes::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for BluetoothClient {
+    async fn create_queue(&self, _: Uuid) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        _device_token: Option<String>,
+        queue_uuid: Uuid,
+        message: WireMessage,
+    ) -> Result<(), Error> {
+        let service_uuid = self
+            .peers
+            .iter()
+            .find(|peer| peer.queue_uuid == queue_uuid)
+            .map(|peer| peer.service_uuid)
+            .ok_or_else(|| Error::Bluetooth("pairing has no bluetooth peer".into()))?;
+
+        self.mailboxes
+            .spawn(queue_uuid, gatt::exchange(service_uuid, message));
+        Ok(())
+    }
+
+    async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
+    where
+        F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
+    {
+        self.mailboxes.receive(queue_uuid, on_messages).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        gatt::adapter_powered().await
+    }
+}
+
+/// Split a message into chunks of `[chunks remaining][data]`
+fn chunk(message: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let chunks = message.chunks(CHUNK_LEN).collect::<Vec<_>>();
+    if chunks.len() > u8::MAX as usize + 1 {
+        return Err(Error::Bluetooth("message too large".into()));
+    }
+
+    let last = chunks.len().saturating_sub(1);
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, data)| [&[(last - i) as u8][..], data].concat())
+        .collect())
+}
+
+/// GATT client through BlueZ's D-Bus API
+/// See: https://git.kernel.org/pub/scm/bluetooth/bluez.git/tree/doc
+#[cfg(target_os = "linux")]
+mod gatt {
+    use super::chunk;
+    use crate::error::Error;
+    use crate::protocol::WireMessage;
+    use futures::StreamExt;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::time::Instant;
+    use uuid::Uuid;
+    use zbus::fdo::{ObjectManagerProxy, PropertiesProxy};
+    use zbus::zvariant::{OwnedObjectPath, Value};
+    use zbus::{Connection, Proxy};
+
+    const BLUEZ: &str = "org.bluez";
+    const ADAPTER: &str = "org.bluez.Adapter1";
+    const DEVICE: &str = "org.bluez.Device1";
+    const CHARACTERISTIC: &str = "org.bluez.GattCharacteristic1";
+
+    /// the characteristic requests and responses go through
+    const MESSAGE_CHARACTERISTIC_UUID: &str = "20f53e48-c08d-423a-b2c2-1c797889af24";
+
+    /// how long to look for the phone before leaving it to the relay
+    const SCAN_TIMEOUT: Duration = Duration::from_secs(5);
+    /// how long to wait for a response, like the relay queues
+    const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    fn bluez_error(e: impl ToString) -> Error {
+        Error::Bluetooth(e.to_string())
+    }
+
+    /// Write `message` to the phone advertising `service_uuid` and wait for its response
+    pub async fn exchange(service_uuid: Uuid, message: WireMessage) -> Result<WireMessage, Error> {
+        let connection = Connection::system().await.map_err(bluez_error)?;
+        let device = find_device(&connection, service_uuid).await?;
+        connect(&connection, &device).await?;
+        let characteristic = find_characteristic(&connection, &device).await?;
+
+        let proxy = Proxy::new(&connection, BLUEZ, characteristic.as_str(), CHARACTERISTIC)
+            .await
+            .map_err(bluez_error)?;
+        let properties = PropertiesProxy::builder(&connection)
+            .destination(BLUEZ)
+            .and_then(|builder| builder.path(characteristic.as_str()))
+            .map_err(bluez_error)?
+            .build()
+            .await
+            .map_err(bluez_error)?;
+
+        // subscribe before writing so the response can't slip by
+        let mut changes = properties
+            .receive_properties_changed()
+            .await
+            .map_err(bluez_error)?;
+        proxy.call_method("StartNotify", &()).await.map_err(bluez_error)?;
+
+        for chunk in chunk(&message.into_wire())? {
+            let options: HashMap<&str, Value> = HashMap::new();
+            proxy
+                .call_method("WriteValue", &(chunk, options))
+                .await
+                .map_err(bluez_error)?;
+        }
+
+        let response = tokio::time::timeout(RESPONSE_TIMEOUT, async {
+            let mut received = vec![];
+            while let Some(change) = changes.next().await {
+                let args = change.args().map_err(bluez_error)?;
+                if args.interface_name().as_str() != CHARACTERISTIC {
+                    continue;
+                }
+                let value = match args.changed_properties().get("Value") {
+                    Some(value) => Vec::<u8>::try_from(value.clone()).map_err(bluez_error)?,
+                    None => continue,
+                };
+
+                let (remaining, data) = value
+                    .split_first()
+                    .ok_or_else(|| bluez_error("empty notification"))?;
+                received.extend_from_slice(data);
+                if *remaining == 0 {
+                    return WireMessage::new(received);
+                }
+            }
+            Err(bluez_error("notifications stopped"))
+        })
+        .await
+        .map_err(|_| Error::ResponseTimedOut)?;
+
+        let _ = proxy.call_method("StopNotify", &()).await;
+        response
+    }
+
+    pub async fn adapter_powered() -> Result<(), Error> {
+        let connection = Connection::system().await.map_err(bluez_error)?;
+        let adapter = find_adapter(&connection).await?;
+        let proxy = Proxy::new(&connection, BLUEZ, adapter.as_str(), ADAPTER)
+            .await
+            .map_err(bluez_error)?;
+
+        match proxy.get_property::<bool>("Powered").await.map_err(bluez_error)? {
+            true => Ok(()),
+            false => Err(bluez_error("bluetooth is off")),
+        }
+    }
+
+    /// the objects BlueZ knows of that implement `interface`, with their properties
+    async fn objects(
+        connection: &Connection,
+        interface: &str,
+    ) -> Result<Vec<(OwnedObjectPath, HashMap<String, zbus::zvariant::OwnedValue>)>, Error> {
+        let manager = ObjectManagerProxy::builder(connection)
+            .destination(BLUEZ)
+            .and_then(|builder| builder.path("/"))
+            .map_err(bluez_error)?
+            .build()
+            .await
+            .map_err(bluez_error)?;
+
+        let objects = manager.get_managed_objects().await.map_err(bluez_error)?;
+        Ok(objects
+            .into_iter()
+            .filter_map(|(path, mut interfaces)| {
+                let properties = interfaces.drain().find(|(name, _)| name.as_str() == interface)?.1;
+                Some((path, properties))
+            })
+            .collect())
+    }
+
+    fn string_property(
+        properties: &HashMap<String, zbus::zvariant::OwnedValue>,
+        name: &str,
+    ) -> Option<String> {
+        properties
+            .get(name)
+            .and_then(|value| String::try_from(value.clone()).ok())
+    }
+
+    async fn find_adapter(connection: &Connection) -> Result<OwnedObjectPath, Error> {
+        objects(connection, ADAPTER)
+            .await?
+            .into_iter()
+            .map(|(path, _)| path)
+            .next()
+            .ok_or_else(|| bluez_error("no bluetooth adapter"))
+    }
+
+    async fn known_device(
+        connection: &Connection,
+        service_uuid: &str,
+    ) -> Result<Option<OwnedObjectPath>, Error> {
+        Ok(objects(connection, DEVICE)
+            .await?
+            .into_iter()
+            .find(|(_, properties)| {
+                properties
+                    .get("UUIDs")
+                    .and_then(|uuids| Vec::<String>::try_from(uuids.clone()).ok())
+                    .is_some_and(|uuids| uuids.iter().any(|uuid| uuid == service_uuid))
+            })
+            .map(|(path, _)| path))
+    }
+
+    /// find the phone, scanning for its service if BlueZ hasn't seen it yet
+    async fn find_device(connection: &Connection, service_uuid: Uuid) -> Result<OwnedObjectPath, Error> {
+        let service_uuid = service_uuid.to_string();
+        if let Some(device) = known_device(connection, &service_uuid).await? {
+            return Ok(device);
+        }
+
+        let adapter = find_adapter(connection).await?;
+        let proxy = Proxy::new(connection, BLUEZ, adapter.as_str(), ADAPTER)
+            .await
+            .map_err(bluez_error)?;
+
+        let mut filter: HashMap<&str, Value> = HashMap::new();
+        filter.insert("UUIDs", Value::from(vec![service_uuid.clone()]));
+        filter.insert("Transport", Value::from("le"));
+        proxy
+            .call_method("SetDiscoveryFilter", &(filter,))
+            .await
+            .map_err(bluez_error)?;
+        proxy
+            .call_method("StartDiscovery", &())
+            .await
+            .map_err(bluez_error)?;
+
+        let deadline = Instant::now() + SCAN_TIMEOUT;
+        let device = loop {
+            if let Some(device) = known_device(connection, &service_uuid).await? {
+                break Ok(device);
+            }
+            if Instant::now() >= deadline {
+                break Err(bluez_error("phone/tablet not in range"));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+
+        let _ = proxy.call_method("StopDiscovery", &()).await;
+        device
+    }
+
+    async fn connect(connection: &Connection, device: &OwnedObjectPath) -> Result<(), Error> {
+        let proxy = Proxy::new(connection, BLUEZ, device.as_str(), DEVICE)
+            .await
+            .map_err(bluez_error)?;
+
+        if !proxy
+            .get_property::<bool>("Connected")
+            .await
+            .map_err(bluez_error)?
+        {
+            proxy.call_method("Connect", &()).await.map_err(bluez_error)?;
+        }
+
+        // the characteristics only show up once services are resolved
+        let deadline = Instant::now() + SCAN_TIMEOUT;
+        while !proxy
+            .get_property::<bool>("ServicesResolved")
+            .await
+            .map_err(bluez_error)?
+        {
+            if Instant::now() >= deadline {
+                return Err(bluez_error("services not resolved"));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    async fn find_characteristic(
+        connection: &Connection,
+        device: &OwnedObjectPath,
+    ) -> Result<OwnedObjectPath, Error> {
+        let prefix = format!("{}/", device.as_str());
+        objects(connection, CHARACTERISTIC)
+            .await?
+            .into_iter()
+            .find(|(path, properties)| {
+                path.as_str().starts_with(&prefix)
+                    && string_property(properties, "UUID").as_deref() == Some(MESSAGE_CHARACTERISTIC_UUID)
+            })
+            .map(|(path, _)| path)
+            .ok_or_else(|| bluez_error("phone/tablet doesn't offer the akr characteristic"))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod gatt {
+    use crate::error::Error;
+    use crate::protocol::WireMessage;
+    use uuid::Uuid;
+
+    pub async fn exchange(_: Uuid, _: WireMessage) -> Result<WireMessage, Error> {
+        Err(Error::Bluetooth("not supported on this platform".into()))
+    }
+
+    pub async fn adapter_powered() -> Result<(), Error> {
+        Err(Error::Bluetooth("not supported on this platform".into()))
+    }
+}
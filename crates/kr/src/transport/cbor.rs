@@ -0,0 +1,263 @@
+//! The subset of CBOR that CTAP2 messages use
+//! See: https://www.rfc-editor.org/rfc/rfc8949 and
+//! https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#ctap2-canonical-cbor-encoding-form
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i128),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    /// entries are encoded sorted canonically as CTAP2 expects, whatever order they're given in
+    Map(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_TAG: u8 = 6;
+const MAJOR_SIMPLE: u8 = 7;
+
+const SIMPLE_FALSE: u8 = 20;
+const SIMPLE_TRUE: u8 = 21;
+const SIMPLE_NULL: u8 = 22;
+
+impl Value {
+    pub fn text(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+
+    /// look up an integer key, as used by CTAP2 requests and responses
+    pub fn get(&self, key: i128) -> Option<&Value> {
+        self.get_value(&Value::Integer(key))
+    }
+
+    /// look up a text key, as used by WebAuthn structures
+    pub fn get_text(&self, key: &str) -> Option<&Value> {
+        self.get_value(&Value::text(key))
+    }
+
+    fn get_value(&self, key: &Value) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i128> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Integer(i) if *i >= 0 => write_head(out, MAJOR_UNSIGNED, *i as u64),
+            Value::Integer(i) => write_head(out, MAJOR_NEGATIVE, (-1 - *i) as u64),
+            Value::Bytes(bytes) => {
+                write_head(out, MAJOR_BYTES, bytes.len() as u64);
+                out.extend_from_slice(bytes);
+            }
+            Value::Text(text) => {
+                write_head(out, MAJOR_TEXT, text.len() as u64);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Value::Array(items) => {
+                write_head(out, MAJOR_ARRAY, items.len() as u64);
+                items.iter().for_each(|item| item.encode_into(out));
+            }
+            Value::Map(entries) => {
+                write_head(out, MAJOR_MAP, entries.len() as u64);
+                // shorter encoded keys first, keys as long as each other bytewise
+                let mut entries = entries
+                    .iter()
+                    .map(|(key, value)| (key.encode(), value))
+                    .collect::<Vec<_>>();
+                entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+                for (key, value) in entries {
+                    out.extend_from_slice(&key);
+                    value.encode_into(out);
+                }
+            }
+            Value::Bool(false) => out.push(MAJOR_SIMPLE << 5 | SIMPLE_FALSE),
+            Value::Bool(true) => out.push(MAJOR_SIMPLE << 5 | SIMPLE_TRUE),
+            Value::Null => out.push(MAJOR_SIMPLE << 5 | SIMPLE_NULL),
+        }
+    }
+
+    /// decode one value, returning it with the number of bytes it took
+    pub fn decode(data: &[u8]) -> Result<(Value, usize), Error> {
+        let mut cursor = 0;
+        let value = decode_value(data, &mut cursor)?;
+        Ok((value, cursor))
+    }
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    match argument {
+        0..=23 => out.push(major | argument as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, argument as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(argument as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+fn take<'a>(data: &'a [u8], cursor: &mut usize, len: usize) -> Result<&'a [u8], Error> {
+    let end = cursor.checked_add(len).ok_or(Error::InvalidCtapResponse)?;
+    let bytes = data.get(*cursor..end).ok_or(Error::InvalidCtapResponse)?;
+    *cursor = end;
+    Ok(bytes)
+}
+
+fn read_head(data: &[u8], cursor: &mut usize) -> Result<(u8, u8, u64), Error> {
+    let initial = take(data, cursor, 1)?[0];
+    let (major, info) = (initial >> 5, initial & 0x1f);
+
+    let argument = match info {
+        0..=23 => info as u64,
+        24 => take(data, cursor, 1)?[0] as u64,
+        25 => u16::from_be_bytes(take(data, cursor, 2)?.try_into().unwrap()) as u64,
+        26 => u32::from_be_bytes(take(data, cursor, 4)?.try_into().unwrap()) as u64,
+        27 => u64::from_be_bytes(take(data, cursor, 8)?.try_into().unwrap()),
+        // indefinite lengths aren't allowed in CTAP2
+        _ => return Err(Error::InvalidCtapResponse),
+    };
+    Ok((major, info, argument))
+}
+
+fn decode_value(data: &[u8], cursor: &mut usize) -> Result<Value, Error> {
+    let (major, info, argument) = read_head(data, cursor)?;
+    let len = argument as usize;
+
+    let value = match major {
+        MAJOR_UNSIGNED => Value::Integer(argument as i128),
+        MAJOR_NEGATIVE => Value::Integer(-1 - argument as i128),
+        MAJOR_BYTES => Value::Bytes(take(data, cursor, len)?.to_vec()),
+        MAJOR_TEXT => Value::Text(String::from_utf8(take(data, cursor, len)?.to_vec())?),
+        MAJOR_ARRAY => {
+            // every item takes at least a byte, so don't trust a huge length up front
+            let mut items = Vec::with_capacity(len.min(data.len()));
+            for _ in 0..len {
+                items.push(decode_value(data, cursor)?);
+            }
+            Value::Array(items)
+        }
+        MAJOR_MAP => {
+            let mut entries = Vec::with_capacity(len.min(data.len()));
+            for _ in 0..len {
+                let key = decode_value(data, cursor)?;
+                let value = decode_value(data, cursor)?;
+                entries.push((key, value));
+            }
+            Value::Map(entries)
+        }
+        // e.g. attestation certificates may be tagged, the tag itself doesn't matter to us
+        MAJOR_TAG => decode_value(data, cursor)?,
+        MAJOR_SIMPLE => match info {
+            SIMPLE_FALSE => Value::Bool(false),
+            SIMPLE_TRUE => Value::Bool(true),
+            SIMPLE_NULL => Value::Null,
+            _ => return Err(Error::InvalidCtapResponse),
+        },
+        _ => unreachable!(),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_maps_in_canonical_order() {
+        let map = Value::Map(vec![
+            (Value::text("hmac-secret"), Value::Bool(true)),
+            (Value::text("credProtect"), Value::Integer(2)),
+            (Value::Integer(-1), Value::Null),
+            (Value::Integer(24), Value::Null),
+            (Value::Integer(1), Value::Null),
+            (Value::text("b"), Value::Null),
+        ]);
+        let Value::Map(entries) = Value::decode(&map.encode()).unwrap().0 else {
+            panic!("not a map");
+        };
+        let keys = entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                Value::Integer(1),
+                Value::Integer(-1),
+                Value::Integer(24),
+                Value::text("b"),
+                Value::text("credProtect"),
+                Value::text("hmac-secret"),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = Value::Map(vec![
+            (Value::Integer(1), Value::text("example.com")),
+            (Value::Integer(2), Value::Bytes(vec![0; 300])),
+            (
+                Value::Integer(3),
+                Value::Array(vec![Value::Integer(-70_000), Value::Bool(false), Value::Null]),
+            ),
+        ]);
+        let encoded = value.encode();
+        assert_eq!(Value::decode(&encoded).unwrap(), (value, encoded.len()));
+    }
+
+    #[test]
+    fn rejects_truncated_values() {
+        let encoded = Value::Bytes(vec![1; 30]).encode();
+        assert!(Value::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+}
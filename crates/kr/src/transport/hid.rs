@@ -0,0 +1,269 @@
+//! CTAPHID framing for a FIDO2 security key on USB
+//! See: https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#usb
+
+use crate::crypto;
+use crate::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+const REPORT_LEN: usize = 64;
+const INIT_DATA_LEN: usize = REPORT_LEN - 7;
+const CONT_DATA_LEN: usize = REPORT_LEN - 5;
+/// an init packet and continuation packets numbered 0 to 0x7f
+const MAX_MESSAGE_LEN: usize = INIT_DATA_LEN + 0x80 * CONT_DATA_LEN;
+/// how long a key may take to answer, waiting for a touch included
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const BROADCAST_CID: u32 = 0xffff_ffff;
+const CMD_INIT: u8 = 0x86;
+const CMD_CBOR: u8 = 0x90;
+const CMD_CANCEL: u8 = 0x91;
+const CMD_KEEPALIVE: u8 = 0xbb;
+const CMD_ERROR: u8 = 0xbf;
+
+/// "Usage Page (FIDO Alliance)" in a HID report descriptor
+#[cfg(target_os = "linux")]
+const FIDO_USAGE_PAGE: [u8; 3] = [0x06, 0xd0, 0xf1];
+
+pub struct HidDevice {
+    file: File,
+    cid: u32,
+}
+
+impl HidDevice {
+    /// Open the first FIDO2 security key plugged in
+    #[cfg(target_os = "linux")]
+    pub fn open() -> Result<Self, Error> {
+        for entry in std::fs::read_dir("/sys/class/hidraw")? {
+            let entry = entry?;
+            let descriptor = match std::fs::read(entry.path().join("device/report_descriptor")) {
+                Ok(descriptor) => descriptor,
+                Err(_) => continue,
+            };
+            if !descriptor
+                .windows(FIDO_USAGE_PAGE.len())
+                .any(|w| w == FIDO_USAGE_PAGE)
+            {
+                continue;
+            }
+
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(std::path::Path::new("/dev").join(entry.file_name()))?;
+            let mut device = HidDevice {
+                file,
+                cid: BROADCAST_CID,
+            };
+            device.cid = device.init()?;
+            return Ok(device);
+        }
+
+        Err(Error::NoSecurityKey)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open() -> Result<Self, Error> {
+        Err(Error::NoSecurityKey)
+    }
+
+    /// allocate a channel of our own
+    fn init(&mut self) -> Result<u32, Error> {
+        let nonce = crypto::random_bytes(8);
+        self.write_message(CMD_INIT, &nonce)?;
+
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        loop {
+            let (cmd, data) = self.read_message(deadline)?;
+            if cmd == CMD_INIT && data.len() >= 12 && data[..8] == nonce[..] {
+                return Ok(u32::from_be_bytes([data[8], data[9], data[10], data[11]]));
+            }
+        }
+    }
+
+    /// Send a CTAP2 command and return its CBOR encoded response
+    pub fn cbor(&mut self, command: u8, parameters: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut request = vec![command];
+        request.extend_from_slice(parameters);
+        self.write_message(CMD_CBOR, &request)?;
+
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        loop {
+            let (cmd, data) = match self.read_message(deadline) {
+                Err(Error::ResponseTimedOut) => {
+                    // stop the key from waiting for a touch nobody answers anymore
+                    let _ = self.write_message(CMD_CANCEL, &[]);
+                    return Err(Error::ResponseTimedOut);
+                }
+                message => message?,
+            };
+            match cmd {
+                // the key is waiting for a touch
+                CMD_KEEPALIVE => continue,
+                CMD_ERROR => return Err(Error::CtapStatus(data.first().copied().unwrap_or(0))),
+                CMD_CBOR => match data.split_first() {
+                    Some((0, response)) => return Ok(response.to_vec()),
+                    Some((status, _)) => return Err(Error::CtapStatus(*status)),
+                    None => return Err(Error::InvalidCtapResponse),
+                },
+                _ => return Err(Error::InvalidCtapResponse),
+            }
+        }
+    }
+
+    /*
+       init packet:          cid[4] | cmd[1] | bcnt[2] | data[57]
+       continuation packets: cid[4] | seq[1] | data[59]
+    */
+    fn write_message(&mut self, cmd: u8, data: &[u8]) -> Result<(), Error> {
+        let (first, rest) = data.split_at(data.len().min(INIT_DATA_LEN));
+
+        let mut packet = self.cid.to_be_bytes().to_vec();
+        packet.push(cmd);
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(first);
+        self.write_report(packet)?;
+
+        for (seq, chunk) in rest.chunks(CONT_DATA_LEN).enumerate() {
+            let mut packet = self.cid.to_be_bytes().to_vec();
+            packet.push(seq as u8);
+            packet.extend_from_slice(chunk);
+            self.write_report(packet)?;
+        }
+        Ok(())
+    }
+
+    fn write_report(&mut self, mut packet: Vec<u8>) -> Result<(), Error> {
+        packet.resize(REPORT_LEN, 0);
+        // hidraw expects the report number first, FIDO keys don't number their reports
+        packet.insert(0, 0);
+        self.file.write_all(&packet)?;
+        Ok(())
+    }
+
+    /// the next message on our channel, `Error::ResponseTimedOut` once `deadline` passes
+    fn read_message(&mut self, deadline: Instant) -> Result<(u8, Vec<u8>), Error> {
+        let mut report = [0u8; REPORT_LEN];
+
+        // skip anything meant for other channels
+        let (cmd, len) = loop {
+            self.read_report(&mut report, deadline)?;
+            if report[..4] == self.cid.to_be_bytes() && report[4] & 0x80 != 0 {
+                break (report[4], u16::from_be_bytes([report[5], report[6]]) as usize);
+            }
+        };
+        if len > MAX_MESSAGE_LEN {
+            return Err(Error::InvalidCtapResponse);
+        }
+
+        // the rest follows on the same channel, numbered from 0, anything else is a broken message
+        let mut data = report[7..7 + len.min(INIT_DATA_LEN)].to_vec();
+        let mut seq = 0;
+        while data.len() < len {
+            self.read_report(&mut report, deadline)?;
+            if report[..4] != self.cid.to_be_bytes() || report[4] != seq {
+                return Err(Error::InvalidCtapResponse);
+            }
+            seq += 1;
+            let remaining = (len - data.len()).min(CONT_DATA_LEN);
+            data.extend_from_slice(&report[5..5 + remaining]);
+        }
+        Ok((cmd, data))
+    }
+
+    #[cfg(unix)]
+    fn read_report(&mut self, report: &mut [u8; REPORT_LEN], deadline: Instant) -> Result<(), Error> {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use std::os::unix::io::AsRawFd;
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [PollFd::new(self.file.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, timeout.as_millis().min(i32::MAX as u128) as i32) {
+            Ok(0) => return Err(Error::ResponseTimedOut),
+            Ok(_) => {}
+            Err(e) => return Err(std::io::Error::from(e).into()),
+        }
+        self.file.read_exact(report)?;
+        Ok(())
+    }
+
+    /// no key is opened there, see `open`
+    #[cfg(not(unix))]
+    fn read_report(&mut self, report: &mut [u8; REPORT_LEN], _deadline: Instant) -> Result<(), Error> {
+        self.file.read_exact(report)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+
+    const CID: u32 = 0x0102_0304;
+
+    /// a device reading `reports` from a file, each padded to a full report
+    fn device(reports: &[Vec<u8>]) -> HidDevice {
+        let mut file = tempfile::tempfile().unwrap();
+        for report in reports {
+            let mut report = report.clone();
+            report.resize(REPORT_LEN, 0);
+            file.write_all(&report).unwrap();
+        }
+        file.seek(SeekFrom::Start(0)).unwrap();
+        HidDevice { file, cid: CID }
+    }
+
+    fn init_packet(cid: u32, len: u16, data: &[u8]) -> Vec<u8> {
+        [&cid.to_be_bytes()[..], &[CMD_CBOR], &len.to_be_bytes(), data].concat()
+    }
+
+    fn continuation_packet(cid: u32, seq: u8, data: &[u8]) -> Vec<u8> {
+        [&cid.to_be_bytes()[..], &[seq], data].concat()
+    }
+
+    fn read(reports: &[Vec<u8>]) -> Result<(u8, Vec<u8>), Error> {
+        device(reports).read_message(Instant::now() + RESPONSE_TIMEOUT)
+    }
+
+    #[test]
+    fn reads_a_message_across_packets() {
+        let message = (0..100).collect::<Vec<u8>>();
+        let (cmd, data) = read(&[
+            init_packet(0x0506_0708, 3, &[1, 2, 3]),
+            init_packet(CID, 100, &message[..INIT_DATA_LEN]),
+            continuation_packet(CID, 0, &message[INIT_DATA_LEN..]),
+        ])
+        .unwrap();
+        assert_eq!(cmd, CMD_CBOR);
+        assert_eq!(data, message);
+    }
+
+    #[test]
+    fn rejects_continuation_packets_of_other_channels() {
+        let message = [7; 100];
+        let result = read(&[
+            init_packet(CID, 100, &message[..INIT_DATA_LEN]),
+            continuation_packet(0x0506_0708, 0, &message[INIT_DATA_LEN..]),
+        ]);
+        assert!(matches!(result, Err(Error::InvalidCtapResponse)));
+    }
+
+    #[test]
+    fn rejects_continuation_packets_out_of_order() {
+        let message = [7; 200];
+        let result = read(&[
+            init_packet(CID, 200, &message[..INIT_DATA_LEN]),
+            continuation_packet(CID, 1, &message[INIT_DATA_LEN..INIT_DATA_LEN + CONT_DATA_LEN]),
+            continuation_packet(CID, 0, &message[INIT_DATA_LEN + CONT_DATA_LEN..]),
+        ]);
+        assert!(matches!(result, Err(Error::InvalidCtapResponse)));
+    }
+
+    #[test]
+    fn rejects_messages_longer_than_the_packets_can_carry() {
+        let result = read(&[init_packet(CID, MAX_MESSAGE_LEN as u16 + 1, &[0; INIT_DATA_LEN])]);
+        assert!(matches!(result, Err(Error::InvalidCtapResponse)));
+    }
+}
@@ -0,0 +1,203 @@
+//! Exchange sealed messages with a paired phone/tablet over the local network
+//!
+//! The phone/tablet announces itself over mDNS as `<instance>._akr._tcp.local`, the instance
+//! being derived from the pairing's queue so the queue itself isn't revealed on the network.
+//! Messages are the same sealed `WireMessage`s the relay queues carry, so they stay end-to-end
+//! encrypted with the pairing keys; on the TCP connection each is prefixed by its length (u32).
+
+use super::mailbox::Mailboxes;
+use super::Transport;
+use crate::crypto;
+use crate::error::Error;
+use crate::protocol::WireMessage;
+use async_trait::async_trait;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use uuid::Uuid;
+
+const SERVICE: &str = "_akr._tcp.local";
+const MDNS_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// how long to wait for the phone/tablet to answer the mDNS query
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// how long to wait for a response, like the relay queues
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+const DNS_TYPE_SRV: u16 = 33;
+const DNS_CLASS_IN: u16 = 1;
+
+#[derive(Clone, Default)]
+pub struct LocalNetworkClient {
+    mailboxes: Mailboxes,
+}
+
+impl LocalNetworkClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Transport for LocalNetworkClient {
+    async fn create_queue(&self, _: Uuid) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        _device_token: Option<String>,
+        queue_uuid: Uuid,
+        message: WireMessage,
+    ) -> Result<(), Error> {
+        self.mailboxes.spawn(queue_uuid, exchange(queue_uuid, message));
+        Ok(())
+    }
+
+    async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
+    where
+        F: Fn(&[WireMessage]) -> Result<Option<T>, Error> + Send + Sync,
+    {
+        self.mailboxes.receive(queue_uuid, on_messages).await
+    }
+
+    /// nothing to check until the phone/tablet is looked up
+    async fn health_check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// the first 8 bytes of sha256(queue uuid), hex encoded
+fn instance_name(queue_uuid: Uuid) -> String {
+    hex::encode(&crypto::sha256(queue_uuid.as_bytes())[..8])
+}
+
+async fn exchange(queue_uuid: Uuid, message: WireMessage) -> Result<WireMessage, Error> {
+    let address = discover(&format!("{}.{}", instance_name(queue_uuid), SERVICE)).await?;
+
+    let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address))
+        .await
+        .map_err(|_| Error::LocalNetwork(format!("couldn't connect to {}", address)))??;
+
+    let wire = message.into_wire();
+    stream.write_u32(wire.len() as u32).await?;
+    stream.write_all(&wire).await?;
+
+    tokio::time::timeout(RESPONSE_TIMEOUT, async {
+        let len = stream.read_u32().await? as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(Error::LocalNetwork("response too large".into()));
+        }
+
+        let mut response = vec![0u8; len];
+        stream.read_exact(&mut response).await?;
+        WireMessage::new(response)
+    })
+    .await
+    .map_err(|_| Error::ResponseTimedOut)?
+}
+
+/// Find the address of `name` with a one-shot mDNS query
+/// queries from a port other than 5353 are answered by unicast, straight to us
+/// See: https://www.rfc-editor.org/rfc/rfc6762#section-5.1
+async fn discover(name: &str) -> Result<SocketAddr, Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.send_to(&srv_query(name), MDNS_ADDR).await?;
+
+    tokio::time::timeout(DISCOVERY_TIMEOUT, async {
+        let mut packet = [0u8; 1500];
+        loop {
+            let (len, from) = socket.recv_from(&mut packet).await?;
+            if let Some(port) = srv_port(&packet[..len], name) {
+                return Ok(SocketAddr::new(from.ip(), port));
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::LocalNetwork(format!("{} not found", name)))?
+}
+
+/*
+   header:   id[2] | flags[2] | qdcount[2] | ancount[2] | nscount[2] | arcount[2]
+   question: name | type[2] | class[2]
+*/
+fn srv_query(name: &str) -> Vec<u8> {
+    let id: [u8; 2] = crypto::random_bytes(2).try_into().unwrap();
+
+    let mut query = id.to_vec();
+    query.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&DNS_TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    query
+}
+
+/// The port of the SRV record for `name` in a DNS response, if it has one
+///   record: name | type[2] | class[2] | ttl[4] | rdlength[2] | rdata
+///   SRV rdata: priority[2] | weight[2] | port[2] | target
+fn srv_port(packet: &[u8], name: &str) -> Option<u16> {
+    let count = |i: usize| Some(u16::from_be_bytes(packet.get(i..i + 2)?.try_into().ok()?) as usize);
+    let questions = count(4)?;
+    let records = count(6)? + count(8)? + count(10)?;
+
+    let mut cursor = 12;
+    for _ in 0..questions {
+        read_name(packet, &mut cursor)?;
+        cursor += 4;
+    }
+
+    for _ in 0..records {
+        let record_name = read_name(packet, &mut cursor)?;
+        let record_type = u16::from_be_bytes(packet.get(cursor..cursor + 2)?.try_into().ok()?);
+        let rdlength = count(cursor + 8)?;
+        let rdata = packet.get(cursor + 10..cursor + 10 + rdlength)?;
+        cursor += 10 + rdlength;
+
+        if record_type == DNS_TYPE_SRV && record_name.eq_ignore_ascii_case(name) {
+            return Some(u16::from_be_bytes(rdata.get(4..6)?.try_into().ok()?));
+        }
+    }
+    None
+}
+
+/// Read a possibly compressed name, leaving `cursor` after it
+fn read_name(packet: &[u8], cursor: &mut usize) -> Option<String> {
+    let mut labels = vec![];
+    let mut position = *cursor;
+    let mut jumped = false;
+
+    // compression pointers could loop, a name can't have more labels than this anyway
+    for _ in 0..128 {
+        let len = *packet.get(position)? as usize;
+        match len {
+            0 => {
+                if !jumped {
+                    *cursor = position + 1;
+                }
+                return Some(labels.join("."));
+            }
+            // pointer to the rest of the name
+            l if l & 0xc0 == 0xc0 => {
+                let offset = (l & 0x3f) << 8 | *packet.get(position + 1)? as usize;
+                if !jumped {
+                    *cursor = position + 2;
+                }
+                jumped = true;
+                position = offset;
+            }
+            _ => {
+                let label = packet.get(position + 1..position + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                position += 1 + len;
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,313 @@
+//! Answer requests with a FIDO2 security key attached to this machine,
+//! instead of the phone, so akr works without a network
+//! See: https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticator-api
+
+use super::cbor::Value;
+use super::hid::HidDevice;
+use super::DeviceTransport;
+use crate::error::Error;
+use crate::identity::StoredIdentity;
+use crate::protocol::{
+    AuthenticateRequest, AuthenticateResponse, Base64Buffer, ClientResult, CredProtect, ExtensionOutputs,
+    Extensions, HmacSecret, HmacSecretOutput, PingResponse, RegisterRequest, RegisterResponse, Request,
+    RequestBody, Response, ResponseBody, PROTOCOL_VERSION,
+};
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+const CTAP2_MAKE_CREDENTIAL: u8 = 0x01;
+const CTAP2_GET_ASSERTION: u8 = 0x02;
+
+/// COSE algorithms, in order of preference
+/// See: https://www.iana.org/assignments/cose/cose.xhtml#algorithms
+const COSE_ALG_ES256: i128 = -7;
+const COSE_ALG_EDDSA: i128 = -8;
+
+/// COSE key parameters
+const COSE_KEY_KTY: i128 = 1;
+const COSE_KEY_X: i128 = -2;
+const COSE_KEY_Y: i128 = -3;
+const COSE_KTY_OKP: i128 = 1;
+const COSE_KTY_EC2: i128 = 2;
+
+/// extension data included, the ED flag of the authenticator data
+const AUTH_FLAG_ED: u8 = 0x80;
+
+pub struct LoopbackTransport;
+
+#[async_trait]
+impl DeviceTransport for LoopbackTransport {
+    async fn exchange(&self, request: &Request, _: Option<Instant>) -> Result<Response, Error> {
+        // talking to the key blocks until it's touched
+        let body = request.body.clone();
+        let body = tokio::task::spawn_blocking(move || Self::handle(body))
+            .await
+            .map_err(|e| Error::DeviceError(e.to_string()))??;
+
+        Ok(Response {
+            request_id: request.id.clone(),
+            aws_push_id: None,
+            device_token: None,
+            version: PROTOCOL_VERSION.to_string(),
+            body,
+        })
+    }
+}
+
+impl LoopbackTransport {
+    fn handle(body: RequestBody) -> Result<ResponseBody, Error> {
+        match body {
+            RequestBody::Authenticate(request) => Ok(ResponseBody::Authenticate(ClientResult::ok(
+                Self::get_assertion(request)?,
+            ))),
+            RequestBody::Register(request) => Ok(ResponseBody::Register(ClientResult::ok(
+                Self::make_credential(request)?,
+            ))),
+            // the key answers as long as it's plugged in
+            RequestBody::Ping(_) => {
+                HidDevice::open()?;
+                Ok(ResponseBody::Ping(ClientResult::ok(PingResponse {})))
+            }
+            body => Err(Error::UnsupportedTransportRequest(body.name())),
+        }
+    }
+
+    fn get_assertion(request: AuthenticateRequest) -> Result<AuthenticateResponse, Error> {
+        // the salts are encrypted with a key agreed on through the PIN protocol, which isn't spoken here
+        if let Some(HmacSecret::Salts { .. }) =
+            request.extensions.as_ref().and_then(|e| e.hmac_secret.as_ref())
+        {
+            return Err(Error::UnsupportedExtension("hmac-secret"));
+        }
+        let key_handles = request
+            .key_handle
+            .into_iter()
+            .chain(request.key_handles.unwrap_or_default())
+            .map(|handle| {
+                Value::Map(vec![
+                    (Value::text("id"), Value::Bytes(handle.0)),
+                    (Value::text("type"), Value::text("public-key")),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        let mut parameters = vec![
+            (Value::Integer(1), Value::text(&request.rp_id)),
+            (Value::Integer(2), Value::Bytes(request.challenge.0)),
+        ];
+        if !key_handles.is_empty() {
+            parameters.push((Value::Integer(3), Value::Array(key_handles)));
+        }
+        parameters.push((
+            Value::Integer(5),
+            Value::Map(vec![(Value::text("up"), Value::Bool(true))]),
+        ));
+
+        let response = HidDevice::open()?.cbor(CTAP2_GET_ASSERTION, &Value::Map(parameters).encode())?;
+        let (response, _) = Value::decode(&response)?;
+
+        /*
+           1: credential { "id", "type" }
+           2: authData
+           3: signature
+           4: user { "id", ... }
+        */
+        let key_handle = response
+            .get(1)
+            .and_then(|credential| credential.get_text("id"))
+            .and_then(Value::as_bytes)
+            .ok_or(Error::InvalidCtapResponse)?
+            .to_vec();
+        let authenticator_data = response
+            .get(2)
+            .and_then(Value::as_bytes)
+            .ok_or(Error::InvalidCtapResponse)?
+            .to_vec();
+        let signature = response
+            .get(3)
+            .and_then(Value::as_bytes)
+            .ok_or(Error::InvalidCtapResponse)?
+            .to_vec();
+        let user_handle = response
+            .get(4)
+            .and_then(|user| user.get_text("id"))
+            .and_then(Value::as_bytes)
+            .map(|id| Base64Buffer(id.to_vec()));
+
+        // assertions don't carry the public key, use the one we registered if any
+        let public_key = Self::stored_public_key(&key_handle).unwrap_or_default();
+
+        let mut response = AuthenticateResponse {
+            public_key: Base64Buffer(public_key),
+            counter: 0,
+            signature: Base64Buffer(signature),
+            key_handle: Base64Buffer(key_handle),
+            user_handle,
+            authenticator_data: Base64Buffer(authenticator_data),
+            extensions: None,
+        };
+        response.counter = response.get_sign_counter()?;
+        Ok(response)
+    }
+
+    fn make_credential(request: RegisterRequest) -> Result<RegisterResponse, Error> {
+        let rp_name = request.rp_name.unwrap_or_else(|| request.rp_id.clone());
+        // like ssh-keygen, fill in a placeholder user when there is none
+        let (user_id, user_name) = match request.user {
+            Some(user) => (user.id.0, user.display_name),
+            None => (vec![0u8; 32], "akr".to_string()),
+        };
+
+        let algorithms = [COSE_ALG_ES256, COSE_ALG_EDDSA].iter().map(|alg| {
+            Value::Map(vec![
+                (Value::text("alg"), Value::Integer(*alg)),
+                (Value::text("type"), Value::text("public-key")),
+            ])
+        });
+
+        let mut parameters = vec![
+            (Value::Integer(1), Value::Bytes(request.challenge.0)),
+            (
+                Value::Integer(2),
+                Value::Map(vec![
+                    (Value::text("id"), Value::text(&request.rp_id)),
+                    (Value::text("name"), Value::text(&rp_name)),
+                ]),
+            ),
+            (
+                Value::Integer(3),
+                Value::Map(vec![
+                    (Value::text("id"), Value::Bytes(user_id)),
+                    (Value::text("name"), Value::text(&user_name)),
+                    (Value::text("displayName"), Value::text(&user_name)),
+                ]),
+            ),
+            (Value::Integer(4), Value::Array(algorithms.collect())),
+        ];
+        let extensions = Self::credential_extensions(request.extensions.as_ref());
+        if !extensions.is_empty() {
+            parameters.push((Value::Integer(6), Value::Map(extensions)));
+        }
+        let mut options = vec![];
+        if request.resident_key {
+            options.push((Value::text("rk"), Value::Bool(true)));
+        }
+        if request.user_verification_required {
+            options.push((Value::text("uv"), Value::Bool(true)));
+        }
+        if !options.is_empty() {
+            parameters.push((Value::Integer(7), Value::Map(options)));
+        }
+        let parameters = Value::Map(parameters);
+
+        let response = HidDevice::open()?.cbor(CTAP2_MAKE_CREDENTIAL, &parameters.encode())?;
+        let (response, _) = Value::decode(&response)?;
+
+        /*
+           1: fmt
+           2: authData
+              rp_id_hash [32] | flags [1] | sign_counter [4] | aaguid [16] |
+              credential_id_len [2] | credential_id | COSE public key
+           3: attStmt
+        */
+        let authenticator_data = response
+            .get(2)
+            .and_then(Value::as_bytes)
+            .ok_or(Error::InvalidCtapResponse)?;
+        let attested = authenticator_data.get(53..).ok_or(Error::BadAuthenticatorData)?;
+        if attested.len() < 2 {
+            return Err(Error::BadAuthenticatorData);
+        }
+        let id_len = u16::from_be_bytes([attested[0], attested[1]]) as usize;
+        let key_handle = attested.get(2..2 + id_len).ok_or(Error::BadAuthenticatorData)?;
+        let (cose_key, cose_key_len) = Value::decode(&attested[2 + id_len..])?;
+        let extensions = match authenticator_data[32] & AUTH_FLAG_ED {
+            0 => None,
+            _ => Some(Self::credential_extension_outputs(
+                &Value::decode(&attested[2 + id_len + cose_key_len..])?.0,
+            )),
+        };
+
+        // attStmt of the "packed" format: sig and, unless self attested, x5c
+        let statement = response.get(3);
+        let certificates = statement
+            .and_then(|statement| statement.get_text("x5c"))
+            .and_then(Value::as_array)
+            .map(|x5c| {
+                x5c.iter()
+                    .filter_map(Value::as_bytes)
+                    .map(|der| Base64Buffer(der.to_vec()))
+                    .collect()
+            });
+
+        Ok(RegisterResponse {
+            public_key: Base64Buffer(Self::public_key_from_cose(&cose_key)?),
+            key_handle: Base64Buffer(key_handle.to_vec()),
+            attestation_data: Some(Base64Buffer(authenticator_data.to_vec())),
+            attestation_format: response.get(1).and_then(Value::as_text).map(str::to_string),
+            attestation_signature: statement
+                .and_then(|statement| statement.get_text("sig"))
+                .and_then(Value::as_bytes)
+                .map(|sig| Base64Buffer(sig.to_vec())),
+            attestation_certificates: certificates,
+            extensions,
+        })
+    }
+
+    /// credProtect and hmac-secret, a key that doesn't support them ignores them
+    fn credential_extensions(extensions: Option<&Extensions>) -> Vec<(Value, Value)> {
+        let mut map = vec![];
+        let Some(extensions) = extensions else {
+            return map;
+        };
+        if let Some(cred_protect) = extensions.cred_protect {
+            map.push((Value::text("credProtect"), Value::Integer(cred_protect.level())));
+        }
+        if let Some(HmacSecret::Enable(enable)) = extensions.hmac_secret {
+            map.push((Value::text("hmac-secret"), Value::Bool(enable)));
+        }
+        map
+    }
+
+    fn credential_extension_outputs(outputs: &Value) -> ExtensionOutputs {
+        ExtensionOutputs {
+            hmac_secret: match outputs.get_text("hmac-secret") {
+                Some(Value::Bool(enabled)) => Some(HmacSecretOutput::Enabled(*enabled)),
+                _ => None,
+            },
+            cred_protect: outputs
+                .get_text("credProtect")
+                .and_then(Value::as_integer)
+                .and_then(CredProtect::from_level),
+        }
+    }
+
+    /// the raw public key, as the phone sends it: ed25519 bytes or an uncompressed P-256 point
+    fn public_key_from_cose(key: &Value) -> Result<Vec<u8>, Error> {
+        let coordinate = |label| {
+            key.get(label)
+                .and_then(Value::as_bytes)
+                .ok_or(Error::InvalidCtapResponse)
+        };
+
+        match key.get(COSE_KEY_KTY).and_then(Value::as_integer) {
+            Some(COSE_KTY_OKP) => Ok(coordinate(COSE_KEY_X)?.to_vec()),
+            Some(COSE_KTY_EC2) => {
+                let mut point = vec![0x04];
+                point.extend_from_slice(coordinate(COSE_KEY_X)?);
+                point.extend_from_slice(coordinate(COSE_KEY_Y)?);
+                Ok(point)
+            }
+            kty => Err(Error::UnsupportedKeyType(format!("COSE key type {:?}", kty))),
+        }
+    }
+
+    fn stored_public_key(key_handle: &[u8]) -> Option<Vec<u8>> {
+        let mut handles = StoredIdentity::load_from_disk().ok()?.key_pair_handles;
+        handles.extend(StoredIdentity::load_added_key_pair_handles().ok()?);
+        handles
+            .into_iter()
+            .find(|handle| handle.key_handle == key_handle)
+            .map(|handle| handle.public_key)
+    }
+}
@@ -0,0 +1,79 @@
+//! Responses from transports that reach the phone/tablet directly (bluetooth, local network),
+//! kept for `receive` the way a relay queue keeps them
+
+use crate::error::Error;
+use crate::protocol::WireMessage;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// how often `receive` looks for a response
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// responses kept around for `receive`, per pairing
+const MAILBOX_LEN: usize = 16;
+
+/// Responses received for one pairing; several requests may be in flight at once
+/// and each `receive` picks out its own response
+#[derive(Default)]
+struct Mailbox {
+    messages: Vec<WireMessage>,
+    /// exchanges still waiting on a response
+    pending: usize,
+}
+
+#[derive(Clone, Default)]
+pub struct Mailboxes(Arc<Mutex<HashMap<Uuid, Mailbox>>>);
+
+impl Mailboxes {
+    /// Run `exchange` in the background, it usually takes a while to reach the phone/tablet
+    /// and the relay shouldn't be held up meanwhile
+    pub fn spawn<E>(&self, queue_uuid: Uuid, exchange: E)
+    where
+        E: Future<Output = Result<WireMessage, Error>> + Send + 'static,
+    {
+        self.0.lock().unwrap().entry(queue_uuid).or_default().pending += 1;
+
+        let mailboxes = self.0.clone();
+        tokio::spawn(async move {
+            let response = exchange.await;
+
+            let mut mailboxes = mailboxes.lock().unwrap();
+            let mailbox = mailboxes.entry(queue_uuid).or_default();
+            if let Ok(response) = response {
+                mailbox.messages.push(response);
+                let excess = mailbox.messages.len().saturating_sub(MAILBOX_LEN);
+                mailbox.messages.drain(..excess);
+            }
+            mailbox.pending -= 1;
+        });
+    }
+
+    /// Wait for a response `on_messages` accepts, until every exchange has finished
+    pub async fn receive<T, F>(&self, queue_uuid: Uuid, on_messages: F) -> Result<T, Error>
+    where
+        F: Fn(&[WireMessage]) -> Result<Option<T>, Error>,
+    {
+        loop {
+            {
+                let mailboxes = self.0.lock().unwrap();
+                let mailbox = match mailboxes.get(&queue_uuid) {
+                    Some(mailbox) => mailbox,
+                    None => return Err(Error::ResponseTimedOut),
+                };
+
+                if !mailbox.messages.is_empty() {
+                    if let Some(res) = on_messages(&mailbox.messages)? {
+                        return Ok(res);
+                    }
+                }
+                if mailbox.pending == 0 {
+                    return Err(Error::ResponseTimedOut);
+                }
+            }
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
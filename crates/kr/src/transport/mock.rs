@@ -0,0 +1,182 @@
+//! Answer requests from a script instead of a phone, to try the agent's flows end to end,
+//! e.g. `AKR_MOCK_SCRIPT=script.json akr start --transport mock`
+//!
+//! The script is a JSON array of the exchanges expected, in order:
+//!
+//! ```json
+//! [
+//!     { "request": "authenticate", "key": "<base64 PKCS#8 of a P-256 key>" },
+//!     { "request": "authenticate", "error": "rejected", "delay_secs": 2 },
+//!     { "request": "ping", "response": { "ping_response": {} } }
+//! ]
+//! ```
+//!
+//! `request` is the name of the request (see `RequestBody::name`), and it's answered with either
+//! `response`, the body as the phone would send it, `error`, an error from the phone, or `key`,
+//! with which register and authenticate requests are answered like a security key would.
+//! Each process replays the script from its start, `akr replay` gives it the one `akr start --record` wrote.
+
+use super::DeviceTransport;
+use crate::crypto;
+use crate::error::Error;
+use crate::protocol::{
+    AuthenticateRequest, AuthenticateResponse, Base64Buffer, ClientResult, RegisterResponse, Request,
+    RequestBody, Response, ResponseBody, PROTOCOL_VERSION,
+};
+use async_trait::async_trait;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const SCRIPT_ENV: &str = "AKR_MOCK_SCRIPT";
+
+/// the exchanges left, shared by every `Client`
+static SCRIPT: OnceLock<Result<Mutex<VecDeque<Exchange>>, String>> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Exchange {
+    pub request: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<ResponseBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<Base64Buffer>,
+    /// how long the phone takes to answer, e.g. to run into the sign timeout
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub delay_secs: u64,
+}
+
+fn is_zero(secs: &u64) -> bool {
+    *secs == 0
+}
+
+/// Replay `exchanges` instead of the script of `AKR_MOCK_SCRIPT`, or of the last call, from the next request on
+pub fn set_script(exchanges: Vec<Exchange>) {
+    if let Ok(script) = SCRIPT.get_or_init(|| Ok(Mutex::default())) {
+        *script.lock().unwrap() = exchanges.into();
+    }
+}
+
+pub struct MockTransport;
+
+#[async_trait]
+impl DeviceTransport for MockTransport {
+    async fn exchange(&self, request: &Request, _: Option<Instant>) -> Result<Response, Error> {
+        let exchange = Self::next(request.body.name())?;
+        tokio::time::sleep(Duration::from_secs(exchange.delay_secs)).await;
+
+        let body = match exchange {
+            Exchange {
+                response: Some(body), ..
+            } => body,
+            Exchange {
+                error: Some(error), ..
+            } => return Err(Error::DeviceError(error)),
+            Exchange { key: Some(key), .. } => Self::answer_with_key(&key.0, request.body.clone())?,
+            Exchange { request, .. } => {
+                return Err(Error::MockTransport(format!(
+                    "the {} exchange has no response, error or key",
+                    request
+                )))
+            }
+        };
+
+        Ok(Response {
+            request_id: request.id.clone(),
+            aws_push_id: None,
+            device_token: None,
+            version: PROTOCOL_VERSION.to_string(),
+            body,
+        })
+    }
+}
+
+impl MockTransport {
+    fn script() -> Result<&'static Mutex<VecDeque<Exchange>>, Error> {
+        let script = SCRIPT.get_or_init(|| {
+            let path = std::env::var_os(SCRIPT_ENV).ok_or(format!("{} isn't set", SCRIPT_ENV))?;
+            let contents = std::fs::read(&path).map_err(|e| format!("{}: {}", path.to_string_lossy(), e))?;
+            serde_json::from_slice(&contents)
+                .map(Mutex::new)
+                .map_err(|e| format!("{}: {}", path.to_string_lossy(), e))
+        });
+        script.as_ref().map_err(|e| Error::MockTransport(e.clone()))
+    }
+
+    /// the next exchange of the script, which has to be for a `name` request
+    fn next(name: &str) -> Result<Exchange, Error> {
+        let mut script = Self::script()?.lock().unwrap();
+        match script.pop_front() {
+            Some(exchange) if exchange.request == name => Ok(exchange),
+            Some(exchange) => Err(Error::MockTransport(format!(
+                "expected a {} request, got {}",
+                exchange.request, name
+            ))),
+            None => Err(Error::MockTransport(format!(
+                "no exchange left for the {} request",
+                name
+            ))),
+        }
+    }
+
+    /// answer like a security key holding the P-256 key of `pkcs8`, its key handle is the public key's hash
+    fn answer_with_key(pkcs8: &[u8], body: RequestBody) -> Result<ResponseBody, Error> {
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8)
+            .map_err(|e| Error::MockTransport(format!("invalid key: {}", e)))?;
+        let public_key = key.public_key().as_ref().to_vec();
+        let key_handle = Base64Buffer(crypto::sha256(&public_key).to_vec());
+
+        match body {
+            RequestBody::Register(_) => Ok(ResponseBody::Register(ClientResult::ok(RegisterResponse {
+                public_key: Base64Buffer(public_key),
+                key_handle,
+                attestation_data: None,
+                attestation_format: None,
+                attestation_signature: None,
+                attestation_certificates: None,
+                extensions: None,
+            }))),
+            RequestBody::Authenticate(request) => Ok(ResponseBody::Authenticate(ClientResult::ok(
+                Self::sign(&key, public_key, key_handle, request)?,
+            ))),
+            body => Err(Error::MockTransport(format!(
+                "a key can't answer the {} request",
+                body.name()
+            ))),
+        }
+    }
+
+    fn sign(
+        key: &EcdsaKeyPair,
+        public_key: Vec<u8>,
+        key_handle: Base64Buffer,
+        request: AuthenticateRequest,
+    ) -> Result<AuthenticateResponse, Error> {
+        // rp_id_hash | flags, the user was present | sign_counter, the time so it only goes up across runs
+        let counter = chrono::Utc::now().timestamp() as u32;
+        let mut authenticator_data = crypto::sha256(request.rp_id.as_bytes()).to_vec();
+        authenticator_data.push(AuthenticateResponse::AUTH_FLAG_UP);
+        authenticator_data.extend_from_slice(&counter.to_be_bytes());
+
+        let mut message = authenticator_data.clone();
+        message.extend_from_slice(&request.challenge.0);
+        let signature = key
+            .sign(&ring::rand::SystemRandom::new(), &message)
+            .map_err(|_| Error::MockTransport("signing failed".to_string()))?;
+
+        Ok(AuthenticateResponse {
+            public_key: Base64Buffer(public_key),
+            counter,
+            signature: Base64Buffer(signature.as_ref().to_vec()),
+            key_handle: request.key_handle.unwrap_or(key_handle),
+            user_handle: None,
+            authenticator_data: Base64Buffer(authenticator_data),
+            extensions: None,
+        })
+    }
+}
@@ -0,0 +1,79 @@
+//! Sealed requests that couldn't be sent because the network was down,
+//! kept in "~/.akr/outbox.json" until `akr flush-queue` sends them
+
+use crate::error::Error;
+use crate::protocol::{Base64Buffer, WireMessage};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// queued requests older than this are dropped instead of sent, the phone/tablet won't care anymore
+const MAX_AGE_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedMessage {
+    pub queue_uuid: Uuid,
+    pub device_token: Option<String>,
+    /// the kind of request, see `RequestBody::name`
+    pub request: String,
+    /// the sealed request, as sent to the relay
+    pub message: Base64Buffer,
+    pub queued_at: i64,
+}
+
+impl QueuedMessage {
+    pub fn new(queue_uuid: Uuid, device_token: Option<String>, request: &str, message: WireMessage) -> Self {
+        Self {
+            queue_uuid,
+            device_token,
+            request: request.to_string(),
+            message: Base64Buffer(message.into_wire()),
+            queued_at: chrono::Utc::now().timestamp(),
+        }
+    }
+
+    pub fn wire_message(&self) -> Result<WireMessage, Error> {
+        WireMessage::new(self.message.0.clone())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        chrono::Utc::now().timestamp() - self.queued_at > MAX_AGE_SECONDS
+    }
+}
+
+pub struct Outbox;
+
+impl Outbox {
+    fn path() -> Result<PathBuf, Error> {
+        Ok(crate::create_home_path()?.join("outbox.json"))
+    }
+
+    pub fn load() -> Result<Vec<QueuedMessage>, Error> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn store(messages: &[QueuedMessage]) -> Result<(), Error> {
+        let path = Self::path()?;
+        if messages.is_empty() {
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        std::fs::write(path, serde_json::to_string_pretty(messages)?)?;
+        Ok(())
+    }
+
+    /// Queue `message`, replacing an older request of the same kind to the same phone/tablet
+    /// so e.g. repeated key list refreshes only get sent once
+    pub fn push(message: QueuedMessage) -> Result<(), Error> {
+        let mut messages = Self::load()?;
+        messages.retain(|m| !(m.queue_uuid == message.queue_uuid && m.request == message.request));
+        messages.push(message);
+        Self::store(&messages)
+    }
+}
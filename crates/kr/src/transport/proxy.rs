@@ -0,0 +1,397 @@
+//! Reaching the relays through an outbound proxy, over HTTP CONNECT or SOCKS5 with optional credentials
+//!
+//! The proxy is `--proxy` if given, otherwise `HTTPS_PROXY` or `ALL_PROXY`, skipping the hosts in
+//! `NO_PROXY`. The AWS client gets connected through it directly; the reqwest clients are pointed at it,
+//! or for SOCKS5 (which reqwest can't speak here) at a local bridge that tunnels through it.
+
+use crate::error::Error;
+use base64::Engine;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// the port curl assumes as well when the proxy url has none
+const DEFAULT_PORT: u16 = 1080;
+/// proxies answer a CONNECT with a short head, don't read forever if one doesn't
+const MAX_RESPONSE_HEAD_LEN: usize = 8192;
+/// how long an idle connection to a relay stays open for the next request
+pub const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// keeps NATs and proxies from dropping idle connections
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// an HTTP proxy, tunneling with CONNECT
+    Http,
+    Socks5,
+}
+
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+}
+
+impl FromStr for Proxy {
+    type Err = Error;
+
+    /// `[scheme://][user:password@]host[:port]`, the scheme being http, socks5 or socks5h
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Proxy(format!("invalid proxy '{}'", s));
+
+        let (scheme, rest) = s.split_once("://").unwrap_or(("http", s));
+        let kind = match scheme.to_ascii_lowercase().as_str() {
+            "http" => ProxyKind::Http,
+            // the relay host names are always resolved by the proxy
+            "socks5" | "socks5h" => ProxyKind::Socks5,
+            _ => return Err(Error::Proxy(format!("unsupported proxy scheme '{}'", scheme))),
+        };
+
+        let authority = rest.split('/').next().unwrap_or_default();
+        let (credentials, address) = match authority.rsplit_once('@') {
+            Some((userinfo, address)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                let decode = |s: &str| {
+                    urlencoding::decode(s)
+                        .map(|s| s.to_string())
+                        .map_err(|_| invalid())
+                };
+                (Some((decode(user)?, decode(password)?)), address)
+            }
+            None => (None, authority),
+        };
+
+        // a bracketed IPv6 address may contain colons itself
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().map_err(|_| invalid())?),
+            _ => (address, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Proxy {
+            kind,
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+}
+
+static CONFIGURED_PROXY: OnceLock<Option<Proxy>> = OnceLock::new();
+static SOCKS_BRIDGE: OnceLock<SocketAddr> = OnceLock::new();
+static REQWEST_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Use `proxy` for every client created afterwards, instead of the one from the environment
+pub fn configure(proxy: Option<Proxy>) {
+    if proxy.is_some() {
+        let _ = CONFIGURED_PROXY.set(proxy);
+    }
+}
+
+/// the proxy to use, if any
+pub fn configured() -> Option<&'static Proxy> {
+    CONFIGURED_PROXY
+        .get_or_init(|| {
+            let from_env = ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+                .iter()
+                .filter_map(|name| std::env::var(name).ok())
+                .find(|value| !value.is_empty())?;
+
+            match from_env.parse() {
+                Ok(proxy) => Some(proxy),
+                Err(e) => {
+                    eprintln!("ignoring the proxy from the environment: {}", e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+/// Whether `host` is listed in NO_PROXY, itself or as a subdomain of an entry
+fn bypasses_proxy(host: &str) -> bool {
+    let no_proxy = std::env::var("NO_PROXY")
+        .or_else(|_| std::env::var("no_proxy"))
+        .unwrap_or_default();
+
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        let entry = entry.trim_start_matches('.');
+        entry == "*" || (!entry.is_empty() && (host == entry || host.ends_with(&format!(".{}", entry))))
+    })
+}
+
+impl Proxy {
+    /// Open a connection to `host:port` through the proxy
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| Error::Proxy(format!("couldn't connect to {}:{}: {}", self.host, self.port, e)))?;
+
+        match self.kind {
+            ProxyKind::Http => self.http_connect(&mut stream, host, port).await?,
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    /// See: https://www.rfc-editor.org/rfc/rfc9110#name-connect
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+        let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some((user, password)) = &self.credentials {
+            let credentials =
+                base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // byte by byte, so nothing of the tunneled connection gets read along
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > MAX_RESPONSE_HEAD_LEN {
+                return Err(Error::Proxy("response to CONNECT too long".into()));
+            }
+            head.push(stream.read_u8().await?);
+        }
+
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some("200") => Ok(()),
+            _ => Err(Error::Proxy(format!("proxy refused to connect: {}", status_line))),
+        }
+    }
+
+    /// See: https://www.rfc-editor.org/rfc/rfc1928 and https://www.rfc-editor.org/rfc/rfc1929
+    async fn socks5_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), Error> {
+        const VERSION: u8 = 0x05;
+        const NO_AUTHENTICATION: u8 = 0x00;
+        const USERNAME_PASSWORD: u8 = 0x02;
+        const CONNECT: u8 = 0x01;
+        const DOMAIN_NAME: u8 = 0x03;
+
+        match &self.credentials {
+            Some(_) => {
+                stream
+                    .write_all(&[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD])
+                    .await?
+            }
+            None => stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?,
+        }
+
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        match (choice[1], &self.credentials) {
+            (NO_AUTHENTICATION, _) => {}
+            (USERNAME_PASSWORD, Some((user, password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    return Err(Error::Proxy("proxy credentials too long".into()));
+                }
+                let mut auth = vec![0x01, user.len() as u8];
+                auth.extend_from_slice(user.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth).await?;
+
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(Error::Proxy("proxy rejected the credentials".into()));
+                }
+            }
+            _ => {
+                return Err(Error::Proxy(
+                    "proxy requires an unsupported authentication".into(),
+                ))
+            }
+        }
+
+        if host.len() > 255 {
+            return Err(Error::Proxy(format!("host name too long: {}", host)));
+        }
+        let mut request = vec![VERSION, CONNECT, 0x00, DOMAIN_NAME, host.len() as u8];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // version | reply | reserved | address type | bound address | bound port
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(Error::Proxy(format!(
+                "proxy refused to connect (SOCKS reply {})",
+                reply[1]
+            )));
+        }
+        let address_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            DOMAIN_NAME => stream.read_u8().await? as usize,
+            _ => return Err(Error::Proxy("invalid SOCKS reply".into())),
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    /// the proxy url for reqwest, which only tunnels through HTTP proxies itself
+    fn reqwest_url(&self) -> Result<String, Error> {
+        let address = match self.kind {
+            ProxyKind::Http => format!("{}:{}", self.host, self.port),
+            ProxyKind::Socks5 => return Ok(format!("http://{}", socks_bridge()?)),
+        };
+
+        Ok(match &self.credentials {
+            Some((user, password)) => format!(
+                "http://{}:{}@{}",
+                urlencoding::encode(user),
+                urlencoding::encode(password),
+                address
+            ),
+            None => format!("http://{}", address),
+        })
+    }
+}
+
+/// The reqwest client that goes through the configured proxy, if any. Every caller shares it and its
+/// connections, back to back requests to a relay don't each pay for a TLS handshake
+pub fn reqwest_client() -> Result<reqwest::Client, Error> {
+    if let Some(client) = REQWEST_CLIENT.get() {
+        return Ok(client.clone());
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE);
+    if let Some(proxy) = configured() {
+        let url = reqwest::Url::parse(&proxy.reqwest_url()?).map_err(|e| Error::Proxy(e.to_string()))?;
+        builder = builder.proxy(reqwest::Proxy::custom(move |target| match target.host_str() {
+            Some(host) if bypasses_proxy(host) => None,
+            _ => Some(url.clone()),
+        }));
+    }
+    let client = builder.build()?;
+    Ok(REQWEST_CLIENT.get_or_init(|| client).clone())
+}
+
+/// Accept CONNECTs from the reqwest clients on localhost and tunnel them through the SOCKS5 proxy
+fn socks_bridge() -> Result<SocketAddr, Error> {
+    if let Some(address) = SOCKS_BRIDGE.get() {
+        return Ok(*address);
+    }
+
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.set_nonblocking(true)?;
+    let address = listener.local_addr()?;
+    let listener = TcpListener::from_std(listener)?;
+
+    tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                if let Err(e) = bridge(client).await {
+                    eprintln!("{}", e);
+                }
+            });
+        }
+    });
+
+    Ok(*SOCKS_BRIDGE.get_or_init(|| address))
+}
+
+async fn bridge(mut client: TcpStream) -> Result<(), Error> {
+    let proxy = configured().ok_or_else(|| Error::Proxy("no proxy configured".into()))?;
+
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_RESPONSE_HEAD_LEN {
+            return Err(Error::Proxy("request head too long".into()));
+        }
+        head.push(client.read_u8().await?);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let target = match head
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>()[..]
+    {
+        ["CONNECT", target, _] => target.to_string(),
+        _ => {
+            client
+                .write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")
+                .await?;
+            return Ok(());
+        }
+    };
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+        .ok_or_else(|| Error::Proxy(format!("invalid CONNECT target '{}'", target)))?;
+
+    let mut upstream = match proxy.connect(&host, port).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            return Err(e);
+        }
+    };
+    client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Connects hyper (and so the AWS client) through the configured proxy, directly without one
+#[derive(Clone)]
+pub struct ProxyConnector;
+
+impl tower_service::Service<http::Uri> for ProxyConnector {
+    type Response = TcpStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<TcpStream, std::io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: http::Uri) -> Self::Future {
+        Box::pin(async move {
+            let host = uri.host().unwrap_or_default().to_string();
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("http") => 80,
+                _ => 443,
+            });
+
+            match configured() {
+                Some(proxy) if !bypasses_proxy(&host) => proxy
+                    .connect(&host, port)
+                    .await
+                    .map_err(|e| std::io::Error::other(e.to_string())),
+                _ => TcpStream::connect((host.as_str(), port)).await,
+            }
+        })
+    }
+}
+
+/// `ProxyConnector` with TLS on top, for `rusoto_core::HttpClient`
+pub fn https_connector() -> hyper_rustls::HttpsConnector<ProxyConnector> {
+    hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(ProxyConnector)
+}
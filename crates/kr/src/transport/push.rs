@@ -0,0 +1,142 @@
+//! Server-sent events subscription to a relay queue, so a response arrives as soon as the phone/tablet
+//! sends it instead of on the next poll
+//!
+//! The relay streams `text/event-stream` from `<queue url>/events`, each event's `data:` lines carrying
+//! one base64 wire message. Relays without the endpoint are polled as before.
+
+use crate::error::Error;
+use crate::protocol::WireMessage;
+use base64::Engine;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushState {
+    /// no subscription was made yet
+    Idle,
+    Connected,
+    /// the subscription broke off, responses are polled for until the next one
+    Disconnected,
+    /// the relay doesn't stream events, responses are polled for
+    Unsupported,
+}
+
+impl fmt::Display for PushState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PushState::Idle => "not connected yet",
+            PushState::Connected => "connected",
+            PushState::Disconnected => "disconnected, polling",
+            PushState::Unsupported => "not supported by the relay, polling",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct PushChannel {
+    client: reqwest::Client,
+    state: Arc<Mutex<PushState>>,
+}
+
+impl PushChannel {
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            state: Arc::new(Mutex::new(PushState::Idle)),
+        }
+    }
+
+    pub fn state(&self) -> PushState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set_state(&self, state: PushState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Subscribe to the events of the queue at `url` until `on_messages` accepts a message or `deadline`
+    /// passes. `Ok(None)` means there is no subscription (anymore) and the caller should poll instead
+    pub async fn receive<T, F>(
+        &self,
+        url: &str,
+        deadline: Instant,
+        on_messages: &F,
+    ) -> Result<Option<T>, Error>
+    where
+        F: Fn(&[WireMessage]) -> Result<Option<T>, Error>,
+    {
+        // once we know the relay doesn't stream, don't ask every time
+        if self.state() == PushState::Unsupported {
+            return Ok(None);
+        }
+
+        let subscribe = self
+            .client
+            .get(format!("{}/events", url))
+            .header(reqwest::header::ACCEPT, "text/event-stream")
+            .send();
+        let mut response = match tokio::time::timeout_at(deadline, subscribe).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => {
+                self.set_state(PushState::Disconnected);
+                return Ok(None);
+            }
+            Err(_) => return Err(Error::ResponseTimedOut),
+        };
+
+        let is_event_stream = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if !response.status().is_success() || !is_event_stream {
+            self.set_state(PushState::Unsupported);
+            return Ok(None);
+        }
+        self.set_state(PushState::Connected);
+
+        let mut buffer = vec![];
+        loop {
+            let chunk = match tokio::time::timeout_at(deadline, response.chunk()).await {
+                Ok(Ok(Some(chunk))) => chunk,
+                Ok(_) => {
+                    self.set_state(PushState::Disconnected);
+                    return Ok(None);
+                }
+                Err(_) => return Err(Error::ResponseTimedOut),
+            };
+            // lines may end with "\r\n" as well
+            buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+
+            while let Some(event) = next_event(&mut buffer) {
+                let messages = event_messages(&event);
+                if messages.is_empty() {
+                    continue;
+                }
+                if let Some(res) = on_messages(&messages)? {
+                    return Ok(Some(res));
+                }
+            }
+        }
+    }
+}
+
+/// Take the first complete event (ending with a blank line) off `buffer`
+fn next_event(buffer: &mut Vec<u8>) -> Option<String> {
+    let end = buffer.windows(2).position(|w| w == b"\n\n")?;
+    let event = String::from_utf8_lossy(&buffer[..end]).to_string();
+    buffer.drain(..end + 2);
+    Some(event)
+}
+
+/// the wire messages in an event's `data:` lines, comments (`:`) and other fields are skipped
+/// See: https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+fn event_messages(event: &str) -> Vec<WireMessage> {
+    event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .filter_map(|data| base64::engine::general_purpose::STANDARD.decode(data.trim()).ok())
+        .filter_map(|message| WireMessage::new(message).ok())
+        .collect()
+}
@@ -0,0 +1,369 @@
+//! `akr update` replaces the akr binary with the latest release of a channel, for installs from a tarball
+//!
+//! A channel is a manifest at `<update_url>/<channel>.json` that lists the binary of every platform:
+//!
+//! ```json
+//! {"version": "1.2.0", "binaries": {"x86_64-linux": "https://example.com/akr-1.2.0-x86_64-linux"}}
+//! ```
+//!
+//! Every binary has a minisign signature next to it, "<url>.minisig", made with
+//! `minisign -S -m akr-1.2.0-x86_64-linux -t version:1.2.0`. A binary is only written once its signature verifies
+//! with `update_public_key` of the config, or the key release builds are made with (AKR_UPDATE_PUBLIC_KEY), and
+//! the version in its trusted comment is the one of the manifest and newer than this akr: the manifest isn't
+//! signed, an older signed binary can't be passed off as the latest. Binaries a package manager installed are
+//! left to it.
+
+use crate::cli::UpdateArgs;
+use crate::error::Error;
+use crate::{config, crypto, output, transport};
+use ansi_term::Colour::Green;
+use base64::Engine;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const DEFAULT_URL: &str = "https://akamai.github.io/akr-pkg/releases";
+/// the minisign public key of the releases, set when building them
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("AKR_UPDATE_PUBLIC_KEY");
+
+/// where package managers put binaries, they update those themselves
+const MANAGED_DIRS: &[&str] = &[
+    "/usr/bin",
+    "/usr/sbin",
+    "/bin",
+    "/opt/homebrew/Cellar",
+    "/usr/local/Cellar",
+];
+
+/// "Ed" signs the file, "ED" its blake2b-512 hash, for files too large to read at once
+const SIGNATURE_ALGORITHM: &[u8] = b"Ed";
+const HASHED_SIGNATURE_ALGORITHM: &[u8] = b"ED";
+const TRUSTED_COMMENT: &str = "trusted comment: ";
+/// the field of the trusted comment with the version of the binary
+const VERSION_FIELD: &str = "version:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Stable,
+    Beta,
+}
+
+impl FromStr for Channel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "beta" => Ok(Channel::Beta),
+            _ => Err(Error::UnknownChannel(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Stable => f.write_str("stable"),
+            Channel::Beta => f.write_str("beta"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    version: String,
+    /// the URL of the binary by "<arch>-<os>", e.g. "aarch64-macos"
+    binaries: BTreeMap<String, String>,
+}
+
+pub async fn run(args: UpdateArgs) -> Result<(), Error> {
+    let current = semver::Version::parse(env!("CARGO_PKG_VERSION"))
+        .map_err(|e| Error::InvalidRelease(e.to_string()))?;
+    let exe = std::env::current_exe()?.canonicalize()?;
+    let client = transport::proxy::reqwest_client()?;
+
+    let mut url = config::get()
+        .update_url
+        .clone()
+        .unwrap_or_else(|| DEFAULT_URL.parse().expect("a valid URL"));
+    url.path_segments_mut()
+        .map_err(|_| Error::InvalidConfig("update_url".to_string(), "it can't be a base".to_string()))?
+        .pop_if_empty()
+        .push(&format!("{}.json", args.channel));
+    let manifest: Manifest = serde_json::from_slice(&get(&client, url).await?)
+        .map_err(|e| Error::InvalidRelease(e.to_string()))?;
+    let latest =
+        semver::Version::parse(&manifest.version).map_err(|e| Error::InvalidRelease(e.to_string()))?;
+
+    let newer = latest > current;
+    if args.check || !newer {
+        if output::is_json() {
+            return output::print_json(&serde_json::json!({
+                "channel": args.channel.to_string(),
+                "current": current.to_string(),
+                "latest": latest.to_string(),
+                "update_available": newer,
+                "updated": false,
+            }));
+        }
+        match newer {
+            true => println!("akr {} is out on {}, you have {}", latest, args.channel, current),
+            false => println!("akr {} is the latest on {}", current, args.channel),
+        }
+        return Ok(());
+    }
+
+    if MANAGED_DIRS.iter().any(|dir| exe.starts_with(dir)) {
+        return Err(Error::ManagedInstall(exe.display().to_string()));
+    }
+    let platform = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+    let binary_url = manifest
+        .binaries
+        .get(&platform)
+        .ok_or_else(|| Error::NoReleaseForPlatform(platform.clone()))?
+        .parse::<reqwest::Url>()
+        .map_err(|e| Error::InvalidRelease(e.to_string()))?;
+    let public_key = public_key()?;
+
+    let mut signature_url = binary_url.clone();
+    signature_url.set_path(&format!("{}.minisig", binary_url.path()));
+    let binary = get(&client, binary_url).await?;
+    let signature = get(&client, signature_url).await?;
+    let comment = verify(&String::from_utf8_lossy(&signature), &binary, &public_key)?;
+    check_version(&signed_version(&comment)?, &latest, &current)?;
+    replace(&exe, &binary)?;
+
+    if output::is_json() {
+        return output::print_json(&serde_json::json!({
+            "channel": args.channel.to_string(),
+            "current": current.to_string(),
+            "latest": latest.to_string(),
+            "update_available": true,
+            "updated": true,
+            "path": exe,
+        }));
+    }
+    println!(
+        "{} {} to {}, `akr restart` runs the agent of the new version",
+        Green.paint("Updated"),
+        exe.display(),
+        latest
+    );
+    Ok(())
+}
+
+async fn get(client: &reqwest::Client, url: reqwest::Url) -> Result<Vec<u8>, Error> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// The key id and the key of the minisign public key, as in its file or only its second line
+fn public_key() -> Result<([u8; 8], [u8; 32]), Error> {
+    let configured = config::get().update_public_key.clone();
+    let key = configured
+        .as_deref()
+        .or(RELEASE_PUBLIC_KEY)
+        .ok_or(Error::NoReleaseKey)?;
+    let invalid = || {
+        Error::InvalidConfig(
+            "update_public_key".to_string(),
+            "expected a minisign public key".to_string(),
+        )
+    };
+    let encoded = key
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+        .ok_or_else(invalid)?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| invalid())?;
+    // the algorithm, the key id and the key
+    if decoded.len() != 2 + 8 + 32 || &decoded[..2] != SIGNATURE_ALGORITHM {
+        return Err(invalid());
+    }
+    let key_id = decoded[2..10].try_into().expect("8 bytes");
+    let key = decoded[10..].try_into().expect("32 bytes");
+    Ok((key_id, key))
+}
+
+/// Check the minisign signature of `message`: the signature of it, or of its hash, and the signature of that
+/// and the trusted comment, which is returned
+fn verify(minisig: &str, message: &[u8], (key_id, key): &([u8; 8], [u8; 32])) -> Result<String, Error> {
+    let invalid = |reason: &str| Error::InvalidRelease(reason.to_string());
+    // the trusted comment is signed as it is, only line endings go
+    let mut lines = minisig
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty());
+    let _untrusted_comment = lines.next();
+    let (signature, trusted_comment, global_signature) = match (lines.next(), lines.next(), lines.next()) {
+        (Some(signature), Some(comment), Some(global)) => (signature, comment, global),
+        _ => return Err(invalid("the signature file is incomplete")),
+    };
+    let decode = |encoded: &str| {
+        base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| invalid("the signature isn't base64"))
+    };
+
+    let signature = decode(signature)?;
+    if signature.len() != 2 + 8 + 64 {
+        return Err(invalid("the signature has the wrong length"));
+    }
+    let (algorithm, rest) = signature.split_at(2);
+    let (signed_by, signature) = rest.split_at(8);
+    if signed_by != key_id {
+        return Err(invalid("it's signed with another key than update_public_key"));
+    }
+    let signed = match algorithm {
+        HASHED_SIGNATURE_ALGORITHM => crypto::blake2b_512(message).to_vec(),
+        SIGNATURE_ALGORITHM => message.to_vec(),
+        _ => return Err(invalid("unknown signature algorithm")),
+    };
+    if !crypto::ed25519_verify(signature, &signed, key) {
+        return Err(invalid("the signature of the binary doesn't verify"));
+    }
+
+    let comment = trusted_comment
+        .strip_prefix(TRUSTED_COMMENT)
+        .ok_or_else(|| invalid("the trusted comment is missing"))?;
+    let global_signature = decode(global_signature)?;
+    if global_signature.len() != crypto::SIGNATURE_LEN {
+        return Err(invalid("not an ed25519 signature"));
+    }
+    let mut global = signature.to_vec();
+    global.extend_from_slice(comment.as_bytes());
+    if !crypto::ed25519_verify(&global_signature, &global, key) {
+        return Err(invalid("the signature of the trusted comment doesn't verify"));
+    }
+    Ok(comment.to_string())
+}
+
+/// The version a trusted comment like "version:1.2.0 file:akr-1.2.0-x86_64-linux" gives
+fn signed_version(comment: &str) -> Result<semver::Version, Error> {
+    let version = comment
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix(VERSION_FIELD))
+        .ok_or_else(|| Error::InvalidRelease("the trusted comment doesn't give the version".to_string()))?;
+    semver::Version::parse(version).map_err(|e| Error::InvalidRelease(e.to_string()))
+}
+
+/// the signed binary has to be the release the manifest lists, and newer than this akr
+fn check_version(
+    signed: &semver::Version,
+    latest: &semver::Version,
+    current: &semver::Version,
+) -> Result<(), Error> {
+    if signed != latest {
+        return Err(Error::InvalidRelease(format!(
+            "the binary is signed as {}, the manifest lists {}",
+            signed, latest
+        )));
+    }
+    if signed <= current {
+        return Err(Error::InvalidRelease(format!(
+            "the binary is signed as {}, which isn't newer than {}",
+            signed, current
+        )));
+    }
+    Ok(())
+}
+
+/// Put `binary` in the place of `exe`, with a rename so a running agent keeps the old one
+fn replace(exe: &Path, binary: &[u8]) -> Result<(), Error> {
+    let new = sibling(exe, "new");
+    std::fs::write(&new, binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&new, std::fs::Permissions::from_mode(0o755))?;
+    }
+    // windows doesn't replace a running binary, but lets it be renamed
+    #[cfg(windows)]
+    {
+        let old = sibling(exe, "old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(exe, &old)?;
+    }
+    let renamed = std::fs::rename(&new, exe);
+    if renamed.is_err() {
+        let _ = std::fs::remove_file(&new);
+    }
+    Ok(renamed?)
+}
+
+fn sibling(exe: &Path, suffix: &str) -> PathBuf {
+    let mut name = exe.as_os_str().to_owned();
+    name.push(format!(".{}", suffix));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Signer;
+
+    const KEY_ID: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    const BINARY: &[u8] = b"the akr binary";
+
+    fn keypair() -> ed25519_dalek::Keypair {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[9; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        ed25519_dalek::Keypair { secret, public }
+    }
+
+    /// what `minisign -S -H -t <comment>` writes
+    fn minisig(comment: &str) -> String {
+        let keypair = keypair();
+        let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        let signature = keypair.sign(&crypto::blake2b_512(BINARY)).to_bytes();
+        let global = keypair
+            .sign(&[&signature[..], comment.as_bytes()].concat())
+            .to_bytes();
+        format!(
+            "untrusted comment: signature from minisign secret key\n{}\ntrusted comment: {}\n{}\n",
+            encode(&[HASHED_SIGNATURE_ALGORITHM, &KEY_ID[..], &signature[..]].concat()),
+            comment,
+            encode(&global)
+        )
+    }
+
+    fn version(version: &str) -> semver::Version {
+        semver::Version::parse(version).unwrap()
+    }
+
+    #[test]
+    fn returns_the_signed_version() {
+        let public_key = (KEY_ID, keypair().public.to_bytes());
+        let comment = verify(
+            &minisig("timestamp:1700000000\tversion:1.2.0"),
+            BINARY,
+            &public_key,
+        )
+        .unwrap();
+        assert_eq!(signed_version(&comment).unwrap(), version("1.2.0"));
+        assert!(verify(&minisig("version:1.2.0"), b"another binary", &public_key).is_err());
+
+        // the comment is signed, changing its version breaks the signature
+        let forged = minisig("version:1.2.0").replace("version:1.2.0", "version:9.0.0");
+        assert!(verify(&forged, BINARY, &public_key).is_err());
+    }
+
+    #[test]
+    fn needs_a_version_in_the_trusted_comment() {
+        assert!(signed_version("timestamp:1700000000\tfile:akr-1.2.0-x86_64-linux").is_err());
+        assert!(signed_version("version:latest").is_err());
+    }
+
+    #[test]
+    fn only_takes_the_release_of_the_manifest_if_newer() {
+        let (latest, current) = (version("1.2.0"), version("1.1.0"));
+        assert!(check_version(&version("1.2.0"), &latest, &current).is_ok());
+        // an older signed binary served as the latest
+        assert!(check_version(&version("1.0.0"), &latest, &current).is_err());
+        assert!(check_version(&version("1.1.0"), &version("1.1.0"), &current).is_err());
+    }
+}
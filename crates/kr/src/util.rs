@@ -1,19 +1,136 @@
 use crate::error::Error;
-use byteorder::{BigEndian, ReadBytesExt};
-use std::io::{Cursor, Read};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The longest public key, key handle or private key in an agent message
+pub const MAX_KEY_LEN: usize = 1024;
+/// The longest comment of a key added to the agent
+pub const MAX_COMMENT_LEN: usize = 4096;
+pub use ssh_agent::{MAX_MESSAGE_LEN, MAX_NAME_LEN};
 
 pub fn read_data(buf: &mut Cursor<Vec<u8>>) -> Result<Vec<u8>, Error> {
-    let length = buf.read_u32::<BigEndian>()?;
-    let mut data = vec![0; length as usize];
+    read_data_at_most(buf, MAX_MESSAGE_LEN)
+}
+
+pub fn read_string(buf: &mut Cursor<Vec<u8>>) -> Result<String, Error> {
+    read_string_at_most(buf, MAX_MESSAGE_LEN)
+}
+
+/// A string of at most `max` bytes, its length is checked against what's left of `buf`
+/// before anything is allocated for it
+pub fn read_data_at_most(buf: &mut Cursor<Vec<u8>>, max: usize) -> Result<Vec<u8>, Error> {
+    let length = buf.read_u32::<BigEndian>()? as usize;
+    let left = buf.get_ref().len().saturating_sub(buf.position() as usize);
+    if length > max {
+        return Err(Error::FieldTooLong(length, max));
+    }
+    if length > left {
+        return Err(Error::TruncatedMessage(length, left));
+    }
+
+    let mut data = vec![0; length];
     buf.read_exact(&mut data)?;
     Ok(data)
 }
 
-pub fn read_string(buf: &mut Cursor<Vec<u8>>) -> Result<String, Error> {
-    let data = read_data(buf)?;
+pub fn read_string_at_most(buf: &mut Cursor<Vec<u8>>, max: usize) -> Result<String, Error> {
+    let data = read_data_at_most(buf, max)?;
     Ok(std::str::from_utf8(&data).map(|s| s.to_string())?)
 }
 
+pub fn write_data(buf: &mut Vec<u8>, data: &[u8]) -> Result<(), Error> {
+    buf.write_u32::<BigEndian>(data.len() as u32)?;
+    buf.write_all(data)?;
+    Ok(())
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over `path`,
+/// so readers see either the old or the new contents, never half of them
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let tmp = temporary_path(path);
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// A path next to `path` to write its new contents to first, one per write as concurrent writers would
+/// rename each other's half written one
+pub fn temporary_path(path: &Path) -> PathBuf {
+    static WRITES: AtomicU32 = AtomicU32::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(tmp)
+}
+
+/// Put the directory `new` in place of `dir` and remove what was there. On Linux both are swapped in one
+/// rename so readers see either the old or the new directory, elsewhere `dir` is moved aside first
+pub fn replace_dir(dir: &Path, new: &Path) -> Result<(), Error> {
+    if !dir.exists() {
+        std::fs::rename(new, dir)?;
+        return Ok(());
+    }
+    let old = swap_dirs(dir, new)?;
+    std::fs::remove_dir_all(old)?;
+    Ok(())
+}
+
+/// where the old directory ended up
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn swap_dirs(dir: &Path, new: &Path) -> Result<PathBuf, Error> {
+    use nix::fcntl::{renameat2, RenameFlags};
+    renameat2(None, new, None, dir, RenameFlags::RENAME_EXCHANGE).map_err(std::io::Error::from)?;
+    Ok(new.to_path_buf())
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn swap_dirs(dir: &Path, new: &Path) -> Result<PathBuf, Error> {
+    let old = temporary_path(dir);
+    std::fs::rename(dir, &old)?;
+    std::fs::rename(new, dir)?;
+    Ok(old)
+}
+
+/// Copy the files of `from` into the new directory `to`, the subdirectories are left out
+pub fn copy_dir(from: &Path, to: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Overwrite a file with zeros before deleting it, or everything in a directory
+/// symlinks and sockets are only removed, the files they point to aren't ours
+pub fn shred(path: &Path) -> Result<(), Error> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            shred(&entry?.path())?;
+        }
+        std::fs::remove_dir(path)?;
+        return Ok(());
+    }
+
+    if metadata.is_file() {
+        let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+        file.write_all(&vec![0; metadata.len() as usize])?;
+        file.sync_all()?;
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
 // #[cfg(unix)]
 // pub fn set_user_protected_permissions(path: &str) -> Result<(), Error> {
 //     use std::os::unix::fs::PermissionsExt;
@@ -27,3 +144,11 @@ pub fn read_string(buf: &mut Cursor<Vec<u8>>) -> Result<String, Error> {
 // pub fn set_user_protected_permissions(path: &str) -> Result<(), Error> {
 //     Ok(())
 // }
+
+/// `path` with a leading "~/" replaced by the home directory
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
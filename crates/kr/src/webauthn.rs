@@ -0,0 +1,442 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::BridgeArgs;
+use crate::client::Client;
+use crate::config;
+use crate::crypto;
+use crate::error::Error;
+use crate::protocol::{
+    AuthenticateRequest, AuthenticateResponse, Base64Buffer, ListKeysRequest, ListKeysResponse, RequestBody,
+};
+use crate::ssh_format::{verify_sk_signature, SkKeyType};
+use base64::Engine;
+use hyper::header::{HeaderValue, ORIGIN};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::{Deserialize, Serialize};
+
+const GET_PATH: &str = "/webauthn/get";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// how long the phone has to list its credentials, the user already approved by then
+const LIST_KEYS_TIMEOUT: Duration = Duration::from_secs(10);
+/// second level labels under which countries let anyone register a name, like "co.uk" or "com.au"
+const COUNTRY_SECOND_LEVELS: &[&str] = &[
+    "ac", "co", "com", "edu", "go", "gob", "gov", "ltd", "mil", "ne", "net", "nom", "or", "org", "plc", "sch",
+];
+/// hosting domains where anyone gets a subdomain, from the private section of the public suffix list
+const HOSTING_SUFFIXES: &[&str] = &[
+    "amplifyapp.com",
+    "appspot.com",
+    "azurestaticapps.net",
+    "azurewebsites.net",
+    "blogspot.com",
+    "cloudfront.net",
+    "duckdns.org",
+    "firebaseapp.com",
+    "fly.dev",
+    "github.io",
+    "gitlab.io",
+    "glitch.me",
+    "herokuapp.com",
+    "netlify.app",
+    "ngrok.io",
+    "onrender.com",
+    "pages.dev",
+    "s3.amazonaws.com",
+    "surge.sh",
+    "vercel.app",
+    "web.app",
+    "workers.dev",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetOptions {
+    challenge: String,
+    rp_id: Option<String>,
+    #[serde(default)]
+    allow_credentials: Vec<CredentialDescriptor>,
+    /// milliseconds
+    timeout: Option<u64>,
+    user_verification: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CredentialDescriptor {
+    id: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PublicKeyCredential {
+    #[serde(rename = "type")]
+    credential_type: &'static str,
+    id: String,
+    raw_id: String,
+    authenticator_attachment: &'static str,
+    response: AssertionResponse,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+    user_handle: Option<String>,
+}
+
+/// https://www.w3.org/TR/webauthn-2/#dictionary-client-data
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientData<'a> {
+    #[serde(rename = "type")]
+    client_data_type: &'static str,
+    challenge: &'a str,
+    origin: &'a str,
+    cross_origin: bool,
+}
+
+/// Why a request was refused, mapped to an HTTP status
+enum BridgeError {
+    BadRequest(String),
+    Forbidden(String),
+    Device(Error),
+}
+
+/// Browser bridge for `navigator.credentials.get`
+///
+/// A page (or an extension shimming `navigator.credentials`) POSTs its
+/// PublicKeyCredentialRequestOptions as JSON to `/webauthn/get`, with binary
+/// fields base64url encoded. The assertion is made by the phone through the same
+/// `Authenticate` request the ssh agent uses, and returned as a PublicKeyCredential.
+/// Only the origins in `webauthn_origins` of the config file get an answer, any page the browser shows could
+/// reach the bridge otherwise.
+/// See: https://www.w3.org/TR/webauthn-2/#sctn-getAssertion
+pub async fn run(args: BridgeArgs) -> Result<(), Error> {
+    let origins = allowed_origins();
+    if origins.is_empty() {
+        return Err(Error::NoWebauthnOrigins);
+    }
+    let client = Arc::new(Client::new()?);
+
+    // only reachable from this machine
+    let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
+    let make_service = make_service_fn(move |_| {
+        let client = client.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| handle(client.clone(), request))) }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    println!("WebAuthn bridge listening on http://{}{}", addr, GET_PATH);
+    server.await?;
+    Ok(())
+}
+
+async fn handle(client: Arc<Client>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    // the browser sets the origin, and it is what the assertions are scoped to
+    let origin = match request.headers().get(ORIGIN).and_then(|o| o.to_str().ok()) {
+        Some(origin) => origin.to_string(),
+        None => return Ok(reply(StatusCode::FORBIDDEN, None, error("missing Origin"))),
+    };
+    // before the preflight, so the browser doesn't even send the request of another origin
+    if !allowed_origins().contains(&normalize_origin(&origin)) {
+        tracing::warn!(
+            "refused a request from origin {}, it isn't in webauthn_origins",
+            origin
+        );
+        return Ok(reply(StatusCode::FORBIDDEN, None, error("origin not allowed")));
+    }
+
+    if request.uri().path() != GET_PATH {
+        return Ok(reply(StatusCode::NOT_FOUND, Some(&origin), error("not found")));
+    }
+
+    let result = match *request.method() {
+        // CORS preflight
+        Method::OPTIONS => return Ok(reply(StatusCode::NO_CONTENT, Some(&origin), String::new())),
+        Method::POST => get_assertion(&client, &origin, request.into_body()).await,
+        _ => {
+            return Ok(reply(
+                StatusCode::METHOD_NOT_ALLOWED,
+                Some(&origin),
+                String::new(),
+            ))
+        }
+    };
+
+    let response = match result {
+        Ok(credential) => match serde_json::to_string(&credential) {
+            Ok(json) => reply(StatusCode::OK, Some(&origin), json),
+            Err(e) => reply(StatusCode::INTERNAL_SERVER_ERROR, Some(&origin), error(e)),
+        },
+        Err(BridgeError::BadRequest(e)) => reply(StatusCode::BAD_REQUEST, Some(&origin), error(e)),
+        Err(BridgeError::Forbidden(e)) => reply(StatusCode::FORBIDDEN, Some(&origin), error(e)),
+        Err(BridgeError::Device(e @ Error::ApprovalTimedOut(_))) => {
+            reply(StatusCode::GATEWAY_TIMEOUT, Some(&origin), error(e))
+        }
+        Err(BridgeError::Device(e)) => reply(StatusCode::BAD_GATEWAY, Some(&origin), error(e)),
+    };
+    Ok(response)
+}
+
+async fn get_assertion(
+    client: &Client,
+    origin: &str,
+    body: Body,
+) -> Result<PublicKeyCredential, BridgeError> {
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| BridgeError::BadRequest(e.to_string()))?;
+    let options: GetOptions =
+        serde_json::from_slice(&body).map_err(|e| BridgeError::BadRequest(e.to_string()))?;
+
+    let host = origin_host(origin).ok_or_else(|| BridgeError::Forbidden("insecure origin".into()))?;
+    let rp_id = options.rp_id.clone().unwrap_or_else(|| host.clone());
+    if !rp_id_permitted(&rp_id, &host) {
+        return Err(BridgeError::Forbidden(format!(
+            "{} can't use rpId {}",
+            origin, rp_id
+        )));
+    }
+
+    let base64url = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let key_handles = options
+        .allow_credentials
+        .iter()
+        .map(|credential| base64url.decode(&credential.id).map(Base64Buffer))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| BridgeError::BadRequest(e.to_string()))?;
+
+    let client_data = serde_json::to_vec(&ClientData {
+        client_data_type: "webauthn.get",
+        challenge: &options.challenge,
+        origin,
+        cross_origin: false,
+    })
+    .map_err(|e| BridgeError::BadRequest(e.to_string()))?;
+    let client_data_hash = crypto::sha256(&client_data);
+
+    let timeout = options
+        .timeout
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_TIMEOUT);
+    let resp = client
+        .send_request_with_timeout::<AuthenticateResponse>(
+            RequestBody::Authenticate(AuthenticateRequest {
+                challenge: Base64Buffer(client_data_hash.as_ref().to_vec()),
+                rp_id: rp_id.clone(),
+                extensions: None,
+                key_handle: None,
+                key_handles: Some(key_handles.clone()).filter(|handles| !handles.is_empty()),
+            }),
+            timeout,
+        )
+        .await
+        .map_err(|e| match e {
+            Error::ResponseTimedOut => Error::ApprovalTimedOut(timeout.as_secs()),
+            e => e,
+        })
+        .map_err(BridgeError::Device)?;
+
+    // check the assertion like the relying party will, so a wrong answer from the phone never reaches the page
+    let flags = check_authenticator_data(&resp.authenticator_data.0, &rp_id).map_err(BridgeError::Device)?;
+    if !key_handles.is_empty() && !key_handles.iter().any(|handle| handle.0 == resp.key_handle.0) {
+        return Err(BridgeError::Device(Error::UnknownCredential(rp_id)));
+    }
+    let public_key = registered_key(client, &rp_id, &resp.key_handle.0)
+        .await
+        .map_err(BridgeError::Device)?;
+    if !verify_sk_signature(
+        SkKeyType::from_public_key(&public_key),
+        &public_key,
+        &resp.authenticator_data.0,
+        client_data_hash.as_ref(),
+        &resp.signature.0,
+    ) {
+        return Err(BridgeError::Device(Error::SignatureVerificationFailed(rp_id)));
+    }
+
+    if options.user_verification.as_deref() == Some("required")
+        && flags & AuthenticateResponse::AUTH_FLAG_UV == 0
+    {
+        return Err(BridgeError::Device(Error::KeyFlagsNotSatisfied("verification")));
+    }
+
+    Ok(PublicKeyCredential {
+        credential_type: "public-key",
+        id: base64url.encode(&resp.key_handle.0),
+        raw_id: base64url.encode(&resp.key_handle.0),
+        authenticator_attachment: "cross-platform",
+        response: AssertionResponse {
+            client_data_json: base64url.encode(&client_data),
+            authenticator_data: base64url.encode(&resp.authenticator_data.0),
+            signature: base64url.encode(&resp.signature.0),
+            user_handle: resp.user_handle.map(|handle| base64url.encode(&handle.0)),
+        },
+    })
+}
+
+/// The flags of authenticator data made for `rp_id` with the user present, an error for any other
+fn check_authenticator_data(authenticator_data: &[u8], rp_id: &str) -> Result<u8, Error> {
+    if authenticator_data.len() < 37 {
+        return Err(Error::BadAuthenticatorData);
+    }
+    if authenticator_data[..32] != crypto::sha256(rp_id.as_bytes()) {
+        return Err(Error::RpIdHashMismatch(rp_id.to_string()));
+    }
+
+    let flags = authenticator_data[32];
+    if flags & AuthenticateResponse::AUTH_FLAG_UP == 0 {
+        return Err(Error::KeyFlagsNotSatisfied("presence"));
+    }
+    Ok(flags)
+}
+
+/// The public key the phone has for the credential of `rp_id`, from its list rather than from the assertion
+async fn registered_key(client: &Client, rp_id: &str, key_handle: &[u8]) -> Result<Vec<u8>, Error> {
+    let listed = client
+        .send_request_with_timeout::<ListKeysResponse>(
+            RequestBody::ListKeys(ListKeysRequest {}),
+            LIST_KEYS_TIMEOUT,
+        )
+        .await?;
+    listed
+        .sk_accounts
+        .into_iter()
+        .find(|account| account.rp_id == rp_id && account.key_handle.0 == key_handle)
+        .map(|account| account.public_key.0)
+        .ok_or_else(|| Error::UnknownCredential(rp_id.to_string()))
+}
+
+/// The host of a secure origin: https, or plain http on localhost and loopback addresses
+fn origin_host(origin: &str) -> Option<String> {
+    let url = url::Url::parse(origin).ok()?;
+    let (host, loopback) = match url.host()? {
+        url::Host::Domain(domain) => (domain.to_string(), domain == "localhost"),
+        url::Host::Ipv4(ip) => (ip.to_string(), ip.is_loopback()),
+        url::Host::Ipv6(ip) => (ip.to_string(), ip.is_loopback()),
+    };
+    match url.scheme() {
+        "https" => Some(host),
+        "http" if loopback => Some(host),
+        _ => None,
+    }
+}
+
+/// the origins of the config file, as `normalize_origin` makes them
+fn allowed_origins() -> Vec<String> {
+    config::get()
+        .webauthn_origins
+        .iter()
+        .flatten()
+        .map(|origin| normalize_origin(origin))
+        .collect()
+}
+
+/// "https://Example.com/" and "https://example.com" are the same origin
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_ascii_lowercase()
+}
+
+/// An origin may use its own host or a parent domain of it as the rpId, but not a public suffix
+fn rp_id_permitted(rp_id: &str, host: &str) -> bool {
+    let (rp_id, host) = (rp_id.to_ascii_lowercase(), host.to_ascii_lowercase());
+    // addresses have no parent domains
+    if host.parse::<IpAddr>().is_ok() {
+        return rp_id == host;
+    }
+    let within = host == rp_id || (host.ends_with(&rp_id) && host[..host.len() - rp_id.len()].ends_with('.'));
+    within && !is_public_suffix(&rp_id)
+}
+
+/// Whether anyone can register a name under `domain`, so e.g. every page on "x.github.io" can't assert for
+/// "github.io". Unlike a browser this doesn't have the whole public suffix list: top level domains, the
+/// `COUNTRY_SECOND_LEVELS` of two letter ones and the `HOSTING_SUFFIXES`
+fn is_public_suffix(domain: &str) -> bool {
+    match domain.split('.').collect::<Vec<_>>().as_slice() {
+        ["localhost"] => false,
+        [_] => true,
+        [second, top] if top.len() == 2 && COUNTRY_SECOND_LEVELS.contains(second) => true,
+        _ => HOSTING_SUFFIXES.contains(&domain),
+    }
+}
+
+fn error(message: impl ToString) -> String {
+    serde_json::json!({ "error": message.to_string() }).to_string()
+}
+
+fn reply(status: StatusCode, origin: Option<&str>, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    if let Some(origin) = origin.and_then(|origin| HeaderValue::from_str(origin).ok()) {
+        headers.insert("access-control-allow-origin", origin);
+        headers.insert("access-control-allow-methods", HeaderValue::from_static("POST"));
+        headers.insert(
+            "access-control-allow-headers",
+            HeaderValue::from_static("content-type"),
+        );
+        // Chrome's private network access preflight
+        headers.insert(
+            "access-control-allow-private-network",
+            HeaderValue::from_static("true"),
+        );
+    }
+    headers.insert("content-type", HeaderValue::from_static("application/json"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_parent_domains_but_not_public_suffixes() {
+        assert!(rp_id_permitted("example.com", "login.example.com"));
+        assert!(rp_id_permitted("example.co.uk", "www.example.co.uk"));
+        assert!(rp_id_permitted("localhost", "localhost"));
+        assert!(!rp_id_permitted("ample.com", "example.com"));
+        assert!(!rp_id_permitted("github.io", "x.github.io"));
+        assert!(!rp_id_permitted("co.uk", "example.co.uk"));
+        assert!(!rp_id_permitted("com", "example.com"));
+        assert!(rp_id_permitted("127.0.0.1", "127.0.0.1"));
+        assert!(!rp_id_permitted("0.0.1", "127.0.0.1"));
+    }
+
+    #[test]
+    fn normalizes_origins() {
+        assert_eq!(normalize_origin("https://Example.com/"), "https://example.com");
+        assert_eq!(
+            origin_host("https://example.com:8443").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(origin_host("http://[::1]:8080").as_deref(), Some("::1"));
+        assert_eq!(origin_host("http://localhost:8080").as_deref(), Some("localhost"));
+        assert_eq!(origin_host("http://example.com"), None);
+    }
+
+    #[test]
+    fn checks_the_rp_id_hash_and_user_presence() {
+        let mut authenticator_data = crypto::sha256(b"example.com").to_vec();
+        authenticator_data.push(AuthenticateResponse::AUTH_FLAG_UP);
+        authenticator_data.extend_from_slice(&1u32.to_be_bytes());
+        assert!(check_authenticator_data(&authenticator_data, "example.com").is_ok());
+        assert!(matches!(
+            check_authenticator_data(&authenticator_data, "evil.example"),
+            Err(Error::RpIdHashMismatch(_))
+        ));
+        assert!(check_authenticator_data(&authenticator_data[..36], "example.com").is_err());
+
+        authenticator_data[32] = 0;
+        assert!(matches!(
+            check_authenticator_data(&authenticator_data, "example.com"),
+            Err(Error::KeyFlagsNotSatisfied("presence"))
+        ));
+    }
+}
@@ -0,0 +1,5 @@
+$action = New-ScheduledTaskAction -Execute "{{bin_path}}" -Argument "start"
+$trigger = New-ScheduledTaskTrigger -AtLogOn -User "{{current_user}}"
+$settings = New-ScheduledTaskSettingsSet -AllowStartIfOnBatteries -DontStopIfGoingOnBatteries -ExecutionTimeLimit ([TimeSpan]::Zero) -RestartCount 3 -RestartInterval (New-TimeSpan -Minutes 1)
+Register-ScheduledTask -TaskName "{{task_name}}" -Description "{{description}}" -Action $action -Trigger $trigger -Settings $settings -Force | Out-Null
+Start-ScheduledTask -TaskName "{{task_name}}"
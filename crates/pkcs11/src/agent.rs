@@ -0,0 +1,152 @@
+//! The bits of the ssh agent protocol the module needs: listing the keys and signing with them
+//! https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent
+
+use std::io::{self, Read, Write};
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH2_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH2_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH2_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH2_AGENT_SIGN_RESPONSE: u8 = 14;
+
+pub const ECDSA_P256: &str = "ecdsa-sha2-nistp256";
+
+/// A P-256 key of the agent
+#[derive(Clone)]
+pub struct Key {
+    pub blob: Vec<u8>,
+    pub comment: String,
+    /// the uncompressed point, 0x04 || x || y
+    pub point: Vec<u8>,
+}
+
+#[cfg(unix)]
+fn connect() -> io::Result<std::os::unix::net::UnixStream> {
+    let path = match std::env::var_os("AKR_AGENT_SOCK") {
+        Some(path) => std::path::PathBuf::from(path),
+        None => std::path::PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+            .join(".akr")
+            .join("akr-ssh-agent.sock"),
+    };
+    std::os::unix::net::UnixStream::connect(path)
+}
+
+#[cfg(windows)]
+fn connect() -> io::Result<std::fs::File> {
+    let path = std::env::var("AKR_AGENT_SOCK").unwrap_or_else(|_| r"\\.\pipe\akr-ssh-agent".to_string());
+    std::fs::OpenOptions::new().read(true).write(true).open(path)
+}
+
+fn request(message: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = connect()?;
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(message)?;
+
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Reads the ssh wire format
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> io::Result<u32> {
+        let (value, rest) = self
+            .0
+            .split_at_checked(4)
+            .ok_or_else(|| invalid("truncated message"))?;
+        self.0 = rest;
+        Ok(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        let (value, rest) = self
+            .0
+            .split_at_checked(len)
+            .ok_or_else(|| invalid("truncated message"))?;
+        self.0 = rest;
+        Ok(value)
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend((value.len() as u32).to_be_bytes());
+    out.extend(value);
+}
+
+/// The P-256 keys the agent holds. Its sk keys don't sign plain ECDSA, the authenticator data is part of what
+/// they sign, so they are left out
+pub fn keys() -> io::Result<Vec<Key>> {
+    let response = request(&[SSH2_AGENTC_REQUEST_IDENTITIES])?;
+    let mut reader = match response.split_first() {
+        Some((&SSH2_AGENT_IDENTITIES_ANSWER, rest)) => Reader(rest),
+        _ => return Err(invalid("the agent didn't list its keys")),
+    };
+
+    let mut keys = vec![];
+    for _ in 0..reader.u32()? {
+        let blob = reader.string()?;
+        let comment = String::from_utf8_lossy(reader.string()?).to_string();
+        let mut key = Reader(blob);
+        if key.string()? != ECDSA_P256.as_bytes() || key.string()? != b"nistp256" {
+            continue;
+        }
+        keys.push(Key {
+            blob: blob.to_vec(),
+            comment,
+            point: key.string()?.to_vec(),
+        });
+    }
+    Ok(keys)
+}
+
+/// The agent's ecdsa-sha2-nistp256 signature of `data`, which it hashes with SHA-256, as r || s
+pub fn sign(key: &Key, data: &[u8]) -> io::Result<[u8; 64]> {
+    let mut message = vec![SSH2_AGENTC_SIGN_REQUEST];
+    write_string(&mut message, &key.blob);
+    write_string(&mut message, data);
+    message.extend(0u32.to_be_bytes());
+
+    let response = request(&message)?;
+    let mut reader = match response.split_first() {
+        Some((&SSH2_AGENT_SIGN_RESPONSE, rest)) => Reader(rest),
+        Some((&SSH_AGENT_FAILURE, _)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the agent refused to sign",
+            ))
+        }
+        _ => return Err(invalid("unexpected answer of the agent")),
+    };
+
+    /*
+       string    "ecdsa-sha2-nistp256"
+       string    ecdsa_signature_blob
+           mpint    r
+           mpint    s
+    */
+    let mut signature = Reader(reader.string()?);
+    if signature.string()? != ECDSA_P256.as_bytes() {
+        return Err(invalid("unexpected signature type"));
+    }
+    let mut blob = Reader(signature.string()?);
+    let mut raw = [0; 64];
+    for half in raw.chunks_mut(32) {
+        // mpints have a leading 0 when the high bit is set, and no leading zeros otherwise
+        let mpint = blob.string()?;
+        let mpint = &mpint[mpint.iter().take_while(|b| **b == 0).count()..];
+        if mpint.len() > 32 {
+            return Err(invalid("invalid signature"));
+        }
+        half[32 - mpint.len()..].copy_from_slice(mpint);
+    }
+    Ok(raw)
+}
@@ -0,0 +1,615 @@
+//! A PKCS#11 module for the keys of the akr agent, for tools that don't speak the ssh agent protocol
+//!
+//! It has a single slot with the agent's P-256 keys, each as a private and a public key object, and signs with
+//! CKM_ECDSA_SHA256 by asking the agent over its socket ($AKR_AGENT_SOCK, "~/.akr/akr-ssh-agent.sock" by default).
+//! The agent hashes what it signs, so CKM_ECDSA over a hash computed elsewhere isn't available. There is no PIN,
+//! the agent decides. Everything else of PKCS#11 answers CKR_FUNCTION_NOT_SUPPORTED.
+//! https://docs.oasis-open.org/pkcs11/pkcs11-base/v2.40/os/pkcs11-base-v2.40-os.html
+
+mod agent;
+mod types;
+
+use agent::Key;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::Mutex;
+use types::*;
+
+/// the only slot
+const SLOT_ID: CK_SLOT_ID = 0;
+const SIGNATURE_LEN: CK_ULONG = 64;
+/// DER of the OID of prime256v1
+const P256_PARAMS: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+struct Session {
+    /// the objects left of C_FindObjectsInit
+    found: Option<Vec<CK_OBJECT_HANDLE>>,
+    /// the key of C_SignInit
+    signing: Option<Key>,
+}
+
+#[derive(Default)]
+struct State {
+    /// as of the last C_FindObjectsInit, object handle 2i + 1 is the private key i, 2i + 2 its public key
+    keys: Vec<Key>,
+    sessions: HashMap<CK_SESSION_HANDLE, Session>,
+    next_session: CK_SESSION_HANDLE,
+}
+
+/// None until C_Initialize
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn with_state(f: impl FnOnce(&mut State) -> CK_RV) -> CK_RV {
+    match STATE.lock() {
+        Ok(mut state) => match state.as_mut() {
+            Some(state) => f(state),
+            None => CKR_CRYPTOKI_NOT_INITIALIZED,
+        },
+        Err(_) => CKR_GENERAL_ERROR,
+    }
+}
+
+fn with_session(session: CK_SESSION_HANDLE, f: impl FnOnce(&mut Session, &[Key]) -> CK_RV) -> CK_RV {
+    with_state(|state| match state.sessions.get_mut(&session) {
+        Some(found) => f(found, &state.keys),
+        None => CKR_SESSION_HANDLE_INVALID,
+    })
+}
+
+/// space padded, as PKCS#11 wants its strings
+fn padded<const N: usize>(s: &str) -> [u8; N] {
+    let mut out = [b' '; N];
+    let len = s.len().min(N);
+    out[..len].copy_from_slice(&s.as_bytes()[..len]);
+    out
+}
+
+fn ulong(value: CK_ULONG) -> Vec<u8> {
+    value.to_ne_bytes().to_vec()
+}
+
+/// The value of an attribute of an object, None when the object doesn't have it
+fn attribute(keys: &[Key], object: CK_OBJECT_HANDLE, kind: CK_ATTRIBUTE_TYPE) -> Option<Vec<u8>> {
+    let index = (object.checked_sub(1)? / 2) as usize;
+    let private = object % 2 == 1;
+    let key = keys.get(index)?;
+    let value = match kind {
+        CKA_CLASS => ulong(if private { CKO_PRIVATE_KEY } else { CKO_PUBLIC_KEY }),
+        CKA_TOKEN => vec![CK_TRUE],
+        CKA_PRIVATE | CKA_SENSITIVE | CKA_ALWAYS_SENSITIVE | CKA_NEVER_EXTRACTABLE => {
+            vec![private as CK_BBOOL]
+        }
+        CKA_EXTRACTABLE | CKA_MODIFIABLE | CKA_DECRYPT | CKA_ENCRYPT | CKA_DERIVE | CKA_WRAP | CKA_UNWRAP => {
+            vec![CK_FALSE]
+        }
+        CKA_SIGN if private => vec![CK_TRUE],
+        CKA_VERIFY if !private => vec![CK_TRUE],
+        CKA_LABEL => key.comment.as_bytes().to_vec(),
+        // the start of the x coordinate, the same for both objects of a key and across restarts
+        CKA_ID => key.point.get(1..17)?.to_vec(),
+        CKA_KEY_TYPE => ulong(CKK_EC),
+        CKA_EC_PARAMS => P256_PARAMS.to_vec(),
+        // DER OCTET STRING of the point
+        CKA_EC_POINT if !private => [&[0x04, key.point.len() as u8][..], &key.point].concat(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+unsafe extern "C" fn c_initialize(_args: *mut c_void) -> CK_RV {
+    match STATE.lock() {
+        Ok(state) if state.is_some() => CKR_CRYPTOKI_ALREADY_INITIALIZED,
+        Ok(mut state) => {
+            *state = Some(State {
+                next_session: 1,
+                ..State::default()
+            });
+            CKR_OK
+        }
+        Err(_) => CKR_GENERAL_ERROR,
+    }
+}
+
+unsafe extern "C" fn c_finalize(_reserved: *mut c_void) -> CK_RV {
+    match STATE.lock() {
+        Ok(mut state) => match state.take() {
+            Some(_) => CKR_OK,
+            None => CKR_CRYPTOKI_NOT_INITIALIZED,
+        },
+        Err(_) => CKR_GENERAL_ERROR,
+    }
+}
+
+unsafe extern "C" fn c_get_info(info: *mut CK_INFO) -> CK_RV {
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    *info = CK_INFO {
+        cryptoki_version: CK_VERSION { major: 2, minor: 40 },
+        manufacturer_id: padded("akr"),
+        flags: 0,
+        library_description: padded("akr agent keys"),
+        library_version: CK_VERSION { major: 0, minor: 1 },
+    };
+    CKR_OK
+}
+
+unsafe extern "C" fn c_get_slot_list(
+    _token_present: CK_BBOOL,
+    slots: *mut CK_SLOT_ID,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    if count.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_state(|_| {
+        if !slots.is_null() {
+            if *count < 1 {
+                *count = 1;
+                return CKR_BUFFER_TOO_SMALL;
+            }
+            *slots = SLOT_ID;
+        }
+        *count = 1;
+        CKR_OK
+    })
+}
+
+unsafe extern "C" fn c_get_slot_info(slot: CK_SLOT_ID, info: *mut CK_SLOT_INFO) -> CK_RV {
+    if slot != SLOT_ID {
+        return CKR_SLOT_ID_INVALID;
+    }
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    *info = CK_SLOT_INFO {
+        slot_description: padded("akr agent"),
+        manufacturer_id: padded("akr"),
+        flags: CKF_TOKEN_PRESENT,
+        hardware_version: CK_VERSION { major: 0, minor: 1 },
+        firmware_version: CK_VERSION { major: 0, minor: 1 },
+    };
+    CKR_OK
+}
+
+unsafe extern "C" fn c_get_token_info(slot: CK_SLOT_ID, info: *mut CK_TOKEN_INFO) -> CK_RV {
+    if slot != SLOT_ID {
+        return CKR_SLOT_ID_INVALID;
+    }
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    *info = CK_TOKEN_INFO {
+        label: padded("akr"),
+        manufacturer_id: padded("akr"),
+        model: padded("ssh agent"),
+        serial_number: padded("0"),
+        flags: CKF_TOKEN_INITIALIZED | CKF_WRITE_PROTECTED,
+        max_session_count: CK_EFFECTIVELY_INFINITE,
+        session_count: CK_UNAVAILABLE_INFORMATION,
+        max_rw_session_count: 0,
+        rw_session_count: 0,
+        max_pin_len: 0,
+        min_pin_len: 0,
+        total_public_memory: CK_UNAVAILABLE_INFORMATION,
+        free_public_memory: CK_UNAVAILABLE_INFORMATION,
+        total_private_memory: CK_UNAVAILABLE_INFORMATION,
+        free_private_memory: CK_UNAVAILABLE_INFORMATION,
+        hardware_version: CK_VERSION { major: 0, minor: 1 },
+        firmware_version: CK_VERSION { major: 0, minor: 1 },
+        utc_time: padded(""),
+    };
+    CKR_OK
+}
+
+unsafe extern "C" fn c_get_mechanism_list(
+    slot: CK_SLOT_ID,
+    mechanisms: *mut CK_MECHANISM_TYPE,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    if slot != SLOT_ID {
+        return CKR_SLOT_ID_INVALID;
+    }
+    if count.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    if !mechanisms.is_null() {
+        if *count < 1 {
+            *count = 1;
+            return CKR_BUFFER_TOO_SMALL;
+        }
+        *mechanisms = CKM_ECDSA_SHA256;
+    }
+    *count = 1;
+    CKR_OK
+}
+
+unsafe extern "C" fn c_get_mechanism_info(
+    slot: CK_SLOT_ID,
+    mechanism: CK_MECHANISM_TYPE,
+    info: *mut CK_MECHANISM_INFO,
+) -> CK_RV {
+    if slot != SLOT_ID {
+        return CKR_SLOT_ID_INVALID;
+    }
+    if mechanism != CKM_ECDSA_SHA256 {
+        return CKR_MECHANISM_INVALID;
+    }
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    *info = CK_MECHANISM_INFO {
+        min_key_size: 256,
+        max_key_size: 256,
+        flags: CKF_SIGN | CKF_EC_F_P | CKF_EC_UNCOMPRESS,
+    };
+    CKR_OK
+}
+
+unsafe extern "C" fn c_open_session(
+    slot: CK_SLOT_ID,
+    flags: CK_FLAGS,
+    _application: *mut c_void,
+    _notify: *mut c_void,
+    session: *mut CK_SESSION_HANDLE,
+) -> CK_RV {
+    if slot != SLOT_ID {
+        return CKR_SLOT_ID_INVALID;
+    }
+    if flags & CKF_SERIAL_SESSION == 0 {
+        return CKR_SESSION_PARALLEL_NOT_SUPPORTED;
+    }
+    if flags & CKF_RW_SESSION != 0 {
+        return CKR_TOKEN_WRITE_PROTECTED;
+    }
+    if session.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_state(|state| {
+        let handle = state.next_session;
+        state.next_session += 1;
+        state.sessions.insert(
+            handle,
+            Session {
+                found: None,
+                signing: None,
+            },
+        );
+        *session = handle;
+        CKR_OK
+    })
+}
+
+unsafe extern "C" fn c_close_session(session: CK_SESSION_HANDLE) -> CK_RV {
+    with_state(|state| match state.sessions.remove(&session) {
+        Some(_) => CKR_OK,
+        None => CKR_SESSION_HANDLE_INVALID,
+    })
+}
+
+unsafe extern "C" fn c_close_all_sessions(slot: CK_SLOT_ID) -> CK_RV {
+    if slot != SLOT_ID {
+        return CKR_SLOT_ID_INVALID;
+    }
+    with_state(|state| {
+        state.sessions.clear();
+        CKR_OK
+    })
+}
+
+unsafe extern "C" fn c_get_session_info(session: CK_SESSION_HANDLE, info: *mut CK_SESSION_INFO) -> CK_RV {
+    if info.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_session(session, |_, _| {
+        *info = CK_SESSION_INFO {
+            slot_id: SLOT_ID,
+            state: CKS_RO_USER_FUNCTIONS,
+            flags: CKF_SERIAL_SESSION,
+            device_error: 0,
+        };
+        CKR_OK
+    })
+}
+
+/// there is no PIN, the agent decides
+unsafe extern "C" fn c_login(
+    session: CK_SESSION_HANDLE,
+    _user: CK_ULONG,
+    _pin: *mut u8,
+    _pin_len: CK_ULONG,
+) -> CK_RV {
+    with_session(session, |_, _| CKR_USER_ALREADY_LOGGED_IN)
+}
+
+unsafe extern "C" fn c_logout(session: CK_SESSION_HANDLE) -> CK_RV {
+    with_session(session, |_, _| CKR_OK)
+}
+
+unsafe extern "C" fn c_get_attribute_value(
+    session: CK_SESSION_HANDLE,
+    object: CK_OBJECT_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+) -> CK_RV {
+    if template.is_null() && count > 0 {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_session(session, |_, keys| {
+        if attribute(keys, object, CKA_CLASS).is_none() {
+            return CKR_OBJECT_HANDLE_INVALID;
+        }
+        let mut result = CKR_OK;
+        for i in 0..count as usize {
+            let requested = &mut *template.add(i);
+            match attribute(keys, object, requested.kind) {
+                None => {
+                    requested.value_len = CK_UNAVAILABLE_INFORMATION;
+                    result = CKR_ATTRIBUTE_TYPE_INVALID;
+                }
+                Some(value) if requested.value.is_null() => requested.value_len = value.len() as CK_ULONG,
+                Some(value) if (requested.value_len as usize) < value.len() => {
+                    requested.value_len = CK_UNAVAILABLE_INFORMATION;
+                    result = CKR_BUFFER_TOO_SMALL;
+                }
+                Some(value) => {
+                    std::ptr::copy_nonoverlapping(value.as_ptr(), requested.value as *mut u8, value.len());
+                    requested.value_len = value.len() as CK_ULONG;
+                }
+            }
+        }
+        result
+    })
+}
+
+unsafe extern "C" fn c_find_objects_init(
+    session: CK_SESSION_HANDLE,
+    template: *mut CK_ATTRIBUTE,
+    count: CK_ULONG,
+) -> CK_RV {
+    if template.is_null() && count > 0 {
+        return CKR_ARGUMENTS_BAD;
+    }
+    let template: Vec<(CK_ATTRIBUTE_TYPE, &[u8])> = (0..count as usize)
+        .map(|i| {
+            let attribute = &*template.add(i);
+            let value = match attribute.value.is_null() {
+                true => &[][..],
+                false => {
+                    std::slice::from_raw_parts(attribute.value as *const u8, attribute.value_len as usize)
+                }
+            };
+            (attribute.kind, value)
+        })
+        .collect();
+
+    // the agent's keys may have changed since the last search
+    let keys = match agent::keys() {
+        Ok(keys) => keys,
+        Err(_) => return CKR_DEVICE_ERROR,
+    };
+    with_state(|state| {
+        let Some(session) = state.sessions.get_mut(&session) else {
+            return CKR_SESSION_HANDLE_INVALID;
+        };
+        if session.found.is_some() {
+            return CKR_OPERATION_ACTIVE;
+        }
+        let objects = (1..=2 * keys.len() as CK_OBJECT_HANDLE)
+            .filter(|object| {
+                template
+                    .iter()
+                    .all(|(kind, value)| attribute(&keys, *object, *kind).as_deref() == Some(*value))
+            })
+            .collect();
+        session.found = Some(objects);
+        state.keys = keys;
+        CKR_OK
+    })
+}
+
+unsafe extern "C" fn c_find_objects(
+    session: CK_SESSION_HANDLE,
+    objects: *mut CK_OBJECT_HANDLE,
+    max_count: CK_ULONG,
+    count: *mut CK_ULONG,
+) -> CK_RV {
+    if objects.is_null() || count.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    with_session(session, |session, _| {
+        let Some(found) = session.found.as_mut() else {
+            return CKR_OPERATION_NOT_INITIALIZED;
+        };
+        let taken: Vec<_> = found.drain(..found.len().min(max_count as usize)).collect();
+        std::ptr::copy_nonoverlapping(taken.as_ptr(), objects, taken.len());
+        *count = taken.len() as CK_ULONG;
+        CKR_OK
+    })
+}
+
+unsafe extern "C" fn c_find_objects_final(session: CK_SESSION_HANDLE) -> CK_RV {
+    with_session(session, |session, _| match session.found.take() {
+        Some(_) => CKR_OK,
+        None => CKR_OPERATION_NOT_INITIALIZED,
+    })
+}
+
+unsafe extern "C" fn c_sign_init(
+    session: CK_SESSION_HANDLE,
+    mechanism: *mut CK_MECHANISM,
+    key: CK_OBJECT_HANDLE,
+) -> CK_RV {
+    if mechanism.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    if (*mechanism).mechanism != CKM_ECDSA_SHA256 {
+        return CKR_MECHANISM_INVALID;
+    }
+    with_session(session, |session, keys| {
+        if session.signing.is_some() {
+            return CKR_OPERATION_ACTIVE;
+        }
+        match attribute(keys, key, CKA_SIGN) {
+            Some(_) => {
+                session.signing = Some(keys[(key as usize - 1) / 2].clone());
+                CKR_OK
+            }
+            None if attribute(keys, key, CKA_CLASS).is_some() => CKR_KEY_TYPE_INCONSISTENT,
+            None => CKR_KEY_HANDLE_INVALID,
+        }
+    })
+}
+
+unsafe extern "C" fn c_sign(
+    session: CK_SESSION_HANDLE,
+    data: *mut u8,
+    data_len: CK_ULONG,
+    signature: *mut u8,
+    signature_len: *mut CK_ULONG,
+) -> CK_RV {
+    if (data.is_null() && data_len > 0) || signature_len.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    // asking for the length, or with too little space, keeps the operation going
+    let mut key = None;
+    let rv = with_session(session, |session, _| {
+        let Some(signing) = session.signing.as_ref() else {
+            return CKR_OPERATION_NOT_INITIALIZED;
+        };
+        if signature.is_null() {
+            *signature_len = SIGNATURE_LEN;
+            return CKR_OK;
+        }
+        if *signature_len < SIGNATURE_LEN {
+            *signature_len = SIGNATURE_LEN;
+            return CKR_BUFFER_TOO_SMALL;
+        }
+        key = Some(signing.clone());
+        session.signing = None;
+        CKR_OK
+    });
+    let Some(key) = key else {
+        return rv;
+    };
+
+    // not holding the lock while the agent signs
+    let data = match data.is_null() {
+        true => &[][..],
+        false => std::slice::from_raw_parts(data, data_len as usize),
+    };
+    match agent::sign(&key, data) {
+        Ok(raw) => {
+            std::ptr::copy_nonoverlapping(raw.as_ptr(), signature, raw.len());
+            *signature_len = SIGNATURE_LEN;
+            CKR_OK
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => CKR_FUNCTION_REJECTED,
+        Err(_) => CKR_DEVICE_ERROR,
+    }
+}
+
+/// The entry point, the other functions are only reachable through the list
+///
+/// # Safety
+/// `list` must be null or point to writable memory for a pointer
+#[no_mangle]
+#[allow(non_snake_case)]
+pub unsafe extern "C" fn C_GetFunctionList(list: *mut *const CK_FUNCTION_LIST) -> CK_RV {
+    if list.is_null() {
+        return CKR_ARGUMENTS_BAD;
+    }
+    *list = &FUNCTION_LIST;
+    CKR_OK
+}
+
+/// the C conventions leave the arguments to the caller, so one function can stand in for all the others
+unsafe extern "C" fn unsupported() -> CK_RV {
+    CKR_FUNCTION_NOT_SUPPORTED
+}
+
+/// every entry as the `Function` it's stored as
+macro_rules! function {
+    ($f:ident($($arg:ty),*)) => {
+        std::mem::transmute::<unsafe extern "C" fn($($arg),*) -> CK_RV, Function>($f)
+    };
+}
+
+/// in the order of the PKCS#11 headers
+static FUNCTION_LIST: CK_FUNCTION_LIST = CK_FUNCTION_LIST {
+    version: CK_VERSION { major: 2, minor: 40 },
+    functions: unsafe {
+        [
+            function!(c_initialize(*mut c_void)),
+            function!(c_finalize(*mut c_void)),
+            function!(c_get_info(*mut CK_INFO)),
+            function!(C_GetFunctionList(*mut *const CK_FUNCTION_LIST)),
+            function!(c_get_slot_list(CK_BBOOL, *mut CK_SLOT_ID, *mut CK_ULONG)),
+            function!(c_get_slot_info(CK_SLOT_ID, *mut CK_SLOT_INFO)),
+            function!(c_get_token_info(CK_SLOT_ID, *mut CK_TOKEN_INFO)),
+            function!(c_get_mechanism_list(CK_SLOT_ID, *mut CK_MECHANISM_TYPE, *mut CK_ULONG)),
+            function!(c_get_mechanism_info(CK_SLOT_ID, CK_MECHANISM_TYPE, *mut CK_MECHANISM_INFO)),
+            unsupported, // C_InitToken
+            unsupported, // C_InitPIN
+            unsupported, // C_SetPIN
+            function!(c_open_session(CK_SLOT_ID, CK_FLAGS, *mut c_void, *mut c_void, *mut CK_SESSION_HANDLE)),
+            function!(c_close_session(CK_SESSION_HANDLE)),
+            function!(c_close_all_sessions(CK_SLOT_ID)),
+            function!(c_get_session_info(CK_SESSION_HANDLE, *mut CK_SESSION_INFO)),
+            unsupported, // C_GetOperationState
+            unsupported, // C_SetOperationState
+            function!(c_login(CK_SESSION_HANDLE, CK_ULONG, *mut u8, CK_ULONG)),
+            function!(c_logout(CK_SESSION_HANDLE)),
+            unsupported, // C_CreateObject
+            unsupported, // C_CopyObject
+            unsupported, // C_DestroyObject
+            unsupported, // C_GetObjectSize
+            function!(
+                c_get_attribute_value(CK_SESSION_HANDLE, CK_OBJECT_HANDLE, *mut CK_ATTRIBUTE, CK_ULONG)
+            ),
+            unsupported, // C_SetAttributeValue
+            function!(c_find_objects_init(CK_SESSION_HANDLE, *mut CK_ATTRIBUTE, CK_ULONG)),
+            function!(c_find_objects(CK_SESSION_HANDLE, *mut CK_OBJECT_HANDLE, CK_ULONG, *mut CK_ULONG)),
+            function!(c_find_objects_final(CK_SESSION_HANDLE)),
+            unsupported, // C_EncryptInit
+            unsupported, // C_Encrypt
+            unsupported, // C_EncryptUpdate
+            unsupported, // C_EncryptFinal
+            unsupported, // C_DecryptInit
+            unsupported, // C_Decrypt
+            unsupported, // C_DecryptUpdate
+            unsupported, // C_DecryptFinal
+            unsupported, // C_DigestInit
+            unsupported, // C_Digest
+            unsupported, // C_DigestUpdate
+            unsupported, // C_DigestKey
+            unsupported, // C_DigestFinal
+            function!(c_sign_init(CK_SESSION_HANDLE, *mut CK_MECHANISM, CK_OBJECT_HANDLE)),
+            function!(c_sign(CK_SESSION_HANDLE, *mut u8, CK_ULONG, *mut u8, *mut CK_ULONG)),
+            unsupported, // C_SignUpdate
+            unsupported, // C_SignFinal
+            unsupported, // C_SignRecoverInit
+            unsupported, // C_SignRecover
+            unsupported, // C_VerifyInit
+            unsupported, // C_Verify
+            unsupported, // C_VerifyUpdate
+            unsupported, // C_VerifyFinal
+            unsupported, // C_VerifyRecoverInit
+            unsupported, // C_VerifyRecover
+            unsupported, // C_DigestEncryptUpdate
+            unsupported, // C_DecryptDigestUpdate
+            unsupported, // C_SignEncryptUpdate
+            unsupported, // C_DecryptVerifyUpdate
+            unsupported, // C_GenerateKey
+            unsupported, // C_GenerateKeyPair
+            unsupported, // C_WrapKey
+            unsupported, // C_UnwrapKey
+            unsupported, // C_DeriveKey
+            unsupported, // C_SeedRandom
+            unsupported, // C_GenerateRandom
+            unsupported, // C_GetFunctionStatus
+            unsupported, // C_CancelFunction
+            unsupported, // C_WaitForSlotEvent
+        ]
+    },
+};
@@ -0,0 +1,174 @@
+//! The types and constants of the PKCS#11 headers the module uses, named as there
+//!
+//! CK_ULONG is C's unsigned long, and the structs are packed on Windows.
+
+#![allow(non_camel_case_types)]
+
+use std::ffi::c_void;
+
+pub type CK_ULONG = std::os::raw::c_ulong;
+pub type CK_RV = CK_ULONG;
+pub type CK_BBOOL = u8;
+pub type CK_FLAGS = CK_ULONG;
+pub type CK_SLOT_ID = CK_ULONG;
+pub type CK_SESSION_HANDLE = CK_ULONG;
+pub type CK_OBJECT_HANDLE = CK_ULONG;
+pub type CK_ATTRIBUTE_TYPE = CK_ULONG;
+pub type CK_MECHANISM_TYPE = CK_ULONG;
+/// how each entry of CK_FUNCTION_LIST is stored, whatever its arguments
+pub type Function = unsafe extern "C" fn() -> CK_RV;
+
+pub const CK_TRUE: CK_BBOOL = 1;
+pub const CK_FALSE: CK_BBOOL = 0;
+pub const CK_UNAVAILABLE_INFORMATION: CK_ULONG = !0;
+pub const CK_EFFECTIVELY_INFINITE: CK_ULONG = 0;
+
+pub const CKR_OK: CK_RV = 0x0;
+pub const CKR_SLOT_ID_INVALID: CK_RV = 0x3;
+pub const CKR_GENERAL_ERROR: CK_RV = 0x5;
+pub const CKR_ARGUMENTS_BAD: CK_RV = 0x7;
+pub const CKR_ATTRIBUTE_TYPE_INVALID: CK_RV = 0x12;
+pub const CKR_DEVICE_ERROR: CK_RV = 0x30;
+pub const CKR_FUNCTION_NOT_SUPPORTED: CK_RV = 0x54;
+pub const CKR_KEY_HANDLE_INVALID: CK_RV = 0x60;
+pub const CKR_KEY_TYPE_INCONSISTENT: CK_RV = 0x63;
+pub const CKR_MECHANISM_INVALID: CK_RV = 0x70;
+pub const CKR_OBJECT_HANDLE_INVALID: CK_RV = 0x82;
+pub const CKR_OPERATION_ACTIVE: CK_RV = 0x90;
+pub const CKR_OPERATION_NOT_INITIALIZED: CK_RV = 0x91;
+pub const CKR_SESSION_HANDLE_INVALID: CK_RV = 0xb3;
+pub const CKR_SESSION_PARALLEL_NOT_SUPPORTED: CK_RV = 0xb4;
+pub const CKR_TOKEN_WRITE_PROTECTED: CK_RV = 0xe2;
+pub const CKR_USER_ALREADY_LOGGED_IN: CK_RV = 0x100;
+pub const CKR_BUFFER_TOO_SMALL: CK_RV = 0x150;
+pub const CKR_CRYPTOKI_NOT_INITIALIZED: CK_RV = 0x190;
+pub const CKR_CRYPTOKI_ALREADY_INITIALIZED: CK_RV = 0x191;
+pub const CKR_FUNCTION_REJECTED: CK_RV = 0x200;
+
+pub const CKF_TOKEN_PRESENT: CK_FLAGS = 0x1;
+pub const CKF_WRITE_PROTECTED: CK_FLAGS = 0x2;
+pub const CKF_TOKEN_INITIALIZED: CK_FLAGS = 0x400;
+pub const CKF_RW_SESSION: CK_FLAGS = 0x2;
+pub const CKF_SERIAL_SESSION: CK_FLAGS = 0x4;
+pub const CKF_SIGN: CK_FLAGS = 0x800;
+pub const CKF_EC_F_P: CK_FLAGS = 0x100000;
+pub const CKF_EC_UNCOMPRESS: CK_FLAGS = 0x1000000;
+
+pub const CKS_RO_USER_FUNCTIONS: CK_ULONG = 1;
+pub const CKO_PUBLIC_KEY: CK_ULONG = 2;
+pub const CKO_PRIVATE_KEY: CK_ULONG = 3;
+pub const CKK_EC: CK_ULONG = 3;
+pub const CKM_ECDSA_SHA256: CK_MECHANISM_TYPE = 0x1044;
+
+pub const CKA_CLASS: CK_ATTRIBUTE_TYPE = 0x0;
+pub const CKA_TOKEN: CK_ATTRIBUTE_TYPE = 0x1;
+pub const CKA_PRIVATE: CK_ATTRIBUTE_TYPE = 0x2;
+pub const CKA_LABEL: CK_ATTRIBUTE_TYPE = 0x3;
+pub const CKA_KEY_TYPE: CK_ATTRIBUTE_TYPE = 0x100;
+pub const CKA_ID: CK_ATTRIBUTE_TYPE = 0x102;
+pub const CKA_SENSITIVE: CK_ATTRIBUTE_TYPE = 0x103;
+pub const CKA_ENCRYPT: CK_ATTRIBUTE_TYPE = 0x104;
+pub const CKA_DECRYPT: CK_ATTRIBUTE_TYPE = 0x105;
+pub const CKA_WRAP: CK_ATTRIBUTE_TYPE = 0x106;
+pub const CKA_UNWRAP: CK_ATTRIBUTE_TYPE = 0x107;
+pub const CKA_SIGN: CK_ATTRIBUTE_TYPE = 0x108;
+pub const CKA_VERIFY: CK_ATTRIBUTE_TYPE = 0x10a;
+pub const CKA_DERIVE: CK_ATTRIBUTE_TYPE = 0x10c;
+pub const CKA_EXTRACTABLE: CK_ATTRIBUTE_TYPE = 0x162;
+pub const CKA_NEVER_EXTRACTABLE: CK_ATTRIBUTE_TYPE = 0x164;
+pub const CKA_ALWAYS_SENSITIVE: CK_ATTRIBUTE_TYPE = 0x165;
+pub const CKA_MODIFIABLE: CK_ATTRIBUTE_TYPE = 0x170;
+pub const CKA_EC_PARAMS: CK_ATTRIBUTE_TYPE = 0x180;
+pub const CKA_EC_POINT: CK_ATTRIBUTE_TYPE = 0x181;
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+#[derive(Clone, Copy)]
+pub struct CK_VERSION {
+    pub major: u8,
+    pub minor: u8,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_INFO {
+    pub cryptoki_version: CK_VERSION,
+    pub manufacturer_id: [u8; 32],
+    pub flags: CK_FLAGS,
+    pub library_description: [u8; 32],
+    pub library_version: CK_VERSION,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_SLOT_INFO {
+    pub slot_description: [u8; 64],
+    pub manufacturer_id: [u8; 32],
+    pub flags: CK_FLAGS,
+    pub hardware_version: CK_VERSION,
+    pub firmware_version: CK_VERSION,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_TOKEN_INFO {
+    pub label: [u8; 32],
+    pub manufacturer_id: [u8; 32],
+    pub model: [u8; 16],
+    pub serial_number: [u8; 16],
+    pub flags: CK_FLAGS,
+    pub max_session_count: CK_ULONG,
+    pub session_count: CK_ULONG,
+    pub max_rw_session_count: CK_ULONG,
+    pub rw_session_count: CK_ULONG,
+    pub max_pin_len: CK_ULONG,
+    pub min_pin_len: CK_ULONG,
+    pub total_public_memory: CK_ULONG,
+    pub free_public_memory: CK_ULONG,
+    pub total_private_memory: CK_ULONG,
+    pub free_private_memory: CK_ULONG,
+    pub hardware_version: CK_VERSION,
+    pub firmware_version: CK_VERSION,
+    pub utc_time: [u8; 16],
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_SESSION_INFO {
+    pub slot_id: CK_SLOT_ID,
+    pub state: CK_ULONG,
+    pub flags: CK_FLAGS,
+    pub device_error: CK_ULONG,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_MECHANISM {
+    pub mechanism: CK_MECHANISM_TYPE,
+    pub parameter: *mut c_void,
+    pub parameter_len: CK_ULONG,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_MECHANISM_INFO {
+    pub min_key_size: CK_ULONG,
+    pub max_key_size: CK_ULONG,
+    pub flags: CK_FLAGS,
+}
+
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_ATTRIBUTE {
+    pub kind: CK_ATTRIBUTE_TYPE,
+    pub value: *mut c_void,
+    pub value_len: CK_ULONG,
+}
+
+/// the version, then the 68 functions of PKCS#11 2.40
+#[cfg_attr(windows, repr(C, packed))]
+#[cfg_attr(not(windows), repr(C))]
+pub struct CK_FUNCTION_LIST {
+    pub version: CK_VERSION,
+    pub functions: [Function; 68],
+}
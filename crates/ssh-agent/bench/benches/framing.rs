@@ -0,0 +1,101 @@
+//! Reading and writing agent messages that carry certificates, the largest ones ssh sends and gets back.
+//! `Request::read` allocates a message per request, the agent loop reads each connection through a
+//! `BufReader` into the one buffer with `Request::read_reusing`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ssh_agent::{Identity, Request, Response};
+use tokio::io::BufReader;
+use tokio::runtime::Runtime;
+
+/// the requests on a connection, e.g. a git push and some scp after it
+const REQUESTS: usize = 64;
+/// certificates with a few principals and extensions, up to ones with long lists of them
+const BLOB_LENS: &[usize] = &[1024, 16 * 1024, 128 * 1024];
+
+/// `REQUESTS` sign requests for a certificate of `blob_len` bytes, framed as on the socket
+fn sign_requests(blob_len: usize) -> Vec<u8> {
+    let request = Request::SignRequest {
+        pubkey_blob: vec![7; blob_len],
+        data: vec![9; 256],
+        flags: 0,
+    };
+    let message = request.encode().expect("a request of a known type");
+
+    let mut stream = Vec::with_capacity(REQUESTS * (4 + message.len()));
+    for _ in 0..REQUESTS {
+        stream.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        stream.extend_from_slice(&message);
+    }
+    stream
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_current_thread().build().unwrap()
+}
+
+fn read(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("read");
+    for &blob_len in BLOB_LENS {
+        let stream = sign_requests(blob_len);
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("fresh buffer", blob_len),
+            &stream,
+            |b, stream| {
+                b.to_async(&runtime).iter(|| async move {
+                    let mut stream = stream.as_slice();
+                    for _ in 0..REQUESTS {
+                        Request::read(&mut stream).await.unwrap();
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("reused buffer", blob_len),
+            &stream,
+            |b, stream| {
+                b.to_async(&runtime).iter(|| async move {
+                    let mut stream = BufReader::new(stream.as_slice());
+                    let mut buf = Vec::new();
+                    for _ in 0..REQUESTS {
+                        Request::read_reusing(&mut stream, &mut buf).await.unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn write(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("write");
+    for &blob_len in BLOB_LENS {
+        // a key and its certificate, for a few keys
+        let identities = (0..8)
+            .map(|i| Identity {
+                key_blob: vec![i; blob_len],
+                key_comment: format!("ssh:key-{}", i),
+            })
+            .collect();
+        let response = Response::Identities(identities);
+        group.throughput(Throughput::Bytes(response.encode().unwrap().len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("identities", blob_len),
+            &response,
+            |b, response| {
+                b.to_async(&runtime).iter(|| async move {
+                    let mut socket = tokio::io::sink();
+                    response.write(&mut socket).await.unwrap();
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, read, write);
+criterion_main!(benches);
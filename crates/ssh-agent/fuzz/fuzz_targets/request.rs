@@ -0,0 +1,16 @@
+//! Parse anything as the body of an agent message, with a length in front that matches it
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ssh_agent::Request;
+use std::sync::OnceLock;
+use tokio::runtime::Runtime;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fuzz_target!(|body: &[u8]| {
+    let runtime = RUNTIME.get_or_init(|| tokio::runtime::Builder::new_current_thread().build().unwrap());
+    let mut message = (body.len() as u32).to_be_bytes().to_vec();
+    message.extend_from_slice(body);
+    let _ = runtime.block_on(Request::read(&mut message.as_slice()));
+});
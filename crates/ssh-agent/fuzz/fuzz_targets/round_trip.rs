@@ -0,0 +1,16 @@
+//! Parse anything as an agent message, what parses has to encode and parse again to the same message
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ssh_agent::Request;
+
+fuzz_target!(|message: &[u8]| {
+    let Ok(request) = Request::parse(message) else {
+        return;
+    };
+    let Some(encoded) = request.encode() else {
+        return;
+    };
+    let again = Request::parse(&encoded).unwrap();
+    assert_eq!(again.encode().unwrap(), encoded);
+});
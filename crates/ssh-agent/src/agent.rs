@@ -1,40 +1,203 @@
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
 use tokio::sync::Mutex;
 
-use tokio::net::{UnixListener, UnixStream};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::ServerOptions;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 
 use crate::error::HandleResult;
-use crate::handler::SSHAgentHandler;
-use crate::protocol::Request;
+use crate::handler::{ConnectionId, PeerCredentials, Reply, SSHAgentHandler};
+use crate::protocol::{self, Request, Response};
+use crate::shutdown::Shutdown;
+
+/// How long to wait before accepting again after it failed
+#[cfg(unix)]
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
 pub struct Agent;
 
 impl Agent {
-    async fn handle_client<T: SSHAgentHandler>(
+    async fn handle_client<T: SSHAgentHandler, S: AsyncRead + AsyncWrite + Unpin>(
         handler: Arc<Mutex<T>>,
-        mut stream: UnixStream,
+        stream: S,
+        peer: Option<PeerCredentials>,
+        connection: ConnectionId,
+        shutdown: &Shutdown,
     ) -> HandleResult<()> {
         debug!("handling new connection");
+        debug!("peer: {:?}", peer);
+        if !handler.lock().await.connection_opened(connection, peer).await {
+            debug!("connection refused");
+            return Ok(());
+        }
+
+        // the length and the message come in one read, into the same buffer for every request
+        let mut stream = BufReader::new(stream);
+        let mut buf = Vec::new();
         loop {
-            let req = Request::read(&mut stream).await?;
+            // only a hang up or a broken length ends the connection, the next message can't be found then
+            protocol::read_message_into(&mut stream, &mut buf).await?;
+            let req = match Request::parse(&buf) {
+                Ok(req) => req,
+                Err(e) => {
+                    debug!("malformed request: {:?}", e);
+                    Response::Failure.write(&mut stream).await?;
+                    continue;
+                }
+            };
             debug!("request: {:?}", req);
 
-            let response = handler.lock().await.handle_request(req).await?;
+            let Some(_answering) = shutdown.answer().await else {
+                debug!("shutting down, hanging up");
+                return Ok(());
+            };
+            let reply = handler.lock().await.handle_request(connection, req).await;
+            // the handler is unlocked while a pending response is awaited
+            let response = match reply {
+                Ok(Reply::Now(response)) => Ok(response),
+                Ok(Reply::Later(pending)) => pending.await,
+                Err(e) => Err(e),
+            };
+            // ssh says "agent refused operation" instead of retrying on a closed socket
+            let response = response.unwrap_or_else(|e| {
+                warn!("handler error: {}", e.details);
+                Response::Failure
+            });
 
             debug!("handler: {:?}", response);
             response.write(&mut stream).await?;
         }
     }
 
-    pub async fn run<T: SSHAgentHandler + 'static>(handler: T, listener: UnixListener) {
+    /// serve a connection on its own task
+    fn spawn_client<T, S>(
+        handler: Arc<Mutex<T>>,
+        stream: S,
+        peer: Option<PeerCredentials>,
+        connection: ConnectionId,
+        shutdown: Arc<Shutdown>,
+    ) where
+        T: SSHAgentHandler + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            match Agent::handle_client(handler.clone(), stream, peer, connection, &shutdown).await {
+                Ok(_) => {}
+                Err(e) => debug!("handler: {:?}", e),
+            };
+            handler.lock().await.connection_closed(connection).await;
+        });
+    }
+
+    /// Serve one connection from this process over an in-memory pipe and hand back its other end,
+    /// for a `Client` to speak to the handler without a socket
+    pub fn connect_in_process<T: SSHAgentHandler + 'static>(
+        handler: Arc<Mutex<T>>,
+        connection: ConnectionId,
+    ) -> tokio::io::DuplexStream {
+        let (client, server) = tokio::io::duplex(crate::protocol::MAX_MESSAGE_LEN);
+        Agent::spawn_client(handler, server, None, connection, Arc::default());
+        client
+    }
+
+    /// Serve the connections of `listener` until `stop` completes, then wait at most `grace` for the
+    /// requests being answered. False if some were still being answered
+    #[cfg(unix)]
+    pub async fn run<T: SSHAgentHandler + 'static>(
+        handler: T,
+        listener: UnixListener,
+        stop: impl Future<Output = ()>,
+        grace: Duration,
+    ) -> bool {
         let arc_handler = Arc::new(Mutex::new(handler));
+        let shutdown = Arc::new(Shutdown::default());
+        let mut next_connection: ConnectionId = 0;
+        tokio::pin!(stop);
 
         // accept the connections and spawn a new task for each one
-        while let Some((stream, _)) = listener.accept().await.ok() {
-            match Agent::handle_client(arc_handler.clone(), stream).await {
-                Ok(_) => {}
-                Err(e) => debug!("handler: {:?}", e),
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        // e.g. out of file descriptors, which frees up as the other connections close
+                        warn!("accept failed: {}", e);
+                        tokio::select! {
+                            _ = tokio::time::sleep(ACCEPT_RETRY_DELAY) => continue,
+                            _ = &mut stop => break,
+                        }
+                    }
+                },
+                _ = &mut stop => break,
             };
+            let connection = next_connection;
+            next_connection += 1;
+
+            let peer = stream.peer_cred().ok().map(|cred| PeerCredentials {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            });
+            Agent::spawn_client(arc_handler.clone(), stream, peer, connection, shutdown.clone());
+        }
+        shutdown.close(grace).await
+    }
+
+    /// Serve the agent on a named pipe like `\\.\pipe\openssh-ssh-agent`, as OpenSSH for Windows expects,
+    /// until `stop` completes like `run`
+    #[cfg(windows)]
+    pub async fn run_named_pipe<T: SSHAgentHandler + 'static>(
+        handler: T,
+        pipe: &str,
+        stop: impl Future<Output = ()>,
+        grace: Duration,
+    ) -> std::io::Result<bool> {
+        Agent::serve_named_pipe(Arc::new(Mutex::new(handler)), pipe, stop, grace).await
+    }
+
+    /// Serve the named pipe, and the same keys to Pageant clients like PuTTY
+    #[cfg(windows)]
+    pub async fn run_named_pipe_and_pageant<T: SSHAgentHandler + 'static>(
+        handler: T,
+        pipe: &str,
+        stop: impl Future<Output = ()>,
+        grace: Duration,
+    ) -> std::io::Result<bool> {
+        let arc_handler = Arc::new(Mutex::new(handler));
+        // the Pageant requests count their connections apart from the pipe's
+        crate::pageant::spawn(arc_handler.clone(), ConnectionId::MAX / 2)?;
+        Agent::serve_named_pipe(arc_handler, pipe, stop, grace).await
+    }
+
+    #[cfg(windows)]
+    async fn serve_named_pipe<T: SSHAgentHandler + 'static>(
+        arc_handler: Arc<Mutex<T>>,
+        pipe: &str,
+        stop: impl Future<Output = ()>,
+        grace: Duration,
+    ) -> std::io::Result<bool> {
+        let shutdown = Arc::new(Shutdown::default());
+        let mut next_connection: ConnectionId = 0;
+        tokio::pin!(stop);
+
+        // fail if someone else, e.g. the Windows ssh-agent service, already serves the pipe
+        let mut server = ServerOptions::new().first_pipe_instance(true).create(pipe)?;
+        loop {
+            tokio::select! {
+                connected = server.connect() => connected?,
+                _ = &mut stop => break,
+            }
+            // a new instance for the next client, before handing this one off
+            let stream = std::mem::replace(&mut server, ServerOptions::new().create(pipe)?);
+
+            let connection = next_connection;
+            next_connection += 1;
+            Agent::spawn_client(arc_handler.clone(), stream, None, connection, shutdown.clone());
         }
+        Ok(shutdown.close(grace).await)
     }
 }
@@ -0,0 +1,141 @@
+//! The client side of the protocol, to drive a handler served with `Agent::connect_in_process`,
+//! e.g. from an integration test, the way ssh and ssh-add would
+//! https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::error::ParsingError;
+use crate::protocol::{read_data, read_message, read_string, Identity, MAX_MESSAGE_LEN};
+
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH2_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH2_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH2_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH2_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH2_AGENTC_ADD_IDENTITY: u8 = 17;
+const SSH2_AGENTC_REMOVE_IDENTITY: u8 = 18;
+const SSH2_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+const SSH_AGENTC_LOCK: u8 = 22;
+const SSH_AGENTC_UNLOCK: u8 = 23;
+const SSH_AGENTC_EXTENSION: u8 = 27;
+const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
+pub struct Client<S> {
+    stream: S,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    pub fn new(stream: S) -> Self {
+        Client { stream }
+    }
+
+    /// The keys of the agent
+    pub async fn request_identities(&mut self) -> ParsingError<Vec<Identity>> {
+        let reply = self.request(vec![SSH2_AGENTC_REQUEST_IDENTITIES]).await?;
+        let mut buf =
+            Self::contents(&reply, SSH2_AGENT_IDENTITIES_ANSWER)?.ok_or("the agent didn't list its keys")?;
+
+        let count = ReadBytesExt::read_u32::<BigEndian>(&mut buf)?;
+        let mut identities = vec![];
+        for _ in 0..count {
+            identities.push(Identity {
+                key_blob: read_data(&mut buf, MAX_MESSAGE_LEN)?,
+                key_comment: read_string(&mut buf, MAX_MESSAGE_LEN)?,
+            });
+        }
+        Ok(identities)
+    }
+
+    /// The signature blob of `data` with the key of `pubkey_blob`, None if the agent refused
+    pub async fn sign(
+        &mut self,
+        pubkey_blob: &[u8],
+        data: &[u8],
+        flags: u32,
+    ) -> ParsingError<Option<Vec<u8>>> {
+        let mut message = vec![SSH2_AGENTC_SIGN_REQUEST];
+        write_data(&mut message, pubkey_blob)?;
+        write_data(&mut message, data)?;
+        WriteBytesExt::write_u32::<BigEndian>(&mut message, flags)?;
+
+        let reply = self.request(message).await?;
+        match Self::contents(&reply, SSH2_AGENT_SIGN_RESPONSE)? {
+            Some(mut buf) => Ok(Some(read_data(&mut buf, MAX_MESSAGE_LEN)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Add a key, `key_contents` being everything after its type, comment and constraints included
+    pub async fn add_identity(&mut self, key_type: &str, key_contents: &[u8]) -> ParsingError<bool> {
+        let mut message = vec![SSH2_AGENTC_ADD_IDENTITY];
+        write_data(&mut message, key_type.as_bytes())?;
+        message.extend_from_slice(key_contents);
+        self.succeeds(message).await
+    }
+
+    pub async fn remove_identity(&mut self, pubkey_blob: &[u8]) -> ParsingError<bool> {
+        let mut message = vec![SSH2_AGENTC_REMOVE_IDENTITY];
+        write_data(&mut message, pubkey_blob)?;
+        self.succeeds(message).await
+    }
+
+    pub async fn remove_all_identities(&mut self) -> ParsingError<bool> {
+        self.succeeds(vec![SSH2_AGENTC_REMOVE_ALL_IDENTITIES]).await
+    }
+
+    pub async fn lock(&mut self, passphrase: &[u8]) -> ParsingError<bool> {
+        let mut message = vec![SSH_AGENTC_LOCK];
+        write_data(&mut message, passphrase)?;
+        self.succeeds(message).await
+    }
+
+    pub async fn unlock(&mut self, passphrase: &[u8]) -> ParsingError<bool> {
+        let mut message = vec![SSH_AGENTC_UNLOCK];
+        write_data(&mut message, passphrase)?;
+        self.succeeds(message).await
+    }
+
+    /// The extension specific contents of the agent's answer, None if it refused
+    pub async fn extension(
+        &mut self,
+        extension_type: &str,
+        contents: &[u8],
+    ) -> ParsingError<Option<Vec<u8>>> {
+        let mut message = vec![SSH_AGENTC_EXTENSION];
+        write_data(&mut message, extension_type.as_bytes())?;
+        message.extend_from_slice(contents);
+
+        let reply = self.request(message).await?;
+        Ok(Self::contents(&reply, SSH_AGENT_SUCCESS)?.map(<[u8]>::to_vec))
+    }
+
+    async fn succeeds(&mut self, message: Vec<u8>) -> ParsingError<bool> {
+        let reply = self.request(message).await?;
+        Ok(Self::contents(&reply, SSH_AGENT_SUCCESS)?.is_some())
+    }
+
+    /// Send a message as is, e.g. one that was recorded, and read the agent's answer
+    pub async fn request(&mut self, message: Vec<u8>) -> ParsingError<Vec<u8>> {
+        self.stream.write_u32(message.len() as u32).await?;
+        self.stream.write_all(&message).await?;
+        read_message(&mut self.stream).await
+    }
+
+    /// what follows the type of `reply` if it is `expected`, None for SSH_AGENT_FAILURE and
+    /// SSH_AGENT_EXTENSION_FAILURE
+    fn contents(reply: &[u8], expected: u8) -> ParsingError<Option<&[u8]>> {
+        match reply.split_first() {
+            Some((&kind, contents)) if kind == expected => Ok(Some(contents)),
+            Some((&(SSH_AGENT_FAILURE | SSH_AGENT_EXTENSION_FAILURE), _)) => Ok(None),
+            Some((kind, _)) => Err(format!("unexpected answer {} from the agent", kind).into()),
+            None => Err("empty answer from the agent".into()),
+        }
+    }
+}
+
+fn write_data(buf: &mut Vec<u8>, data: &[u8]) -> std::io::Result<()> {
+    WriteBytesExt::write_u32::<BigEndian>(buf, data.len() as u32)?;
+    std::io::Write::write_all(buf, data)
+}
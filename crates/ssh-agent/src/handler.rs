@@ -3,35 +3,108 @@ use crate::protocol::Response;
 
 use crate::error::HandleResult;
 use async_trait::async_trait;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Identifies a client connection for the lifetime of its socket
+pub type ConnectionId = u64;
+
+/// The process on the other end of a connection (SO_PEERCRED / getpeereid)
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// not available on every platform
+    pub pid: Option<i32>,
+}
+
+/// A response that is still being worked on, e.g. waiting for a user's approval
+pub type PendingResponse = Pin<Box<dyn Future<Output = HandleResult<Response>> + Send>>;
+
+/// What a handler answers a request with
+pub enum Reply {
+    /// answer right away
+    Now(Response),
+    /// answer once the future completes, the handler is free to
+    /// serve other connections in the meantime
+    Later(PendingResponse),
+}
+
+impl From<Response> for Reply {
+    fn from(response: Response) -> Self {
+        Reply::Now(response)
+    }
+}
 
 #[async_trait]
 pub trait SSHAgentHandler: Send + Sync {
     async fn identities(&mut self) -> HandleResult<Response>;
     async fn sign_request(
         &mut self,
+        connection: ConnectionId,
         pubkey: Vec<u8>,
         data: Vec<u8>,
         flags: u32,
-    ) -> HandleResult<Response>;
+    ) -> HandleResult<Reply>;
+    /// May answer `Later`, e.g. after asking the user for a passphrase
     async fn add_identity(
         &mut self,
         key_type: String,
         key_contents: Vec<u8>,
+    ) -> HandleResult<Reply>;
+    async fn remove_identity(&mut self, pubkey: Vec<u8>) -> HandleResult<Response>;
+    async fn remove_all_identities(&mut self) -> HandleResult<Response>;
+    async fn add_smartcard_key(&mut self, provider: String, pin: Vec<u8>) -> HandleResult<Response>;
+    async fn remove_smartcard_key(&mut self, provider: String, pin: Vec<u8>) -> HandleResult<Response>;
+    /// May answer `Later`, e.g. once the passphrase is hashed
+    async fn lock(&mut self, passphrase: Vec<u8>) -> HandleResult<Reply>;
+    async fn unlock(&mut self, passphrase: Vec<u8>) -> HandleResult<Reply>;
+    async fn extension(
+        &mut self,
+        connection: ConnectionId,
+        extension_type: String,
+        contents: Vec<u8>,
     ) -> HandleResult<Response>;
+    /// Called before the first request of a new connection, which is hung up on unless this returns true
+    async fn connection_opened(&mut self, connection: ConnectionId, peer: Option<PeerCredentials>) -> bool;
+    /// Drop any state kept for a connection once the client hung up
+    async fn connection_closed(&mut self, connection: ConnectionId);
 
-    async fn handle_request(&mut self, request: Request) -> HandleResult<Response> {
-        match request {
-            Request::RequestIdentities => self.identities().await,
-            Request::SignRequest {
-                pubkey_blob,
-                data,
-                flags,
-            } => self.sign_request(pubkey_blob, data, flags).await,
-            Request::AddIdentity {
-                key_type,
-                key_contents,
-            } => self.add_identity(key_type, key_contents).await,
-            Request::Unknown => Ok(Response::Failure),
-        }
+    async fn handle_request(&mut self, connection: ConnectionId, request: Request) -> HandleResult<Reply> {
+        dispatch(self, connection, request).await
     }
 }
+
+/// Answer `request` with the method of `handler` it is for. Handlers that override `handle_request`,
+/// e.g. to log every request, call this to answer it
+pub async fn dispatch<H: SSHAgentHandler + ?Sized>(
+    handler: &mut H,
+    connection: ConnectionId,
+    request: Request,
+) -> HandleResult<Reply> {
+    let response = match request {
+        Request::RequestIdentities => handler.identities().await,
+        Request::SignRequest {
+            pubkey_blob,
+            data,
+            flags,
+        } => return handler.sign_request(connection, pubkey_blob, data, flags).await,
+        Request::AddIdentity {
+            key_type,
+            key_contents,
+        } => return handler.add_identity(key_type, key_contents).await,
+        Request::RemoveIdentity { pubkey_blob } => handler.remove_identity(pubkey_blob).await,
+        Request::RemoveAllIdentities => handler.remove_all_identities().await,
+        Request::AddSmartcardKey { provider, pin } => handler.add_smartcard_key(provider, pin).await,
+        Request::RemoveSmartcardKey { provider, pin } => handler.remove_smartcard_key(provider, pin).await,
+        Request::Lock { passphrase } => return handler.lock(passphrase).await,
+        Request::Unlock { passphrase } => return handler.unlock(passphrase).await,
+        Request::Extension {
+            extension_type,
+            contents,
+        } => handler.extension(connection, extension_type, contents).await,
+        Request::Unknown => Ok(Response::Failure),
+    };
+
+    response.map(Reply::Now)
+}
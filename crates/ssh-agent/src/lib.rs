@@ -4,11 +4,21 @@ extern crate byteorder;
 extern crate tokio;
 
 mod agent;
+mod client;
 mod protocol;
 mod handler;
+mod shutdown;
 pub mod error;
+#[cfg(windows)]
+mod pageant;
 
 pub use handler::SSHAgentHandler;
 pub use agent::Agent;
+pub use client::Client;
+pub use protocol::Request;
 pub use protocol::Response;
-pub use protocol::Identity;
\ No newline at end of file
+pub use protocol::Identity;
+pub use protocol::{MAX_MESSAGE_LEN, MAX_NAME_LEN, MAX_PROVIDER_LEN};
+pub use handler::ConnectionId;
+pub use handler::PeerCredentials;
+pub use handler::{dispatch, PendingResponse, Reply};
\ No newline at end of file
@@ -0,0 +1,267 @@
+//! The Pageant protocol PuTTY, WinSCP and FileZilla speak instead of the agent pipe
+//!
+//! A client finds the hidden window of class "Pageant" and sends it a WM_COPYDATA message naming a file
+//! mapping it created. The mapping holds one agent protocol request, which the reply overwrites before
+//! the message returns.
+
+use std::ffi::{c_void, CStr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Handle;
+use tokio::sync::Mutex;
+use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, HWND, LPARAM, LRESULT, PSID, WPARAM};
+use windows_sys::Win32::Security::Authorization::{GetSecurityInfo, SE_KERNEL_OBJECT};
+use windows_sys::Win32::Security::{
+    EqualSid, GetTokenInformation, TokenUser, OWNER_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR, TOKEN_QUERY,
+    TOKEN_USER,
+};
+use windows_sys::Win32::System::DataExchange::COPYDATASTRUCT;
+use windows_sys::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows_sys::Win32::System::Memory::{
+    LocalFree, MapViewOfFile, OpenFileMappingA, UnmapViewOfFile, VirtualQuery, FILE_MAP_WRITE,
+    MEMORY_BASIC_INFORMATION,
+};
+use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+use windows_sys::Win32::UI::WindowsAndMessaging::{
+    CreateWindowExW, DefWindowProcW, DispatchMessageW, FindWindowW, GetMessageW, RegisterClassW,
+    TranslateMessage, MSG, WM_COPYDATA, WNDCLASSW,
+};
+
+use crate::handler::{ConnectionId, Reply, SSHAgentHandler};
+use crate::protocol::{Request, Response};
+
+/// `dwData` of the WM_COPYDATA messages from Pageant clients
+const AGENT_COPYDATA_ID: usize = 0x804e_50ba;
+/// the size of the mapping clients create, requests and replies included
+const AGENT_MAX_MSGLEN: usize = 8192;
+
+/// answers a request, from the raw message in the mapping to the raw reply
+type Serve = Box<dyn Fn(Vec<u8>) -> Option<Vec<u8>> + Send + Sync>;
+
+/// the window procedure has no way to carry state, there's a single Pageant window per process
+static SERVE: OnceLock<Serve> = OnceLock::new();
+
+/// Serve `handler` to Pageant clients until the process exits, on a thread of its own for the message loop
+pub fn spawn<T: SSHAgentHandler + 'static>(
+    handler: Arc<Mutex<T>>,
+    first_connection: ConnectionId,
+) -> std::io::Result<()> {
+    let class = wide("Pageant");
+    if unsafe { FindWindowW(class.as_ptr(), class.as_ptr()) } != 0 {
+        return Err(std::io::Error::other("Pageant is already running"));
+    }
+
+    let runtime = Handle::current();
+    let next_connection = AtomicU64::new(first_connection);
+    let serve: Serve = Box::new(move |message| {
+        let connection = next_connection.fetch_add(1, Ordering::Relaxed);
+        runtime.block_on(respond(handler.clone(), connection, message))
+    });
+    if SERVE.set(serve).is_err() {
+        return Err(std::io::Error::other("Pageant is already being served"));
+    }
+
+    std::thread::spawn(move || {
+        if let Err(e) = run_window(&class) {
+            error!("pageant: {}", e);
+        }
+    });
+    Ok(())
+}
+
+/// every request is a connection of its own, Pageant clients keep no session
+async fn respond<T: SSHAgentHandler>(
+    handler: Arc<Mutex<T>>,
+    connection: ConnectionId,
+    message: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let request = match Request::read(&mut message.as_slice()).await {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("malformed pageant request: {:?}", e);
+            let mut reply = vec![];
+            Response::Failure.write(&mut reply).await.ok()?;
+            return Some(reply);
+        }
+    };
+    debug!("pageant request: {:?}", request);
+
+    if !handler.lock().await.connection_opened(connection, None).await {
+        return None;
+    }
+    let reply = handler.lock().await.handle_request(connection, request).await;
+    let response = match reply {
+        Ok(Reply::Now(response)) => Ok(response),
+        Ok(Reply::Later(pending)) => pending.await,
+        Err(e) => Err(e),
+    };
+    handler.lock().await.connection_closed(connection).await;
+
+    let mut reply = vec![];
+    response
+        .unwrap_or(Response::Failure)
+        .write(&mut reply)
+        .await
+        .ok()?;
+    Some(reply)
+}
+
+fn run_window(class: &[u16]) -> std::io::Result<()> {
+    unsafe {
+        let instance = GetModuleHandleW(std::ptr::null());
+        let window_class = WNDCLASSW {
+            style: 0,
+            lpfnWndProc: Some(window_proc),
+            cbClsExtra: 0,
+            cbWndExtra: 0,
+            hInstance: instance,
+            hIcon: 0,
+            hCursor: 0,
+            hbrBackground: 0,
+            lpszMenuName: std::ptr::null(),
+            lpszClassName: class.as_ptr(),
+        };
+        if RegisterClassW(&window_class) == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // an invisible top level window, clients look for it with FindWindow which skips message-only ones
+        let window = CreateWindowExW(
+            0,
+            class.as_ptr(),
+            class.as_ptr(),
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            instance,
+            std::ptr::null(),
+        );
+        if window == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut message: MSG = std::mem::zeroed();
+        while GetMessageW(&mut message, 0, 0, 0) > 0 {
+            TranslateMessage(&message);
+            DispatchMessageW(&message);
+        }
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn window_proc(window: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    if message != WM_COPYDATA {
+        return DefWindowProcW(window, message, wparam, lparam);
+    }
+
+    let data = &*(lparam as *const COPYDATASTRUCT);
+    if data.dwData != AGENT_COPYDATA_ID || data.lpData.is_null() {
+        return 0;
+    }
+    // the name of the mapping, nul terminated
+    let name = match CStr::from_ptr(data.lpData as *const _).to_str() {
+        Ok(name) => name.to_string(),
+        Err(_) => return 0,
+    };
+    match answer(&name) {
+        true => 1,
+        false => 0,
+    }
+}
+
+/// Replace the request in the mapping called `name` with its reply
+unsafe fn answer(name: &str) -> bool {
+    let Ok(c_name) = std::ffi::CString::new(name) else {
+        return false;
+    };
+    let mapping = OpenFileMappingA(FILE_MAP_WRITE, 0, c_name.as_ptr() as *const u8);
+    if mapping == 0 {
+        return false;
+    }
+
+    let answered = (|| {
+        // like Pageant, only serve the processes of our own user
+        if !owned_by_current_user(mapping) {
+            warn!("pageant: refused a request from a mapping of another user");
+            return false;
+        }
+
+        let view = MapViewOfFile(mapping, FILE_MAP_WRITE, 0, 0, 0);
+        if view == 0 {
+            return false;
+        }
+        // the client picks the size of the mapping, don't trust it to be AGENT_MAX_MSGLEN
+        let mut info: MEMORY_BASIC_INFORMATION = std::mem::zeroed();
+        let size = match VirtualQuery(view as *const c_void, &mut info, std::mem::size_of_val(&info)) {
+            0 => 0,
+            _ => info.RegionSize.min(AGENT_MAX_MSGLEN),
+        };
+        if size < 4 {
+            UnmapViewOfFile(view);
+            return false;
+        }
+        let buffer = std::slice::from_raw_parts_mut(view as *mut u8, size);
+
+        let len = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+        let reply = match len + 4 <= size {
+            true => SERVE.get().and_then(|serve| serve(buffer[..len + 4].to_vec())),
+            false => None,
+        };
+        let fits = reply.as_ref().is_some_and(|reply| reply.len() <= size);
+        if let (Some(reply), true) = (&reply, fits) {
+            buffer[..reply.len()].copy_from_slice(reply);
+        }
+
+        UnmapViewOfFile(view);
+        fits
+    })();
+
+    CloseHandle(mapping);
+    answered
+}
+
+unsafe fn owned_by_current_user(mapping: HANDLE) -> bool {
+    let mut owner: PSID = std::ptr::null_mut();
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    if GetSecurityInfo(
+        mapping,
+        SE_KERNEL_OBJECT,
+        OWNER_SECURITY_INFORMATION,
+        &mut owner,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        &mut descriptor,
+    ) != 0
+    {
+        return false;
+    }
+
+    let mut token: HANDLE = 0;
+    let mut user = vec![0u8; 256];
+    let mut len = 0;
+    let same = OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) != 0
+        && GetTokenInformation(
+            token,
+            TokenUser,
+            user.as_mut_ptr() as *mut c_void,
+            user.len() as u32,
+            &mut len,
+        ) != 0
+        && EqualSid(owner, (*(user.as_ptr() as *const TOKEN_USER)).User.Sid) != 0;
+
+    if token != 0 {
+        CloseHandle(token);
+    }
+    LocalFree(descriptor as isize);
+    same
+}
+
+/// a nul terminated UTF-16 string
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
@@ -1,7 +1,5 @@
 use std::io::{self, Write};
 
-use tokio::net::UnixStream;
-
 use crate::error::{ParsingError, WritingError};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -43,35 +41,63 @@ impl MessageRequest {
     }
 }
 
-async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> ParsingError<Vec<u8>> {
-    let len = stream.read_u32().await?;
-
-    let mut buf = vec![0; len as usize];
-    stream.read_exact(&mut buf).await?;
+/// The longest message read, the same as OpenSSH's agent (AGENT_MAX_LEN)
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024;
+/// The longest key type or extension name, RFC 4251 limits names to 64 characters
+pub const MAX_NAME_LEN: usize = 64;
+/// The longest path of a PKCS#11 provider, PATH_MAX
+pub const MAX_PROVIDER_LEN: usize = 4096;
 
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(stream: &mut R) -> ParsingError<Vec<u8>> {
+    let mut buf = Vec::new();
+    read_message_into(stream, &mut buf).await?;
     Ok(buf)
 }
 
-async fn read_string<R: AsyncRead + Unpin>(stream: &mut R) -> ParsingError<String> {
-    let len = stream.read_u32().await?;
+/// Read a message into `buf`, replacing what it held. Its allocation is kept, the messages of a connection
+/// only grow it to the longest of them
+pub(crate) async fn read_message_into<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buf: &mut Vec<u8>,
+) -> ParsingError<()> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(format!("message of {} bytes is longer than {}", len, MAX_MESSAGE_LEN).into());
+    }
 
-    let mut buf = vec![0; len as usize];
-    stream.read_exact(&mut buf).await?;
+    buf.clear();
+    buf.reserve(len);
+    // straight into the spare capacity, without zeroing it first
+    stream.take(len as u64).read_to_end(buf).await?;
+    if buf.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
 
-    if buf.is_empty() {
-        return Ok(String::new());
+/// A string of at most `max` bytes from a message, its length is checked against what's left
+/// before anything is allocated
+pub(crate) fn read_data(buf: &mut &[u8], max: usize) -> ParsingError<Vec<u8>> {
+    let len = ReadBytesExt::read_u32::<BigEndian>(buf)? as usize;
+    if len > max {
+        return Err(format!("field of {} bytes is longer than {}", len, max).into());
+    }
+    if len > buf.len() {
+        return Err(format!("field of {} bytes with only {} left", len, buf.len()).into());
     }
-    let res = std::str::from_utf8(&buf).map_err(|_| crate::error::Error {
-        details: "invalid type found, expected string".into(),
-    })?;
 
-    Ok(res.to_string())
+    let (data, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(data.to_vec())
 }
 
-async fn write_message<W: AsyncWrite + Unpin>(w: &mut W, string: &[u8]) -> WritingError<()> {
-    w.write_u32(string.len() as u32).await?;
-    w.write_all(string).await?;
-    Ok(())
+pub(crate) fn read_string(buf: &mut &[u8], max: usize) -> ParsingError<String> {
+    let data = read_data(buf, max)?;
+    let res = String::from_utf8(data).map_err(|_| crate::error::Error {
+        details: "invalid type found, expected string".into(),
+    })?;
+
+    Ok(res)
 }
 
 /// This is used to format a SSH signature.
@@ -96,24 +122,139 @@ pub enum Request {
         // Request flags.
         flags: u32,
     },
+    RemoveIdentity {
+        // Blob of the public key to remove
+        pubkey_blob: Vec<u8>,
+    },
+    RemoveAllIdentities,
+    /// `ssh-add -s`, the keys of a PKCS#11 provider. The constraints of
+    /// SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED aren't kept
+    AddSmartcardKey {
+        // the provider, `ssh-add` resolves it to an absolute path
+        provider: String,
+        pin: Vec<u8>,
+    },
+    /// `ssh-add -e`
+    RemoveSmartcardKey {
+        provider: String,
+        pin: Vec<u8>,
+    },
+    Lock {
+        passphrase: Vec<u8>,
+    },
+    Unlock {
+        passphrase: Vec<u8>,
+    },
+    Extension {
+        // e.g. "session-bind@openssh.com"
+        extension_type: String,
+        // extension specific contents
+        contents: Vec<u8>,
+    },
     Unknown,
 }
 impl Request {
-    pub async fn read(stream: &mut UnixStream) -> ParsingError<Self> {
+    /// the name of the message in draft-miller-ssh-agent, e.g. "sign_request"
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::RequestIdentities => "request_identities",
+            Request::AddIdentity { .. } => "add_identity",
+            Request::SignRequest { .. } => "sign_request",
+            Request::RemoveIdentity { .. } => "remove_identity",
+            Request::RemoveAllIdentities => "remove_all_identities",
+            Request::AddSmartcardKey { .. } => "add_smartcard_key",
+            Request::RemoveSmartcardKey { .. } => "remove_smartcard_key",
+            Request::Lock { .. } => "lock",
+            Request::Unlock { .. } => "unlock",
+            Request::Extension { .. } => "extension",
+            Request::Unknown => "unknown",
+        }
+    }
+
+    /// The message of the request, without its length, None for `Unknown` requests as their type isn't kept.
+    /// Keys added with constraints come out as SSH_AGENTC_ADD_IDENTITY, the constraints stay in their contents
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self {
+            Request::RequestIdentities => buf.push(11),
+            Request::AddIdentity {
+                key_type,
+                key_contents,
+            } => {
+                buf.push(17);
+                write_string(&mut buf, key_type.as_bytes()).ok()?;
+                buf.extend_from_slice(key_contents);
+            }
+            Request::SignRequest {
+                pubkey_blob,
+                data,
+                flags,
+            } => {
+                buf.push(13);
+                write_string(&mut buf, pubkey_blob).ok()?;
+                write_string(&mut buf, data).ok()?;
+                WriteBytesExt::write_u32::<BigEndian>(&mut buf, *flags).ok()?;
+            }
+            Request::RemoveIdentity { pubkey_blob } => {
+                buf.push(18);
+                write_string(&mut buf, pubkey_blob).ok()?;
+            }
+            Request::RemoveAllIdentities => buf.push(19),
+            Request::AddSmartcardKey { provider, pin } => {
+                buf.push(20);
+                write_string(&mut buf, provider.as_bytes()).ok()?;
+                write_string(&mut buf, pin).ok()?;
+            }
+            Request::RemoveSmartcardKey { provider, pin } => {
+                buf.push(21);
+                write_string(&mut buf, provider.as_bytes()).ok()?;
+                write_string(&mut buf, pin).ok()?;
+            }
+            Request::Lock { passphrase } => {
+                buf.push(22);
+                write_string(&mut buf, passphrase).ok()?;
+            }
+            Request::Unlock { passphrase } => {
+                buf.push(23);
+                write_string(&mut buf, passphrase).ok()?;
+            }
+            Request::Extension {
+                extension_type,
+                contents,
+            } => {
+                buf.push(27);
+                write_string(&mut buf, extension_type.as_bytes()).ok()?;
+                buf.extend_from_slice(contents);
+            }
+            Request::Unknown => return None,
+        }
+        Some(buf)
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(stream: &mut R) -> ParsingError<Self> {
+        Self::read_reusing(stream, &mut Vec::new()).await
+    }
+
+    /// `read` with `buf` to hold the message while it's parsed, the one buffer of a connection saves an
+    /// allocation per request
+    pub async fn read_reusing<R: AsyncRead + Unpin>(stream: &mut R, buf: &mut Vec<u8>) -> ParsingError<Self> {
         debug!("reading request");
-        let raw_msg = read_message(stream).await?;
-        let mut buf = raw_msg.as_slice();
+        read_message_into(stream, buf).await?;
+        Self::parse(buf)
+    }
 
+    /// A request from its message, without its length
+    pub fn parse(mut buf: &[u8]) -> ParsingError<Self> {
         let msg = ReadBytesExt::read_u8(&mut buf)?;
         match MessageRequest::from_u8(msg) {
             MessageRequest::RequestIdentities => Ok(Request::RequestIdentities),
             MessageRequest::SignRequest => Ok(Request::SignRequest {
-                pubkey_blob: read_message(&mut buf).await?,
-                data: read_message(&mut buf).await?,
+                pubkey_blob: read_data(&mut buf, MAX_MESSAGE_LEN)?,
+                data: read_data(&mut buf, MAX_MESSAGE_LEN)?,
                 flags: ReadBytesExt::read_u32::<BigEndian>(&mut buf)?,
             }),
             MessageRequest::AddIdentity | MessageRequest::AddIdConstrained => {
-                let key_type = read_string(&mut buf).await?;
+                let key_type = read_string(&mut buf, MAX_NAME_LEN)?;
                 let key_contents = buf.to_vec();
 
                 Ok(Request::AddIdentity {
@@ -121,14 +262,30 @@ impl Request {
                     key_contents,
                 })
             }
-            MessageRequest::RemoveIdentity => Ok(Request::Unknown),
-            MessageRequest::RemoveAllIdentities => Ok(Request::Unknown),
-            MessageRequest::AddSmartcardKey => Ok(Request::Unknown),
-            MessageRequest::RemoveSmartcardKey => Ok(Request::Unknown),
-            MessageRequest::Lock => Ok(Request::Unknown),
-            MessageRequest::Unlock => Ok(Request::Unknown),
-            MessageRequest::AddSmartcardKeyConstrained => Ok(Request::Unknown),
-            MessageRequest::Extension => Ok(Request::Unknown),
+            MessageRequest::RemoveIdentity => Ok(Request::RemoveIdentity {
+                pubkey_blob: read_data(&mut buf, MAX_MESSAGE_LEN)?,
+            }),
+            MessageRequest::RemoveAllIdentities => Ok(Request::RemoveAllIdentities),
+            MessageRequest::AddSmartcardKey | MessageRequest::AddSmartcardKeyConstrained => {
+                Ok(Request::AddSmartcardKey {
+                    provider: read_string(&mut buf, MAX_PROVIDER_LEN)?,
+                    pin: read_data(&mut buf, MAX_MESSAGE_LEN)?,
+                })
+            }
+            MessageRequest::RemoveSmartcardKey => Ok(Request::RemoveSmartcardKey {
+                provider: read_string(&mut buf, MAX_PROVIDER_LEN)?,
+                pin: read_data(&mut buf, MAX_MESSAGE_LEN)?,
+            }),
+            MessageRequest::Lock => Ok(Request::Lock {
+                passphrase: read_data(&mut buf, MAX_MESSAGE_LEN)?,
+            }),
+            MessageRequest::Unlock => Ok(Request::Unlock {
+                passphrase: read_data(&mut buf, MAX_MESSAGE_LEN)?,
+            }),
+            MessageRequest::Extension => Ok(Request::Extension {
+                extension_type: read_string(&mut buf, MAX_NAME_LEN)?,
+                contents: buf.to_vec(),
+            }),
             MessageRequest::Unknown => {
                 debug!("Unknown request {}", msg);
                 Ok(Request::Unknown)
@@ -142,6 +299,7 @@ enum MessageResponse {
     AgentSuccess = 6,
     AgentIdentitiesAnswer = 12,
     AgentSignResponse = 14,
+    AgentExtensionFailure = 28,
 }
 
 #[derive(Debug, Clone)]
@@ -164,30 +322,41 @@ pub enum Response {
         /// Actual signature blob.
         signature: Vec<u8>,
     },
+    /// SSH_AGENT_SUCCESS followed by extension specific contents
+    Extension(Vec<u8>),
+    /// SSH_AGENT_EXTENSION_FAILURE, an extension the agent knows failed. Unknown extensions get `Failure`
+    ExtensionFailure,
 }
 
 impl Response {
-    pub async fn write(&self, stream: &mut UnixStream) -> WritingError<()> {
-        let mut buf = Vec::new();
+    pub async fn write<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> WritingError<()> {
+        stream.write_all(&self.encode()?).await?;
+        Ok(())
+    }
+
+    /// The message of the response with its length in front, as it goes out in one write
+    pub fn encode(&self) -> WritingError<Vec<u8>> {
+        let mut buf = Vec::with_capacity(4 + 1 + self.encoded_len_hint());
+        // the length, filled in once it's known
+        buf.extend_from_slice(&[0; 4]);
         match *self {
-            Response::Success => {
-                WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentSuccess as u8)?
-            }
-            Response::Failure => {
-                WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentFailure as u8)?
+            Response::Success => WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentSuccess as u8)?,
+            Response::Failure => WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentFailure as u8)?,
+            Response::ExtensionFailure => {
+                WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentExtensionFailure as u8)?
             }
             Response::Identities(ref identities) => {
                 WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentIdentitiesAnswer as u8)?;
                 WriteBytesExt::write_u32::<BigEndian>(&mut buf, identities.len() as u32)?;
 
                 for identity in identities {
-                    write_message(&mut buf, &identity.key_blob).await?;
-                    write_message(&mut buf, &identity.key_comment.as_bytes()).await?;
+                    write_string(&mut buf, &identity.key_blob)?;
+                    write_string(&mut buf, identity.key_comment.as_bytes())?;
                 }
             }
             Response::SignResponse { ref signature } => {
                 WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentSignResponse as u8)?;
-                write_message(&mut buf, signature.as_slice()).await?;
+                write_string(&mut buf, signature.as_slice())?;
             }
 
             Response::SignResponse2 {
@@ -196,15 +365,172 @@ impl Response {
             } => {
                 WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentSignResponse as u8)?;
 
-                let mut full_sig = Vec::new();
-                write_string(&mut full_sig, algo_name.as_bytes())?;
-                write_string(&mut full_sig, signature)?;
+                // the signature blob is a string of the algorithm and signature strings
+                let len = 4 + algo_name.len() + 4 + signature.len();
+                WriteBytesExt::write_u32::<BigEndian>(&mut buf, len as u32)?;
+                write_string(&mut buf, algo_name.as_bytes())?;
+                write_string(&mut buf, signature)?;
+            }
+            Response::Extension(ref contents) => {
+                WriteBytesExt::write_u8(&mut buf, MessageResponse::AgentSuccess as u8)?;
+                buf.extend_from_slice(contents);
+            }
+        }
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        Ok(buf)
+    }
 
-                write_string(&mut buf, &full_sig)?;
+    /// about how long the message is after its type, for the first allocation
+    fn encoded_len_hint(&self) -> usize {
+        match self {
+            Response::Success | Response::Failure | Response::ExtensionFailure => 0,
+            Response::Identities(identities) => {
+                4 + identities
+                    .iter()
+                    .map(|identity| 8 + identity.key_blob.len() + identity.key_comment.len())
+                    .sum::<usize>()
             }
+            Response::SignResponse { signature } => 4 + signature.len(),
+            Response::SignResponse2 { algo_name, signature } => 12 + algo_name.len() + signature.len(),
+            Response::Extension(contents) => contents.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// the properties hold for this many inputs of a fixed seed, proptest isn't a dependency
+    const CASES: usize = 500;
+
+    fn bytes(rng: &mut StdRng, max: usize) -> Vec<u8> {
+        let len = rng.gen_range(0..=max);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn text(rng: &mut StdRng, max: usize) -> String {
+        let len = rng.gen_range(0..=max);
+        (0..len).map(|_| rng.gen_range('!'..='~')).collect()
+    }
+
+    fn request(rng: &mut StdRng) -> Request {
+        match rng.gen_range(0..10) {
+            0 => Request::RequestIdentities,
+            1 => Request::AddIdentity {
+                key_type: text(rng, MAX_NAME_LEN),
+                key_contents: bytes(rng, 300),
+            },
+            2 => Request::SignRequest {
+                pubkey_blob: bytes(rng, 300),
+                data: bytes(rng, 300),
+                flags: rng.gen(),
+            },
+            3 => Request::RemoveIdentity {
+                pubkey_blob: bytes(rng, 300),
+            },
+            4 => Request::RemoveAllIdentities,
+            5 => Request::AddSmartcardKey {
+                provider: text(rng, 100),
+                pin: bytes(rng, 10),
+            },
+            6 => Request::RemoveSmartcardKey {
+                provider: text(rng, 100),
+                pin: bytes(rng, 10),
+            },
+            7 => Request::Lock {
+                passphrase: bytes(rng, 50),
+            },
+            8 => Request::Unlock {
+                passphrase: bytes(rng, 50),
+            },
+            _ => Request::Extension {
+                extension_type: text(rng, MAX_NAME_LEN),
+                contents: bytes(rng, 300),
+            },
+        }
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..CASES {
+            let request = request(&mut rng);
+            let message = request.encode().unwrap();
+            let parsed = Request::parse(&message).unwrap();
+            assert_eq!(parsed.name(), request.name());
+            // Request isn't PartialEq, its encoding is compared instead
+            assert_eq!(parsed.encode().unwrap(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_read_back_with_their_length() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut buf = Vec::new();
+        for _ in 0..CASES {
+            let message = request(&mut rng).encode().unwrap();
+            let mut framed = (message.len() as u32).to_be_bytes().to_vec();
+            framed.extend_from_slice(&message);
+            let parsed = Request::read_reusing(&mut framed.as_slice(), &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(parsed.encode().unwrap(), message);
+
+            let cut = &framed[..rng.gen_range(0..framed.len())];
+            assert!(Request::read(&mut &cut[..]).await.is_err());
+        }
+    }
+
+    #[test]
+    fn cut_or_random_input_is_an_error() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..CASES {
+            let message = Request::SignRequest {
+                pubkey_blob: bytes(&mut rng, 300),
+                data: bytes(&mut rng, 300),
+                flags: rng.gen(),
+            }
+            .encode()
+            .unwrap();
+            assert!(Request::parse(&message[..rng.gen_range(0..message.len())]).is_err());
+
+            // anything goes, as long as it doesn't panic
+            let mut random = bytes(&mut rng, 200);
+            let _ = Request::parse(&random);
+            random.insert(0, rng.gen_range(11..=27));
+            let _ = Request::parse(&random);
+        }
+    }
+
+    #[test]
+    fn responses_start_with_their_length() {
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..CASES {
+            let response = match rng.gen_range(0..4) {
+                0 => Response::Identities(
+                    (0..rng.gen_range(0..4))
+                        .map(|_| Identity {
+                            key_blob: bytes(&mut rng, 300),
+                            key_comment: text(&mut rng, 40),
+                        })
+                        .collect(),
+                ),
+                1 => Response::SignResponse {
+                    signature: bytes(&mut rng, 100),
+                },
+                2 => Response::SignResponse2 {
+                    algo_name: text(&mut rng, MAX_NAME_LEN),
+                    signature: bytes(&mut rng, 100),
+                },
+                _ => Response::Extension(bytes(&mut rng, 300)),
+            };
+            let message = response.encode().unwrap();
+            let len = u32::from_be_bytes(message[..4].try_into().unwrap()) as usize;
+            assert_eq!(len, message.len() - 4);
         }
-        stream.write_u32(buf.len() as u32).await?;
-        stream.write_all(&buf).await?;
-        Ok(())
     }
 }
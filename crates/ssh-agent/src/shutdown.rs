@@ -0,0 +1,32 @@
+//! Stopping the agent without cutting off the requests it's in the middle of answering
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+/// Shared by the connections of an agent, each holds it while answering a request
+#[derive(Default)]
+pub(crate) struct Shutdown {
+    answering: RwLock<()>,
+    closed: AtomicBool,
+}
+
+impl Shutdown {
+    /// None once the agent is shutting down, the request is left unanswered then
+    pub(crate) async fn answer(&self) -> Option<RwLockReadGuard<'_, ()>> {
+        let answering = self.answering.read().await;
+        match self.closed.load(Ordering::SeqCst) {
+            true => None,
+            false => Some(answering),
+        }
+    }
+
+    /// Stop answering requests once the ones being answered are, waiting at most `grace` for them.
+    /// False if some were still being answered
+    pub(crate) async fn close(&self, grace: Duration) -> bool {
+        // requests read in the meantime wait behind this
+        let drained = tokio::time::timeout(grace, self.answering.write()).await;
+        self.closed.store(true, Ordering::SeqCst);
+        drained.is_ok()
+    }
+}